@@ -0,0 +1,205 @@
+//! Contract bytecode extraction (`--bytecode`).
+//!
+//! Writes every entry of reth's `Bytecodes` table into a second PIR database so clients can
+//! privately fetch code by the `bytecode_hash` stored in the account entry:
+//!
+//! - `code-database.bin`: code chunked into 32-byte words, last word zero-padded.
+//! - `code-mapping.bin`: a [`MappingHeader`] with 32-byte keys, then
+//!   `CodeHash (32) || StartIndex (4 or 8, LE) || Length in bytes (4, LE)` sorted by code hash.
+
+use crate::progress::{Phase, Progress};
+use eyre::Result;
+use reth_db::{cursor::DbCursorRO, database::Database, tables, transaction::DbTx};
+use state_syncer::mapping::{index_width_for, write_index, MappingHeader};
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Largest deployable code (EIP-170: 24576 bytes) in 32-byte words.
+const MAX_CODE_WORDS: u64 = 768;
+
+/// Counts produced by [`extract_bytecodes`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CodeTotals {
+    pub bytecodes: u64,
+    pub code_words: u64,
+}
+
+/// Writes `code-database.bin` and `code-mapping.bin`; codes must arrive sorted by hash.
+pub struct CodeWriter {
+    code_db: BufWriter<File>,
+    code_map: BufWriter<File>,
+    index_width: u32,
+    totals: CodeTotals,
+}
+
+impl CodeWriter {
+    /// Creates both files in `dir`; the mapping header is rewritten by [`finish`](Self::finish).
+    pub fn create(dir: &Path, index_width: u32) -> Result<Self> {
+        let code_db = BufWriter::new(File::create(dir.join("code-database.bin"))?);
+        let mut code_map = BufWriter::new(File::create(dir.join("code-mapping.bin"))?);
+        code_map.write_all(&MappingHeader::new(32, index_width, 0).encode())?;
+        Ok(Self {
+            code_db,
+            code_map,
+            index_width,
+            totals: CodeTotals::default(),
+        })
+    }
+
+    /// Appends `code` as zero-padded words and records where it starts.
+    pub fn write(&mut self, code_hash: &[u8], code: &[u8]) -> Result<()> {
+        for chunk in code.chunks(32) {
+            let mut word = [0u8; 32];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.code_db.write_all(&word)?;
+        }
+
+        // CodeHash (32) + StartIndex (index_width) + Length (4)
+        self.code_map.write_all(code_hash)?;
+        write_index(&mut self.code_map, self.totals.code_words, self.index_width)?;
+        write_index(&mut self.code_map, code.len() as u64, 4)?;
+
+        self.totals.code_words += code.len().div_ceil(32) as u64;
+        self.totals.bytecodes += 1;
+        Ok(())
+    }
+
+    /// Flushes both files and writes the final record count into the mapping header.
+    pub fn finish(mut self) -> Result<CodeTotals> {
+        self.code_db.flush()?;
+        let header = MappingHeader::new(32, self.index_width, self.totals.bytecodes);
+        self.code_map.seek(SeekFrom::Start(0))?;
+        self.code_map.write_all(&header.encode())?;
+        self.code_map.flush()?;
+        Ok(self.totals)
+    }
+}
+
+/// Walks the `Bytecodes` table in batches and writes the code database and mapping into
+/// `output_dir` (or only counts when `output_dir` is `None`).
+pub fn extract_bytecodes<DB: Database>(
+    db: &DB,
+    output_dir: Option<&Path>,
+    batch_size: usize,
+    progress: &Progress,
+) -> Result<CodeTotals> {
+    let mut writer = match output_dir {
+        Some(dir) => {
+            let entries = db.tx()?.entries::<tables::Bytecodes>()? as u64;
+            Some(CodeWriter::create(
+                dir,
+                index_width_for(entries * MAX_CODE_WORDS),
+            )?)
+        }
+        None => None,
+    };
+
+    let mut totals = CodeTotals::default();
+    let mut last_key = None;
+
+    loop {
+        let tx = db.tx()?;
+        let mut cursor = tx.cursor_read::<tables::Bytecodes>()?;
+
+        let mut batch_count = 0;
+        let mut current_key = None;
+
+        for entry in cursor.walk(last_key)? {
+            let (code_hash, bytecode) = entry?;
+            if last_key == Some(code_hash) {
+                continue;
+            }
+
+            let code = bytecode.original_bytes();
+
+            if let Some(writer) = writer.as_mut() {
+                writer.write(code_hash.as_slice(), code)?;
+            }
+
+            totals.code_words += code.len().div_ceil(32) as u64;
+            totals.bytecodes += 1;
+            batch_count += 1;
+            current_key = Some(code_hash);
+
+            if totals.bytecodes % 10000 == 0 {
+                progress.update(
+                    Phase::Bytecode,
+                    totals.bytecodes,
+                    format!("Code: {}", totals.bytecodes),
+                );
+            }
+
+            if batch_count >= batch_size {
+                break;
+            }
+        }
+
+        if batch_count == 0 {
+            break;
+        }
+        last_key = current_key;
+    }
+
+    if let Some(writer) = writer {
+        writer.finish()?;
+    }
+    progress.finish_phase(Phase::Bytecode, totals.bytecodes);
+
+    Ok(totals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state_syncer::mapping::{CodeLocation, CodeMapping};
+
+    #[test]
+    fn code_is_chunked_into_padded_words() {
+        let dir = std::env::temp_dir().join(format!("plinko-bytecode-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let codes: [(u8, Vec<u8>); 4] = [
+            (1, vec![0xaa; 33]),
+            (2, Vec::new()),
+            (3, vec![0xbb; 64]),
+            (4, vec![0xcc]),
+        ];
+        let mut writer = CodeWriter::create(&dir, 4).unwrap();
+        for (hash, code) in &codes {
+            writer.write(&[*hash; 32], code).unwrap();
+        }
+        let totals = writer.finish().unwrap();
+        assert_eq!(totals.bytecodes, 4);
+        assert_eq!(totals.code_words, 5);
+
+        let db = std::fs::read(dir.join("code-database.bin")).unwrap();
+        assert_eq!(db.len(), 5 * 32);
+        // 33 bytes take two words, the second holding one byte then zeros
+        assert_eq!(db[..33], [0xaa; 33]);
+        assert_eq!(db[33..64], [0; 31]);
+        assert_eq!(db[64..128], [0xbb; 64]);
+        assert_eq!(db[128], 0xcc);
+        assert_eq!(db[129..], [0; 31]);
+
+        let mapping = CodeMapping::open(dir.join("code-mapping.bin")).unwrap();
+        assert_eq!(mapping.len(), 4);
+        let starts: Vec<_> = codes
+            .iter()
+            .map(|(hash, code)| {
+                let location = mapping.get(&[*hash; 32]).unwrap();
+                assert_eq!(location.len, code.len() as u64);
+                location.start
+            })
+            .collect();
+        assert_eq!(starts, [0, 2, 2, 4]);
+        assert_eq!(
+            mapping.get(&[4; 32]),
+            Some(CodeLocation { start: 4, len: 1 })
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
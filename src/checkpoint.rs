@@ -0,0 +1,90 @@
+//! Crash-safe extraction checkpoints.
+//!
+//! After every batch the extractor syncs its outputs and records where it is in
+//! `checkpoint.json`. `--resume` truncates the outputs back to the recorded byte offsets and
+//! continues from the recorded cursor position, so the final artifacts are byte-identical to
+//! an uninterrupted run.
+
+use alloy_primitives::{Address, B256};
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use state_syncer::{layout::AccountLayout, metadata::FilterInfo};
+use std::path::{Path, PathBuf};
+
+const FILE_NAME: &str = "checkpoint.json";
+
+/// Which table the extractor was walking when the checkpoint was taken.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Accounts,
+    Storage,
+}
+
+/// Lengths of the artifact files at a consistent point.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileOffsets {
+    pub database: u64,
+    pub account_mapping: u64,
+    pub storage_mapping: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Checkpoint {
+    /// Block the snapshot is taken at; a resume must target the same block.
+    pub block: u64,
+    pub phase: Phase,
+    /// Last `PlainAccountState` key fully written (accounts phase).
+    pub last_account: Option<Address>,
+    /// Last `PlainStorageState` (address, slot) fully written (storage phase). When
+    /// rewinding, the slot is all ones: the address's later overlay slots were written too.
+    pub last_storage: Option<(Address, B256)>,
+    pub accounts: u64,
+    pub storage_slots: u64,
+    pub total_indices: u64,
+    pub offsets: FileOffsets,
+    /// Filters of the interrupted run; a resume must use the same ones.
+    #[serde(default)]
+    pub filter: Option<FilterInfo>,
+    /// Account layout of the interrupted run; a resume must use the same one.
+    #[serde(default)]
+    pub account_layout: AccountLayout,
+    /// `--storage-alignment` of the interrupted run; a resume must use the same one.
+    #[serde(default)]
+    pub storage_alignment: Option<u64>,
+}
+
+impl Checkpoint {
+    fn path(output_dir: &Path) -> PathBuf {
+        output_dir.join(FILE_NAME)
+    }
+
+    /// Loads the checkpoint from `output_dir`, if one exists.
+    pub fn load(output_dir: &Path) -> Result<Option<Self>> {
+        let path = Self::path(output_dir);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&std::fs::read(path)?)?))
+    }
+
+    /// Atomically replaces the checkpoint in `output_dir`.
+    ///
+    /// The caller must have synced the artifact files up to `self.offsets` first.
+    pub fn save(&self, output_dir: &Path) -> Result<()> {
+        let path = Self::path(output_dir);
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Removes the checkpoint after a completed extraction.
+    pub fn remove(output_dir: &Path) -> Result<()> {
+        let path = Self::path(output_dir);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
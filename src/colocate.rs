@@ -0,0 +1,347 @@
+//! Page-aligned storage runs (`--storage-alignment`) and the `colocation` report.
+//!
+//! Storage slots are written in (address, slot) order, so each contract's slots form one
+//! run of consecutive entries. Without alignment a run can straddle a page (or Plinko block)
+//! boundary even when it would fit inside one, and reading the contract then touches two
+//! pages. With an alignment, a run that would straddle a boundary is moved to the next one
+//! by writing zero padding entries in front of it; a run longer than a page starts on a
+//! boundary. The padding before a run is always shorter than the run, so at most doubles
+//! the storage entries, and typically costs far less.
+
+use crate::writer::{ArtifactWriter, Totals};
+use alloy_primitives::{Address, B256, U256};
+use eyre::{ensure, eyre, Result};
+use state_syncer::{
+    db::DB_ENTRY_SIZE,
+    mapping::StorageMapping,
+    metadata::{KeyOrder, Metadata},
+};
+use std::path::Path;
+
+/// Padding entries to write at `position` before a run of `count` entries, with pages of
+/// `words` entries: none if the run fits in the rest of the current page, otherwise up to
+/// the next boundary.
+pub fn padding_before(position: u64, count: u64, words: u64) -> u64 {
+    let offset = position % words;
+    if offset == 0 || offset + count <= words {
+        0
+    } else {
+        words - offset
+    }
+}
+
+/// Places storage slots, buffering each contract's run until its length decides whether
+/// it needs padding. At most one page of slots is buffered: a longer run is known to need a
+/// boundary and is streamed from there.
+pub struct Colocator {
+    /// Page size in entries; `None` writes slots straight through.
+    words: Option<u64>,
+    /// Address of the current run.
+    address: Option<Address>,
+    /// Slots of the current run not yet written.
+    pending: Vec<(B256, U256)>,
+    /// Whether the current run has been placed; its later slots follow directly.
+    placed: bool,
+    /// Address of the last run ended by [`Colocator::flush`], which no slot may extend.
+    flushed: Option<Address>,
+}
+
+impl Colocator {
+    /// Aligns runs to `alignment` bytes, or not at all when `None`.
+    pub fn new(alignment: Option<u64>) -> Self {
+        Self {
+            words: alignment.map(|bytes| bytes / DB_ENTRY_SIZE as u64),
+            address: None,
+            pending: Vec::new(),
+            placed: false,
+            flushed: None,
+        }
+    }
+
+    /// Adds the next slot in key order. `totals.storage_slots` counts it right away;
+    /// `totals.total_indices` advances as entries are written.
+    pub fn push(
+        &mut self,
+        mut writer: Option<&mut ArtifactWriter>,
+        totals: &mut Totals,
+        address: Address,
+        key: B256,
+        value: U256,
+    ) -> Result<()> {
+        totals.storage_slots += 1;
+        let Some(words) = self.words else {
+            return write_slot(writer, totals, address, &key, &value);
+        };
+
+        if self.address != Some(address) {
+            // A slot arriving after its run was placed could make the run straddle a page
+            ensure!(
+                self.flushed != Some(address),
+                "Storage slot {} of {} arrived after its run was written",
+                key,
+                address
+            );
+            self.place(writer.as_deref_mut(), totals)?;
+            self.address = Some(address);
+            self.placed = false;
+        }
+        if self.placed {
+            return write_slot(writer, totals, address, &key, &value);
+        }
+        self.pending.push((key, value));
+        if self.pending.len() as u64 > words {
+            self.place(writer, totals)?;
+        }
+        Ok(())
+    }
+
+    /// Ends the current run, writing what is buffered of it. Call only once every slot of
+    /// the run's address has been pushed: before a checkpoint (batches end on address
+    /// boundaries) and at the end.
+    pub fn flush(
+        &mut self,
+        writer: Option<&mut ArtifactWriter>,
+        totals: &mut Totals,
+    ) -> Result<()> {
+        self.place(writer, totals)?;
+        if self.address.is_some() {
+            self.flushed = self.address.take();
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered slots of the current run, padded as needed; its later slots
+    /// follow directly.
+    fn place(
+        &mut self,
+        mut writer: Option<&mut ArtifactWriter>,
+        totals: &mut Totals,
+    ) -> Result<()> {
+        let (Some(words), Some(address)) = (self.words, self.address) else {
+            return Ok(());
+        };
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let padding = padding_before(totals.total_indices, self.pending.len() as u64, words);
+        if let Some(writer) = writer.as_deref_mut() {
+            writer.write_padding(padding)?;
+        }
+        totals.total_indices += padding;
+
+        for (key, value) in std::mem::take(&mut self.pending) {
+            write_slot(writer.as_deref_mut(), totals, address, &key, &value)?;
+        }
+        self.placed = true;
+        Ok(())
+    }
+}
+
+fn write_slot(
+    writer: Option<&mut ArtifactWriter>,
+    totals: &mut Totals,
+    address: Address,
+    key: &B256,
+    value: &U256,
+) -> Result<()> {
+    if let Some(writer) = writer {
+        writer.write_storage(address.as_slice(), key, value, totals.total_indices)?;
+    }
+    totals.total_indices += 1;
+    Ok(())
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ColocationArgs {
+    /// Page size to evaluate, in bytes (4096 for memory pages, or the Plinko block size)
+    #[arg(long, default_value_t = 4096)]
+    alignment: u64,
+}
+
+/// Storage layout of a snapshot with and without `--storage-alignment`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub padding: u64,
+    /// Pages touched reading every contract's whole run once.
+    pub pages: u64,
+    /// Contracts that fit in one page but straddle two.
+    pub split: u64,
+}
+
+/// Padding overhead against read locality, for runs of the given lengths.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ColocationReport {
+    /// Page size in entries.
+    pub words: u64,
+    pub contracts: u64,
+    /// Contracts whose run fits in one page.
+    pub fitting: u64,
+    pub slots: u64,
+    pub unaligned: Layout,
+    pub aligned: Layout,
+}
+
+impl ColocationReport {
+    /// Lays out `runs` (slot counts per contract, in order) from index `start` both ways.
+    pub fn simulate(start: u64, words: u64, runs: impl IntoIterator<Item = u64>) -> Self {
+        let pages =
+            |position: u64, count: u64| (position + count - 1) / words - position / words + 1;
+        let mut report = Self {
+            words,
+            ..Default::default()
+        };
+        let (mut plain, mut aligned) = (start, start);
+        for count in runs.into_iter().filter(|&count| count > 0) {
+            let fits = count <= words;
+            report.contracts += 1;
+            report.fitting += fits as u64;
+            report.slots += count;
+
+            let padding = padding_before(aligned, count, words);
+            report.aligned.padding += padding;
+            aligned += padding;
+            for (layout, position) in [
+                (&mut report.unaligned, &mut plain),
+                (&mut report.aligned, &mut aligned),
+            ] {
+                let touched = pages(*position, count);
+                layout.pages += touched;
+                layout.split += (fits && touched > 1) as u64;
+                *position += count;
+            }
+        }
+        report
+    }
+
+    /// Prints the comparison.
+    pub fn print(&self, total_indices: u64) {
+        let percent = |part: u64, whole: u64| 100.0 * part as f64 / whole.max(1) as f64;
+        println!(
+            "Storage colocation, {}-byte pages ({} entries):",
+            self.words * DB_ENTRY_SIZE as u64,
+            self.words
+        );
+        println!(
+            "  Contracts:       {} ({} fit in one page), {} slots",
+            self.contracts, self.fitting, self.slots
+        );
+        println!("                   {:>14} {:>14}", "unaligned", "aligned");
+        println!(
+            "  Padding entries: {:>14} {:>14}  (+{:.2}% of N)",
+            self.unaligned.padding,
+            self.aligned.padding,
+            percent(self.aligned.padding, total_indices)
+        );
+        println!(
+            "  Pages read:      {:>14} {:>14}  ({:.2}% fewer)",
+            self.unaligned.pages,
+            self.aligned.pages,
+            percent(
+                self.unaligned.pages.saturating_sub(self.aligned.pages),
+                self.unaligned.pages
+            )
+        );
+        println!(
+            "  Split contracts: {:>14} {:>14}",
+            self.unaligned.split, self.aligned.split
+        );
+    }
+}
+
+/// Fails unless `alignment` is a whole number of entries, at least two.
+pub fn check_alignment(alignment: u64) -> Result<()> {
+    ensure!(
+        alignment >= 2 * DB_ENTRY_SIZE as u64 && alignment.is_multiple_of(DB_ENTRY_SIZE as u64),
+        "Alignment {} must be a multiple of {} bytes, at least {}",
+        alignment,
+        DB_ENTRY_SIZE,
+        2 * DB_ENTRY_SIZE
+    );
+    Ok(())
+}
+
+/// Prints the [`ColocationReport`] of the snapshot in `output_dir` for `args.alignment`.
+pub fn run(output_dir: &Path, args: &ColocationArgs) -> Result<()> {
+    report(output_dir, args.alignment)
+}
+
+/// Prints the [`ColocationReport`] of the snapshot in `output_dir` for `alignment` bytes.
+pub fn report(output_dir: &Path, alignment: u64) -> Result<()> {
+    check_alignment(alignment)?;
+    let metadata =
+        Metadata::load(output_dir)?.ok_or_else(|| eyre!("No metadata.json in {:?}", output_dir))?;
+    ensure!(
+        metadata.cuckoo.is_none(),
+        "A cuckoo layout does not keep contracts' slots together"
+    );
+    let path = output_dir.join("storage-mapping.bin");
+    let sto_map = if metadata.key_order == KeyOrder::Hashed {
+        StorageMapping::open_hashed(path)?
+    } else {
+        StorageMapping::open(path)?
+    };
+
+    // Runs as extracted, i.e. slots per contract in mapping order
+    let mut runs = Vec::new();
+    let mut last: Option<&[u8]> = None;
+    for i in 0..sto_map.len() {
+        let (address, _, _) = sto_map.slot(i).expect("slot below count");
+        match runs.last_mut() {
+            Some(count) if last == Some(address) => *count += 1,
+            _ => runs.push(1u64),
+        }
+        last = Some(address);
+    }
+
+    let start = metadata.accounts * metadata.layout.account_words;
+    let report = ColocationReport::simulate(start, alignment / DB_ENTRY_SIZE as u64, runs);
+    report.print(start + report.slots + report.aligned.padding);
+    if let Some(padding) = &metadata.padding {
+        println!(
+            "Snapshot was extracted with --storage-alignment {} ({} padding entries)",
+            padding.alignment, padding.entries
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pads_only_runs_that_straddle_a_page() {
+        // Fits in the rest of the page, or starts on a boundary
+        assert_eq!(padding_before(5, 3, 8), 0);
+        assert_eq!(padding_before(8, 20, 8), 0);
+        // Would straddle: move to the next boundary
+        assert_eq!(padding_before(6, 3, 8), 2);
+        // Longer than a page: start on a boundary
+        assert_eq!(padding_before(1, 9, 8), 7);
+    }
+
+    #[test]
+    fn simulation_trades_padding_for_pages() {
+        // Runs of 3, 3, 3 and 10 slots after 2 account words, with 4-entry pages
+        let report = ColocationReport::simulate(2, 4, [3, 3, 0, 3, 10]);
+        assert_eq!((report.contracts, report.fitting, report.slots), (4, 3, 19));
+        // Unaligned: [2,5) [5,8) [8,11) [11,21)
+        assert_eq!(
+            report.unaligned,
+            Layout {
+                padding: 0,
+                pages: 2 + 1 + 1 + 4,
+                split: 1
+            }
+        );
+        // Aligned: pad 2 -> [4,7), pad 1 -> [8,11), pad 1 -> [12,15), pad 1 -> [16,26)
+        assert_eq!(
+            report.aligned,
+            Layout {
+                padding: 5,
+                pages: 1 + 1 + 1 + 3,
+                split: 0
+            }
+        );
+    }
+}
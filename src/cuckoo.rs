@@ -0,0 +1,332 @@
+//! Cuckoo-hashed keyword layout (`--cuckoo`).
+//!
+//! Rewrites the flat artifacts of a finished extraction into two cuckoo tables so clients can
+//! find an entry from its key alone, without downloading the mapping files:
+//!
+//! - Accounts: `KeyTag || Account` slots (1 + the `--account-layout` words), keyed by the
+//!   address.
+//! - Storage: 2-word slots, `KeyTag || Value`, keyed by `Address || SlotKey`.
+//!
+//! `KeyTag` is `BLAKE3(key)`; empty buckets and unused stash slots are all zeros. The storage
+//! table follows the account table in `database.bin`, and each table's stash follows its
+//! buckets. The mapping files are rewritten to point at the record word after the tag, so
+//! they (and the delta producer) keep working for servers; their index width is chosen for
+//! the padded table size.
+
+use eyre::{ensure, Result};
+use memmap2::Mmap;
+use state_syncer::{
+    cuckoo::{self, BuildOptions, CuckooLayout, CuckooTable},
+    layout::AccountLayout,
+    mapping::{index_width_for, write_index, AccountMapping, MappingHeader, StorageMapping},
+    metadata::CuckooInfo,
+};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// Words per storage slot: tag + value.
+const STORAGE_SLOT_WORDS: u64 = 2;
+
+/// Both tables of a cuckoo layout, as recorded in `metadata.json`.
+pub struct CuckooSummary {
+    pub kappa: usize,
+    pub accounts: CuckooTable,
+    pub storage: CuckooTable,
+}
+
+impl CuckooSummary {
+    /// Total number of database words.
+    pub fn total_indices(&self) -> u64 {
+        self.accounts.words() + self.storage.words()
+    }
+
+    /// `cuckoo` section of `metadata.json`.
+    pub fn info(&self) -> CuckooInfo {
+        CuckooInfo {
+            kappa: self.kappa,
+            tag: "blake3".to_string(),
+            accounts: (&self.accounts).into(),
+            storage: (&self.storage).into(),
+        }
+    }
+}
+
+/// Rebuilds `database.bin` and the mappings in `output_dir` into the cuckoo layout.
+pub fn apply(
+    output_dir: &Path,
+    opts: BuildOptions,
+    layout: AccountLayout,
+) -> Result<CuckooSummary> {
+    let db_path = output_dir.join("database.bin");
+    let acc_path = output_dir.join("account-mapping.bin");
+    let sto_path = output_dir.join("storage-mapping.bin");
+
+    let db = map(&db_path)?;
+    let acc_map = AccountMapping::open(&acc_path)?;
+    let sto_map = StorageMapping::open(&sto_path)?;
+
+    let accounts = acc_map.len();
+    let storage_slots = sto_map.len();
+    let account_words = layout.words();
+    ensure!(
+        db.len() as u64 == (accounts * account_words + storage_slots) * 32,
+        "database.bin does not match the mappings ({} accounts, {} storage slots)",
+        accounts,
+        storage_slots
+    );
+    ensure!(
+        accounts < cuckoo::EMPTY as u64 && storage_slots < cuckoo::EMPTY as u64,
+        "too many entries for a cuckoo layout"
+    );
+
+    let acc_key = |i: u32| acc_map.record(i as u64).expect("item below count").0;
+    let sto_key = |i: u32| sto_map.record(i as u64).expect("item below count").0;
+
+    // Independent seed streams per table so both are reproducible from one --cuckoo-seed
+    let acc_layout = cuckoo::build(accounts as u32, acc_key, opts)?;
+    let sto_layout = cuckoo::build(
+        storage_slots as u32,
+        sto_key,
+        BuildOptions {
+            seed: opts.seed.wrapping_add(1),
+            ..opts
+        },
+    )?;
+
+    let acc_table = CuckooTable {
+        params: acc_layout.params.clone(),
+        offset: 0,
+        // Tag + the account words
+        slot_words: 1 + account_words,
+    };
+    let sto_table = CuckooTable {
+        params: sto_layout.params.clone(),
+        offset: acc_table.words(),
+        slot_words: STORAGE_SLOT_WORDS,
+    };
+
+    // --- DATABASE ---
+    let db_tmp = output_dir.join("database.bin.tmp");
+    let mut out = BufWriter::new(File::create(&db_tmp)?);
+    write_table(&mut out, &acc_table, &acc_layout, acc_key, |i| {
+        let start = (i as u64 * account_words * 32) as usize;
+        &db[start..start + account_words as usize * 32]
+    })?;
+    let storage_base = (accounts * account_words * 32) as usize;
+    write_table(&mut out, &sto_table, &sto_layout, sto_key, |i| {
+        let start = storage_base + i as usize * 32;
+        &db[start..start + 32]
+    })?;
+    out.flush()?;
+    drop(out);
+
+    // --- MAPPINGS ---
+    let index_width = index_width_for(acc_table.words() + sto_table.words());
+    let acc_header = MappingHeader::new(
+        if acc_map.is_hashed() { 32 } else { 20 },
+        index_width,
+        accounts,
+    );
+    let sto_header = MappingHeader::new(
+        if sto_map.is_hashed() { 64 } else { 52 },
+        index_width,
+        storage_slots,
+    );
+    let acc_tmp = output_dir.join("account-mapping.bin.tmp");
+    remap(&acc_tmp, &acc_header, acc_key, &acc_table, &acc_layout)?;
+    let sto_tmp = output_dir.join("storage-mapping.bin.tmp");
+    remap(&sto_tmp, &sto_header, sto_key, &sto_table, &sto_layout)?;
+
+    drop((db, acc_map, sto_map));
+    std::fs::rename(db_tmp, db_path)?;
+    std::fs::rename(acc_tmp, acc_path)?;
+    std::fs::rename(sto_tmp, sto_path)?;
+
+    Ok(CuckooSummary {
+        kappa: opts.kappa,
+        accounts: acc_table,
+        storage: sto_table,
+    })
+}
+
+fn map(path: &Path) -> Result<Mmap> {
+    let file = File::open(path)?;
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+/// Writes every slot of `table` in order: tag + record, or zeros when the slot is empty.
+fn write_table<'a>(
+    out: &mut impl Write,
+    table: &CuckooTable,
+    layout: &CuckooLayout,
+    key_of: impl Fn(u32) -> &'a [u8],
+    record_of: impl Fn(u32) -> &'a [u8],
+) -> Result<()> {
+    let empty = vec![0u8; table.slot_words as usize * 32];
+    for slot in 0..table.slots() {
+        match layout.item_at(slot) {
+            Some(item) => {
+                out.write_all(&cuckoo::key_tag(key_of(item)))?;
+                out.write_all(record_of(item))?;
+            }
+            None => out.write_all(&empty)?,
+        }
+    }
+    Ok(())
+}
+
+/// Writes the mapping described by `header`, pointing each key at the word after its tag.
+fn remap<'a>(
+    path: &Path,
+    header: &MappingHeader,
+    key_of: impl Fn(u32) -> &'a [u8],
+    table: &CuckooTable,
+    layout: &CuckooLayout,
+) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(&header.encode())?;
+    for i in 0..header.count as u32 {
+        let key = key_of(i);
+        let slot = layout
+            .slot_of(i, key)
+            .expect("every item is placed in a bucket or the stash");
+        out.write_all(key)?;
+        write_index(&mut out, table.slot_index(slot) + 1, header.index_width)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filter::Filter,
+        progress::Progress,
+        sequential::{extract_plain, PlainOptions},
+        source::{MemorySource, StateSource},
+        writer::{ArtifactWriter, MappingFormat},
+    };
+    use alloy_primitives::{Address, B256, U256};
+    use reth_primitives::Account;
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/state.jsonl");
+
+    /// Finds the slot among `key`'s query indices whose tag matches; returns the index of the
+    /// record word after the tag and the record.
+    fn resolve<'a>(db: &'a [u8], table: &CuckooTable, key: &[u8]) -> (u64, &'a [u8]) {
+        let tag = cuckoo::key_tag(key);
+        // Colliding candidates are repeated in the query
+        let mut indices = table.query_indices(key);
+        indices.sort_unstable();
+        indices.dedup();
+        let matches: Vec<_> = indices
+            .into_iter()
+            .filter_map(|index| {
+                let start = index as usize * 32;
+                let slot = &db[start..start + table.slot_words as usize * 32];
+                (slot[..32] == tag).then(|| (index + 1, &slot[32..]))
+            })
+            .collect();
+        assert_eq!(matches.len(), 1, "key {:?}", key);
+        matches[0]
+    }
+
+    #[test]
+    fn every_key_resolves_through_its_query_indices() {
+        let dir = std::env::temp_dir().join(format!("plinko-cuckoo-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut source = MemorySource::from_jsonl(Path::new(FIXTURE)).unwrap();
+        for i in 0..40u8 {
+            let address = Address::with_last_byte(i);
+            let account = Account {
+                nonce: i as u64 + 1,
+                ..Default::default()
+            };
+            source.insert_account(address, account);
+            source.insert_storage(
+                address,
+                B256::with_last_byte(i),
+                U256::from(i as u64 + 1000),
+            );
+        }
+
+        let filter = Filter::new(None, None, false, false, false, false).unwrap();
+        let format = MappingFormat {
+            key_size: 20,
+            index_width: 4,
+        };
+        let layout = AccountLayout::Standard;
+        let mut writer = ArtifactWriter::create(&dir, format, layout).unwrap();
+        let opts = PlainOptions {
+            block: source.tip_block().unwrap(),
+            output_dir: &dir,
+            filter: &filter,
+            layout,
+            limit: usize::MAX,
+            batch_size: 1000,
+            storage_alignment: None,
+        };
+        extract_plain(
+            &source,
+            Some(&mut writer),
+            None,
+            None,
+            opts,
+            &Progress::hidden(),
+        )
+        .unwrap();
+        writer.finish().unwrap();
+
+        // Every key with its flat record, before the rewrite
+        let flat = std::fs::read(dir.join("database.bin")).unwrap();
+        let words = layout.words() as usize;
+        let acc_map = AccountMapping::open(dir.join("account-mapping.bin")).unwrap();
+        let accounts: Vec<(Vec<u8>, Vec<u8>)> = (0..acc_map.len())
+            .map(|i| {
+                let (key, index) = acc_map.record(i).unwrap();
+                let start = index as usize * 32;
+                (key.to_vec(), flat[start..start + words * 32].to_vec())
+            })
+            .collect();
+        let sto_map = StorageMapping::open(dir.join("storage-mapping.bin")).unwrap();
+        let slots: Vec<(Vec<u8>, Vec<u8>)> = (0..sto_map.len())
+            .map(|i| {
+                let (key, index) = sto_map.record(i).unwrap();
+                let start = index as usize * 32;
+                (key.to_vec(), flat[start..start + 32].to_vec())
+            })
+            .collect();
+        assert_eq!((accounts.len(), slots.len()), (43, 43));
+        drop((acc_map, sto_map));
+
+        let opts = BuildOptions {
+            seed: 7,
+            ..Default::default()
+        };
+        let summary = apply(&dir, opts, layout).unwrap();
+        let db = std::fs::read(dir.join("database.bin")).unwrap();
+        assert_eq!(db.len() as u64, summary.total_indices() * 32);
+        assert_eq!(summary.storage.offset, summary.accounts.words());
+
+        let acc_map = AccountMapping::open(dir.join("account-mapping.bin")).unwrap();
+        for (key, record) in &accounts {
+            let (index, found) = resolve(&db, &summary.accounts, key);
+            assert_eq!(found, &record[..]);
+            assert_eq!(acc_map.get(key), Some(index));
+        }
+        let sto_map = StorageMapping::open(dir.join("storage-mapping.bin")).unwrap();
+        for (key, value) in &slots {
+            let (index, found) = resolve(&db, &summary.storage, key);
+            assert_eq!(found, &value[..]);
+            assert_eq!(sto_map.get(&key[..20], &key[20..]), Some(index));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,459 @@
+//! Per-block XOR delta producer (`plinko-extractor deltas --from A --to B`).
+//!
+//! Writes one `delta-<block>.bin` per block in the format described in
+//! `state-syncer/docs/delta-format.md`, bound to the block's and its parent's canonical
+//! hashes. Old and new values come straight from reth's changesets: the state as of `--to`
+//! is reconstructed with [`StateOverlay`], then blocks are walked backwards, each block's
+//! changeset pre-state being the new value of the block before.
+
+use crate::{history::StateOverlay, writer::account_record};
+use alloy_primitives::{keccak256, Address, B256, U256};
+use eyre::{ensure, Result};
+use reth_db::{
+    cursor::{DbCursorRO, DbDupCursorRO},
+    database::Database,
+    models::BlockNumberAddress,
+    open_db_read_only, tables,
+    transaction::DbTx,
+};
+use reth_primitives::Account;
+use state_syncer::{
+    delta::{delta_path, write_delta, ChainBinding, DeltaRecord},
+    manifest::Manifest,
+    mapping::{AccountMapping, StorageMapping},
+    metadata::{KeyOrder, Metadata},
+};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct DeltasArgs {
+    /// First block to produce a delta for
+    #[arg(long)]
+    from: u64,
+
+    /// Last block to produce a delta for (inclusive)
+    #[arg(long)]
+    to: u64,
+
+    /// Directory for delta files (default: <output-dir>/deltas)
+    #[arg(long)]
+    deltas_dir: Option<PathBuf>,
+}
+
+/// Produces delta files for `args.from..=args.to`.
+///
+/// Indices are resolved through the mapping files of the snapshot in `output_dir`, which
+/// should have been extracted at block `args.from - 1` (plain or `--hashed`). Accounts and
+/// slots that are not in the snapshot (created after it) have no index and are skipped.
+pub fn run(db_path: &Path, output_dir: &Path, args: &DeltasArgs) -> Result<()> {
+    let now = || chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+
+    ensure!(
+        args.from <= args.to,
+        "--from ({}) must not exceed --to ({})",
+        args.from,
+        args.to
+    );
+
+    // Refuse to index deltas through truncated or corrupted mappings
+    if let Some(manifest) = Manifest::load(output_dir)? {
+        manifest.check(output_dir, "account-mapping.bin")?;
+        manifest.check(output_dir, "storage-mapping.bin")?;
+    }
+
+    let metadata = Metadata::load(output_dir)?;
+    if let Some(metadata) = &metadata {
+        // A cuckoo database.bin no longer follows the indices in the mapping files
+        ensure!(
+            metadata.cuckoo.is_none(),
+            "Snapshot in {:?} has a cuckoo layout; deltas need the mapping indices of a plain one",
+            output_dir
+        );
+        if metadata.block + 1 != args.from {
+            println!(
+                "[{}] Warning: snapshot in {:?} is at block #{}, expected #{} for --from {}",
+                now(),
+                output_dir,
+                metadata.block,
+                args.from.saturating_sub(1),
+                args.from
+            );
+        }
+    }
+    let layout = metadata
+        .as_ref()
+        .map(|m| m.layout.account_layout)
+        .unwrap_or_default();
+    let hashed = metadata.is_some_and(|m| m.key_order == KeyOrder::Hashed);
+    let (acc_map, sto_map) = if hashed {
+        (
+            AccountMapping::open_hashed(output_dir.join("account-mapping.bin"))?,
+            StorageMapping::open_hashed(output_dir.join("storage-mapping.bin"))?,
+        )
+    } else {
+        (
+            AccountMapping::open(output_dir.join("account-mapping.bin"))?,
+            StorageMapping::open(output_dir.join("storage-mapping.bin"))?,
+        )
+    };
+    let account_index = |address: Address| {
+        if hashed {
+            acc_map.get(keccak256(address).as_slice())
+        } else {
+            acc_map.get(address.as_slice())
+        }
+    };
+    let storage_index = |address: Address, key: B256| {
+        if hashed {
+            sto_map.get(keccak256(address).as_slice(), keccak256(key).as_slice())
+        } else {
+            sto_map.get(address.as_slice(), key.as_slice())
+        }
+    };
+    println!(
+        "[{}] Loaded mappings: {} accounts, {} storage slots",
+        now(),
+        acc_map.len(),
+        sto_map.len()
+    );
+
+    let deltas_dir = args
+        .deltas_dir
+        .clone()
+        .unwrap_or_else(|| output_dir.join("deltas"));
+    std::fs::create_dir_all(&deltas_dir)?;
+
+    println!("[{}] Opening database at {:?}", now(), db_path);
+    let db = open_db_read_only(db_path, Default::default())?;
+    let tx = db.tx()?;
+
+    let tip = tx
+        .cursor_read::<tables::CanonicalHeaders>()?
+        .last()?
+        .map(|(num, _hash)| num)
+        .unwrap_or(0);
+    ensure!(
+        args.to <= tip,
+        "--to #{} is beyond the database tip #{}",
+        args.to,
+        tip
+    );
+
+    // State as of `--to`, then rewound one block at a time as we walk backwards
+    let (accounts, storage) = StateOverlay::rewind(&tx, args.to, tip)?.into_maps();
+    let mut state = WorkingState {
+        accounts,
+        storage,
+        plain_accounts: tx.cursor_read::<tables::PlainAccountState>()?,
+        plain_storage: tx.cursor_dup_read::<tables::PlainStorageState>()?,
+    };
+
+    let mut acc_changes = tx.cursor_read::<tables::AccountChangeSets>()?;
+    let mut sto_changes = tx.cursor_read::<tables::StorageChangeSets>()?;
+
+    let mut total_records = 0u64;
+    let mut unmapped = 0u64;
+    let canonical_hash = |number: u64| -> Result<Option<[u8; 32]>> {
+        Ok(tx
+            .get::<tables::CanonicalHeaders>(number)?
+            .map(|hash| hash.0))
+    };
+
+    for block in (args.from..=args.to).rev() {
+        let mut records: Vec<DeltaRecord> = Vec::new();
+
+        for entry in acc_changes.walk_range(block..=block)? {
+            let (_block, before) = entry?;
+            let new = state.account(before.address)?.unwrap_or_default();
+            let old = before.info.unwrap_or_default();
+
+            match account_index(before.address) {
+                Some(index) => {
+                    let old_words = layout.encode(&account_record(&old))?;
+                    let new_words = layout.encode(&account_record(&new))?;
+                    let words = layout.words() as usize;
+                    for (i, (o, n)) in old_words[..words]
+                        .iter()
+                        .zip(new_words[..words].iter())
+                        .enumerate()
+                    {
+                        let delta = xor_words(o, n);
+                        if delta != [0u64; 4] {
+                            records.push((index + i as u64, delta));
+                        }
+                    }
+                }
+                None => unmapped += 1,
+            }
+
+            state.accounts.insert(before.address, before.info);
+        }
+
+        for entry in sto_changes.walk_range(BlockNumberAddress::range(block..=block))? {
+            let (block_address, before) = entry?;
+            let address = block_address.address();
+            let new = state.storage(address, before.key)?;
+
+            match storage_index(address, before.key) {
+                Some(index) => {
+                    let delta =
+                        xor_words(&before.value.to_le_bytes::<32>(), &new.to_le_bytes::<32>());
+                    if delta != [0u64; 4] {
+                        records.push((index, delta));
+                    }
+                }
+                None => unmapped += 1,
+            }
+
+            state.storage.insert((address, before.key), before.value);
+        }
+
+        let parent_hash = match block.checked_sub(1) {
+            Some(parent) => canonical_hash(parent)?,
+            None => None,
+        };
+        let binding = ChainBinding::block(block, parent_hash, canonical_hash(block)?);
+        let path = delta_path(&deltas_dir, block);
+        write_delta(&path, &binding, &records)?;
+        total_records += records.len() as u64;
+        println!(
+            "[{}] Block #{}: {} records -> {:?}",
+            now(),
+            block,
+            records.len(),
+            path
+        );
+    }
+
+    println!(
+        "[{}] Wrote {} delta files ({} records). Skipped {} changes not in the snapshot mappings.",
+        now(),
+        args.to - args.from + 1,
+        total_records,
+        unmapped
+    );
+
+    Ok(())
+}
+
+/// State as of the block currently being processed: explicit values for every key touched
+/// so far, falling back to the plain state tables at the tip.
+struct WorkingState<A, S> {
+    accounts: BTreeMap<Address, Option<Account>>,
+    storage: BTreeMap<(Address, B256), U256>,
+    plain_accounts: A,
+    plain_storage: S,
+}
+
+impl<A, S> WorkingState<A, S>
+where
+    A: DbCursorRO<tables::PlainAccountState>,
+    S: DbDupCursorRO<tables::PlainStorageState> + DbCursorRO<tables::PlainStorageState>,
+{
+    fn account(&mut self, address: Address) -> Result<Option<Account>> {
+        if let Some(info) = self.accounts.get(&address) {
+            return Ok(*info);
+        }
+        Ok(self.plain_accounts.seek_exact(address)?.map(|(_, acc)| acc))
+    }
+
+    fn storage(&mut self, address: Address, key: B256) -> Result<U256> {
+        if let Some(value) = self.storage.get(&(address, key)) {
+            return Ok(*value);
+        }
+        Ok(self
+            .plain_storage
+            .seek_by_key_subkey(address, key)?
+            .filter(|entry| entry.key == key)
+            .map(|entry| entry.value)
+            .unwrap_or_default())
+    }
+}
+
+/// XORs two 32-byte entries into little-endian `u64` words.
+fn xor_words(old: &[u8; 32], new: &[u8; 32]) -> [u64; 4] {
+    let mut out = [0u64; 4];
+    for (i, word) in out.iter_mut().enumerate() {
+        let o = u64::from_le_bytes(old[i * 8..(i + 1) * 8].try_into().unwrap());
+        let n = u64::from_le_bytes(new[i * 8..(i + 1) * 8].try_into().unwrap());
+        *word = o ^ n;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filter::Filter,
+        progress::Progress,
+        sequential::{extract_plain, PlainOptions},
+        source::{MemorySource, StateSource},
+        writer::{ArtifactWriter, MappingFormat},
+    };
+    use alloy_primitives::{address, b256};
+    use reth_db::{init_db, models::AccountBeforeTx, transaction::DbTxMut};
+    use reth_primitives::StorageEntry;
+    use state_syncer::{delta::read_delta, layout::AccountLayout};
+
+    const A: Address = address!("1111111111111111111111111111111111111111");
+    const B: Address = address!("2222222222222222222222222222222222222222");
+    const SLOT_1: B256 = b256!("0000000000000000000000000000000000000000000000000000000000000001");
+    const SLOT_2: B256 = b256!("0000000000000000000000000000000000000000000000000000000000000002");
+
+    fn temp_dir(tag: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("plinko-deltas-{}-{}", tag, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn account(nonce: u64, balance: u64) -> Account {
+        Account {
+            nonce,
+            balance: U256::from(balance),
+            bytecode_hash: None,
+        }
+    }
+
+    /// State as of block 0 (`at_genesis`) or block 2: A and B change in both blocks, as do
+    /// A's slot 1 and B's slot 2.
+    fn state(at_genesis: bool) -> MemorySource {
+        let mut source = MemorySource::new(if at_genesis { 0 } else { 2 });
+        let (a, b, a1, b2) = if at_genesis {
+            (account(1, 100), account(0, 50), 10, 5)
+        } else {
+            (account(3, 300), account(1, 100), 30, 7)
+        };
+        source.insert_account(A, a);
+        source.insert_account(B, b);
+        source.insert_storage(A, SLOT_1, U256::from(a1));
+        source.insert_storage(B, SLOT_2, U256::from(b2));
+        source
+    }
+
+    fn extract(source: &MemorySource, dir: &Path) {
+        let format = MappingFormat {
+            key_size: 20,
+            index_width: 4,
+        };
+        let mut writer = ArtifactWriter::create(dir, format, AccountLayout::Standard).unwrap();
+        let filter = Filter::new(None, None, false, false, false, false).unwrap();
+        let opts = PlainOptions {
+            block: source.tip_block().unwrap(),
+            output_dir: dir,
+            filter: &filter,
+            layout: AccountLayout::Standard,
+            limit: usize::MAX,
+            batch_size: 100,
+            storage_alignment: None,
+        };
+        let progress = Progress::hidden();
+        extract_plain(source, Some(&mut writer), None, None, opts, &progress).unwrap();
+        writer.finish().unwrap();
+    }
+
+    /// A reth database at block 2 whose changesets record blocks 1 and 2.
+    fn database(path: &Path) {
+        let db = init_db(path, Default::default()).unwrap();
+        let tx = db.tx_mut().unwrap();
+        for block in 0..=2u64 {
+            tx.put::<tables::CanonicalHeaders>(block, B256::repeat_byte(block as u8 + 1))
+                .unwrap();
+        }
+        let tip = state(false);
+        for (address, account) in tip.accounts(None, usize::MAX).unwrap() {
+            tx.put::<tables::PlainAccountState>(address, account)
+                .unwrap();
+        }
+        for (address, key, value) in tip.storage(None, usize::MAX).unwrap() {
+            tx.put::<tables::PlainStorageState>(address, StorageEntry { key, value })
+                .unwrap();
+        }
+
+        let accounts = [
+            (1, A, account(1, 100)),
+            (2, A, account(2, 200)),
+            (2, B, account(0, 50)),
+        ];
+        for (block, address, before) in accounts {
+            let before = AccountBeforeTx {
+                address,
+                info: Some(before),
+            };
+            tx.put::<tables::AccountChangeSets>(block, before).unwrap();
+        }
+        let slots = [(1, A, SLOT_1, 10), (1, B, SLOT_2, 5), (2, A, SLOT_1, 20)];
+        for (block, address, key, value) in slots {
+            let entry = StorageEntry {
+                key,
+                value: U256::from(value),
+            };
+            tx.put::<tables::StorageChangeSets>((block, address).into(), entry)
+                .unwrap();
+        }
+        tx.commit().unwrap();
+    }
+
+    fn entries(dir: &Path) -> Vec<[u64; 4]> {
+        std::fs::read(dir.join("database.bin"))
+            .unwrap()
+            .chunks(32)
+            .map(|chunk| {
+                let mut words = [0u64; 4];
+                for (i, word) in words.iter_mut().enumerate() {
+                    *word = u64::from_le_bytes(chunk[i * 8..(i + 1) * 8].try_into().unwrap());
+                }
+                words
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deltas_patch_the_old_snapshot_into_the_new_one() {
+        let dir = temp_dir("walk");
+        let (old, new) = (dir.join("old"), dir.join("new"));
+        for (path, at_genesis) in [(&old, true), (&new, false)] {
+            std::fs::create_dir_all(path).unwrap();
+            extract(&state(at_genesis), path);
+        }
+        database(&dir.join("db"));
+
+        let args = DeltasArgs {
+            from: 1,
+            to: 2,
+            deltas_dir: None,
+        };
+        run(&dir.join("db"), &old, &args).unwrap();
+
+        let mut patched = entries(&old);
+        for block in 1..=2 {
+            let delta = read_delta(&delta_path(&old.join("deltas"), block)).unwrap();
+            let binding = delta.binding.unwrap();
+            assert_eq!(binding.parent_hash, Some([block as u8; 32]));
+            assert_eq!(binding.block_hash, Some([block as u8 + 1; 32]));
+            assert!(!delta.records.is_empty());
+            for (index, words) in delta.records {
+                for (entry, word) in patched[index as usize].iter_mut().zip(words) {
+                    *entry ^= word;
+                }
+            }
+        }
+        assert_ne!(entries(&old), entries(&new));
+        assert_eq!(patched, entries(&new));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn xor_words_is_little_endian() {
+        let mut new = [0u8; 32];
+        new[0] = 1;
+        new[31] = 0x80;
+        assert_eq!(xor_words(&[0; 32], &new), [1, 0, 0, 0x80 << 56]);
+        assert_eq!(xor_words(&new, &new), [0; 4]);
+    }
+}
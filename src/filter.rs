@@ -0,0 +1,251 @@
+//! Selective extraction filters.
+//!
+//! Entries are filtered as they are written, so indices stay dense and a filtered database
+//! has exactly as many words as the entries it keeps. The settings are recorded in
+//! `metadata.json` (and in checkpoints, so a resume cannot silently change them).
+
+use alloy_primitives::{keccak256, Address, B256, U256};
+use eyre::{ensure, eyre, Result};
+use reth_primitives::Account;
+use state_syncer::metadata::{AddressListInfo, FilterInfo};
+use std::{collections::HashSet, path::Path};
+
+/// Addresses from an `--include-addresses`/`--exclude-addresses` file.
+struct AddressList {
+    addresses: HashSet<Address>,
+    /// `keccak256` of every address, for `--hashed` extractions.
+    hashed: HashSet<B256>,
+    info: AddressListInfo,
+}
+
+impl AddressList {
+    /// Reads one 0x-prefixed address per line; blank lines and `#` comments are ignored.
+    fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read(path)?;
+        let text = std::str::from_utf8(&contents)
+            .map_err(|_| eyre!("Address list {:?} is not valid UTF-8", path))?;
+
+        let mut addresses = HashSet::new();
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let address: Address = line.parse().map_err(|_| {
+                eyre!(
+                    "{:?} line {}: invalid address {:?}",
+                    path,
+                    line_no + 1,
+                    line
+                )
+            })?;
+            addresses.insert(address);
+        }
+        ensure!(!addresses.is_empty(), "Address list {:?} is empty", path);
+
+        let hashed = addresses.iter().map(keccak256).collect();
+        let info = AddressListInfo {
+            file: path.display().to_string(),
+            addresses: addresses.len() as u64,
+            blake3: blake3::hash(&contents).to_hex().to_string(),
+        };
+        Ok(Self {
+            addresses,
+            hashed,
+            info,
+        })
+    }
+
+    /// Whether `key` (a 20-byte address or its 32-byte hash) is listed.
+    fn contains(&self, key: &[u8]) -> bool {
+        match key.len() {
+            20 => self.addresses.contains(&Address::from_slice(key)),
+            32 => self.hashed.contains(&B256::from_slice(key)),
+            _ => false,
+        }
+    }
+}
+
+/// Which accounts and slots an extraction keeps.
+pub struct Filter {
+    include: Option<AddressList>,
+    exclude: Option<AddressList>,
+    skip_empty_accounts: bool,
+    skip_zero_storage: bool,
+    accounts: bool,
+    storage: bool,
+}
+
+impl Filter {
+    pub fn new(
+        include: Option<&Path>,
+        exclude: Option<&Path>,
+        skip_empty_accounts: bool,
+        skip_zero_storage: bool,
+        accounts_only: bool,
+        storage_only: bool,
+    ) -> Result<Self> {
+        ensure!(
+            !(accounts_only && storage_only),
+            "--accounts-only cannot be combined with --storage-only"
+        );
+        Ok(Self {
+            include: include.map(AddressList::load).transpose()?,
+            exclude: exclude.map(AddressList::load).transpose()?,
+            skip_empty_accounts,
+            skip_zero_storage,
+            accounts: !storage_only,
+            storage: !accounts_only,
+        })
+    }
+
+    /// Whether the account table is walked at all.
+    pub fn accounts(&self) -> bool {
+        self.accounts
+    }
+
+    /// Whether the storage table is walked at all.
+    pub fn storage(&self) -> bool {
+        self.storage
+    }
+
+    fn keep_address(&self, key: &[u8]) -> bool {
+        self.include.as_ref().is_none_or(|list| list.contains(key))
+            && !self.exclude.as_ref().is_some_and(|list| list.contains(key))
+    }
+
+    /// Whether to write the account at `key` (address, or hashed address with `--hashed`).
+    pub fn keep_account(&self, key: &[u8], account: &Account) -> bool {
+        self.accounts && !(self.skip_empty_accounts && account.is_empty()) && self.keep_address(key)
+    }
+
+    /// Whether to write a storage slot of the account at `key`.
+    pub fn keep_storage(&self, key: &[u8], value: &U256) -> bool {
+        self.storage && !(self.skip_zero_storage && value.is_zero()) && self.keep_address(key)
+    }
+
+    /// The settings as recorded in `metadata.json`; `None` when nothing is filtered.
+    pub fn info(&self) -> Option<FilterInfo> {
+        let info = FilterInfo {
+            include: self.include.as_ref().map(|list| list.info.clone()),
+            exclude: self.exclude.as_ref().map(|list| list.info.clone()),
+            skip_empty_accounts: self.skip_empty_accounts,
+            skip_zero_storage: self.skip_zero_storage,
+            accounts: self.accounts,
+            storage: self.storage,
+        };
+        (info != FilterInfo::default()).then_some(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::address;
+    use std::path::PathBuf;
+
+    const LISTED: Address = address!("1111111111111111111111111111111111111111");
+    const OTHER: Address = address!("2222222222222222222222222222222222222222");
+
+    fn list_file(tag: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("plinko-filter-{}-{}", tag, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn account(nonce: u64) -> Account {
+        Account {
+            nonce,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn include_and_exclude_match_plain_and_hashed_keys() {
+        let path = list_file(
+            "list",
+            "# allowlist\n\n0x1111111111111111111111111111111111111111  # trailing comment\n",
+        );
+        let include = Filter::new(Some(&path), None, false, false, false, false).unwrap();
+        let exclude = Filter::new(None, Some(&path), false, false, false, false).unwrap();
+
+        for (key, listed) in [
+            (LISTED.to_vec(), true),
+            (OTHER.to_vec(), false),
+            (keccak256(LISTED).to_vec(), true),
+            (keccak256(OTHER).to_vec(), false),
+            // Neither an address nor a hash
+            (vec![0x11; 8], false),
+        ] {
+            assert_eq!(include.keep_account(&key, &account(1)), listed);
+            assert_eq!(include.keep_storage(&key, &U256::from(1)), listed);
+            assert_eq!(exclude.keep_account(&key, &account(1)), !listed);
+        }
+
+        let info = include.info().unwrap();
+        let list = info.include.unwrap();
+        assert_eq!(list.addresses, 1);
+        assert_eq!(
+            list.blake3,
+            blake3::hash(&std::fs::read(&path).unwrap())
+                .to_hex()
+                .to_string()
+        );
+        assert!(info.exclude.is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn skips_empty_accounts_and_zero_slots() {
+        let filter = Filter::new(None, None, true, true, false, false).unwrap();
+        assert!(!filter.keep_account(LISTED.as_slice(), &account(0)));
+        assert!(filter.keep_account(LISTED.as_slice(), &account(1)));
+        assert!(!filter.keep_storage(LISTED.as_slice(), &U256::ZERO));
+        assert!(filter.keep_storage(LISTED.as_slice(), &U256::from(1)));
+
+        let unfiltered = Filter::new(None, None, false, false, false, false).unwrap();
+        assert!(unfiltered.keep_account(LISTED.as_slice(), &account(0)));
+        assert!(unfiltered.keep_storage(LISTED.as_slice(), &U256::ZERO));
+        assert!(unfiltered.info().is_none());
+
+        let accounts_only = Filter::new(None, None, false, false, true, false).unwrap();
+        assert!(accounts_only.accounts() && !accounts_only.storage());
+        assert!(!accounts_only.keep_storage(LISTED.as_slice(), &U256::from(1)));
+        assert!(Filter::new(None, None, false, false, true, true).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_lists() {
+        let bad = list_file(
+            "bad",
+            "0x1111111111111111111111111111111111111111\n0x1234\n",
+        );
+        let err = Filter::new(Some(&bad), None, false, false, false, false)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.contains("line 2: invalid address \"0x1234\""),
+            "{}",
+            err
+        );
+
+        let empty = list_file("empty", "# nothing here\n\n");
+        let err = Filter::new(None, Some(&empty), false, false, false, false)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("is empty"), "{}", err);
+
+        let binary = list_file("binary", [0xff, 0xfe]);
+        let err = Filter::new(Some(&binary), None, false, false, false, false)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("not valid UTF-8"), "{}", err);
+        for path in [bad, empty, binary] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,825 @@
+//! Import of geth-style state dumps (`--geth-dump`).
+//!
+//! Reads the output of `geth dump` (one JSON object with an `accounts` map) and of
+//! `geth dump --iterative` (JSON lines: a `{"root": ..}` line, then one account per line),
+//! which erigon's state dumps also follow. Accounts are streamed as they are parsed into
+//! fixed-size records in a spill directory, sorted by plain or hashed key, and extracted from
+//! there through [`DumpSource`]; only one account's storage is held in memory at a time.
+//!
+//! Sorting is an external merge sort: records are buffered up to `sort_buffer` bytes, each
+//! full buffer is written out as a sorted run, and the runs are merged at the end. Records
+//! that arrive in order skip the sort and go straight to the output, so an iterative dump
+//! (ordered by hashed key) is written directly in the `--hashed` layout, and a `geth dump`
+//! object (ordered by address) directly in the plain one.
+//!
+//! Dumps only carry addresses and slot keys the node has preimages for (geth
+//! `--cache.preimages`); accounts without an `address` are rejected.
+
+use crate::source::{HashedSource, StateSource};
+use alloy_primitives::{keccak256, Address, B256, KECCAK256_EMPTY, U256};
+use eyre::{ensure, eyre, Result};
+use memmap2::Mmap;
+use reth_primitives::Account;
+use serde::{
+    de::{DeserializeSeed, Error as _, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap},
+    fmt,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// `nonce (8, BE) || balance (32, BE) || has code (1) || code hash (32)`, after the key.
+const ACCOUNT_RECORD: usize = 73;
+/// `slot key (32) || value (32, BE)`, after the address key.
+const SLOT_RECORD: usize = 64;
+
+/// An account as written by geth's `DumpAccount`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DumpAccount {
+    /// Decimal wei
+    balance: String,
+    nonce: u64,
+    #[serde(default)]
+    code_hash: Option<B256>,
+    /// Slot key to the value's hex bytes (no `0x`, leading zeros trimmed)
+    #[serde(default)]
+    storage: BTreeMap<B256, String>,
+    #[serde(default)]
+    address: Option<Address>,
+    /// `keccak256(address)`
+    #[serde(default)]
+    key: Option<B256>,
+}
+
+/// State of a geth dump.
+pub struct GethDump {
+    pub source: DumpSource,
+    /// State root from the dump, if it has one.
+    pub root: Option<B256>,
+}
+
+/// Loads the dump at `path`, taken at `block`, keyed by `keccak256` of the addresses and
+/// slots when `hashed` is set.
+///
+/// The sorted records are kept in `spill_dir` until the returned source is dropped; each of
+/// the two sorts buffers up to `sort_buffer` bytes before spilling a run.
+pub fn load(
+    path: &Path,
+    block: u64,
+    hashed: bool,
+    spill_dir: &Path,
+    sort_buffer: usize,
+) -> Result<GethDump> {
+    std::fs::create_dir_all(spill_dir)?;
+    let dump = sort_dump(path, block, hashed, spill_dir, sort_buffer);
+    if dump.is_err() {
+        let _ = std::fs::remove_dir_all(spill_dir);
+    }
+    dump
+}
+
+fn sort_dump(
+    path: &Path,
+    block: u64,
+    hashed: bool,
+    spill_dir: &Path,
+    sort_buffer: usize,
+) -> Result<GethDump> {
+    let key_size = if hashed { 32 } else { 20 };
+    let mut accounts = RecordSorter::new(
+        spill_dir.join("accounts.bin"),
+        key_size + ACCOUNT_RECORD,
+        sort_buffer,
+    )?;
+    let mut storage = RecordSorter::new(
+        spill_dir.join("storage.bin"),
+        key_size + SLOT_RECORD,
+        sort_buffer,
+    )?;
+
+    let mut sink = |name: Option<&str>, dump_account: DumpAccount| -> Result<()> {
+        // Accounts without a preimage are keyed `pre(<hashed address>)`
+        let address = match (dump_account.address, name) {
+            (Some(address), _) => address,
+            (None, Some(name)) => name.parse().map_err(|_| missing_address(path, name))?,
+            (None, None) => {
+                let key = dump_account.key.unwrap_or_default().to_string();
+                return Err(missing_address(path, &key));
+            }
+        };
+        let (account, slots) = parse_account(address, dump_account)?;
+
+        if hashed {
+            let key = keccak256(address);
+            accounts.push(&account_record(key.as_slice(), &account))?;
+            // Sorted here so an ordered dump stays on the sorters' fast path
+            let mut slots: Vec<_> = slots
+                .into_iter()
+                .map(|(slot, value)| (keccak256(slot), value))
+                .collect();
+            slots.sort_unstable();
+            for (slot, value) in slots {
+                storage.push(&slot_record(key.as_slice(), slot, value))?;
+            }
+        } else {
+            accounts.push(&account_record(address.as_slice(), &account))?;
+            for (slot, value) in slots {
+                storage.push(&slot_record(address.as_slice(), slot, value))?;
+            }
+        }
+        Ok(())
+    };
+
+    let mut root = None;
+    let mut de = serde_json::Deserializer::from_reader(BufReader::new(File::open(path)?));
+    let mut value = 0;
+    while de.end().is_err() {
+        value += 1;
+        DumpSeed {
+            root: &mut root,
+            sink: &mut sink,
+        }
+        .deserialize(&mut de)
+        .map_err(|e| eyre!("{:?} value {}: {}", path, value, e))?;
+    }
+
+    Ok(GethDump {
+        source: DumpSource {
+            block,
+            key_size,
+            accounts: SortedRecords::open(accounts.finish()?, key_size + ACCOUNT_RECORD)?,
+            storage: SortedRecords::open(storage.finish()?, key_size + SLOT_RECORD)?,
+            spill_dir: spill_dir.to_path_buf(),
+        },
+        root,
+    })
+}
+
+fn missing_address(path: &Path, key: &str) -> eyre::Report {
+    eyre!(
+        "{:?}: account {} has no address; dump from a node that keeps preimages",
+        path,
+        key
+    )
+}
+
+/// Reads one top-level value of a dump: the root line or an account line of an iterative
+/// dump, or a whole `geth dump` object, whose `accounts` are handed to `sink` one at a time
+/// instead of being collected into a map.
+struct DumpSeed<'a, F> {
+    root: &'a mut Option<B256>,
+    sink: &'a mut F,
+}
+
+impl<'de, F: FnMut(Option<&str>, DumpAccount) -> Result<()>> DeserializeSeed<'de>
+    for DumpSeed<'_, F>
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, F: FnMut(Option<&str>, DumpAccount) -> Result<()>> Visitor<'de> for DumpSeed<'_, F> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a geth dump, or a line of an iterative one")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        // Everything but `accounts` is small: an account line, or the dump's `root`
+        let mut fields = serde_json::Map::new();
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "accounts" => map.next_value_seed(AccountsSeed {
+                    sink: &mut *self.sink,
+                })?,
+                "next" => {
+                    let next: String = map.next_value()?;
+                    return Err(A::Error::custom(format!(
+                        "dump is incomplete (it continues at key {}); dump the whole state",
+                        next
+                    )));
+                }
+                _ => {
+                    fields.insert(key, map.next_value()?);
+                }
+            }
+        }
+
+        // Account lines carry their storage root too, so only a line without a balance is
+        // the dump's root
+        if fields.contains_key("balance") {
+            let account = DumpAccount::deserialize(serde_json::Value::Object(fields))
+                .map_err(A::Error::custom)?;
+            (self.sink)(None, account).map_err(A::Error::custom)
+        } else {
+            if let Some(root) = fields.remove("root") {
+                *self.root = Some(B256::deserialize(root).map_err(A::Error::custom)?);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The `accounts` map of a `geth dump`, keyed by address.
+struct AccountsSeed<'a, F> {
+    sink: &'a mut F,
+}
+
+impl<'de, F: FnMut(Option<&str>, DumpAccount) -> Result<()>> DeserializeSeed<'de>
+    for AccountsSeed<'_, F>
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, F: FnMut(Option<&str>, DumpAccount) -> Result<()>> Visitor<'de> for AccountsSeed<'_, F> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a map of accounts")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(name) = map.next_key::<String>()? {
+            let account: DumpAccount = map.next_value()?;
+            (self.sink)(Some(&name), account).map_err(A::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+/// Converts `account` the way reth stores it: no code hash for accounts without code, and
+/// no zero-valued slots.
+fn parse_account(address: Address, account: DumpAccount) -> Result<(Account, Vec<(B256, U256)>)> {
+    let balance = match account.balance.strip_prefix("0x") {
+        Some(hex) => U256::from_str_radix(hex, 16),
+        None => U256::from_str_radix(&account.balance, 10),
+    }
+    .map_err(|_| eyre!("Account {}: invalid balance {:?}", address, account.balance))?;
+
+    let mut slots = Vec::with_capacity(account.storage.len());
+    for (key, value) in account.storage {
+        let hex = value.strip_prefix("0x").unwrap_or(&value);
+        let value = if hex.is_empty() {
+            U256::ZERO
+        } else {
+            U256::from_str_radix(hex, 16).map_err(|_| {
+                eyre!(
+                    "Account {} slot {}: invalid value {:?}",
+                    address,
+                    key,
+                    value
+                )
+            })?
+        };
+        if !value.is_zero() {
+            slots.push((key, value));
+        }
+    }
+
+    let account = Account {
+        nonce: account.nonce,
+        balance,
+        bytecode_hash: account.code_hash.filter(|hash| *hash != KECCAK256_EMPTY),
+    };
+    Ok((account, slots))
+}
+
+fn account_record(key: &[u8], account: &Account) -> Vec<u8> {
+    let mut record = Vec::with_capacity(key.len() + ACCOUNT_RECORD);
+    record.extend_from_slice(key);
+    record.extend_from_slice(&account.nonce.to_be_bytes());
+    record.extend_from_slice(&account.balance.to_be_bytes::<32>());
+    record.push(account.bytecode_hash.is_some() as u8);
+    record.extend_from_slice(account.bytecode_hash.unwrap_or_default().as_slice());
+    record
+}
+
+fn slot_record(key: &[u8], slot: B256, value: U256) -> Vec<u8> {
+    let mut record = Vec::with_capacity(key.len() + SLOT_RECORD);
+    record.extend_from_slice(key);
+    record.extend_from_slice(slot.as_slice());
+    record.extend_from_slice(&value.to_be_bytes::<32>());
+    record
+}
+
+fn decode_account(data: &[u8]) -> Account {
+    Account {
+        nonce: u64::from_be_bytes(data[..8].try_into().unwrap()),
+        balance: U256::from_be_slice(&data[8..40]),
+        bytecode_hash: (data[40] != 0).then(|| B256::from_slice(&data[41..73])),
+    }
+}
+
+/// External merge sort of fixed-size records by their bytes.
+struct RecordSorter {
+    /// Where the sorted records end up.
+    path: PathBuf,
+    record_size: usize,
+    /// Records buffered before a run is spilled.
+    run_records: usize,
+    /// Output while every record so far has arrived in order; it becomes the first run once
+    /// one does not.
+    in_order: Option<BufWriter<File>>,
+    last: Vec<u8>,
+    buffer: Vec<u8>,
+    runs: Vec<PathBuf>,
+    count: usize,
+}
+
+impl RecordSorter {
+    fn new(path: PathBuf, record_size: usize, sort_buffer: usize) -> Result<Self> {
+        Ok(Self {
+            in_order: Some(BufWriter::new(File::create(&path)?)),
+            path,
+            record_size,
+            run_records: (sort_buffer / record_size).max(1),
+            last: Vec::new(),
+            buffer: Vec::new(),
+            runs: Vec::new(),
+            count: 0,
+        })
+    }
+
+    fn push(&mut self, record: &[u8]) -> Result<()> {
+        debug_assert_eq!(record.len(), self.record_size);
+        self.count += 1;
+
+        if let Some(out) = self.in_order.as_mut() {
+            if self.last.is_empty() || record > self.last.as_slice() {
+                out.write_all(record)?;
+                self.last.clear();
+                self.last.extend_from_slice(record);
+                return Ok(());
+            }
+            // What came so far is sorted already: keep it as the first run
+            out.flush()?;
+            self.in_order = None;
+            let run = self.run_path(0);
+            std::fs::rename(&self.path, &run)?;
+            self.runs.push(run);
+        }
+
+        self.buffer.extend_from_slice(record);
+        if self.buffer.len() >= self.run_records * self.record_size {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn run_path(&self, run: usize) -> PathBuf {
+        self.path.with_extension(format!("run{}", run))
+    }
+
+    /// Writes the buffered records out as a sorted run.
+    fn spill(&mut self) -> Result<()> {
+        let mut records: Vec<&[u8]> = self.buffer.chunks_exact(self.record_size).collect();
+        records.sort_unstable();
+        let run = self.run_path(self.runs.len());
+        let mut out = BufWriter::new(File::create(&run)?);
+        for record in records {
+            out.write_all(record)?;
+        }
+        out.flush()?;
+        self.runs.push(run);
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Merges the runs into the output file and returns its path.
+    fn finish(mut self) -> Result<PathBuf> {
+        if let Some(mut out) = self.in_order.take() {
+            out.flush()?;
+            return Ok(self.path);
+        }
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+
+        let mut runs = self
+            .runs
+            .iter()
+            .map(|run| Ok(BufReader::new(File::open(run)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let mut heads = BinaryHeap::new();
+        for (i, run) in runs.iter_mut().enumerate() {
+            if let Some(record) = read_record(run, self.record_size)? {
+                heads.push(Reverse((record, i)));
+            }
+        }
+
+        let mut out = BufWriter::new(File::create(&self.path)?);
+        while let Some(Reverse((record, i))) = heads.pop() {
+            out.write_all(&record)?;
+            if let Some(next) = read_record(&mut runs[i], self.record_size)? {
+                heads.push(Reverse((next, i)));
+            }
+        }
+        out.flush()?;
+
+        for run in &self.runs {
+            std::fs::remove_file(run)?;
+        }
+        Ok(self.path)
+    }
+}
+
+/// The next record of a run, or `None` at its end.
+fn read_record(run: &mut impl Read, record_size: usize) -> Result<Option<Vec<u8>>> {
+    let mut record = vec![0u8; record_size];
+    match run.read_exact(&mut record) {
+        Ok(()) => Ok(Some(record)),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A sorted record file, mapped for binary search.
+struct SortedRecords {
+    /// `None` for an empty file, which cannot be mapped.
+    mmap: Option<Mmap>,
+    record_size: usize,
+}
+
+impl SortedRecords {
+    fn open(path: PathBuf, record_size: usize) -> Result<Self> {
+        let file = File::open(path)?;
+        let mmap = if file.metadata()?.len() == 0 {
+            None
+        } else {
+            // SAFETY: the spill files are only written by `RecordSorter`, which is done
+            Some(unsafe { Mmap::map(&file)? })
+        };
+        Ok(Self { mmap, record_size })
+    }
+
+    fn len(&self) -> usize {
+        self.mmap.as_ref().map_or(0, |m| m.len() / self.record_size)
+    }
+
+    fn get(&self, i: usize) -> &[u8] {
+        let data = self.mmap.as_deref().unwrap_or_default();
+        &data[i * self.record_size..(i + 1) * self.record_size]
+    }
+
+    /// Index of the first record whose key (its first `after.len()` bytes) is above `after`.
+    fn above(&self, after: Option<&[u8]>) -> usize {
+        let Some(after) = after else {
+            return 0;
+        };
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if &self.get(mid)[..after.len()] <= after {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+}
+
+/// The sorted records of a loaded dump.
+///
+/// Serves [`StateSource`] when loaded in plain order and [`HashedSource`] when loaded with
+/// `hashed`; the spill directory is removed when it is dropped.
+pub struct DumpSource {
+    block: u64,
+    key_size: usize,
+    accounts: SortedRecords,
+    storage: SortedRecords,
+    spill_dir: PathBuf,
+}
+
+impl DumpSource {
+    fn account_batch<K>(
+        &self,
+        after: Option<&[u8]>,
+        limit: usize,
+        key: impl Fn(&[u8]) -> K,
+    ) -> Vec<(K, Account)> {
+        let start = self.accounts.above(after);
+        let end = start.saturating_add(limit).min(self.accounts.len());
+        (start..end)
+            .map(|i| {
+                let record = self.accounts.get(i);
+                let (k, data) = record.split_at(self.key_size);
+                (key(k), decode_account(data))
+            })
+            .collect()
+    }
+
+    /// Whole addresses above `after` until at least `limit` slots, as
+    /// [`StateSource::storage`] batches them.
+    fn storage_batch<K: PartialEq>(
+        &self,
+        after: Option<&[u8]>,
+        limit: usize,
+        key: impl Fn(&[u8]) -> K,
+    ) -> Vec<(K, B256, U256)> {
+        let mut slots: Vec<(K, B256, U256)> = Vec::new();
+        for i in self.storage.above(after)..self.storage.len() {
+            let record = self.storage.get(i);
+            let (k, data) = record.split_at(self.key_size);
+            let address = key(k);
+            if slots.len() >= limit && slots.last().is_some_and(|(last, _, _)| *last != address) {
+                break;
+            }
+            slots.push((
+                address,
+                B256::from_slice(&data[..32]),
+                U256::from_be_slice(&data[32..]),
+            ));
+        }
+        slots
+    }
+
+    fn ensure_order(&self, hashed: bool) -> Result<()> {
+        ensure!(
+            (self.key_size == 32) == hashed,
+            "dump was loaded {}",
+            if hashed {
+                "in plain order"
+            } else {
+                "in hashed order"
+            }
+        );
+        Ok(())
+    }
+}
+
+impl Drop for DumpSource {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.spill_dir);
+    }
+}
+
+impl StateSource for DumpSource {
+    fn tip_block(&self) -> Result<u64> {
+        Ok(self.block)
+    }
+
+    fn entries(&self) -> Result<(usize, usize)> {
+        Ok((self.accounts.len(), self.storage.len()))
+    }
+
+    fn accounts(&self, after: Option<Address>, limit: usize) -> Result<Vec<(Address, Account)>> {
+        self.ensure_order(false)?;
+        let after = after.as_ref().map(|a| a.as_slice());
+        Ok(self.account_batch(after, limit, Address::from_slice))
+    }
+
+    fn storage(&self, after: Option<Address>, limit: usize) -> Result<Vec<(Address, B256, U256)>> {
+        self.ensure_order(false)?;
+        let after = after.as_ref().map(|a| a.as_slice());
+        Ok(self.storage_batch(after, limit, Address::from_slice))
+    }
+}
+
+impl HashedSource for DumpSource {
+    fn hashed_accounts(&self, after: Option<B256>, limit: usize) -> Result<Vec<(B256, Account)>> {
+        self.ensure_order(true)?;
+        let after = after.as_ref().map(|k| k.as_slice());
+        Ok(self.account_batch(after, limit, B256::from_slice))
+    }
+
+    fn hashed_storage(&self, after: Option<B256>, limit: usize) -> Result<Vec<(B256, B256, U256)>> {
+        self.ensure_order(true)?;
+        let after = after.as_ref().map(|k| k.as_slice());
+        Ok(self.storage_batch(after, limit, B256::from_slice))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::MemorySource;
+
+    const STATE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/state.jsonl");
+    const DUMP: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/geth-dump.jsonl"
+    );
+
+    fn temp_dir(tag: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("plinko-geth-{}-{}", tag, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    type PlainState = (Vec<(Address, Account)>, Vec<(Address, B256, U256)>);
+    type HashedState = (Vec<(B256, Account)>, Vec<(B256, B256, U256)>);
+
+    fn plain(source: &impl StateSource) -> PlainState {
+        (
+            source.accounts(None, usize::MAX).unwrap(),
+            source.storage(None, usize::MAX).unwrap(),
+        )
+    }
+
+    fn hashed(source: &impl HashedSource) -> HashedState {
+        (
+            source.hashed_accounts(None, usize::MAX).unwrap(),
+            source.hashed_storage(None, usize::MAX).unwrap(),
+        )
+    }
+
+    /// Writes `state` as an iterative dump, in hashed-key order like geth.
+    fn write_dump(state: &MemorySource, path: &Path) {
+        let (accounts, storage) = plain(state);
+        let mut accounts: Vec<_> = accounts
+            .into_iter()
+            .map(|(address, account)| (keccak256(address), address, account))
+            .collect();
+        accounts.sort_unstable_by_key(|(key, _, _)| *key);
+
+        let mut lines = vec![serde_json::json!({ "root": B256::repeat_byte(0x77) }).to_string()];
+        for (key, address, account) in accounts {
+            let slots: serde_json::Map<_, _> = storage
+                .iter()
+                .filter(|(a, _, _)| *a == address)
+                .map(|(_, slot, value)| (slot.to_string(), format!("{:x}", value).into()))
+                .collect();
+            let line = serde_json::json!({
+                "balance": account.balance.to_string(),
+                "nonce": account.nonce,
+                "codeHash": account.bytecode_hash.unwrap_or(KECCAK256_EMPTY),
+                "storage": slots,
+                "address": address,
+                "key": key,
+            });
+            lines.push(line.to_string());
+        }
+        std::fs::write(path, lines.join("\n")).unwrap();
+    }
+
+    #[test]
+    fn iterative_dump_matches_state_fixture() {
+        let dir = temp_dir("iterative");
+        let expected = MemorySource::from_jsonl(Path::new(STATE)).unwrap();
+
+        let dump = load(Path::new(DUMP), 1000, false, &dir, 1 << 20).unwrap();
+        assert_eq!(
+            dump.root,
+            Some(
+                "0x1a8c9e1e0f6fb4b1b2f5c8b8f3b7d26c4f2b0a1e9c8d7f6e5d4c3b2a19080706"
+                    .parse()
+                    .unwrap()
+            )
+        );
+        // Same state, so the extractor writes the same artifacts
+        assert_eq!(dump.source.tip_block().unwrap(), 1000);
+        assert_eq!(dump.source.entries().unwrap(), expected.entries().unwrap());
+        assert_eq!(plain(&dump.source), plain(&expected));
+        assert!(dump.source.hashed_accounts(None, 1).is_err());
+        drop(dump);
+        assert!(!dir.exists());
+
+        let dump = load(Path::new(DUMP), 1000, true, &dir, 1 << 20).unwrap();
+        assert_eq!(hashed(&dump.source), hashed(&expected));
+        assert!(dump.source.accounts(None, 1).is_err());
+    }
+
+    #[test]
+    fn full_dump_matches_iterative() {
+        let dir = temp_dir("full");
+        let iterative = load(
+            Path::new(DUMP),
+            1000,
+            false,
+            &dir.join("iterative"),
+            1 << 20,
+        )
+        .unwrap();
+
+        // Re-nest the iterative lines into one `geth dump` object, keyed by address
+        let text = std::fs::read_to_string(DUMP).unwrap();
+        let mut lines = text
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap());
+        let root = lines.next().unwrap();
+        let accounts: serde_json::Map<String, serde_json::Value> = lines
+            .map(|mut account| {
+                let address = account["address"].as_str().unwrap().to_string();
+                account.as_object_mut().unwrap().remove("address");
+                (address, account)
+            })
+            .collect();
+        let full = serde_json::json!({ "root": root["root"], "accounts": accounts });
+
+        let path = dir.join("dump.json");
+        std::fs::write(&path, serde_json::to_vec_pretty(&full).unwrap()).unwrap();
+        let loaded = load(&path, 1000, false, &dir.join("full"), 1 << 20).unwrap();
+
+        assert_eq!(loaded.root, iterative.root);
+        assert_eq!(plain(&loaded.source), plain(&iterative.source));
+        drop(loaded);
+
+        // Whatever order the accounts come in, they load in both layouts
+        let loaded = load(&path, 1000, true, &dir.join("full"), 1 << 20).unwrap();
+        let expected = MemorySource::from_jsonl(Path::new(STATE)).unwrap();
+        assert_eq!(hashed(&loaded.source), hashed(&expected));
+        drop(loaded);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn spills_sorted_runs_and_batches_them() {
+        let dir = temp_dir("runs");
+        let mut state = MemorySource::new(7);
+        for i in 1..=60u8 {
+            let address = Address::repeat_byte(i);
+            let account = Account {
+                nonce: i as u64,
+                balance: U256::from(i) * U256::from(1_000_000_007u64),
+                bytecode_hash: (i % 3 == 0).then(|| B256::repeat_byte(i)),
+            };
+            state.insert_account(address, account);
+            for slot in 0..(i % 4) {
+                state.insert_storage(
+                    address,
+                    B256::with_last_byte(slot),
+                    U256::from(i) + U256::ONE,
+                );
+            }
+        }
+        let path = dir.join("dump.jsonl");
+        write_dump(&state, &path);
+
+        // A few records per run, so the plain order is merged from many runs
+        let spill = dir.join("spill");
+        let dump = load(&path, 7, false, &spill, 4 * (20 + ACCOUNT_RECORD)).unwrap();
+        assert!(!std::fs::read_dir(&spill).unwrap().any(|e| e
+            .unwrap()
+            .path()
+            .to_string_lossy()
+            .contains(".run")));
+        assert_eq!(dump.root, Some(B256::repeat_byte(0x77)));
+        assert_eq!(plain(&dump.source), plain(&state));
+
+        // Batches resume after the last key, and storage batches end on address boundaries
+        let mut after = None;
+        let mut accounts = Vec::new();
+        loop {
+            let batch = dump.source.accounts(after, 7).unwrap();
+            let Some(&(last, _)) = batch.last() else {
+                break;
+            };
+            assert!(batch.len() <= 7);
+            accounts.extend(batch);
+            after = Some(last);
+        }
+        assert_eq!(accounts, plain(&state).0);
+
+        let mut after = None;
+        let mut slots = Vec::new();
+        loop {
+            let batch = dump.source.storage(after, 5).unwrap();
+            let Some(&(last, _, _)) = batch.last() else {
+                break;
+            };
+            assert_eq!(batch, state.storage(after, 5).unwrap());
+            slots.extend(batch);
+            after = Some(last);
+        }
+        assert_eq!(slots, plain(&state).1);
+        drop(dump);
+
+        // In hashed order the dump is sorted already
+        let dump = load(&path, 7, true, &spill, 4 * (32 + ACCOUNT_RECORD)).unwrap();
+        assert_eq!(hashed(&dump.source), hashed(&state));
+        drop(dump);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_accounts_without_preimages() {
+        let dir = temp_dir("nopre");
+        let path = dir.join("dump.jsonl");
+        std::fs::write(
+            &path,
+            r#"{"balance": "1", "nonce": 0, "key": "0x0000000000000000000000000000000000000000000000000000000000000001"}"#,
+        )
+        .unwrap();
+        let err = load(&path, 0, false, &dir.join("spill"), 1 << 20)
+            .err()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(err.to_string().contains("no address"), "{}", err);
+    }
+}
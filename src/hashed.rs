@@ -0,0 +1,229 @@
+//! Hashed-key extraction (`--hashed`).
+//!
+//! Walks reth's `HashedAccounts`/`HashedStorages` instead of the plain state tables, so
+//! `database.bin` is ordered by `keccak256(address)` and `keccak256(slot)` — the order Merkle
+//! proofs are keyed in — and entries are spread uniformly over the index space rather than
+//! clustered by address. Mapping records carry the 32-byte hashed keys:
+//!
+//! - `account-mapping.bin`: `keccak256(Address) (32) || Index (4 or 8, LE)`
+//! - `storage-mapping.bin`: `keccak256(Address) (32) || keccak256(SlotKey) (32) || Index (4 or 8, LE)`
+
+use crate::{
+    filter::Filter,
+    progress::{Phase, Progress},
+    source::HashedSource,
+    writer::{ArtifactWriter, Totals},
+};
+use eyre::Result;
+use state_syncer::layout::AccountLayout;
+
+/// Extracts the state of `source` in hashed-key order, writing through `writer` when given.
+pub fn extract_hashed<S: HashedSource>(
+    source: &S,
+    mut writer: Option<&mut ArtifactWriter>,
+    filter: &Filter,
+    layout: AccountLayout,
+    limit: usize,
+    batch_size: usize,
+    progress: &Progress,
+) -> Result<Totals> {
+    let mut totals = Totals::default();
+
+    // --- ACCOUNTS ---
+    let mut last_key = None;
+    while filter.accounts() && (totals.accounts as usize) < limit {
+        let batch = source.hashed_accounts(last_key, batch_size)?;
+        let Some(&(last, _)) = batch.last() else {
+            break;
+        };
+
+        for (hashed_address, account) in batch {
+            if totals.accounts as usize >= limit {
+                break;
+            }
+            if !filter.keep_account(hashed_address.as_slice(), &account) {
+                continue;
+            }
+
+            if let Some(writer) = writer.as_mut() {
+                writer.write_account(hashed_address.as_slice(), &account, totals.total_indices)?;
+            }
+            totals.total_indices += layout.words();
+            totals.accounts += 1;
+
+            if totals.accounts % 10000 == 0 {
+                progress.update(
+                    Phase::Accounts,
+                    totals.accounts,
+                    format!("Acc: {}, Sto: 0", totals.accounts),
+                );
+            }
+        }
+        last_key = Some(last);
+    }
+    progress.finish_phase(Phase::Accounts, totals.accounts);
+
+    // --- STORAGE ---
+    let mut last_addr = None;
+    while filter.storage() && (totals.storage_slots as usize) < limit {
+        // Batches end on address boundaries, so resume after the last address
+        let batch = source.hashed_storage(last_addr, batch_size)?;
+        let Some(&(last, _, _)) = batch.last() else {
+            break;
+        };
+
+        for (hashed_address, hashed_key, value) in batch {
+            if totals.storage_slots as usize >= limit {
+                break;
+            }
+            if !filter.keep_storage(hashed_address.as_slice(), &value) {
+                continue;
+            }
+
+            if let Some(writer) = writer.as_mut() {
+                writer.write_storage(
+                    hashed_address.as_slice(),
+                    &hashed_key,
+                    &value,
+                    totals.total_indices,
+                )?;
+            }
+            totals.total_indices += 1;
+            totals.storage_slots += 1;
+
+            if totals.storage_slots % 10000 == 0 {
+                progress.update(
+                    Phase::Storage,
+                    totals.storage_slots,
+                    format!("Acc: {}, Sto: {}", totals.accounts, totals.storage_slots),
+                );
+            }
+        }
+        last_addr = Some(last);
+    }
+    progress.finish_phase(Phase::Storage, totals.storage_slots);
+
+    Ok(totals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{source::MemorySource, writer::MappingFormat};
+    use alloy_primitives::{address, b256, keccak256, B256, U256};
+    use state_syncer::mapping::{AccountMapping, MappingHeader, StorageMapping};
+    use std::path::{Path, PathBuf};
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/state.jsonl");
+
+    fn temp_dir(tag: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("plinko-hashed-{}-{}", tag, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn extract(source: &MemorySource, dir: &Path, batch_size: usize) -> Totals {
+        let format = MappingFormat {
+            key_size: 32,
+            index_width: 4,
+        };
+        let mut writer = ArtifactWriter::create(dir, format, AccountLayout::Standard).unwrap();
+        let filter = Filter::new(None, None, false, false, false, false).unwrap();
+        let totals = extract_hashed(
+            source,
+            Some(&mut writer),
+            &filter,
+            AccountLayout::Standard,
+            usize::MAX,
+            batch_size,
+            &Progress::hidden(),
+        )
+        .unwrap();
+        writer.finish().unwrap();
+        totals
+    }
+
+    fn read(dir: &Path, name: &str) -> Vec<u8> {
+        std::fs::read(dir.join(name)).unwrap()
+    }
+
+    #[test]
+    fn mappings_are_keyed_and_ordered_by_hash() {
+        let source = MemorySource::from_jsonl(Path::new(FIXTURE)).unwrap();
+        let dir = temp_dir("fixture");
+        let totals = extract(&source, &dir, 1000);
+        assert_eq!(
+            (totals.accounts, totals.storage_slots, totals.total_indices),
+            (3, 3, 12)
+        );
+
+        let mut accounts = [
+            address!("1111111111111111111111111111111111111111"),
+            address!("2222222222222222222222222222222222222222"),
+            address!("3333333333333333333333333333333333333333"),
+        ]
+        .map(|address| (keccak256(address), address));
+        accounts.sort();
+        let mut expected_acc = MappingHeader::new(32, 4, 3).encode().to_vec();
+        for (i, (hashed, _)) in accounts.iter().enumerate() {
+            expected_acc.extend_from_slice(hashed.as_slice());
+            expected_acc.extend_from_slice(&(i as u32 * 3).to_le_bytes());
+        }
+        assert_eq!(read(&dir, "account-mapping.bin"), expected_acc);
+
+        // Entries follow the hashed order: each account's nonce word sits at its index
+        let db = read(&dir, "database.bin");
+        let acc_map = AccountMapping::open_hashed(dir.join("account-mapping.bin")).unwrap();
+        for (hashed, address) in &accounts {
+            let index = acc_map.get(hashed.as_slice()).unwrap() as usize;
+            let nonce = u64::from_le_bytes(db[index * 32..index * 32 + 8].try_into().unwrap());
+            let expected = if address[0] == 0x33 { 0 } else { 1 };
+            assert_eq!(nonce, expected, "{}", address);
+        }
+
+        let contract = address!("2222222222222222222222222222222222222222");
+        let holder = address!("3333333333333333333333333333333333333333");
+        let mut slots = [
+            (contract, B256::ZERO, 0x2au64),
+            (
+                contract,
+                b256!("0000000000000000000000000000000000000000000000000000000000000001"),
+                0x0100,
+            ),
+            (
+                holder,
+                b256!("00000000000000000000000000000000000000000000000000000000000000aa"),
+                0x07,
+            ),
+        ]
+        .map(|(address, slot, value)| (keccak256(address), keccak256(slot), value));
+        slots.sort();
+        let mut expected_sto = MappingHeader::new(64, 4, 3).encode().to_vec();
+        for (i, (address, slot, value)) in slots.iter().enumerate() {
+            expected_sto.extend_from_slice(address.as_slice());
+            expected_sto.extend_from_slice(slot.as_slice());
+            expected_sto.extend_from_slice(&(9 + i as u32).to_le_bytes());
+            assert_eq!(
+                db[(9 + i) * 32..(10 + i) * 32],
+                U256::from(*value).to_le_bytes::<32>()
+            );
+        }
+        assert_eq!(read(&dir, "storage-mapping.bin"), expected_sto);
+        let sto_map = StorageMapping::open_hashed(dir.join("storage-mapping.bin")).unwrap();
+        assert_eq!(
+            sto_map.get(slots[2].0.as_slice(), slots[2].1.as_slice()),
+            Some(11)
+        );
+
+        // Batches end on hashed-address boundaries without moving anything
+        let batched = temp_dir("batched");
+        extract(&source, &batched, 1);
+        for name in ["database.bin", "account-mapping.bin", "storage-mapping.bin"] {
+            assert_eq!(read(&dir, name), read(&batched, name), "{}", name);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(batched).unwrap();
+    }
+}
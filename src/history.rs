@@ -0,0 +1,358 @@
+//! Historical state reconstruction from reth changesets.
+//!
+//! Reth only keeps the latest state in `PlainAccountState`/`PlainStorageState`. Every block
+//! also records the pre-state of everything it touched in `AccountChangeSets` and
+//! `StorageChangeSets`, so walking the changesets of blocks `N+1..=tip` and keeping the
+//! *first* pre-state seen for each key yields that key's value as of block `N`.
+
+use alloy_primitives::{Address, B256, U256};
+use eyre::{bail, Result};
+use reth_db::{cursor::DbCursorRO, models::BlockNumberAddress, tables, transaction::DbTx};
+use reth_primitives::Account;
+use std::collections::BTreeMap;
+
+/// Differences between the database tip and a target block.
+///
+/// The extraction loops walk the tip tables in key order and pass each entry through
+/// [`StateOverlay::merge_account`]/[`StateOverlay::merge_storage`], which substitutes the
+/// historical value and splices in keys that no longer exist at the tip.
+pub struct StateOverlay {
+    /// Account state at the target block (`None` = account did not exist yet).
+    accounts: BTreeMap<Address, Option<Account>>,
+    /// Storage values at the target block (zero = slot was empty).
+    storage: BTreeMap<(Address, B256), U256>,
+}
+
+impl StateOverlay {
+    /// Builds the overlay that rewinds the tip state back to `target`.
+    ///
+    /// Fails if the account or storage changesets for `target + 1` have been pruned from the
+    /// database.
+    pub fn rewind<TX: DbTx>(tx: &TX, target: u64, tip: u64) -> Result<Self> {
+        if target >= tip {
+            return Ok(Self::from_changesets([], []));
+        }
+
+        // Account and storage history are pruned independently
+        let mut acc_cursor = tx.cursor_read::<tables::AccountChangeSets>()?;
+        let first = acc_cursor.first()?.map(|(block, _)| block);
+        check_available("Account", first, target)?;
+        let mut sto_cursor = tx.cursor_read::<tables::StorageChangeSets>()?;
+        let first = sto_cursor.first()?.map(|(key, _)| key.block_number());
+        check_available("Storage", first, target)?;
+
+        let accounts = acc_cursor
+            .walk_range(target + 1..=tip)?
+            .map(|entry| entry.map(|(_block, before)| (before.address, before.info)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let storage = sto_cursor
+            .walk_range(BlockNumberAddress::range(target + 1..=tip))?
+            .map(|entry| {
+                entry.map(|(block_address, before)| {
+                    ((block_address.address(), before.key), before.value)
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self::from_changesets(accounts, storage))
+    }
+
+    /// Folds changeset pre-states, given in block order, into an overlay. The first
+    /// pre-state seen for a key is its value before the earliest block that touched it.
+    pub fn from_changesets(
+        accounts: impl IntoIterator<Item = (Address, Option<Account>)>,
+        storage: impl IntoIterator<Item = ((Address, B256), U256)>,
+    ) -> Self {
+        let mut overlay = Self {
+            accounts: BTreeMap::new(),
+            storage: BTreeMap::new(),
+        };
+        for (address, info) in accounts {
+            overlay.accounts.entry(address).or_insert(info);
+        }
+        for (key, value) in storage {
+            overlay.storage.entry(key).or_insert(value);
+        }
+        overlay
+    }
+
+    /// Consumes the overlay, returning the per-key historical values.
+    #[allow(clippy::type_complexity)]
+    pub fn into_maps(
+        self,
+    ) -> (
+        BTreeMap<Address, Option<Account>>,
+        BTreeMap<(Address, B256), U256>,
+    ) {
+        (self.accounts, self.storage)
+    }
+
+    /// Number of accounts and storage slots whose value differs from the tip.
+    pub fn counts(&self) -> (usize, usize) {
+        (self.accounts.len(), self.storage.len())
+    }
+
+    /// Splits off the part of the overlay at or after `address`, leaving the rest in `self`.
+    pub fn split_off(&mut self, address: &Address) -> Self {
+        Self {
+            accounts: self.accounts.split_off(address),
+            storage: self.storage.split_off(&(*address, B256::ZERO)),
+        }
+    }
+
+    /// Drops accounts up to and including `address`, which a resumed run already wrote.
+    pub fn skip_accounts_through(&mut self, address: &Address) {
+        self.accounts.retain(|addr, _| addr > address);
+    }
+
+    /// Drops storage slots up to and including `(address, key)`, which a resumed run
+    /// already wrote.
+    pub fn skip_storage_through(&mut self, address: &Address, key: &B256) {
+        self.storage
+            .retain(|(addr, slot), _| (addr, slot) > (address, key));
+    }
+
+    /// Resolves the tip entry `(address, account)` to its historical value.
+    ///
+    /// Pushes into `out`, in address order, every overlay-only account sorting before
+    /// `address` followed by `address` itself if it existed at the target block.
+    pub fn merge_account(
+        &mut self,
+        address: Address,
+        account: Account,
+        out: &mut Vec<(Address, Account)>,
+    ) {
+        while let Some(entry) = self.accounts.first_entry() {
+            if *entry.key() >= address {
+                break;
+            }
+            let (addr, info) = entry.remove_entry();
+            out.extend(info.map(|acc| (addr, acc)));
+        }
+
+        match self.accounts.remove(&address) {
+            Some(info) => out.extend(info.map(|acc| (address, acc))),
+            None => out.push((address, account)),
+        }
+    }
+
+    /// Pushes all remaining overlay-only accounts (those after the last tip entry).
+    pub fn drain_accounts(&mut self, out: &mut Vec<(Address, Account)>) {
+        for (addr, info) in std::mem::take(&mut self.accounts) {
+            out.extend(info.map(|acc| (addr, acc)));
+        }
+    }
+
+    /// Resolves the tip slot `(address, key, value)` to its historical value.
+    ///
+    /// Same contract as [`StateOverlay::merge_account`]; slots that were zero at the target
+    /// block are omitted, matching reth which never stores zero-valued slots.
+    pub fn merge_storage(
+        &mut self,
+        address: Address,
+        key: B256,
+        value: U256,
+        out: &mut Vec<(Address, B256, U256)>,
+    ) {
+        while let Some(entry) = self.storage.first_entry() {
+            if *entry.key() >= (address, key) {
+                break;
+            }
+            let ((addr, slot), old) = entry.remove_entry();
+            if !old.is_zero() {
+                out.push((addr, slot, old));
+            }
+        }
+
+        match self.storage.remove(&(address, key)) {
+            Some(old) if old.is_zero() => {}
+            Some(old) => out.push((address, key, old)),
+            None => out.push((address, key, value)),
+        }
+    }
+
+    /// Pushes the remaining overlay-only slots of the addresses up to and including
+    /// `address`: the ones sorting after the tip's last slot of `address`, once no more tip
+    /// slots of it will come.
+    pub fn drain_storage_through(
+        &mut self,
+        address: &Address,
+        out: &mut Vec<(Address, B256, U256)>,
+    ) {
+        while let Some(entry) = self.storage.first_entry() {
+            if entry.key().0 > *address {
+                break;
+            }
+            let ((addr, slot), old) = entry.remove_entry();
+            if !old.is_zero() {
+                out.push((addr, slot, old));
+            }
+        }
+    }
+
+    /// Pushes all remaining overlay-only storage slots (those after the last tip entry).
+    pub fn drain_storage(&mut self, out: &mut Vec<(Address, B256, U256)>) {
+        for ((addr, slot), old) in std::mem::take(&mut self.storage) {
+            if !old.is_zero() {
+                out.push((addr, slot, old));
+            }
+        }
+    }
+}
+
+/// Fails unless the `kind` changesets, whose first block is `first`, reach back to the block
+/// after `target`.
+fn check_available(kind: &str, first: Option<u64>, target: u64) -> Result<()> {
+    match first {
+        Some(first) if first <= target + 1 => Ok(()),
+        Some(first) => bail!(
+            "{} changesets are only available from block #{}, cannot rewind to #{}",
+            kind,
+            first,
+            target
+        ),
+        None => bail!(
+            "No {} changesets in database, cannot rewind to #{}",
+            kind.to_lowercase(),
+            target
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::{address, b256};
+
+    const A: Address = address!("1111111111111111111111111111111111111111");
+    const B: Address = address!("2222222222222222222222222222222222222222");
+    const C: Address = address!("3333333333333333333333333333333333333333");
+    const SLOT_1: B256 = b256!("0000000000000000000000000000000000000000000000000000000000000001");
+    const SLOT_2: B256 = b256!("0000000000000000000000000000000000000000000000000000000000000002");
+
+    fn account(nonce: u64) -> Account {
+        Account {
+            nonce,
+            balance: U256::from(nonce * 100),
+            bytecode_hash: None,
+        }
+    }
+
+    /// Blocks 11 and 12 both touched A and A's slot 1; B was created in block 12 and C
+    /// deleted in block 11.
+    fn overlay() -> StateOverlay {
+        StateOverlay::from_changesets(
+            [
+                (A, Some(account(1))),
+                (C, Some(account(7))),
+                (A, Some(account(2))),
+                (B, None),
+            ],
+            [
+                ((A, SLOT_1), U256::from(10)),
+                ((A, SLOT_1), U256::from(20)),
+                ((A, SLOT_2), U256::ZERO),
+                ((C, SLOT_1), U256::from(5)),
+            ],
+        )
+    }
+
+    #[test]
+    fn first_pre_state_wins() {
+        let (accounts, storage) = overlay().into_maps();
+        assert_eq!(
+            accounts.into_iter().collect::<Vec<_>>(),
+            [(A, Some(account(1))), (B, None), (C, Some(account(7)))]
+        );
+        assert_eq!(storage[&(A, SLOT_1)], U256::from(10));
+        assert_eq!(storage[&(A, SLOT_2)], U256::ZERO);
+        assert_eq!(overlay().counts(), (3, 3));
+    }
+
+    #[test]
+    fn merges_tip_entries_with_historical_values() {
+        // Tip: A (changed since), B (created since); C was deleted since
+        let mut overlay = overlay();
+        let mut accounts = Vec::new();
+        overlay.merge_account(A, account(3), &mut accounts);
+        overlay.merge_account(B, account(9), &mut accounts);
+        overlay.drain_accounts(&mut accounts);
+        assert_eq!(accounts, [(A, account(1)), (C, account(7))]);
+
+        // Tip: A's slot 1 (changed) and slot 2 (written since, empty before); C's slot 1 is
+        // gone at the tip
+        let mut storage = Vec::new();
+        overlay.merge_storage(A, SLOT_1, U256::from(30), &mut storage);
+        overlay.merge_storage(A, SLOT_2, U256::from(1), &mut storage);
+        overlay.drain_storage(&mut storage);
+        assert_eq!(
+            storage,
+            [(A, SLOT_1, U256::from(10)), (C, SLOT_1, U256::from(5))]
+        );
+
+        // A batch ending on A's slot 1 takes A's later slots with it, but not C's
+        let mut overlay = StateOverlay::from_changesets(
+            [],
+            [
+                ((A, SLOT_1), U256::from(10)),
+                ((A, SLOT_2), U256::from(4)),
+                ((C, SLOT_1), U256::from(5)),
+            ],
+        );
+        let mut storage = Vec::new();
+        overlay.merge_storage(A, SLOT_1, U256::from(30), &mut storage);
+        overlay.drain_storage_through(&A, &mut storage);
+        assert_eq!(
+            storage,
+            [(A, SLOT_1, U256::from(10)), (A, SLOT_2, U256::from(4))]
+        );
+        assert_eq!(overlay.counts(), (0, 1));
+
+        // Keys the changesets never touched keep their tip value
+        let mut overlay = StateOverlay::from_changesets([], []);
+        let mut accounts = Vec::new();
+        overlay.merge_account(A, account(3), &mut accounts);
+        assert_eq!(accounts, [(A, account(3))]);
+    }
+
+    #[test]
+    fn splits_and_skips_at_key_boundaries() {
+        let mut low = overlay();
+        let high = low.split_off(&B);
+        let (accounts, storage) = low.into_maps();
+        assert_eq!(accounts.keys().collect::<Vec<_>>(), [&A]);
+        assert_eq!(storage.len(), 2);
+        let (accounts, storage) = high.into_maps();
+        assert_eq!(accounts.keys().collect::<Vec<_>>(), [&B, &C]);
+        assert_eq!(storage.keys().collect::<Vec<_>>(), [&(C, SLOT_1)]);
+
+        let mut overlay = overlay();
+        overlay.skip_accounts_through(&B);
+        overlay.skip_storage_through(&A, &SLOT_1);
+        let (accounts, storage) = overlay.into_maps();
+        assert_eq!(accounts.keys().collect::<Vec<_>>(), [&C]);
+        assert_eq!(
+            storage.keys().collect::<Vec<_>>(),
+            [&(A, SLOT_2), &(C, SLOT_1)]
+        );
+    }
+
+    #[test]
+    fn pruned_changesets_are_refused() {
+        assert!(check_available("Account", Some(5), 10).is_ok());
+        assert!(check_available("Storage", Some(11), 10).is_ok());
+
+        // Storage history pruned further than account history
+        let err = check_available("Storage", Some(12), 10).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Storage changesets are only available from block #12, cannot rewind to #10"
+        );
+        let err = check_available("Storage", None, 10).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No storage changesets in database, cannot rewind to #10"
+        );
+    }
+}
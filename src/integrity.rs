@@ -0,0 +1,94 @@
+//! Integrity manifest writing and the `verify` subcommand.
+//!
+//! The state artifacts are hashed by [`ArtifactWriter`](crate::writer::ArtifactWriter) as
+//! they are written; every other artifact, and any file rewritten after extraction (cuckoo
+//! layout, sharded stitching, grouped storage mapping), is hashed in one streaming pass once
+//! final, so the manifest always covers the bytes actually shipped.
+
+use eyre::{bail, eyre, Result};
+use state_syncer::manifest::{
+    FileHashes, Manifest, DEFAULT_CHUNK_SIZE, MANIFEST_FILE, MANIFEST_VERSION,
+};
+use std::path::Path;
+
+/// Writes `manifest.json` next to `metadata.json`, hashing the artifacts in `output_dir`
+/// that `written` (hashes taken while writing the final files) does not cover.
+pub fn write_manifest(
+    output_dir: &Path,
+    bytecode: bool,
+    trie: bool,
+    mut written: Vec<FileHashes>,
+) -> Result<Manifest> {
+    let mut names: Vec<String> = ["database.bin", "account-mapping.bin", "storage-mapping.bin"]
+        .map(String::from)
+        .to_vec();
+    if bytecode {
+        names.push("code-database.bin".to_string());
+        names.push("code-mapping.bin".to_string());
+    }
+    if trie {
+        let mut trie_files: Vec<String> = std::fs::read_dir(output_dir.join("trie"))?
+            .map(|entry| Ok(format!("trie/{}", entry?.file_name().to_string_lossy())))
+            .collect::<Result<_>>()?;
+        trie_files.sort();
+        names.extend(trie_files);
+    }
+
+    let missing: Vec<String> = names
+        .iter()
+        .filter(|name| !written.iter().any(|f| &f.name == *name))
+        .cloned()
+        .collect();
+    written.extend(Manifest::build(output_dir, &missing, DEFAULT_CHUNK_SIZE)?.files);
+    let files = names
+        .iter()
+        .map(|name| {
+            let i = written.iter().position(|f| &f.name == name).unwrap();
+            written.swap_remove(i)
+        })
+        .collect();
+
+    let manifest = Manifest {
+        version: MANIFEST_VERSION,
+        chunk_size: DEFAULT_CHUNK_SIZE,
+        files,
+    };
+    manifest.save(output_dir)?;
+    Ok(manifest)
+}
+
+/// Re-hashes every file listed in the manifest and reports the first bad chunk of each.
+pub fn run_verify(output_dir: &Path) -> Result<()> {
+    let now = || chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+
+    let manifest = Manifest::load(output_dir)?
+        .ok_or_else(|| eyre!("No {} in {:?}", MANIFEST_FILE, output_dir))?;
+    println!(
+        "[{}] Verifying {} files in {:?} ({} byte chunks)",
+        now(),
+        manifest.files.len(),
+        output_dir,
+        manifest.chunk_size
+    );
+
+    let mut failed = 0;
+    for entry in &manifest.files {
+        match manifest.verify_file(output_dir, entry)? {
+            None => println!("[{}] OK    {} ({} bytes)", now(), entry.name, entry.size),
+            Some(problem) => {
+                println!("[{}] BAD   {}: {}", now(), entry.name, problem);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!(
+            "{} of {} files failed verification",
+            failed,
+            manifest.files.len()
+        );
+    }
+    println!("[{}] All files verified.", now());
+    Ok(())
+}
@@ -0,0 +1,195 @@
+//! Address and slot lookups over finished artifacts (`plinko-extractor lookup`).
+//!
+//! Binary-searches `account-mapping.bin`/`storage-mapping.bin` in `--output-dir` and decodes
+//! the `database.bin` words the index points at. Only the artifacts are read, not the reth
+//! database. Snapshots extracted with `--hashed` (per `metadata.json`) are looked up by the
+//! hashed address and slot, and accounts are decoded with the recorded account layout.
+
+use alloy_primitives::{keccak256, Address, B256, U256};
+use eyre::{ensure, eyre, Result};
+use memmap2::Mmap;
+use state_syncer::{
+    mapping::{AccountMapping, StorageMapping},
+    metadata::{KeyOrder, Metadata},
+};
+use std::{fs::File, io::Write, path::Path};
+
+#[derive(clap::Args, Debug)]
+pub struct LookupArgs {
+    /// Account address (0x-prefixed hex)
+    address: Address,
+
+    /// Storage slot key; looks up the slot of `address` instead of the account
+    #[arg(long)]
+    slot: Option<B256>,
+}
+
+/// Prints the index and decoded entry of an account or storage slot.
+pub fn run(output_dir: &Path, args: &LookupArgs) -> Result<()> {
+    lookup(output_dir, args, &mut std::io::stdout().lock())
+}
+
+fn lookup(output_dir: &Path, args: &LookupArgs, out: &mut impl Write) -> Result<()> {
+    let metadata = Metadata::load(output_dir)?;
+    let layout = metadata
+        .as_ref()
+        .map(|m| m.layout.account_layout)
+        .unwrap_or_default();
+    let hashed = metadata.is_some_and(|m| m.key_order == KeyOrder::Hashed);
+    let hashed_address = keccak256(args.address);
+    let address_key = if hashed {
+        hashed_address.as_slice()
+    } else {
+        args.address.as_slice()
+    };
+
+    let file = File::open(output_dir.join("database.bin"))?;
+    let db = unsafe { Mmap::map(&file)? };
+    let words = |index: u64, n: u64| -> Result<&[u8]> {
+        let (start, end) = (index as usize * 32, (index + n) as usize * 32);
+        ensure!(
+            end <= db.len(),
+            "Index {} is beyond the end of database.bin ({} words)",
+            index + n - 1,
+            db.len() / 32
+        );
+        Ok(&db[start..end])
+    };
+
+    match args.slot {
+        None => {
+            let acc_map = if hashed {
+                AccountMapping::open_hashed(output_dir.join("account-mapping.bin"))?
+            } else {
+                AccountMapping::open(output_dir.join("account-mapping.bin"))?
+            };
+            let index = acc_map
+                .get(address_key)
+                .ok_or_else(|| eyre!("Account {} is not in the snapshot", args.address))?;
+
+            let account = layout.decode(words(index, layout.words())?)?;
+            writeln!(out, "Account {}", args.address)?;
+            if hashed {
+                writeln!(out, "  Key:       {}", hashed_address)?;
+            }
+            writeln!(out, "  Index:     {}", index)?;
+            if let Some(nonce) = account.nonce {
+                writeln!(out, "  Nonce:     {}", nonce)?;
+            }
+            writeln!(
+                out,
+                "  Balance:   {} wei",
+                U256::from_le_bytes(account.balance)
+            )?;
+            if let Some(code_hash) = account.code_hash {
+                writeln!(out, "  Code hash: {}", B256::from(code_hash))?;
+            }
+        }
+        Some(slot) => {
+            let sto_map = if hashed {
+                StorageMapping::open_hashed(output_dir.join("storage-mapping.bin"))?
+            } else {
+                StorageMapping::open(output_dir.join("storage-mapping.bin"))?
+            };
+            let slot_key = if hashed { keccak256(slot) } else { slot };
+            let index = sto_map
+                .get(address_key, slot_key.as_slice())
+                .ok_or_else(|| eyre!("Slot {} of {} is not in the snapshot", slot, args.address))?;
+
+            let value = U256::from_le_bytes::<32>(words(index, 1)?.try_into().unwrap());
+            writeln!(out, "Storage {} slot {}", args.address, slot)?;
+            if hashed {
+                writeln!(out, "  Key:   {} {}", hashed_address, slot_key)?;
+            }
+            writeln!(out, "  Index: {}", index)?;
+            writeln!(out, "  Value: {:#x} ({})", value, value)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state_syncer::{
+        layout::AccountLayout,
+        synthetic::{generate, SyntheticConfig},
+    };
+
+    fn lookup_output(dir: &Path, address: Address, slot: Option<B256>) -> Result<String> {
+        let mut out = Vec::new();
+        lookup(dir, &LookupArgs { address, slot }, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn prints_decoded_entries_of_a_synthetic_snapshot() {
+        let dir = std::env::temp_dir().join(format!("plinko-lookup-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = SyntheticConfig {
+            accounts: 200,
+            storage_slots: 500,
+            seed: 5,
+            ..Default::default()
+        };
+        generate(&dir, &config).unwrap();
+
+        // Contracts hold all the storage, so the first slot's owner is a contract
+        let sto_map = StorageMapping::open(dir.join("storage-mapping.bin")).unwrap();
+        let (key, slot_index) = sto_map.record(0).unwrap();
+        let address = Address::from_slice(&key[..20]);
+        let slot = B256::from_slice(&key[20..]);
+        let acc_map = AccountMapping::open(dir.join("account-mapping.bin")).unwrap();
+        let index = acc_map.get(address.as_slice()).unwrap();
+
+        // Expected values straight from the Standard layout's words
+        let db = std::fs::read(dir.join("database.bin")).unwrap();
+        let word = |i: u64| &db[i as usize * 32..(i as usize + 1) * 32];
+        let nonce = u64::from_le_bytes(word(index)[..8].try_into().unwrap());
+        let balance = U256::from_le_slice(word(index + 1));
+        let code_hash = B256::from_slice(word(index + 2));
+        let value = U256::from_le_slice(word(slot_index));
+        assert_eq!(nonce, 1);
+        assert_ne!(code_hash, B256::ZERO);
+
+        let account = format!(
+            "Account {}\n  Index:     {}\n  Nonce:     1\n  Balance:   {} wei\n  Code hash: {}\n",
+            address, index, balance, code_hash
+        );
+        assert_eq!(lookup_output(&dir, address, None).unwrap(), account);
+        assert_eq!(
+            lookup_output(&dir, address, Some(slot)).unwrap(),
+            format!(
+                "Storage {} slot {}\n  Index: {}\n  Value: {:#x} ({})\n",
+                address, slot, slot_index, value, value
+            )
+        );
+
+        let absent = Address::repeat_byte(0xfe);
+        assert!(acc_map.get(absent.as_slice()).is_none());
+        let err = lookup_output(&dir, absent, None).unwrap_err();
+        assert!(
+            err.to_string().contains("is not in the snapshot"),
+            "{}",
+            err
+        );
+
+        drop((acc_map, sto_map));
+        // The same state packed into two words decodes to the same account
+        generate(
+            &dir,
+            &SyntheticConfig {
+                account_layout: AccountLayout::Packed,
+                ..config
+            },
+        )
+        .unwrap();
+        let packed = account.replace(
+            &format!("Index:     {}", index),
+            &format!("Index:     {}", index / 3 * 2),
+        );
+        assert_eq!(lookup_output(&dir, address, None).unwrap(), packed);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
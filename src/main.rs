@@ -1,29 +1,61 @@
-use alloy_primitives::U256;
-use clap::Parser;
-use eyre::Result;
-use indicatif::{ProgressBar, ProgressStyle};
-use reth_db::{
-    cursor::{DbCursorRO, DbDupCursorRO},
-    database::Database,
-    open_db_read_only, tables,
-    transaction::DbTx,
-};
-use std::{
-    fs::File,
-    io::{BufWriter, Write},
-    path::PathBuf,
-    time::{Duration, Instant},
+mod bytecode;
+mod checkpoint;
+mod colocate;
+mod cuckoo;
+mod deltas;
+mod filter;
+mod geth;
+mod hashed;
+mod history;
+mod integrity;
+mod lookup;
+mod parallel;
+mod progress;
+mod sequential;
+mod source;
+mod trie;
+mod writer;
+
+use alloy_primitives::B256;
+use bytecode::CodeTotals;
+use checkpoint::Checkpoint;
+use clap::{Parser, Subcommand};
+use cuckoo::CuckooSummary;
+use eyre::{ensure, Result};
+use filter::Filter;
+use history::StateOverlay;
+use parallel::ShardOptions;
+use progress::{Phase, Progress};
+use reth_chainspec::{HOLESKY, HOODI, MAINNET, SEPOLIA};
+use reth_db::{database::Database, open_db_read_only, tables, transaction::DbTx};
+use sequential::PlainOptions;
+use source::{HashedSource, MemorySource, RethSource, StateSource};
+use state_syncer::{
+    db::DB_ENTRY_SIZE,
+    layout::AccountLayout,
+    manifest::FileHashes,
+    mapping::{index_width_for, StorageMapping, StorageMappingFormat},
+    metadata::{
+        CodeInfo, FileInfo, KeyOrder, Metadata, PaddingInfo, RecordLayout, TrieInfo,
+        METADATA_SCHEMA_VERSION,
+    },
 };
+use std::{path::PathBuf, time::Duration};
+use trie::TrieTotals;
+use writer::{ArtifactWriter, MappingFormat, Totals};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path to the Reth database folder (e.g. /home/user/.local/share/reth/mainnet/db)
-    #[arg(long, default_value = "/var/lib/reth/mainnet/db")]
+    #[arg(long, global = true, default_value = "/var/lib/reth/mainnet/db")]
     db_path: PathBuf,
 
     /// Output directory for artifacts
-    #[arg(long, default_value = "data")]
+    #[arg(long, global = true, default_value = "data")]
     output_dir: PathBuf,
 
     /// Limit the number of accounts/slots to extract (useful for testing)
@@ -37,303 +69,837 @@ struct Args {
     /// Batch size for transactions (items per transaction)
     #[arg(long, default_value_t = 100_000)]
     batch_size: usize,
+
+    /// Extract state as of this block instead of the database tip.
+    /// Requires the changesets for all later blocks (not pruned).
+    #[arg(long)]
+    at_block: Option<u64>,
+
+    /// Resume an interrupted extraction from the checkpoint in --output-dir
+    #[arg(long, default_value_t = false)]
+    resume: bool,
+
+    /// Split the address keyspace into this many ranges and extract them in parallel.
+    /// Output is identical to the sequential extraction.
+    #[arg(long, default_value_t = 1)]
+    shards: usize,
+
+    /// Also extract contract code from the Bytecodes table into
+    /// code-database.bin/code-mapping.bin
+    #[arg(long, default_value_t = false)]
+    bytecode: bool,
+
+    /// Lay out database.bin as cuckoo hash tables (key tag + record per slot) so clients
+    /// can locate entries from the seeds in metadata.json instead of the mapping files
+    #[arg(long, default_value_t = false)]
+    cuckoo: bool,
+
+    /// Seed for the cuckoo hash seeds (the layout is reproducible for a given seed)
+    #[arg(long, default_value_t = 0)]
+    cuckoo_seed: u64,
+
+    /// Read HashedAccounts/HashedStorages so entries are ordered by keccak256(address) and
+    /// keccak256(slot), with the mappings keyed by the hashed keys
+    #[arg(long, default_value_t = false)]
+    hashed: bool,
+
+    /// Also extract AccountsTrie/StoragesTrie branch nodes into one database per trie depth
+    /// under <output-dir>/trie
+    #[arg(long, default_value_t = false)]
+    trie: bool,
+
+    /// Chain id to record in metadata.json (detected from the genesis hash for mainnet and
+    /// the public testnets)
+    #[arg(long)]
+    chain_id: Option<u64>,
+
+    /// Only extract the accounts (and storage) of the addresses in this file, one 0x address
+    /// per line
+    #[arg(long)]
+    include_addresses: Option<PathBuf>,
+
+    /// Skip the accounts (and storage) of the addresses in this file
+    #[arg(long)]
+    exclude_addresses: Option<PathBuf>,
+
+    /// Skip accounts with nonce 0, balance 0 and no code
+    #[arg(long, default_value_t = false)]
+    skip_empty_accounts: bool,
+
+    /// Skip zero-valued storage slots
+    #[arg(long, default_value_t = false)]
+    skip_zero_storage: bool,
+
+    /// Extract accounts only (no storage)
+    #[arg(long, default_value_t = false)]
+    accounts_only: bool,
+
+    /// Extract storage only (no accounts)
+    #[arg(long, default_value_t = false)]
+    storage_only: bool,
+
+    /// How accounts are encoded in database.bin: standard (nonce, balance, code hash; 3
+    /// words), packed (nonce and a 24-byte balance in one word, then the code hash; 2 words)
+    /// or balance-only (1 word)
+    #[arg(long, value_enum, default_value_t = AccountLayout::Standard)]
+    account_layout: AccountLayout,
+
+    /// How storage-mapping.bin is written: flat (address, slot and index per slot) or
+    /// grouped (one entry per contract, then the slot keys; roughly half the size)
+    #[arg(long, value_enum, default_value_t = StorageMappingFormat::Flat)]
+    storage_mapping: StorageMappingFormat,
+
+    /// Keep each contract's storage slots within pages of this many bytes (e.g. 4096, or the
+    /// Plinko block size), padding database.bin with zero entries where a run would straddle
+    /// a boundary
+    #[arg(long)]
+    storage_alignment: Option<u64>,
+
+    /// Extract the state in this JSON-lines file instead of reading a reth database
+    #[arg(long)]
+    state_jsonl: Option<PathBuf>,
+
+    /// Extract the state in this `geth dump` output (JSON or `--iterative` JSON lines)
+    /// instead of reading a reth database
+    #[arg(long)]
+    geth_dump: Option<PathBuf>,
+
+    /// Block the --geth-dump was taken at, recorded in metadata.json
+    #[arg(long, requires = "geth_dump")]
+    dump_block: Option<u64>,
+
+    /// Memory in MiB each of the two --geth-dump sorts (accounts, storage) uses before
+    /// spilling sorted runs to the output directory
+    #[arg(long, default_value_t = 1024, requires = "geth_dump")]
+    sort_buffer: usize,
+
+    /// Write JSON-lines progress events (phase, rows done, estimated total, bytes written,
+    /// rate, ETA) and a final summary matching metadata.json to this file, or `-` for stderr
+    #[arg(long)]
+    progress_json: Option<PathBuf>,
+
+    /// Minimum seconds between --progress-json events within a phase
+    #[arg(long, default_value_t = 10, requires = "progress_json")]
+    progress_interval: u64,
 }
 
-fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let args = Args::parse();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Produce per-block XOR delta files from reth changesets, indexed through the
+    /// mapping files in --output-dir
+    Deltas(deltas::DeltasArgs),
 
-    let now = || chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    /// Re-hash the artifacts in --output-dir against manifest.json and report the first
+    /// bad chunk of each file
+    Verify,
 
-    println!("[{}] Opening database at {:?}", now(), args.db_path);
+    /// Print the index and decoded database.bin entry of an account, or of one of its
+    /// storage slots with --slot, from the mapping files in --output-dir
+    Lookup(lookup::LookupArgs),
 
-    // We keep the DB open, but we will open/close TXs
-    let db = open_db_read_only(&args.db_path, Default::default())?;
+    /// Report the padding overhead and pages read of aligning the storage runs in
+    /// --output-dir to a page size, against the layout without alignment
+    Colocation(colocate::ColocationArgs),
+}
 
-    let (mut db_writer, mut acc_map_writer, mut sto_map_writer) = if !args.count_only {
-        // Create output directory
-        std::fs::create_dir_all(&args.output_dir)?;
-        let db_file_path = args.output_dir.join("database.bin");
-        let acc_map_path = args.output_dir.join("account-mapping.bin");
-        let sto_map_path = args.output_dir.join("storage-mapping.bin");
+fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::parse();
 
-        println!("[{}] Writing outputs to:", now());
-        println!("  Database: {:?}", db_file_path);
-        println!("  Acc Map:  {:?}", acc_map_path);
-        println!("  Sto Map:  {:?}", sto_map_path);
+    match &args.command {
+        Some(Command::Deltas(cmd)) => deltas::run(&args.db_path, &args.output_dir, cmd),
+        Some(Command::Verify) => integrity::run_verify(&args.output_dir),
+        Some(Command::Lookup(cmd)) => lookup::run(&args.output_dir, cmd),
+        Some(Command::Colocation(cmd)) => colocate::run(&args.output_dir, cmd),
+        None => extract(&args),
+    }
+}
 
-        (
-            Some(BufWriter::new(File::create(&db_file_path)?)),
-            Some(BufWriter::new(File::create(&acc_map_path)?)),
-            Some(BufWriter::new(File::create(&sto_map_path)?)),
-        )
-    } else {
-        println!(
-            "[{}] Running in COUNT-ONLY mode. No files will be written.",
-            now()
+/// Extracts the full snapshot (`database.bin`, mappings and `metadata.json`).
+fn extract(args: &Args) -> Result<()> {
+    let now = || chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+
+    ensure!(
+        !(args.resume && args.count_only),
+        "--resume cannot be combined with --count-only"
+    );
+    ensure!(
+        !(args.cuckoo && args.count_only),
+        "--cuckoo cannot be combined with --count-only"
+    );
+    ensure!(
+        !(args.cuckoo && args.storage_mapping == StorageMappingFormat::Grouped),
+        "--storage-mapping grouped cannot be combined with --cuckoo"
+    );
+    if let Some(alignment) = args.storage_alignment {
+        colocate::check_alignment(alignment)?;
+        ensure!(
+            args.shards <= 1 && !args.hashed && !args.cuckoo,
+            "--storage-alignment cannot be combined with --shards, --hashed or --cuckoo"
         );
-        (None, None, None)
+    }
+    ensure!(
+        args.shards <= 1 || (!args.resume && !args.count_only && args.limit.is_none()),
+        "--shards cannot be combined with --resume, --count-only or --limit"
+    );
+    ensure!(
+        !args.hashed
+            || (args.at_block.is_none() && args.shards <= 1 && !args.resume && !args.cuckoo),
+        "--hashed cannot be combined with --at-block, --shards, --resume or --cuckoo"
+    );
+    ensure!(
+        !(args.trie && args.at_block.is_some()),
+        "--trie cannot be combined with --at-block (reth only keeps the tip trie)"
+    );
+    ensure!(
+        !(args.state_jsonl.is_some() && args.geth_dump.is_some()),
+        "--state-jsonl cannot be combined with --geth-dump"
+    );
+    ensure!(
+        (args.state_jsonl.is_none() && args.geth_dump.is_none())
+            || (args.at_block.is_none() && args.shards <= 1 && !args.bytecode && !args.trie),
+        "--state-jsonl and --geth-dump cannot be combined with --at-block, --shards, --bytecode \
+         or --trie"
+    );
+    ensure!(
+        args.geth_dump.is_none() || args.dump_block.is_some(),
+        "--geth-dump needs --dump-block (the block the dump was taken at)"
+    );
+    let filter = Filter::new(
+        args.include_addresses.as_deref(),
+        args.exclude_addresses.as_deref(),
+        args.skip_empty_accounts,
+        args.skip_zero_storage,
+        args.accounts_only,
+        args.storage_only,
+    )?;
+    let resume_from = if args.resume {
+        let checkpoint = Checkpoint::load(&args.output_dir)?;
+        if checkpoint.is_none() {
+            println!("[{}] No checkpoint found, starting from scratch", now());
+        }
+        checkpoint
+    } else {
+        None
     };
 
     // Setup progress bar
-    let pb = ProgressBar::new_spinner();
-    pb.set_style(
-        ProgressStyle::default_spinner()
-            .template("{spinner:.green} [{elapsed_precise}] {msg}")
-            .unwrap(),
-    );
+    let progress = Progress::new(
+        args.progress_json.as_deref(),
+        Duration::from_secs(args.progress_interval),
+        (!args.count_only).then_some(args.output_dir.as_path()),
+    )?;
 
     let limit = args.limit.unwrap_or(usize::MAX);
     let batch_size = args.batch_size;
 
+    // --- FILE INPUTS ---
+    if let Some(path) = &args.state_jsonl {
+        println!("[{}] Loading state from {:?}", now(), path);
+        let source = MemorySource::from_jsonl(path)?;
+        return extract_memory(
+            args,
+            &source,
+            None,
+            &filter,
+            resume_from.as_ref(),
+            &progress,
+        );
+    }
+    if let Some(path) = &args.geth_dump {
+        println!("[{}] Loading geth dump from {:?}", now(), path);
+        let dump = geth::load(
+            path,
+            args.dump_block.unwrap_or_default(),
+            args.hashed,
+            &args.output_dir.join("geth-sort"),
+            args.sort_buffer << 20,
+        )?;
+        let (accounts, slots) = dump.source.entries()?;
+        println!(
+            "[{}] Sorted {} accounts and {} storage slots",
+            now(),
+            accounts,
+            slots
+        );
+        return extract_memory(
+            args,
+            &dump.source,
+            dump.root,
+            &filter,
+            resume_from.as_ref(),
+            &progress,
+        );
+    }
+
+    println!("[{}] Opening database at {:?}", now(), args.db_path);
+
+    // We keep the DB open, but we will open/close TXs
+    let db = open_db_read_only(&args.db_path, Default::default())?;
+    let source = RethSource::new(&db);
+
     // --- READ CHAIN INFO ---
     println!("[{}] Reading Chain Info...", now());
+    let last_block = source.tip_block()?;
     let tx = db.tx()?;
-    let last_block = tx
-        .cursor_read::<tables::CanonicalHeaders>()?
-        .last()?
-        .map(|(num, _hash)| num)
-        .unwrap_or(0);
     println!("[{}] Database Tip: Block #{}", now(), last_block);
-    drop(tx);
 
-    // --- PROCESS ACCOUNTS ---
-    println!("[{}] Processing Accounts...", now());
-    let mut count_acc = 0;
-    let mut total_indices = 0;
-    let mut last_acc_key = None;
-
-    loop {
-        if count_acc >= limit {
-            break;
+    // --- REWIND TO TARGET BLOCK ---
+    let overlay = match args.at_block {
+        Some(target) => {
+            ensure!(
+                target <= last_block,
+                "Target block #{} is beyond the database tip #{}",
+                target,
+                last_block
+            );
+            println!(
+                "[{}] Rewinding state from #{} to #{}...",
+                now(),
+                last_block,
+                target
+            );
+            let overlay = StateOverlay::rewind(&tx, target, last_block)?;
+            let (acc_changes, sto_changes) = overlay.counts();
+            println!(
+                "[{}] Rewound {} accounts and {} storage slots",
+                now(),
+                acc_changes,
+                sto_changes
+            );
+            Some(overlay)
         }
+        None => None,
+    };
+    let snapshot_block = args.at_block.unwrap_or(last_block);
+    let chain_id = match args.chain_id {
+        Some(id) => Some(id),
+        None => tx
+            .get::<tables::CanonicalHeaders>(0)?
+            .and_then(known_chain_id),
+    };
+    let snapshot = SnapshotInfo {
+        chain_id,
+        block: snapshot_block,
+        block_hash: tx.get::<tables::CanonicalHeaders>(snapshot_block)?,
+        state_root: tx
+            .get::<tables::Headers>(snapshot_block)?
+            .map(|header| header.state_root),
+    };
+    match chain_id {
+        Some(id) => println!("[{}] Chain ID: {}", now(), id),
+        None => println!(
+            "[{}] Unknown chain (pass --chain-id to record it in metadata.json)",
+            now()
+        ),
+    }
 
-        let tx = db.tx()?;
-        let mut cursor = tx.cursor_read::<tables::PlainAccountState>()?;
-
-        // Resume from last key
-        let walker = if let Some(key) = last_acc_key.clone() {
-            let mut w = cursor.walk(Some(key))?;
-            // Skip the first element if it matches the last key we processed
-            if let Some(Ok((addr, _))) = w.next() {
-                if addr != key {
-                    // If it's different, we shouldn't have skipped it.
-                    drop(w);
-                    let mut w2 = cursor.walk(Some(key))?;
-                    w2
-                } else {
-                    w
-                }
-            } else {
-                w
-            }
-        } else {
-            cursor.walk(None)?
-        };
+    // --- MAPPING FORMAT ---
+    // Upper bound on the index space: every tip entry plus every key the rewind adds back
+    let (tip_accounts, tip_slots) = if args.hashed {
+        (
+            tx.entries::<tables::HashedAccounts>()?,
+            tx.entries::<tables::HashedStorages>()?,
+        )
+    } else {
+        source.entries()?
+    };
+    let (rewound_accounts, rewound_slots) = overlay.as_ref().map_or((0, 0), |o| o.counts());
+    estimate_state(
+        &progress,
+        args,
+        (tip_accounts + rewound_accounts) as u64,
+        (tip_slots + rewound_slots) as u64,
+    );
+    let account_words = args.account_layout.words();
+    let max_indices = (tip_accounts + rewound_accounts) as u64 * account_words
+        + max_storage_entries(args, (tip_slots + rewound_slots) as u64);
+    let format = MappingFormat {
+        key_size: if args.hashed { 32 } else { 20 },
+        index_width: index_width_for(max_indices),
+    };
+    drop(tx);
 
-        let mut batch_count = 0;
-        let mut current_acc_key = None;
-
-        for entry in walker {
-            let (address, account) = entry?;
-
-            // Double check skipping
-            if let Some(last) = &last_acc_key {
-                if &address == last {
-                    continue;
-                }
-            }
-
-            if count_acc >= limit {
-                break;
-            }
-
-            // --- WRITE DATABASE ENTRY ---
-            // Account layout: 3 words (96 bytes)
-            //   Word 0: Nonce (u64 in first 8 bytes, zero-padded to 32)
-            //   Word 1: Balance (u256, 32 bytes LE)
-            //   Word 2: Bytecode Hash (32 bytes)
-            // Note: Previously used 4 words with padding, removed to reduce N by ~12%
-            if let Some(writer) = db_writer.as_mut() {
-                // 1. Nonce (u64 -> 32 bytes)
-                let mut nonce_bytes = [0u8; 32];
-                nonce_bytes[0..8].copy_from_slice(&account.nonce.to_le_bytes());
-                writer.write_all(&nonce_bytes)?;
-
-                // 2. Balance (u256 -> 32 bytes)
-                writer.write_all(&account.balance.to_le_bytes::<32>())?;
-
-                // 3. Bytecode Hash (Option<B256> -> 32 bytes)
-                let code_hash = account.bytecode_hash.unwrap_or_default();
-                writer.write_all(code_hash.as_slice())?;
-            }
-
-            // --- WRITE MAPPING ---
-            if let Some(writer) = acc_map_writer.as_mut() {
-                // Address (20) + Index (4)
-                writer.write_all(address.as_slice())?;
-                writer.write_all(&(total_indices as u32).to_le_bytes())?;
-            }
-
-            total_indices += 3; // 3 words per account
-            count_acc += 1;
-            batch_count += 1;
-            current_acc_key = Some(address);
-
-            if count_acc % 10000 == 0 {
-                pb.set_message(format!("Acc: {}, Sto: 0", count_acc));
-                pb.inc(1);
-            }
-
-            if batch_count >= batch_size {
-                break;
-            }
-        }
+    let mut writer = open_writer(args, format, resume_from.as_ref())?;
 
-        // End of batch
-        if batch_count == 0 {
-            break;
-        }
+    // --- PARALLEL EXTRACTION ---
+    if args.shards > 1 {
+        println!(
+            "[{}] Extracting {} shards in parallel...",
+            now(),
+            args.shards
+        );
+        let totals = parallel::extract_sharded(
+            &source,
+            args.shards,
+            overlay,
+            &args.output_dir,
+            ShardOptions {
+                format,
+                layout: args.account_layout,
+                filter: &filter,
+                batch_size,
+            },
+            &progress,
+        )?;
+        let code = extract_code(&db, args, &progress)?;
+        let trie = extract_trie(&db, args, &progress)?;
+        progress.finish_and_clear();
+        println!(
+            "[{}] Done! Acc: {}, Sto: {}, Total Indices: {}",
+            now(),
+            totals.accounts,
+            totals.storage_slots,
+            totals.total_indices
+        );
+        let cuckoo = build_cuckoo(args)?;
+        write_metadata(
+            args,
+            &snapshot,
+            &filter,
+            totals,
+            code,
+            trie.as_ref(),
+            cuckoo.as_ref(),
+            Vec::new(),
+            &progress,
+        )?;
+        println!("[{}] Extraction complete.", now());
+        return Ok(());
+    }
 
-        last_acc_key = current_acc_key;
-        drop(tx); // Close transaction
+    // --- HASHED EXTRACTION ---
+    if args.hashed {
+        println!("[{}] Processing HashedAccounts/HashedStorages...", now());
+        let totals = hashed::extract_hashed(
+            &source,
+            writer.as_mut(),
+            &filter,
+            args.account_layout,
+            limit,
+            batch_size,
+            &progress,
+        )?;
+        let code = extract_code(&db, args, &progress)?;
+        let trie = extract_trie(&db, args, &progress)?;
+        progress.finish_and_clear();
+        println!(
+            "[{}] Done! Acc: {}, Sto: {}, Total Indices: {}",
+            now(),
+            totals.accounts,
+            totals.storage_slots,
+            totals.total_indices
+        );
+        if let Some(writer) = writer {
+            let written = writer.finish()?;
+            write_metadata(
+                args,
+                &snapshot,
+                &filter,
+                totals,
+                code,
+                trie.as_ref(),
+                None,
+                written,
+                &progress,
+            )?;
+        }
+        println!("[{}] Extraction complete.", now());
+        return Ok(());
     }
 
+    let opts = PlainOptions {
+        block: snapshot_block,
+        output_dir: &args.output_dir,
+        filter: &filter,
+        layout: args.account_layout,
+        limit,
+        batch_size,
+        storage_alignment: args.storage_alignment,
+    };
+    let totals = sequential::extract_plain(
+        &source,
+        writer.as_mut(),
+        overlay,
+        resume_from.as_ref(),
+        opts,
+        &progress,
+    )?;
+
+    let code = extract_code(&db, args, &progress)?;
+    let trie = extract_trie(&db, args, &progress)?;
+
+    progress.finish_and_clear();
     println!(
-        "[{}] Processed {} accounts. Current Index: {}",
+        "[{}] Done! Acc: {}, Sto: {}, Total Indices: {}",
         now(),
-        count_acc,
-        total_indices
+        totals.accounts,
+        totals.storage_slots,
+        totals.total_indices
     );
 
-    // --- PROCESS STORAGE ---
-    println!("[{}] Processing Storage...", now());
-    let mut count_sto = 0;
-    let mut last_sto_addr = None;
+    let written = match writer {
+        Some(writer) => writer.finish()?,
+        None => Vec::new(),
+    };
 
-    loop {
-        if count_sto >= limit {
-            break;
-        }
+    // --- WRITE METADATA ---
+    if !args.count_only {
+        let cuckoo = build_cuckoo(args)?;
+        write_metadata(
+            args,
+            &snapshot,
+            &filter,
+            totals,
+            code,
+            trie.as_ref(),
+            cuckoo.as_ref(),
+            written,
+            &progress,
+        )?;
+        Checkpoint::remove(&args.output_dir)?;
+    }
 
-        let tx = db.tx()?;
-        let mut cursor = tx.cursor_dup_read::<tables::PlainStorageState>()?;
-
-        // Resume Logic for Storage
-        let walker = if let Some(addr) = last_sto_addr.clone() {
-            // Try to seek to the address
-            if let Some((_k, _)) = cursor.seek_exact(addr)? {
-                // If found, jump to next unique key
-                if let Some((next_k, _)) = cursor.next_no_dup()? {
-                    cursor.walk(Some(next_k))?
-                } else {
-                    // End of database
-                    break;
-                }
-            } else {
-                // If `addr` not found, just walk from `addr` and filtering will handle it
-                cursor.walk(Some(addr))?
-            }
-        } else {
-            cursor.walk(None)?
-        };
+    println!("[{}] Extraction complete.", now());
 
-        let mut batch_count = 0;
-        let mut current_addr = None;
-
-        for entry in walker {
-            let (address, storage_entry) = entry?;
-
-            // Robust skipping: if we are still seeing the old address, skip it
-            if let Some(last) = &last_sto_addr {
-                if &address == last {
-                    continue;
-                }
-            }
-
-            // Check if we crossed a boundary and should stop for this batch
-            if let Some(curr) = current_addr {
-                if address != curr && batch_count >= batch_size {
-                    break;
-                }
-            }
-
-            current_addr = Some(address);
-
-            if count_sto >= limit {
-                break;
-            }
-
-            // --- WRITE DATABASE ENTRY ---
-            if let Some(writer) = db_writer.as_mut() {
-                writer.write_all(&storage_entry.value.to_le_bytes::<32>())?;
-            }
-
-            // --- WRITE MAPPING ---
-            if let Some(writer) = sto_map_writer.as_mut() {
-                writer.write_all(address.as_slice())?;
-                writer.write_all(storage_entry.key.as_slice())?;
-                writer.write_all(&(total_indices as u32).to_le_bytes())?;
-            }
-
-            total_indices += 1;
-            count_sto += 1;
-            batch_count += 1;
-
-            if count_sto % 10000 == 0 {
-                pb.set_message(format!("Acc: {}, Sto: {}", count_acc, count_sto));
-                pb.inc(1);
-            }
-        }
+    Ok(())
+}
 
-        if batch_count == 0 {
-            break;
-        }
+/// Extracts state loaded from a `--state-jsonl` file or `--geth-dump`; there is no reth
+/// database to read code, trie nodes or headers from.
+fn extract_memory<S: StateSource + HashedSource>(
+    args: &Args,
+    source: &S,
+    state_root: Option<B256>,
+    filter: &Filter,
+    resume_from: Option<&Checkpoint>,
+    progress: &Progress,
+) -> Result<()> {
+    let now = || chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    let snapshot = SnapshotInfo {
+        chain_id: args.chain_id,
+        block: source.tip_block()?,
+        block_hash: None,
+        state_root,
+    };
 
-        last_sto_addr = current_addr;
-        drop(tx);
-    }
+    let (accounts, slots) = source.entries()?;
+    estimate_state(progress, args, accounts as u64, slots as u64);
+    let format = MappingFormat {
+        key_size: if args.hashed { 32 } else { 20 },
+        index_width: index_width_for(
+            accounts as u64 * args.account_layout.words() + max_storage_entries(args, slots as u64),
+        ),
+    };
+    let mut writer = open_writer(args, format, resume_from)?;
 
-    pb.finish_and_clear();
+    let limit = args.limit.unwrap_or(usize::MAX);
+    let totals = if args.hashed {
+        hashed::extract_hashed(
+            source,
+            writer.as_mut(),
+            filter,
+            args.account_layout,
+            limit,
+            args.batch_size,
+            progress,
+        )?
+    } else {
+        let opts = PlainOptions {
+            block: snapshot.block,
+            output_dir: &args.output_dir,
+            filter,
+            layout: args.account_layout,
+            limit,
+            batch_size: args.batch_size,
+            storage_alignment: args.storage_alignment,
+        };
+        sequential::extract_plain(source, writer.as_mut(), None, resume_from, opts, progress)?
+    };
+    progress.finish_and_clear();
     println!(
         "[{}] Done! Acc: {}, Sto: {}, Total Indices: {}",
         now(),
-        count_acc,
-        count_sto,
-        total_indices
+        totals.accounts,
+        totals.storage_slots,
+        totals.total_indices
     );
 
-    if let Some(mut writer) = db_writer {
-        writer.flush()?;
-    }
-    if let Some(mut writer) = acc_map_writer {
-        writer.flush()?;
+    if let Some(writer) = writer {
+        let written = writer.finish()?;
+        let cuckoo = build_cuckoo(args)?;
+        write_metadata(
+            args,
+            &snapshot,
+            filter,
+            totals,
+            None,
+            None,
+            cuckoo.as_ref(),
+            written,
+            progress,
+        )?;
+        Checkpoint::remove(&args.output_dir)?;
     }
-    if let Some(mut writer) = sto_map_writer {
-        writer.flush()?;
+    println!("[{}] Extraction complete.", now());
+    Ok(())
+}
+
+/// Records the expected rows of the state phases for `--progress-json`.
+fn estimate_state(progress: &Progress, args: &Args, accounts: u64, slots: u64) {
+    let limit = args.limit.unwrap_or(usize::MAX) as u64;
+    progress.estimate(Phase::Accounts, accounts.min(limit));
+    progress.estimate(Phase::Storage, slots.min(limit));
+    progress.estimate(Phase::State, accounts + slots);
+}
+
+/// Upper bound on the storage entries of `slots` slots, counting `--storage-alignment`
+/// padding (which is shorter than the run it precedes).
+fn max_storage_entries(args: &Args, slots: u64) -> u64 {
+    match args.storage_alignment {
+        Some(_) => 2 * slots,
+        None => slots,
     }
+}
 
-    // --- WRITE METADATA ---
-    if !args.count_only {
-        let meta_path = args.output_dir.join("metadata.json");
-        let json = format!(
-            r#"{{
-  "block": {},
-  "accounts": {},
-  "storage_slots": {},
-  "total_indices": {},
-  "generated_at": "{}"
-}}"#,
-            last_block,
-            count_acc,
-            count_sto,
-            total_indices,
+/// Creates the artifact files, or reopens them at `resume_from`'s offsets.
+///
+/// Returns `None` in count-only mode and for sharded runs, whose shards write their own
+/// files that are stitched together at the end.
+fn open_writer(
+    args: &Args,
+    format: MappingFormat,
+    resume_from: Option<&Checkpoint>,
+) -> Result<Option<ArtifactWriter>> {
+    let now = || chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    if args.count_only {
+        println!(
+            "[{}] Running in COUNT-ONLY mode. No files will be written.",
             now()
         );
-        std::fs::write(meta_path, json)?;
+        return Ok(None);
     }
 
-    println!("[{}] Extraction complete.", now());
+    // Create output directory
+    std::fs::create_dir_all(&args.output_dir)?;
+    let db_file_path = args.output_dir.join("database.bin");
+    let acc_map_path = args.output_dir.join("account-mapping.bin");
+    let sto_map_path = args.output_dir.join("storage-mapping.bin");
+
+    println!("[{}] Writing outputs to:", now());
+    println!("  Database: {:?}", db_file_path);
+    println!("  Acc Map:  {:?}", acc_map_path);
+    println!("  Sto Map:  {:?}", sto_map_path);
+
+    Ok(match resume_from {
+        _ if args.shards > 1 => None,
+        Some(checkpoint) => Some(ArtifactWriter::resume(
+            &args.output_dir,
+            checkpoint.offsets,
+            args.account_layout,
+        )?),
+        None => Some(ArtifactWriter::create(
+            &args.output_dir,
+            format,
+            args.account_layout,
+        )?),
+    })
+}
+
+/// Runs the bytecode phase when `--bytecode` is set.
+fn extract_code<DB: Database>(
+    db: &DB,
+    args: &Args,
+    progress: &Progress,
+) -> Result<Option<CodeTotals>> {
+    if !args.bytecode {
+        return Ok(None);
+    }
+    let now = || chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    println!("[{}] Processing Bytecodes...", now());
+    progress.estimate(
+        Phase::Bytecode,
+        db.tx()?.entries::<tables::Bytecodes>()? as u64,
+    );
+    let output_dir = (!args.count_only).then_some(args.output_dir.as_path());
+    let code = bytecode::extract_bytecodes(db, output_dir, args.batch_size, progress)?;
+    println!(
+        "[{}] Processed {} bytecodes ({} code words)",
+        now(),
+        code.bytecodes,
+        code.code_words
+    );
+    Ok(Some(code))
+}
+
+/// Runs the trie node phase when `--trie` is set.
+fn extract_trie<DB: Database>(
+    db: &DB,
+    args: &Args,
+    progress: &Progress,
+) -> Result<Option<TrieTotals>> {
+    if !args.trie {
+        return Ok(None);
+    }
+    let now = || chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    println!("[{}] Processing Trie Nodes...", now());
+    let tx = db.tx()?;
+    progress.estimate(
+        Phase::Trie,
+        (tx.entries::<tables::AccountsTrie>()? + tx.entries::<tables::StoragesTrie>()?) as u64,
+    );
+    drop(tx);
+    let output_dir = (!args.count_only).then_some(args.output_dir.as_path());
+    let trie = trie::extract_trie(db, output_dir, args.batch_size, progress)?;
+    println!(
+        "[{}] Processed {} account trie nodes and {} storage trie nodes",
+        now(),
+        trie.account_nodes.iter().sum::<u64>(),
+        trie.storage_nodes.iter().sum::<u64>()
+    );
+    Ok(Some(trie))
+}
+
+/// Rewrites the artifacts into the cuckoo layout when `--cuckoo` is set.
+fn build_cuckoo(args: &Args) -> Result<Option<CuckooSummary>> {
+    if !args.cuckoo {
+        return Ok(None);
+    }
+    let now = || chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    println!("[{}] Building cuckoo layout...", now());
+    let opts = state_syncer::cuckoo::BuildOptions {
+        seed: args.cuckoo_seed,
+        ..Default::default()
+    };
+    let summary = cuckoo::apply(&args.output_dir, opts, args.account_layout)?;
+    println!(
+        "[{}] Cuckoo layout: {} account buckets, {} storage buckets, {} total indices",
+        now(),
+        summary.accounts.params.num_buckets,
+        summary.storage.params.num_buckets,
+        summary.total_indices()
+    );
+    Ok(Some(summary))
+}
+
+/// Rewrites `storage-mapping.bin` grouped by contract when `--storage-mapping grouped` is set.
+fn group_storage_mapping(args: &Args) -> Result<()> {
+    if args.storage_mapping != StorageMappingFormat::Grouped {
+        return Ok(());
+    }
+    let now = || chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    println!("[{}] Grouping storage mapping by contract...", now());
+    let path = args.output_dir.join("storage-mapping.bin");
+    let tmp = args.output_dir.join("storage-mapping.bin.tmp");
+    let flat_size = std::fs::metadata(&path)?.len();
+    let header = state_syncer::mapping::write_grouped(&StorageMapping::open(&path)?, &tmp)?;
+    std::fs::rename(&tmp, &path)?;
+    println!(
+        "[{}] Grouped storage mapping: {} contracts, {} slots, {} -> {} bytes",
+        now(),
+        header.groups,
+        header.slots,
+        flat_size,
+        header.file_size()
+    );
+    Ok(())
+}
+
+/// Chain context of the extracted block, recorded in `metadata.json`.
+struct SnapshotInfo {
+    chain_id: Option<u64>,
+    block: u64,
+    block_hash: Option<B256>,
+    state_root: Option<B256>,
+}
+
+/// Chain id of a well-known network, identified by its genesis hash.
+fn known_chain_id(genesis_hash: B256) -> Option<u64> {
+    [&MAINNET, &SEPOLIA, &HOLESKY, &HOODI]
+        .into_iter()
+        .find(|spec| spec.genesis_hash() == genesis_hash)
+        .map(|spec| spec.chain.id())
+}
+
+/// Groups the storage mapping if asked to, hashes the finished artifacts into
+/// `manifest.json` (reusing the `written` hashes of files that were not rewritten since),
+/// then writes `metadata.json` with the file sizes and whole-file hashes from it. Aligned
+/// extractions also get their colocation report.
+#[allow(clippy::too_many_arguments)]
+fn write_metadata(
+    args: &Args,
+    snapshot: &SnapshotInfo,
+    filter: &Filter,
+    totals: Totals,
+    code: Option<CodeTotals>,
+    trie: Option<&TrieTotals>,
+    cuckoo: Option<&CuckooSummary>,
+    written: Vec<FileHashes>,
+    progress: &Progress,
+) -> Result<()> {
+    group_storage_mapping(args)?;
+    let grouped = args.storage_mapping == StorageMappingFormat::Grouped;
+    let written = written
+        .into_iter()
+        .filter(|f| cuckoo.is_none() && !(grouped && f.name == "storage-mapping.bin"))
+        .collect();
+    let now = || chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    println!("[{}] Hashing artifacts for manifest.json...", now());
+    let manifest = integrity::write_manifest(&args.output_dir, args.bytecode, args.trie, written)?;
+
+    let metadata = Metadata {
+        schema_version: METADATA_SCHEMA_VERSION,
+        chain_id: snapshot.chain_id,
+        block: snapshot.block,
+        block_hash: snapshot.block_hash.map(|hash| hash.to_string()),
+        state_root: snapshot.state_root.map(|root| root.to_string()),
+        entry_size: DB_ENTRY_SIZE as u64,
+        layout: RecordLayout::new(
+            args.account_layout,
+            ArtifactWriter::read_format(&args.output_dir)?.index_width,
+        ),
+        key_order: if args.hashed {
+            KeyOrder::Hashed
+        } else {
+            KeyOrder::Plain
+        },
+        accounts: totals.accounts,
+        storage_slots: totals.storage_slots,
+        total_indices: cuckoo.map_or(totals.total_indices, |c| c.total_indices()),
+        filter: filter.info(),
+        files: manifest
+            .files
+            .iter()
+            .map(|f| FileInfo {
+                name: f.name.clone(),
+                size: f.size,
+                blake3: f.blake3.clone(),
+            })
+            .collect(),
+        bytecode: code.map(|code| CodeInfo {
+            bytecodes: code.bytecodes,
+            code_words: code.code_words,
+        }),
+        trie: trie.map(|trie| TrieInfo {
+            node_words: trie::NODE_WORDS,
+            account_nodes: trie.account_nodes.clone(),
+            storage_nodes: trie.storage_nodes.clone(),
+        }),
+        cuckoo: cuckoo.map(CuckooSummary::info),
+        padding: args.storage_alignment.map(|alignment| PaddingInfo {
+            alignment,
+            entries: totals.total_indices
+                - totals.accounts * args.account_layout.words()
+                - totals.storage_slots,
+        }),
+        synthetic: None,
+        generated_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+    };
+    metadata.save(&args.output_dir)?;
+    progress.summary(&metadata);
 
+    if let Some(alignment) = args.storage_alignment {
+        colocate::report(&args.output_dir, alignment)?;
+    }
     Ok(())
 }
@@ -0,0 +1,609 @@
+//! Range-sharded parallel extraction (`--shards N`).
+//!
+//! The address keyspace is split into `N` contiguous ranges on the first two address bytes.
+//! Each shard reads its range of accounts and storage from the [`StateSource`] one batch at
+//! a time and writes a private set of artifacts with shard-relative indices.
+//! The shards are then stitched together in range order (all accounts first, then all
+//! storage), rebasing the mapping indices, which reproduces the sequential output exactly.
+//! Shards use the final mapping format, so rebased indices are checked against its width.
+//!
+//! At most `available_parallelism()` shards run at once, each worker taking the next shard
+//! when it finishes one; the first shard to fail stops the others.
+
+use crate::{
+    filter::Filter,
+    history::StateOverlay,
+    progress::{Phase, Progress},
+    source::StateSource,
+    writer::{ArtifactWriter, MappingFormat, Totals},
+};
+use alloy_primitives::{Address, B256, U256};
+use eyre::{ensure, eyre, Report, Result};
+use reth_primitives::Account;
+use state_syncer::{
+    layout::AccountLayout,
+    mapping::{write_index, MappingHeader, MAPPING_HEADER_SIZE},
+};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+/// Maximum number of shards (one per two-byte address prefix). Shards beyond the number
+/// of available cores queue for a worker.
+pub const MAX_SHARDS: usize = 1 << 16;
+
+/// Half-open address range `[start, end)` handled by one shard; `None` means unbounded.
+#[derive(Debug, Clone, Copy)]
+struct KeyRange {
+    start: Option<Address>,
+    end: Option<Address>,
+}
+
+/// The part of a [`StateSource`] in one shard's address range.
+struct ShardSource<'a, S> {
+    inner: &'a S,
+    range: KeyRange,
+}
+
+impl<S: StateSource> ShardSource<'_, S> {
+    /// Reads after `after`, or from the start of the range.
+    fn after(&self, after: Option<Address>) -> Option<Address> {
+        after.or_else(|| self.range.start.map(predecessor))
+    }
+
+    fn contains(&self, address: &Address) -> bool {
+        self.range.end.is_none_or(|end| *address < end)
+    }
+
+    /// As [`StateSource::accounts`], up to the end of the range.
+    fn accounts(&self, after: Option<Address>, limit: usize) -> Result<Vec<(Address, Account)>> {
+        let mut batch = self.inner.accounts(self.after(after), limit)?;
+        batch.retain(|(address, _)| self.contains(address));
+        Ok(batch)
+    }
+
+    /// As [`StateSource::storage`], up to the end of the range.
+    fn storage(&self, after: Option<Address>, limit: usize) -> Result<Vec<(Address, B256, U256)>> {
+        let mut batch = self.inner.storage(self.after(after), limit)?;
+        batch.retain(|(address, _, _)| self.contains(address));
+        Ok(batch)
+    }
+}
+
+/// The address right before `address`, which must not be zero.
+fn predecessor(address: Address) -> Address {
+    let mut bytes = address.0 .0;
+    for byte in bytes.iter_mut().rev() {
+        let (previous, borrow) = byte.overflowing_sub(1);
+        *byte = previous;
+        if !borrow {
+            break;
+        }
+    }
+    Address::from(bytes)
+}
+
+/// Output settings shared by every shard.
+#[derive(Clone, Copy)]
+pub struct ShardOptions<'a> {
+    pub format: MappingFormat,
+    pub layout: AccountLayout,
+    pub filter: &'a Filter,
+    pub batch_size: usize,
+}
+
+/// Row counts produced by one shard.
+#[derive(Debug, Default, Clone, Copy)]
+struct ShardCounts {
+    accounts: u64,
+    storage_slots: u64,
+}
+
+/// Splits the address space into `n` ranges on the two leading address bytes.
+fn shard_ranges(n: usize) -> Vec<KeyRange> {
+    let boundary = |i: usize| {
+        let prefix = ((i * MAX_SHARDS) / n) as u16;
+        let mut bytes = [0u8; 20];
+        bytes[..2].copy_from_slice(&prefix.to_be_bytes());
+        Address::from(bytes)
+    };
+    (0..n)
+        .map(|i| KeyRange {
+            start: (i > 0).then(|| boundary(i)),
+            end: (i + 1 < n).then(|| boundary(i + 1)),
+        })
+        .collect()
+}
+
+/// Runs a sharded extraction of `source` into `output_dir` and returns the totals.
+pub fn extract_sharded<S: StateSource + Sync>(
+    source: &S,
+    shards: usize,
+    overlay: Option<StateOverlay>,
+    output_dir: &Path,
+    opts: ShardOptions<'_>,
+    progress: &Progress,
+) -> Result<Totals> {
+    ensure!(
+        (2..=MAX_SHARDS).contains(&shards),
+        "--shards must be between 2 and {}",
+        MAX_SHARDS
+    );
+
+    let ranges = shard_ranges(shards);
+
+    // Hand each shard the part of the overlay that falls into its range
+    let overlays: Vec<Option<StateOverlay>> = match overlay {
+        Some(mut overlay) => {
+            let mut parts: Vec<_> = ranges
+                .iter()
+                .rev()
+                .map(|range| match range.start {
+                    Some(start) => Some(overlay.split_off(&start)),
+                    None => None,
+                })
+                .collect();
+            parts.reverse();
+            parts[0] = Some(overlay);
+            parts
+        }
+        None => (0..shards).map(|_| None).collect(),
+    };
+
+    let shard_root = output_dir.join("shards");
+    let shard_dirs: Vec<PathBuf> = (0..shards)
+        .map(|i| shard_root.join(format!("shard-{:05}", i)))
+        .collect();
+    for dir in &shard_dirs {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let done_acc = AtomicU64::new(0);
+    let done_sto = AtomicU64::new(0);
+    let report = |acc: u64, sto: u64| {
+        let acc = done_acc.fetch_add(acc, Ordering::Relaxed) + acc;
+        let sto = done_sto.fetch_add(sto, Ordering::Relaxed) + sto;
+        progress.update(
+            Phase::State,
+            acc + sto,
+            format!("Acc: {}, Sto: {}", acc, sto),
+        );
+    };
+
+    let workers = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(shards);
+    let overlays = Mutex::new(overlays);
+    let next = AtomicUsize::new(0);
+    let cancel = AtomicBool::new(false);
+    let failure = Mutex::new(None::<Report>);
+
+    // Each worker takes the next shard until none are left or one has failed
+    let worker = || -> Vec<(usize, ShardCounts)> {
+        let mut done = Vec::new();
+        while !cancel.load(Ordering::Relaxed) {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            if i >= shards {
+                break;
+            }
+            let overlay = overlays.lock().unwrap()[i].take();
+            let shard = ShardSource {
+                inner: source,
+                range: ranges[i],
+            };
+            match extract_shard(&shard, overlay, &shard_dirs[i], opts, &report, &cancel) {
+                Ok(counts) => done.push((i, counts)),
+                Err(e) => {
+                    // Shards stopped by the cancellation fail too; keep the first error
+                    if !cancel.swap(true, Ordering::Relaxed) {
+                        *failure.lock().unwrap() = Some(e.wrap_err(format!("Shard {}", i)));
+                    }
+                    break;
+                }
+            }
+        }
+        done
+    };
+    let finished = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers).map(|_| scope.spawn(worker)).collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle.join().map_err(|_| {
+                    cancel.store(true, Ordering::Relaxed);
+                    eyre!("Shard worker panicked")
+                })
+            })
+            .collect::<Vec<_>>()
+    });
+    if let Some(e) = failure.into_inner().unwrap() {
+        return Err(e);
+    }
+    let mut counts = vec![None; shards];
+    for (i, shard) in finished
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+    {
+        counts[i] = Some(shard);
+    }
+    let counts: Vec<ShardCounts> = counts
+        .into_iter()
+        .collect::<Option<_>>()
+        .ok_or_else(|| eyre!("Not every shard was extracted"))?;
+    progress.finish_phase(Phase::State, done_acc.into_inner() + done_sto.into_inner());
+
+    let totals = merge_shards(output_dir, &shard_dirs, &counts, opts)?;
+    std::fs::remove_dir_all(&shard_root)?;
+    Ok(totals)
+}
+
+/// Extracts one address range with shard-relative indices (accounts from 0, storage from 0).
+///
+/// Gives up between batches once `cancel` is set.
+fn extract_shard<S: StateSource>(
+    source: &ShardSource<'_, S>,
+    mut overlay: Option<StateOverlay>,
+    dir: &Path,
+    opts: ShardOptions<'_>,
+    progress: &(dyn Fn(u64, u64) + Sync),
+    cancel: &AtomicBool,
+) -> Result<ShardCounts> {
+    let cancelled = || {
+        ensure!(
+            !cancel.load(Ordering::Relaxed),
+            "Cancelled after another shard failed"
+        );
+        Ok(())
+    };
+    let ShardOptions {
+        filter, batch_size, ..
+    } = opts;
+    let account_words = opts.layout.words();
+    let mut writer = ArtifactWriter::create(dir, opts.format, opts.layout)?;
+    let mut counts = ShardCounts::default();
+
+    // --- ACCOUNTS ---
+    let mut pending_acc = Vec::new();
+    let mut last_acc_key = None;
+    while filter.accounts() {
+        cancelled()?;
+        let batch = source.accounts(last_acc_key, batch_size)?;
+        if batch.is_empty() {
+            break;
+        }
+        progress(batch.len() as u64, 0);
+
+        for (address, account) in batch {
+            match overlay.as_mut() {
+                Some(overlay) => overlay.merge_account(address, account, &mut pending_acc),
+                None => pending_acc.push((address, account)),
+            }
+            for (address, account) in pending_acc.drain(..) {
+                if !filter.keep_account(address.as_slice(), &account) {
+                    continue;
+                }
+                writer.write_account(
+                    address.as_slice(),
+                    &account,
+                    counts.accounts * account_words,
+                )?;
+                counts.accounts += 1;
+            }
+            last_acc_key = Some(address);
+        }
+    }
+    if let Some(overlay) = overlay.as_mut() {
+        overlay.drain_accounts(&mut pending_acc);
+        for (address, account) in pending_acc.drain(..) {
+            if !filter.keep_account(address.as_slice(), &account) {
+                continue;
+            }
+            writer.write_account(
+                address.as_slice(),
+                &account,
+                counts.accounts * account_words,
+            )?;
+            counts.accounts += 1;
+        }
+    }
+
+    // --- STORAGE ---
+    let mut pending_sto = Vec::new();
+    let mut last_sto_addr = None;
+    while filter.storage() {
+        cancelled()?;
+        // Batches end on address boundaries, so resume after the last address
+        let batch = source.storage(last_sto_addr, batch_size)?;
+        if batch.is_empty() {
+            break;
+        }
+        progress(0, batch.len() as u64);
+
+        for (address, key, value) in batch {
+            match overlay.as_mut() {
+                Some(overlay) => overlay.merge_storage(address, key, value, &mut pending_sto),
+                None => pending_sto.push((address, key, value)),
+            }
+            for (address, key, value) in pending_sto.drain(..) {
+                if !filter.keep_storage(address.as_slice(), &value) {
+                    continue;
+                }
+                writer.write_storage(address.as_slice(), &key, &value, counts.storage_slots)?;
+                counts.storage_slots += 1;
+            }
+            last_sto_addr = Some(address);
+        }
+    }
+    if let Some(overlay) = overlay.as_mut() {
+        overlay.drain_storage(&mut pending_sto);
+        for (address, key, value) in pending_sto.drain(..) {
+            if !filter.keep_storage(address.as_slice(), &value) {
+                continue;
+            }
+            writer.write_storage(address.as_slice(), &key, &value, counts.storage_slots)?;
+            counts.storage_slots += 1;
+        }
+    }
+
+    writer.finish()?;
+    Ok(counts)
+}
+
+/// Concatenates shard outputs into the canonical artifacts, rebasing mapping indices.
+fn merge_shards(
+    output_dir: &Path,
+    shard_dirs: &[PathBuf],
+    counts: &[ShardCounts],
+    opts: ShardOptions<'_>,
+) -> Result<Totals> {
+    let format = opts.format;
+    let account_words = opts.layout.words();
+    let accounts: u64 = counts.iter().map(|c| c.accounts).sum();
+    let storage_slots: u64 = counts.iter().map(|c| c.storage_slots).sum();
+    let account_bytes = |c: &ShardCounts| c.accounts * account_words * 32;
+
+    // database.bin: every shard's accounts, then every shard's storage
+    let mut db = BufWriter::new(File::create(output_dir.join("database.bin"))?);
+    for (dir, c) in shard_dirs.iter().zip(counts) {
+        let mut part = File::open(dir.join("database.bin"))?;
+        std::io::copy(&mut (&mut part).take(account_bytes(c)), &mut db)?;
+    }
+    for (dir, c) in shard_dirs.iter().zip(counts) {
+        let mut part = File::open(dir.join("database.bin"))?;
+        part.seek(SeekFrom::Start(account_bytes(c)))?;
+        std::io::copy(&mut part, &mut db)?;
+    }
+    db.flush()?;
+
+    // Mappings: rebase shard-relative indices onto their global position
+    let mut base = 0u64;
+    let acc_header = MappingHeader::new(format.key_size, format.index_width, accounts);
+    let mut acc_map = BufWriter::new(File::create(output_dir.join("account-mapping.bin"))?);
+    acc_map.write_all(&acc_header.encode())?;
+    for (dir, c) in shard_dirs.iter().zip(counts) {
+        rebase_mapping(
+            &dir.join("account-mapping.bin"),
+            &acc_header,
+            base,
+            &mut acc_map,
+        )?;
+        base += c.accounts * account_words;
+    }
+    acc_map.flush()?;
+
+    let sto_header = MappingHeader::new(format.key_size + 32, format.index_width, storage_slots);
+    let mut sto_map = BufWriter::new(File::create(output_dir.join("storage-mapping.bin"))?);
+    sto_map.write_all(&sto_header.encode())?;
+    for (dir, c) in shard_dirs.iter().zip(counts) {
+        rebase_mapping(
+            &dir.join("storage-mapping.bin"),
+            &sto_header,
+            base,
+            &mut sto_map,
+        )?;
+        base += c.storage_slots;
+    }
+    sto_map.flush()?;
+
+    Ok(Totals {
+        accounts,
+        storage_slots,
+        total_indices: base,
+    })
+}
+
+/// Copies a shard's mapping records (past its header), adding `base` to each index.
+///
+/// Fails if a rebased index does not fit the `header`'s index width.
+fn rebase_mapping(
+    path: &Path,
+    header: &MappingHeader,
+    base: u64,
+    out: &mut impl Write,
+) -> Result<()> {
+    let mut reader = BufReader::new(File::open(path)?);
+    reader.seek(SeekFrom::Start(MAPPING_HEADER_SIZE as u64))?;
+    let key_size = header.key_size as usize;
+    let mut record = vec![0u8; header.record_size()];
+    loop {
+        match reader.read_exact(&mut record) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let mut rel = [0u8; 8];
+        rel[..record.len() - key_size].copy_from_slice(&record[key_size..]);
+        out.write_all(&record[..key_size])?;
+        write_index(out, u64::from_le_bytes(rel) + base, header.index_width)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sequential::{extract_plain, PlainOptions},
+        source::{MemorySource, StateSource},
+    };
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/state.jsonl");
+
+    fn temp_dir(tag: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("plinko-parallel-{}-{}", tag, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read(dir: &Path, name: &str) -> Vec<u8> {
+        std::fs::read(dir.join(name)).unwrap()
+    }
+
+    fn extract_sequential(source: &MemorySource, dir: &Path, opts: ShardOptions<'_>) -> Totals {
+        let mut writer = ArtifactWriter::create(dir, opts.format, opts.layout).unwrap();
+        let plain = PlainOptions {
+            block: source.tip_block().unwrap(),
+            output_dir: dir,
+            filter: opts.filter,
+            layout: opts.layout,
+            limit: usize::MAX,
+            batch_size: opts.batch_size,
+            storage_alignment: None,
+        };
+        let totals = extract_plain(
+            source,
+            Some(&mut writer),
+            None,
+            None,
+            plain,
+            &Progress::hidden(),
+        )
+        .unwrap();
+        writer.finish().unwrap();
+        totals
+    }
+
+    #[test]
+    fn sharded_output_matches_sequential_output() {
+        let mut source = MemorySource::from_jsonl(Path::new(FIXTURE)).unwrap();
+        // Accounts on either side of the boundary between shards 0 and 1 of 16, and at the
+        // very ends of the keyspace
+        let mut boundary = [0u8; 20];
+        boundary[0] = 0x10;
+        let edges = [
+            Address::ZERO,
+            predecessor(Address::from(boundary)),
+            Address::from(boundary),
+            Address::repeat_byte(0xff),
+        ];
+        for (i, address) in edges.into_iter().enumerate() {
+            let account = Account {
+                nonce: i as u64 + 1,
+                ..Default::default()
+            };
+            source.insert_account(address, account);
+            source.insert_storage(address, B256::with_last_byte(1), U256::from(i + 1));
+        }
+        let filter = Filter::new(None, None, false, false, false, false).unwrap();
+
+        for batch_size in [1, 1000] {
+            let opts = ShardOptions {
+                format: MappingFormat {
+                    key_size: 20,
+                    index_width: 4,
+                },
+                layout: AccountLayout::Standard,
+                filter: &filter,
+                batch_size,
+            };
+            let sequential = temp_dir("sequential");
+            let expected = extract_sequential(&source, &sequential, opts);
+
+            let sharded = temp_dir("sharded");
+            let totals =
+                extract_sharded(&source, 16, None, &sharded, opts, &Progress::hidden()).unwrap();
+            assert_eq!(
+                (totals.accounts, totals.storage_slots, totals.total_indices),
+                (
+                    expected.accounts,
+                    expected.storage_slots,
+                    expected.total_indices
+                )
+            );
+            assert_eq!((totals.accounts, totals.storage_slots), (7, 7));
+            for name in ["database.bin", "account-mapping.bin", "storage-mapping.bin"] {
+                assert_eq!(read(&sequential, name), read(&sharded, name), "{}", name);
+            }
+            assert!(!sharded.join("shards").exists());
+            std::fs::remove_dir_all(sequential).unwrap();
+            std::fs::remove_dir_all(sharded).unwrap();
+        }
+    }
+
+    #[test]
+    fn shard_sources_keep_to_their_range() {
+        let mut source = MemorySource::new(1);
+        for byte in [0x0f, 0x10, 0x1f, 0x20] {
+            source.insert_account(Address::repeat_byte(byte), Account::default());
+        }
+        let ranges = shard_ranges(16);
+        let shard = ShardSource {
+            inner: &source,
+            range: ranges[1],
+        };
+        let addresses =
+            |batch: Vec<(Address, Account)>| batch.into_iter().map(|(a, _)| a).collect::<Vec<_>>();
+        assert_eq!(
+            addresses(shard.accounts(None, 10).unwrap()),
+            [Address::repeat_byte(0x10), Address::repeat_byte(0x1f)]
+        );
+        assert_eq!(
+            addresses(shard.accounts(None, 1).unwrap()),
+            [Address::repeat_byte(0x10)]
+        );
+        assert!(shard
+            .accounts(Some(Address::repeat_byte(0x1f)), 10)
+            .unwrap()
+            .is_empty());
+        assert_eq!(predecessor(Address::with_last_byte(1)), Address::ZERO);
+        let (mut start, mut before) = ([0u8; 20], [0xffu8; 20]);
+        start[0] = 0x10;
+        before[0] = 0x0f;
+        assert_eq!(predecessor(Address::from(start)), Address::from(before));
+    }
+
+    #[test]
+    fn rebased_indices_must_fit_the_index_width() {
+        let dir = temp_dir("rebase");
+        let header = MappingHeader::new(20, 4, 2);
+        let mut shard = header.encode().to_vec();
+        for (key, index) in [([0x11u8; 20], 0u64), ([0x22; 20], 3)] {
+            shard.extend_from_slice(&key);
+            write_index(&mut shard, index, 4).unwrap();
+        }
+        let path = dir.join("account-mapping.bin");
+        std::fs::write(&path, shard).unwrap();
+
+        let mut out = Vec::new();
+        rebase_mapping(&path, &header, 9, &mut out).unwrap();
+        let mut expected = Vec::new();
+        for (key, index) in [([0x11u8; 20], 9u64), ([0x22; 20], 12)] {
+            expected.extend_from_slice(&key);
+            write_index(&mut expected, index, 4).unwrap();
+        }
+        assert_eq!(out, expected);
+
+        assert!(rebase_mapping(&path, &header, u32::MAX as u64 - 2, &mut Vec::new()).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
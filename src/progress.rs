@@ -0,0 +1,289 @@
+//! Progress reporting: the terminal spinner, plus an optional JSON-lines event stream
+//! (`--progress-json`) for job runners.
+//!
+//! Each line is one event. `progress` events carry the phase, the rows done, the estimated
+//! total from the table stats, the bytes written under the output directory, the rate over
+//! the phase and an ETA. They are written at most once per interval, and whenever a phase
+//! starts or ends, so a stream that goes quiet for several intervals means a stalled run.
+//! The last line is a `summary` event holding the `metadata.json` that was written.
+
+use eyre::Result;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use state_syncer::metadata::Metadata;
+use std::{
+    fs::OpenOptions,
+    io::{LineWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Extraction phase an event reports on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Accounts,
+    Storage,
+    /// Accounts and storage together (sharded runs).
+    State,
+    Bytecode,
+    Trie,
+}
+
+/// Rows done and the estimated total are counted in table entries.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    Progress {
+        time: String,
+        elapsed_secs: f64,
+        phase: Phase,
+        rows: u64,
+        estimated_total: Option<u64>,
+        bytes_written: u64,
+        /// Rows per second since the phase started.
+        rate: f64,
+        eta_secs: Option<f64>,
+    },
+    Summary {
+        time: String,
+        elapsed_secs: f64,
+        metadata: &'a Metadata,
+    },
+}
+
+/// Where `--progress-json` events go and the state needed to throttle them.
+struct JsonStream {
+    out: Box<dyn Write + Send>,
+    interval: Duration,
+    output_dir: Option<PathBuf>,
+    start: Instant,
+    last_event: Option<Instant>,
+    estimates: Vec<(Phase, u64)>,
+    /// Current phase, when it started and the rows it started from (non-zero on resume).
+    phase: Option<(Phase, Instant, u64)>,
+}
+
+/// The spinner and, with `--progress-json`, the event stream. Shared by every phase (and
+/// every shard thread) of an extraction.
+pub struct Progress {
+    bar: ProgressBar,
+    json: Option<Mutex<JsonStream>>,
+}
+
+impl Progress {
+    /// A spinner, and events written to `json` (`-` for stderr, otherwise appended to the
+    /// file) at most every `interval`. `output_dir` is measured for `bytes_written`.
+    pub fn new(json: Option<&Path>, interval: Duration, output_dir: Option<&Path>) -> Result<Self> {
+        let bar = ProgressBar::new_spinner();
+        bar.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner:.green} [{elapsed_precise}] {msg}")
+                .unwrap(),
+        );
+
+        let json = match json {
+            Some(path) => {
+                let out: Box<dyn Write + Send> = if path == Path::new("-") {
+                    Box::new(std::io::stderr())
+                } else {
+                    Box::new(LineWriter::new(
+                        OpenOptions::new().create(true).append(true).open(path)?,
+                    ))
+                };
+                Some(Mutex::new(JsonStream {
+                    out,
+                    interval,
+                    output_dir: output_dir.map(Path::to_path_buf),
+                    start: Instant::now(),
+                    last_event: None,
+                    estimates: Vec::new(),
+                    phase: None,
+                }))
+            }
+            None => None,
+        };
+        Ok(Self { bar, json })
+    }
+
+    /// Reports nothing (tests).
+    #[cfg(test)]
+    pub fn hidden() -> Self {
+        Self {
+            bar: ProgressBar::hidden(),
+            json: None,
+        }
+    }
+
+    /// Records the expected number of rows of `phase`, from the table stats.
+    pub fn estimate(&self, phase: Phase, total: u64) {
+        if let Some(json) = &self.json {
+            let mut json = json.lock().unwrap();
+            json.estimates.retain(|(p, _)| *p != phase);
+            json.estimates.push((phase, total));
+        }
+    }
+
+    /// Shows `message` on the spinner and reports `rows` of `phase` done.
+    pub fn update(&self, phase: Phase, rows: u64, message: String) {
+        self.bar.set_message(message);
+        self.bar.inc(1);
+        self.report(phase, rows, false);
+    }
+
+    /// Reports the final row count of `phase`.
+    pub fn finish_phase(&self, phase: Phase, rows: u64) {
+        self.report(phase, rows, true);
+    }
+
+    pub fn finish_and_clear(&self) {
+        self.bar.finish_and_clear();
+    }
+
+    /// Writes the `summary` event for the `metadata.json` just saved.
+    pub fn summary(&self, metadata: &Metadata) {
+        if let Some(json) = &self.json {
+            let mut json = json.lock().unwrap();
+            let event = Event::Summary {
+                time: now(),
+                elapsed_secs: json.start.elapsed().as_secs_f64(),
+                metadata,
+            };
+            json.write(&event);
+        }
+    }
+
+    fn report(&self, phase: Phase, rows: u64, force: bool) {
+        let Some(json) = &self.json else {
+            return;
+        };
+        let mut json = json.lock().unwrap();
+        let now_instant = Instant::now();
+
+        let (phase_start, start_rows) = match json.phase {
+            Some((current, started, start_rows)) if current == phase => (started, start_rows),
+            _ => {
+                // A new phase is always reported
+                json.phase = Some((phase, now_instant, rows));
+                json.last_event = None;
+                (now_instant, rows)
+            }
+        };
+        if !force
+            && json
+                .last_event
+                .is_some_and(|last| now_instant.duration_since(last) < json.interval)
+        {
+            return;
+        }
+        json.last_event = Some(now_instant);
+
+        let phase_secs = now_instant.duration_since(phase_start).as_secs_f64();
+        let rate = if phase_secs > 0.0 {
+            rows.saturating_sub(start_rows) as f64 / phase_secs
+        } else {
+            0.0
+        };
+        let estimated_total = json
+            .estimates
+            .iter()
+            .find(|(p, _)| *p == phase)
+            .map(|(_, total)| *total);
+        let eta_secs = estimated_total
+            .filter(|_| rate > 0.0)
+            .map(|total| total.saturating_sub(rows) as f64 / rate);
+        let event = Event::Progress {
+            time: now(),
+            elapsed_secs: json.start.elapsed().as_secs_f64(),
+            phase,
+            rows,
+            estimated_total,
+            bytes_written: json.output_dir.as_deref().map_or(0, dir_size),
+            rate,
+            eta_secs,
+        };
+        json.write(&event);
+    }
+}
+
+impl JsonStream {
+    /// Writes one line. A failing stream is reported once and then dropped rather than
+    /// failing the extraction.
+    fn write(&mut self, event: &Event<'_>) {
+        let line = serde_json::to_string(event).expect("events serialize");
+        if let Err(e) = writeln!(self.out, "{}", line).and_then(|_| self.out.flush()) {
+            eprintln!(
+                "Progress stream failed ({}); no further events are written",
+                e
+            );
+            self.out = Box::new(std::io::sink());
+        }
+    }
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+/// Total size of the files under `dir` (artifacts are still being written, so this lags
+/// by the write buffers).
+fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(kind) if kind.is_dir() => dir_size(&entry.path()),
+            Ok(_) => entry.metadata().map_or(0, |m| m.len()),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state_syncer::synthetic::{generate, SyntheticConfig};
+
+    #[test]
+    fn events_are_throttled_within_a_phase() {
+        let dir = std::env::temp_dir().join(format!("plinko-progress-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let metadata = generate(&dir, &SyntheticConfig::default()).unwrap();
+        let log = dir.join("progress.jsonl");
+
+        let progress = Progress::new(Some(&log), Duration::from_secs(3600), Some(&dir)).unwrap();
+        progress.estimate(Phase::Accounts, 30_000);
+        progress.update(Phase::Accounts, 10_000, String::new());
+        // Within the interval: dropped
+        progress.update(Phase::Accounts, 20_000, String::new());
+        progress.finish_phase(Phase::Accounts, 25_000);
+        // A new phase is reported right away
+        progress.update(Phase::Storage, 10_000, String::new());
+        progress.summary(&metadata);
+
+        let events: Vec<serde_json::Value> = std::fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let rows: Vec<_> = events.iter().map(|e| e["rows"].as_u64()).collect();
+        assert_eq!(rows, [Some(10_000), Some(25_000), Some(10_000), None]);
+
+        assert_eq!(events[0]["event"], "progress");
+        assert_eq!(events[0]["phase"], "accounts");
+        assert_eq!(events[0]["estimated_total"], 30_000);
+        assert!(events[0]["bytes_written"].as_u64().unwrap() > 0);
+        assert_eq!(events[2]["phase"], "storage");
+        assert!(events[2]["estimated_total"].is_null());
+
+        // The summary is metadata.json
+        assert_eq!(events[3]["event"], "summary");
+        let saved: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dir.join("metadata.json")).unwrap()).unwrap();
+        assert_eq!(events[3]["metadata"], saved);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,821 @@
+//! Sequential extraction of the plain state (the default mode).
+//!
+//! Walks every account and then every storage slot of a [`StateSource`] in key order, one
+//! batch at a time, writing through an [`ArtifactWriter`] and saving a [`Checkpoint`] after
+//! each batch. With a [`StateOverlay`] the values are rewound to the target block as they
+//! stream past.
+
+use crate::{
+    checkpoint::{Checkpoint, Phase},
+    colocate::Colocator,
+    filter::Filter,
+    history::StateOverlay,
+    progress::{Phase as ProgressPhase, Progress},
+    source::StateSource,
+    writer::{ArtifactWriter, Totals},
+};
+use alloy_primitives::{Address, B256};
+use eyre::{ensure, Result};
+use state_syncer::layout::AccountLayout;
+use std::path::Path;
+
+/// Settings of a sequential extraction.
+#[derive(Clone, Copy)]
+pub struct PlainOptions<'a> {
+    /// Block the snapshot is taken at; checkpoints record it.
+    pub block: u64,
+    /// Where checkpoints are saved (next to the artifacts).
+    pub output_dir: &'a Path,
+    pub filter: &'a Filter,
+    pub layout: AccountLayout,
+    /// Maximum number of accounts, and of storage slots, to extract.
+    pub limit: usize,
+    pub batch_size: usize,
+    /// Page size in bytes that contracts' storage runs are aligned to, if any.
+    pub storage_alignment: Option<u64>,
+}
+
+impl PlainOptions<'_> {
+    /// Records the position after a batch; the writer is synced up to it first.
+    fn checkpoint(
+        &self,
+        writer: &mut ArtifactWriter,
+        phase: Phase,
+        last_account: Option<Address>,
+        last_storage: Option<(Address, B256)>,
+        totals: &Totals,
+    ) -> Result<()> {
+        Checkpoint {
+            block: self.block,
+            phase,
+            last_account,
+            last_storage,
+            accounts: totals.accounts,
+            storage_slots: totals.storage_slots,
+            total_indices: totals.total_indices,
+            offsets: writer.sync()?,
+            filter: self.filter.info(),
+            account_layout: self.layout,
+            storage_alignment: self.storage_alignment,
+        }
+        .save(self.output_dir)
+    }
+}
+
+/// Extracts the state of `source` (rewound through `overlay`, if given), writing through
+/// `writer` when given and continuing from `resume` when given.
+pub fn extract_plain<S: StateSource>(
+    source: &S,
+    mut writer: Option<&mut ArtifactWriter>,
+    mut overlay: Option<StateOverlay>,
+    resume: Option<&Checkpoint>,
+    opts: PlainOptions<'_>,
+    progress: &Progress,
+) -> Result<Totals> {
+    let now = || chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    let PlainOptions {
+        filter,
+        limit,
+        batch_size,
+        ..
+    } = opts;
+    let account_words = opts.layout.words();
+
+    let mut totals = Totals::default();
+    let mut last_acc_key = None;
+    let mut last_sto_key = None;
+    let mut accounts_done = !filter.accounts();
+
+    // --- RESUME FROM CHECKPOINT ---
+    if let Some(checkpoint) = resume {
+        ensure!(
+            checkpoint.block == opts.block,
+            "Checkpoint was taken for block #{} but this run targets #{} (pass --at-block {})",
+            checkpoint.block,
+            opts.block,
+            checkpoint.block
+        );
+        ensure!(
+            checkpoint.filter == filter.info(),
+            "Checkpoint was taken with different filters ({:?})",
+            checkpoint.filter
+        );
+        ensure!(
+            checkpoint.account_layout == opts.layout,
+            "Checkpoint was taken with --account-layout {:?}",
+            checkpoint.account_layout
+        );
+        ensure!(
+            checkpoint.storage_alignment == opts.storage_alignment,
+            "Checkpoint was taken with --storage-alignment {:?}",
+            checkpoint.storage_alignment
+        );
+        println!(
+            "[{}] Resuming {:?} phase: Acc: {}, Sto: {}, Index: {}",
+            now(),
+            checkpoint.phase,
+            checkpoint.accounts,
+            checkpoint.storage_slots,
+            checkpoint.total_indices
+        );
+
+        totals = Totals {
+            accounts: checkpoint.accounts,
+            storage_slots: checkpoint.storage_slots,
+            total_indices: checkpoint.total_indices,
+        };
+        last_acc_key = checkpoint.last_account;
+        last_sto_key = checkpoint.last_storage;
+        accounts_done |= checkpoint.phase == Phase::Storage;
+
+        // Drop overlay entries that were already written before the interruption
+        if let Some(overlay) = overlay.as_mut() {
+            if accounts_done {
+                overlay.skip_accounts_through(&Address::repeat_byte(0xff));
+            } else if let Some(last) = &last_acc_key {
+                overlay.skip_accounts_through(last);
+            }
+            if let Some((addr, key)) = &last_sto_key {
+                overlay.skip_storage_through(addr, key);
+            }
+        }
+    }
+
+    // --- PROCESS ACCOUNTS ---
+    println!("[{}] Processing Accounts...", now());
+    let mut pending_acc = Vec::new();
+
+    while !accounts_done && (totals.accounts as usize) < limit {
+        let batch = source.accounts(last_acc_key, batch_size)?;
+        if batch.is_empty() {
+            break;
+        }
+
+        for (address, account) in batch {
+            if totals.accounts as usize >= limit {
+                break;
+            }
+
+            // Substitute the historical value (and any accounts deleted since) when rewinding
+            match overlay.as_mut() {
+                Some(overlay) => overlay.merge_account(address, account, &mut pending_acc),
+                None => pending_acc.push((address, account)),
+            }
+
+            for (address, account) in pending_acc.drain(..) {
+                if totals.accounts as usize >= limit {
+                    break;
+                }
+                if !filter.keep_account(address.as_slice(), &account) {
+                    continue;
+                }
+
+                // --- WRITE DATABASE ENTRY + MAPPING ---
+                if let Some(writer) = writer.as_mut() {
+                    writer.write_account(address.as_slice(), &account, totals.total_indices)?;
+                }
+
+                totals.total_indices += account_words;
+                totals.accounts += 1;
+
+                if totals.accounts % 10000 == 0 {
+                    progress.update(
+                        ProgressPhase::Accounts,
+                        totals.accounts,
+                        format!("Acc: {}, Sto: 0", totals.accounts),
+                    );
+                }
+            }
+
+            last_acc_key = Some(address);
+        }
+
+        if let Some(writer) = writer.as_mut() {
+            opts.checkpoint(writer, Phase::Accounts, last_acc_key, None, &totals)?;
+        }
+    }
+
+    // Accounts that sort after the last tip entry but existed at the target block
+    if let Some(overlay) = overlay.as_mut() {
+        overlay.drain_accounts(&mut pending_acc);
+        for (address, account) in pending_acc.drain(..) {
+            if totals.accounts as usize >= limit {
+                break;
+            }
+            if !filter.keep_account(address.as_slice(), &account) {
+                continue;
+            }
+            if let Some(writer) = writer.as_mut() {
+                writer.write_account(address.as_slice(), &account, totals.total_indices)?;
+            }
+            totals.total_indices += account_words;
+            totals.accounts += 1;
+        }
+    }
+
+    if !accounts_done {
+        if let Some(writer) = writer.as_mut() {
+            opts.checkpoint(writer, Phase::Storage, last_acc_key, None, &totals)?;
+        }
+    }
+
+    progress.finish_phase(ProgressPhase::Accounts, totals.accounts);
+    println!(
+        "[{}] Processed {} accounts. Current Index: {}",
+        now(),
+        totals.accounts,
+        totals.total_indices
+    );
+
+    // --- PROCESS STORAGE ---
+    println!("[{}] Processing Storage...", now());
+    let mut last_sto_addr = last_sto_key.map(|(addr, _)| addr);
+    let mut pending_sto = Vec::new();
+    let mut runs = Colocator::new(opts.storage_alignment);
+
+    while filter.storage() && (totals.storage_slots as usize) < limit {
+        // Batches end on address boundaries, so resume after the last address
+        let batch = source.storage(last_sto_addr, batch_size)?;
+        if batch.is_empty() {
+            break;
+        }
+
+        for (address, key, value) in batch {
+            if totals.storage_slots as usize >= limit {
+                break;
+            }
+
+            match overlay.as_mut() {
+                Some(overlay) => overlay.merge_storage(address, key, value, &mut pending_sto),
+                None => pending_sto.push((address, key, value)),
+            }
+
+            for (address, key, value) in pending_sto.drain(..) {
+                if totals.storage_slots as usize >= limit {
+                    break;
+                }
+                if !filter.keep_storage(address.as_slice(), &value) {
+                    continue;
+                }
+
+                // --- WRITE DATABASE ENTRY + MAPPING ---
+                runs.push(writer.as_deref_mut(), &mut totals, address, key, value)?;
+
+                if totals.storage_slots % 10000 == 0 {
+                    progress.update(
+                        ProgressPhase::Storage,
+                        totals.storage_slots,
+                        format!("Acc: {}, Sto: {}", totals.accounts, totals.storage_slots),
+                    );
+                }
+            }
+
+            last_sto_addr = Some(address);
+            last_sto_key = Some((address, key));
+        }
+
+        // The batch ends on an address boundary, but the overlay can still hold slots of
+        // its last address that sort after the tip's; they belong to the same run, which
+        // must be complete before it is placed and checkpointed
+        if let (Some(overlay), Some(address)) = (overlay.as_mut(), last_sto_addr) {
+            overlay.drain_storage_through(&address, &mut pending_sto);
+            for (address, key, value) in pending_sto.drain(..) {
+                if totals.storage_slots as usize >= limit {
+                    break;
+                }
+                if !filter.keep_storage(address.as_slice(), &value) {
+                    continue;
+                }
+                runs.push(writer.as_deref_mut(), &mut totals, address, key, value)?;
+            }
+            // A resumed run skips every overlay slot of the address
+            last_sto_key = Some((address, B256::repeat_byte(0xff)));
+        }
+        runs.flush(writer.as_deref_mut(), &mut totals)?;
+        if let Some(writer) = writer.as_mut() {
+            opts.checkpoint(writer, Phase::Storage, last_acc_key, last_sto_key, &totals)?;
+        }
+    }
+
+    // Slots that sort after the last tip entry but were non-zero at the target block
+    if let Some(overlay) = overlay.as_mut() {
+        overlay.drain_storage(&mut pending_sto);
+        for (address, key, value) in pending_sto.drain(..) {
+            if totals.storage_slots as usize >= limit {
+                break;
+            }
+            if !filter.keep_storage(address.as_slice(), &value) {
+                continue;
+            }
+            runs.push(writer.as_deref_mut(), &mut totals, address, key, value)?;
+        }
+    }
+    runs.flush(writer, &mut totals)?;
+    progress.finish_phase(ProgressPhase::Storage, totals.storage_slots);
+
+    Ok(totals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{source::MemorySource, writer::MappingFormat};
+    use alloy_primitives::{address, b256, U256};
+    use reth_primitives::Account;
+    use state_syncer::{
+        manifest::{hash_file, DEFAULT_CHUNK_SIZE},
+        mapping::{AccountMapping, MappingHeader, StorageMapping, MAPPING_HEADER_SIZE},
+    };
+    use std::{cell::Cell, io::Write, path::PathBuf};
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/state.jsonl");
+
+    fn temp_dir(tag: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("plinko-sequential-{}-{}", tag, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn no_filter() -> Filter {
+        Filter::new(None, None, false, false, false, false).unwrap()
+    }
+
+    /// Extracts `source` into `dir` and returns the totals.
+    fn extract(source: &MemorySource, dir: &Path, filter: &Filter, batch_size: usize) -> Totals {
+        extract_aligned(source, dir, filter, batch_size, None)
+    }
+
+    fn extract_aligned(
+        source: &MemorySource,
+        dir: &Path,
+        filter: &Filter,
+        batch_size: usize,
+        storage_alignment: Option<u64>,
+    ) -> Totals {
+        let format = MappingFormat {
+            key_size: 20,
+            index_width: 4,
+        };
+        let mut writer = ArtifactWriter::create(dir, format, AccountLayout::Standard).unwrap();
+        let opts = PlainOptions {
+            block: source.tip_block().unwrap(),
+            output_dir: dir,
+            filter,
+            layout: AccountLayout::Standard,
+            limit: usize::MAX,
+            batch_size,
+            storage_alignment,
+        };
+        let totals = extract_plain(
+            source,
+            Some(&mut writer),
+            None,
+            None,
+            opts,
+            &Progress::hidden(),
+        )
+        .unwrap();
+        writer.finish().unwrap();
+        totals
+    }
+
+    fn read(dir: &Path, name: &str) -> Vec<u8> {
+        std::fs::read(dir.join(name)).unwrap()
+    }
+
+    /// A source that fails once `calls` batches have been read, like a crashed run.
+    struct Interrupted<'a> {
+        inner: &'a MemorySource,
+        calls: Cell<usize>,
+    }
+
+    impl Interrupted<'_> {
+        fn call(&self) -> Result<()> {
+            let left = self.calls.get();
+            ensure!(left > 0, "interrupted");
+            self.calls.set(left - 1);
+            Ok(())
+        }
+    }
+
+    impl StateSource for Interrupted<'_> {
+        fn tip_block(&self) -> Result<u64> {
+            self.inner.tip_block()
+        }
+
+        fn entries(&self) -> Result<(usize, usize)> {
+            self.inner.entries()
+        }
+
+        fn accounts(
+            &self,
+            after: Option<Address>,
+            limit: usize,
+        ) -> Result<Vec<(Address, Account)>> {
+            self.call()?;
+            self.inner.accounts(after, limit)
+        }
+
+        fn storage(
+            &self,
+            after: Option<Address>,
+            limit: usize,
+        ) -> Result<Vec<(Address, B256, U256)>> {
+            self.call()?;
+            self.inner.storage(after, limit)
+        }
+    }
+
+    #[test]
+    fn fixture_artifacts_are_byte_exact() {
+        let source = MemorySource::from_jsonl(Path::new(FIXTURE)).unwrap();
+        assert_eq!(source.tip_block().unwrap(), 1000);
+        let dir = temp_dir("fixture");
+        let totals = extract(&source, &dir, &no_filter(), 1000);
+        assert_eq!(
+            (totals.accounts, totals.storage_slots, totals.total_indices),
+            (3, 3, 12)
+        );
+
+        let word = |bytes: &[u8]| {
+            let mut word = [0u8; 32];
+            word[..bytes.len()].copy_from_slice(bytes);
+            word
+        };
+        let code_hash = b256!("1dfa6b7d1ba0cd6b78cc5511dbf649bf078cc47a738e9bd0a693ac0021c96ce5");
+        let mut expected_db = Vec::new();
+        // 0x11..: nonce 1, balance 1 ether, no code
+        for w in [
+            word(&1u64.to_le_bytes()),
+            U256::from(1_000_000_000_000_000_000u64).to_le_bytes::<32>(),
+            [0u8; 32],
+        ] {
+            expected_db.extend_from_slice(&w);
+        }
+        // 0x22..: contract with nonce 1, no balance
+        for w in [word(&1u64.to_le_bytes()), [0u8; 32], code_hash.0] {
+            expected_db.extend_from_slice(&w);
+        }
+        // 0x33..: balance 0xff only
+        for w in [[0u8; 32], word(&[0xff]), [0u8; 32]] {
+            expected_db.extend_from_slice(&w);
+        }
+        // Storage of 0x22.. in slot order, then 0x33..
+        for value in [0x2au64, 0x0100, 0x07] {
+            expected_db.extend_from_slice(&U256::from(value).to_le_bytes::<32>());
+        }
+        assert_eq!(read(&dir, "database.bin"), expected_db);
+
+        let accounts = [
+            address!("1111111111111111111111111111111111111111"),
+            address!("2222222222222222222222222222222222222222"),
+            address!("3333333333333333333333333333333333333333"),
+        ];
+        let mut expected_acc = MappingHeader::new(20, 4, 3).encode().to_vec();
+        for (i, address) in accounts.iter().enumerate() {
+            expected_acc.extend_from_slice(address.as_slice());
+            expected_acc.extend_from_slice(&(i as u32 * 3).to_le_bytes());
+        }
+        assert_eq!(read(&dir, "account-mapping.bin"), expected_acc);
+
+        let slots = [
+            (
+                accounts[1],
+                b256!("0000000000000000000000000000000000000000000000000000000000000000"),
+            ),
+            (
+                accounts[1],
+                b256!("0000000000000000000000000000000000000000000000000000000000000001"),
+            ),
+            (
+                accounts[2],
+                b256!("00000000000000000000000000000000000000000000000000000000000000aa"),
+            ),
+        ];
+        let mut expected_sto = MappingHeader::new(52, 4, 3).encode().to_vec();
+        for (i, (address, slot)) in slots.iter().enumerate() {
+            expected_sto.extend_from_slice(address.as_slice());
+            expected_sto.extend_from_slice(slot.as_slice());
+            expected_sto.extend_from_slice(&(9 + i as u32).to_le_bytes());
+        }
+        assert_eq!(read(&dir, "storage-mapping.bin"), expected_sto);
+
+        // The lib readers agree
+        let acc_map = AccountMapping::open(dir.join("account-mapping.bin")).unwrap();
+        assert_eq!(acc_map.get(accounts[2].as_slice()), Some(6));
+        let sto_map = StorageMapping::open(dir.join("storage-mapping.bin")).unwrap();
+        assert_eq!(
+            sto_map.get(accounts[2].as_slice(), slots[2].1.as_slice()),
+            Some(11)
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn batch_size_does_not_change_artifacts() {
+        let source = MemorySource::from_jsonl(Path::new(FIXTURE)).unwrap();
+        let whole = temp_dir("whole");
+        let batched = temp_dir("batched");
+        extract(&source, &whole, &no_filter(), 1000);
+        extract(&source, &batched, &no_filter(), 1);
+
+        for name in ["database.bin", "account-mapping.bin", "storage-mapping.bin"] {
+            assert_eq!(read(&whole, name), read(&batched, name), "{}", name);
+        }
+        std::fs::remove_dir_all(whole).unwrap();
+        std::fs::remove_dir_all(batched).unwrap();
+    }
+
+    #[test]
+    fn filters_keep_indices_dense() {
+        let source = MemorySource::from_jsonl(Path::new(FIXTURE)).unwrap();
+        let dir = temp_dir("filtered");
+        let filter = Filter::new(None, None, false, false, false, true).unwrap();
+        let totals = extract(&source, &dir, &filter, 1000);
+        assert_eq!(
+            (totals.accounts, totals.storage_slots, totals.total_indices),
+            (0, 3, 3)
+        );
+        assert_eq!(read(&dir, "database.bin").len(), 3 * 32);
+
+        let sto_map = StorageMapping::open(dir.join("storage-mapping.bin")).unwrap();
+        assert_eq!(sto_map.record(0).unwrap().1, 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn aligned_storage_runs_skip_page_boundaries() {
+        let mut source = MemorySource::from_jsonl(Path::new(FIXTURE)).unwrap();
+        // A run longer than a page, after the fixture's two contracts
+        let big = address!("4444444444444444444444444444444444444444");
+        for slot in 0..5u64 {
+            source.insert_storage(big, B256::from(U256::from(slot)), U256::from(slot + 1));
+        }
+
+        // 2-entry pages after 9 account words: 0x22.. (2 slots) would straddle 9/10, 0x33..
+        // (1 slot) fits, and 0x44.. (5 slots) starts on a boundary
+        let whole = temp_dir("aligned");
+        let totals = extract_aligned(&source, &whole, &no_filter(), 1000, Some(64));
+        assert_eq!(
+            (totals.accounts, totals.storage_slots, totals.total_indices),
+            (3, 8, 19)
+        );
+        let sto_map = StorageMapping::open(whole.join("storage-mapping.bin")).unwrap();
+        let indices: Vec<u64> = (0..8).map(|i| sto_map.slot(i).unwrap().2).collect();
+        assert_eq!(indices, [10, 11, 12, 14, 15, 16, 17, 18]);
+        let db = read(&whole, "database.bin");
+        assert_eq!(db.len(), 19 * 32);
+        for padding in [9, 13] {
+            assert_eq!(db[padding * 32..(padding + 1) * 32], [0u8; 32]);
+        }
+
+        // Checkpoints after every address do not move anything
+        let batched = temp_dir("aligned-batched");
+        extract_aligned(&source, &batched, &no_filter(), 1, Some(64));
+        for name in ["database.bin", "storage-mapping.bin"] {
+            assert_eq!(read(&whole, name), read(&batched, name), "{}", name);
+        }
+        std::fs::remove_dir_all(whole).unwrap();
+        std::fs::remove_dir_all(batched).unwrap();
+    }
+
+    #[test]
+    fn rewound_aligned_runs_survive_batches_and_resumes() {
+        let source = MemorySource::from_jsonl(Path::new(FIXTURE)).unwrap();
+        let slot = |n: u64| B256::from(U256::from(n));
+        let [b, c, d] = [0x22, 0x33, 0x44].map(Address::repeat_byte);
+        // Rewinding to an earlier block: 0x22..'s slot 0 was written since, slot 1 changed
+        // and slots 5 and 6 were cleared; 0x33..'s slot 0xbb was cleared and 0x44..'s
+        // storage destroyed. The cleared slots sort after each contract's tip slots, so the
+        // overlay hands them out after the batch holding the rest of the run.
+        let overlay = || {
+            StateOverlay::from_changesets(
+                [],
+                [
+                    ((b, slot(0)), U256::ZERO),
+                    ((b, slot(1)), U256::from(0x55)),
+                    ((b, slot(5)), U256::from(9)),
+                    ((b, slot(6)), U256::from(10)),
+                    ((c, slot(0xbb)), U256::from(3)),
+                    ((d, slot(0)), U256::from(1)),
+                    ((d, slot(1)), U256::from(2)),
+                ],
+            )
+        };
+        let mut rewound = MemorySource::new(1000);
+        for (address, account) in source.accounts(None, usize::MAX).unwrap() {
+            rewound.insert_account(address, account);
+        }
+        for (address, n, value) in [
+            (b, 1, 0x55),
+            (b, 5, 9),
+            (b, 6, 10),
+            (c, 0xaa, 7),
+            (c, 0xbb, 3),
+            (d, 0, 1),
+            (d, 1, 2),
+        ] {
+            rewound.insert_storage(address, slot(n), U256::from(value));
+        }
+
+        // 2-entry pages after 9 account words: 0x22.. (3 slots) starts on a boundary at 10,
+        // 0x33.. (2 slots) is moved from 13 to 14, and 0x44.. (2 slots) follows at 16
+        let whole = temp_dir("rewound-whole");
+        let totals = extract_aligned(&rewound, &whole, &no_filter(), 1000, Some(64));
+        assert_eq!(
+            (totals.accounts, totals.storage_slots, totals.total_indices),
+            (3, 7, 18)
+        );
+        let sto_map = StorageMapping::open(whole.join("storage-mapping.bin")).unwrap();
+        let indices: Vec<u64> = (0..7).map(|i| sto_map.slot(i).unwrap().2).collect();
+        assert_eq!(indices, [10, 11, 12, 14, 15, 16, 17]);
+
+        // Whole and one-row batches, crashing before each read in turn and resuming with a
+        // fresh overlay and colocator
+        let filter = no_filter();
+        let format = MappingFormat {
+            key_size: 20,
+            index_width: 4,
+        };
+        for batch_size in [1, 1000] {
+            for calls in 0..=7 {
+                let dir = temp_dir(&format!("rewound-{}-{}", batch_size, calls));
+                let opts = PlainOptions {
+                    block: 1000,
+                    output_dir: &dir,
+                    filter: &filter,
+                    layout: AccountLayout::Standard,
+                    limit: usize::MAX,
+                    batch_size,
+                    storage_alignment: Some(64),
+                };
+                let crashing = Interrupted {
+                    inner: &source,
+                    calls: Cell::new(calls),
+                };
+                let mut writer =
+                    ArtifactWriter::create(&dir, format, AccountLayout::Standard).unwrap();
+                let run = extract_plain(
+                    &crashing,
+                    Some(&mut writer),
+                    Some(overlay()),
+                    None,
+                    opts,
+                    &Progress::hidden(),
+                );
+                let totals = match run {
+                    Ok(totals) => totals,
+                    Err(err) => {
+                        assert!(err.to_string().contains("interrupted"), "{}", err);
+                        drop(writer);
+                        let checkpoint = Checkpoint::load(&dir).unwrap();
+                        writer = match &checkpoint {
+                            Some(checkpoint) => ArtifactWriter::resume(
+                                &dir,
+                                checkpoint.offsets,
+                                AccountLayout::Standard,
+                            )
+                            .unwrap(),
+                            None => ArtifactWriter::create(&dir, format, AccountLayout::Standard)
+                                .unwrap(),
+                        };
+                        extract_plain(
+                            &source,
+                            Some(&mut writer),
+                            Some(overlay()),
+                            checkpoint.as_ref(),
+                            opts,
+                            &Progress::hidden(),
+                        )
+                        .unwrap()
+                    }
+                };
+                writer.finish().unwrap();
+                assert_eq!(
+                    (totals.accounts, totals.storage_slots, totals.total_indices),
+                    (3, 7, 18)
+                );
+
+                for name in ["database.bin", "account-mapping.bin", "storage-mapping.bin"] {
+                    assert_eq!(
+                        read(&whole, name),
+                        read(&dir, name),
+                        "{} with batches of {} after {}",
+                        name,
+                        batch_size,
+                        calls
+                    );
+                }
+                std::fs::remove_dir_all(dir).unwrap();
+            }
+        }
+        std::fs::remove_dir_all(whole).unwrap();
+    }
+
+    #[test]
+    fn resumed_runs_match_uninterrupted_ones() {
+        let source = MemorySource::from_jsonl(Path::new(FIXTURE)).unwrap();
+        let whole = temp_dir("resume-whole");
+        let filter = no_filter();
+        extract(&source, &whole, &filter, 1);
+
+        // With one-row batches the fixture takes 4 account and 3 storage reads; crash
+        // before each of them in turn
+        for calls in 0..7 {
+            let dir = temp_dir(&format!("resume-{}", calls));
+            let format = MappingFormat {
+                key_size: 20,
+                index_width: 4,
+            };
+            let opts = PlainOptions {
+                block: 1000,
+                output_dir: &dir,
+                filter: &filter,
+                layout: AccountLayout::Standard,
+                limit: usize::MAX,
+                batch_size: 1,
+                storage_alignment: None,
+            };
+            let crashing = Interrupted {
+                inner: &source,
+                calls: Cell::new(calls),
+            };
+            let mut writer = ArtifactWriter::create(&dir, format, AccountLayout::Standard).unwrap();
+            let err = extract_plain(
+                &crashing,
+                Some(&mut writer),
+                None,
+                None,
+                opts,
+                &Progress::hidden(),
+            )
+            .unwrap_err();
+            assert!(err.to_string().contains("interrupted"), "{}", err);
+            drop(writer);
+            // A torn write past the last checkpoint
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(dir.join("database.bin"))
+                .unwrap()
+                .write_all(&[0xee; 40])
+                .unwrap();
+
+            let checkpoint = Checkpoint::load(&dir).unwrap();
+            let mut writer = match &checkpoint {
+                Some(checkpoint) => {
+                    ArtifactWriter::resume(&dir, checkpoint.offsets, AccountLayout::Standard)
+                        .unwrap()
+                }
+                None => ArtifactWriter::create(&dir, format, AccountLayout::Standard).unwrap(),
+            };
+            let totals = extract_plain(
+                &source,
+                Some(&mut writer),
+                None,
+                checkpoint.as_ref(),
+                opts,
+                &Progress::hidden(),
+            )
+            .unwrap();
+            let hashes = writer.finish().unwrap();
+            assert_eq!(
+                (totals.accounts, totals.storage_slots, totals.total_indices),
+                (3, 3, 12)
+            );
+
+            for name in ["database.bin", "account-mapping.bin", "storage-mapping.bin"] {
+                assert_eq!(
+                    read(&whole, name),
+                    read(&dir, name),
+                    "{} after {}",
+                    name,
+                    calls
+                );
+            }
+            // finish() patches the record counts into the headers
+            let count = |name: &str| {
+                MappingHeader::decode(&read(&dir, name)[..MAPPING_HEADER_SIZE])
+                    .unwrap()
+                    .unwrap()
+                    .count
+            };
+            assert_eq!(count("account-mapping.bin"), 3);
+            assert_eq!(count("storage-mapping.bin"), 3);
+            // The hashes taken while writing, across the resume and the header rewrite,
+            // match a fresh pass over the files
+            for entry in &hashes {
+                assert_eq!(
+                    entry,
+                    &hash_file(&dir.join(&entry.name), &entry.name, DEFAULT_CHUNK_SIZE).unwrap(),
+                    "{} after {}",
+                    entry.name,
+                    calls
+                );
+            }
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+        std::fs::remove_dir_all(whole).unwrap();
+    }
+}
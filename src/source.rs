@@ -0,0 +1,437 @@
+//! Plain state sources for the sequential extractor.
+//!
+//! [`StateSource`] is everything [`crate::sequential::extract_plain`] needs from a node:
+//! accounts and storage slots in key order, read one batch at a time, plus the block the
+//! state belongs to. [`RethSource`] reads reth's `PlainAccountState`/`PlainStorageState`;
+//! [`MemorySource`] holds the state in memory and can be loaded from a JSON-lines file
+//! (`--state-jsonl`), so extraction can run (and be tested) without a synced node.
+//!
+//! [`HashedSource`] is the same for `--hashed` extractions, keyed by `keccak256` of the
+//! address and slot: reth's `HashedAccounts`/`HashedStorages`, or the hashed keys of a
+//! [`MemorySource`].
+
+use alloy_primitives::{keccak256, Address, B256, U256};
+use eyre::{eyre, Result};
+use reth_db::{
+    cursor::{DbCursorRO, DbDupCursorRO},
+    database::Database,
+    tables,
+    transaction::DbTx,
+};
+use reth_primitives::Account;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    ops::Bound,
+    path::Path,
+    sync::OnceLock,
+};
+
+/// Plain (unhashed) state, read in batches in key order.
+pub trait StateSource {
+    /// Block the state is at.
+    fn tip_block(&self) -> Result<u64>;
+
+    /// Upper bounds on the number of accounts and storage slots, used to size mapping indices.
+    fn entries(&self) -> Result<(usize, usize)>;
+
+    /// Up to `limit` accounts with an address above `after` (from the first when `None`),
+    /// in address order. Empty once every account has been read.
+    fn accounts(&self, after: Option<Address>, limit: usize) -> Result<Vec<(Address, Account)>>;
+
+    /// Storage slots of the addresses above `after`, in (address, slot) order.
+    ///
+    /// Batches end on address boundaries: slots are added until at least `limit` have been
+    /// read and the next slot belongs to another address, so `after` can resume from the
+    /// last address of the previous batch. Empty once every slot has been read.
+    fn storage(&self, after: Option<Address>, limit: usize) -> Result<Vec<(Address, B256, U256)>>;
+}
+
+/// Hashed state, read in batches in hashed-key order.
+pub trait HashedSource {
+    /// Up to `limit` accounts with a hashed address above `after`, in hashed-address order.
+    fn hashed_accounts(&self, after: Option<B256>, limit: usize) -> Result<Vec<(B256, Account)>>;
+
+    /// Storage slots `(hashed address, hashed slot, value)` of the hashed addresses above
+    /// `after`, in key order. Batches end on address boundaries, as in
+    /// [`StateSource::storage`].
+    fn hashed_storage(&self, after: Option<B256>, limit: usize) -> Result<Vec<(B256, B256, U256)>>;
+}
+
+/// Reads the plain state tables of a reth database, one read transaction per batch.
+pub struct RethSource<'a, DB> {
+    db: &'a DB,
+}
+
+impl<'a, DB: Database> RethSource<'a, DB> {
+    pub fn new(db: &'a DB) -> Self {
+        Self { db }
+    }
+}
+
+impl<DB: Database> StateSource for RethSource<'_, DB> {
+    fn tip_block(&self) -> Result<u64> {
+        let tx = self.db.tx()?;
+        let last = tx.cursor_read::<tables::CanonicalHeaders>()?.last()?;
+        Ok(last.map(|(num, _hash)| num).unwrap_or(0))
+    }
+
+    fn entries(&self) -> Result<(usize, usize)> {
+        let tx = self.db.tx()?;
+        Ok((
+            tx.entries::<tables::PlainAccountState>()?,
+            tx.entries::<tables::PlainStorageState>()?,
+        ))
+    }
+
+    fn accounts(&self, after: Option<Address>, limit: usize) -> Result<Vec<(Address, Account)>> {
+        let tx = self.db.tx()?;
+        let mut cursor = tx.cursor_read::<tables::PlainAccountState>()?;
+
+        let mut accounts = Vec::new();
+        for entry in cursor.walk(after)? {
+            let (address, account) = entry?;
+            // `walk` starts at `after` itself if it still exists
+            if after == Some(address) {
+                continue;
+            }
+            if accounts.len() >= limit {
+                break;
+            }
+            accounts.push((address, account));
+        }
+        Ok(accounts)
+    }
+
+    fn storage(&self, after: Option<Address>, limit: usize) -> Result<Vec<(Address, B256, U256)>> {
+        let tx = self.db.tx()?;
+        let mut cursor = tx.cursor_dup_read::<tables::PlainStorageState>()?;
+
+        // Jump past the slots of `after`; if it has none left, walk from where it would be
+        let start = match after {
+            Some(addr) => match cursor.seek_exact(addr)? {
+                Some(_) => match cursor.next_no_dup()? {
+                    Some((next, _)) => Some(next),
+                    None => return Ok(Vec::new()),
+                },
+                None => Some(addr),
+            },
+            None => None,
+        };
+
+        let mut slots: Vec<(Address, B256, U256)> = Vec::new();
+        for entry in cursor.walk(start)? {
+            let (address, storage_entry) = entry?;
+            if after == Some(address) {
+                continue;
+            }
+            if slots.len() >= limit && slots.last().is_some_and(|(last, _, _)| *last != address) {
+                break;
+            }
+            slots.push((address, storage_entry.key, storage_entry.value));
+        }
+        Ok(slots)
+    }
+}
+
+impl<DB: Database> HashedSource for RethSource<'_, DB> {
+    fn hashed_accounts(&self, after: Option<B256>, limit: usize) -> Result<Vec<(B256, Account)>> {
+        let tx = self.db.tx()?;
+        let mut cursor = tx.cursor_read::<tables::HashedAccounts>()?;
+
+        let mut accounts = Vec::new();
+        for entry in cursor.walk(after)? {
+            let (hashed_address, account) = entry?;
+            if after == Some(hashed_address) {
+                continue;
+            }
+            if accounts.len() >= limit {
+                break;
+            }
+            accounts.push((hashed_address, account));
+        }
+        Ok(accounts)
+    }
+
+    fn hashed_storage(&self, after: Option<B256>, limit: usize) -> Result<Vec<(B256, B256, U256)>> {
+        let tx = self.db.tx()?;
+        let mut cursor = tx.cursor_dup_read::<tables::HashedStorages>()?;
+
+        let start = match after {
+            Some(addr) => match cursor.seek_exact(addr)? {
+                Some(_) => match cursor.next_no_dup()? {
+                    Some((next, _)) => Some(next),
+                    None => return Ok(Vec::new()),
+                },
+                None => Some(addr),
+            },
+            None => None,
+        };
+
+        let mut slots: Vec<(B256, B256, U256)> = Vec::new();
+        for entry in cursor.walk(start)? {
+            let (hashed_address, storage_entry) = entry?;
+            if after == Some(hashed_address) {
+                continue;
+            }
+            if slots.len() >= limit
+                && slots
+                    .last()
+                    .is_some_and(|(last, _, _)| *last != hashed_address)
+            {
+                break;
+            }
+            slots.push((hashed_address, storage_entry.key, storage_entry.value));
+        }
+        Ok(slots)
+    }
+}
+
+/// One line of a `--state-jsonl` file.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonLine {
+    /// `{"block": N}`: the block the state is at.
+    Block {
+        block: u64,
+    },
+    Account(JsonAccount),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonAccount {
+    address: Address,
+    #[serde(default)]
+    nonce: u64,
+    #[serde(default)]
+    balance: U256,
+    #[serde(default)]
+    code_hash: Option<B256>,
+    #[serde(default)]
+    storage: BTreeMap<B256, U256>,
+}
+
+/// State held in sorted maps, e.g. a test fixture.
+#[derive(Debug, Default, Clone)]
+pub struct MemorySource {
+    block: u64,
+    accounts: BTreeMap<Address, Account>,
+    storage: BTreeMap<Address, BTreeMap<B256, U256>>,
+    /// The state rekeyed for [`HashedSource`], built on the first hashed read.
+    hashed: OnceLock<HashedState>,
+}
+
+/// [`MemorySource`]'s state keyed by `keccak256` of the address and slot.
+#[derive(Debug, Default, Clone)]
+struct HashedState {
+    accounts: BTreeMap<B256, Account>,
+    storage: BTreeMap<B256, BTreeMap<B256, U256>>,
+}
+
+// The hashed state is only a cache of the plain one
+impl PartialEq for MemorySource {
+    fn eq(&self, other: &Self) -> bool {
+        self.block == other.block
+            && self.accounts == other.accounts
+            && self.storage == other.storage
+    }
+}
+
+impl Eq for MemorySource {}
+
+impl MemorySource {
+    pub fn new(block: u64) -> Self {
+        Self {
+            block,
+            ..Default::default()
+        }
+    }
+
+    pub fn insert_account(&mut self, address: Address, account: Account) {
+        self.hashed = OnceLock::new();
+        self.accounts.insert(address, account);
+    }
+
+    pub fn insert_storage(&mut self, address: Address, key: B256, value: U256) {
+        self.hashed = OnceLock::new();
+        self.storage.entry(address).or_default().insert(key, value);
+    }
+
+    fn hashed(&self) -> &HashedState {
+        self.hashed.get_or_init(|| HashedState {
+            accounts: self
+                .accounts
+                .iter()
+                .map(|(address, account)| (keccak256(address), *account))
+                .collect(),
+            storage: self
+                .storage
+                .iter()
+                .map(|(address, slots)| {
+                    let slots = slots.iter().map(|(key, value)| (keccak256(key), *value));
+                    (keccak256(address), slots.collect())
+                })
+                .collect(),
+        })
+    }
+
+    /// Loads a JSON-lines state file.
+    ///
+    /// Each line is either `{"block": N}` or an account:
+    /// `{"address": "0x..", "nonce": 1, "balance": "0x..", "code_hash": "0x..", "storage": {"0x<slot>": "0x<value>"}}`.
+    /// Every field but `address` is optional; blank lines are ignored. Lines may come in any
+    /// order, and a later line for the same account replaces the earlier one.
+    pub fn from_jsonl(path: &Path) -> Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut source = Self::default();
+        for (line_no, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let parsed: JsonLine = serde_json::from_str(&line)
+                .map_err(|e| eyre!("{:?} line {}: {}", path, line_no + 1, e))?;
+            match parsed {
+                JsonLine::Block { block } => source.block = block,
+                JsonLine::Account(account) => {
+                    source.insert_account(
+                        account.address,
+                        Account {
+                            nonce: account.nonce,
+                            balance: account.balance,
+                            bytecode_hash: account.code_hash,
+                        },
+                    );
+                    source.hashed = OnceLock::new();
+                    source.storage.remove(&account.address);
+                    for (key, value) in account.storage {
+                        source.insert_storage(account.address, key, value);
+                    }
+                }
+            }
+        }
+        Ok(source)
+    }
+}
+
+/// Keys strictly above `after`.
+fn above<K>(after: Option<K>) -> (Bound<K>, Bound<K>) {
+    match after {
+        Some(addr) => (Bound::Excluded(addr), Bound::Unbounded),
+        None => (Bound::Unbounded, Bound::Unbounded),
+    }
+}
+
+/// A [`StateSource::storage`] batch of `storage`: whole addresses above `after` until at
+/// least `limit` slots.
+fn storage_batch<K: Ord + Copy>(
+    storage: &BTreeMap<K, BTreeMap<B256, U256>>,
+    after: Option<K>,
+    limit: usize,
+) -> Vec<(K, B256, U256)> {
+    let mut slots = Vec::new();
+    for (address, account_slots) in storage.range(above(after)) {
+        if slots.len() >= limit {
+            break;
+        }
+        slots.extend(
+            account_slots
+                .iter()
+                .map(|(key, value)| (*address, *key, *value)),
+        );
+    }
+    slots
+}
+
+impl StateSource for MemorySource {
+    fn tip_block(&self) -> Result<u64> {
+        Ok(self.block)
+    }
+
+    fn entries(&self) -> Result<(usize, usize)> {
+        let slots = self.storage.values().map(BTreeMap::len).sum();
+        Ok((self.accounts.len(), slots))
+    }
+
+    fn accounts(&self, after: Option<Address>, limit: usize) -> Result<Vec<(Address, Account)>> {
+        Ok(self
+            .accounts
+            .range(above(after))
+            .take(limit)
+            .map(|(address, account)| (*address, *account))
+            .collect())
+    }
+
+    fn storage(&self, after: Option<Address>, limit: usize) -> Result<Vec<(Address, B256, U256)>> {
+        Ok(storage_batch(&self.storage, after, limit))
+    }
+}
+
+impl HashedSource for MemorySource {
+    fn hashed_accounts(&self, after: Option<B256>, limit: usize) -> Result<Vec<(B256, Account)>> {
+        Ok(self
+            .hashed()
+            .accounts
+            .range(above(after))
+            .take(limit)
+            .map(|(k, a)| (*k, *a))
+            .collect())
+    }
+
+    fn hashed_storage(&self, after: Option<B256>, limit: usize) -> Result<Vec<(B256, B256, U256)>> {
+        Ok(storage_batch(&self.hashed().storage, after, limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/state.jsonl");
+
+    #[test]
+    fn hashed_batches_follow_hashed_order_and_later_inserts() {
+        let mut source = MemorySource::from_jsonl(Path::new(FIXTURE)).unwrap();
+        assert_eq!(source.tip_block().unwrap(), 1000);
+
+        let mut expected: Vec<_> = source
+            .storage
+            .iter()
+            .flat_map(|(address, slots)| {
+                slots
+                    .iter()
+                    .map(move |(key, value)| (keccak256(address), keccak256(key), *value))
+            })
+            .collect();
+        expected.sort();
+        let walk = |source: &MemorySource| {
+            let mut slots = Vec::new();
+            let mut after = None;
+            loop {
+                let batch = source.hashed_storage(after, 1).unwrap();
+                let Some(&(last, _, _)) = batch.last() else {
+                    break;
+                };
+                slots.extend(batch);
+                after = Some(last);
+            }
+            slots
+        };
+        assert_eq!(walk(&source), expected);
+
+        // Inserting after a hashed read drops the cached hashed state
+        let address = Address::repeat_byte(0x44);
+        source.insert_account(address, Account::default());
+        source.insert_storage(address, B256::ZERO, U256::from(9));
+        expected.push((keccak256(address), keccak256(B256::ZERO), U256::from(9)));
+        expected.sort();
+        assert_eq!(walk(&source), expected);
+        let accounts = source.hashed_accounts(None, usize::MAX).unwrap();
+        assert_eq!(accounts.len(), 4);
+        assert!(accounts.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(accounts.iter().any(|(key, _)| *key == keccak256(address)));
+    }
+}
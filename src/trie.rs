@@ -0,0 +1,376 @@
+//! Merkle trie node extraction (`--trie`).
+//!
+//! Writes reth's `AccountsTrie`/`StoragesTrie` branch nodes into one flat PIR database per
+//! trie depth (number of path nibbles), so a client can fetch a Merkle branch privately with
+//! one query per level and check the values it retrieved against the state root in
+//! `metadata.json`. Files live in `<output-dir>/trie/`:
+//!
+//! - `account-<depth>.bin`: account trie nodes at that depth, [`NODE_WORDS`] words each.
+//! - `account-<depth>-mapping.bin`: `PackedPath (ceil(depth / 2)) || Index (4 or 8, LE)`.
+//! - `storage-<depth>.bin`: storage trie nodes of every account at that depth.
+//! - `storage-<depth>-mapping.bin`: `keccak256(Address) (32) || PackedPath || Index (4 or 8, LE)`.
+//!
+//! Each mapping starts with a [`MappingHeader`] whose key size covers the prefix and the
+//! packed path, and whose index width is sized for the trie's node count. Paths are packed two nibbles per byte (odd paths padded with a zero low nibble); records
+//! are sorted by key. Reth does not persist root nodes (empty path), so depths start at 1.
+
+use crate::progress::{Phase, Progress};
+use alloy_primitives::B256;
+use eyre::Result;
+use reth_db::{
+    cursor::{DbCursorRO, DbDupCursorRO},
+    database::Database,
+    tables,
+    transaction::DbTx,
+};
+use reth_trie_common::BranchNodeCompact;
+use state_syncer::mapping::{index_width_for, write_index, MappingHeader};
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Words per trie node entry.
+///
+/// Node layout: 17 words (544 bytes)
+///   Word 0: StateMask (u16 LE) || TreeMask (u16 LE) || HashMask (u16 LE), zero-padded
+///   Word 1 + i: hash of child `i` if bit `i` of HashMask is set, zero otherwise
+pub const NODE_WORDS: u64 = 17;
+
+/// Node counts per depth produced by [`extract_trie`].
+#[derive(Debug, Default, Clone)]
+pub struct TrieTotals {
+    pub account_nodes: Vec<u64>,
+    pub storage_nodes: Vec<u64>,
+}
+
+/// Encodes a branch node as its database words.
+pub fn node_words(node: &BranchNodeCompact) -> [[u8; 32]; NODE_WORDS as usize] {
+    let mut words = [[0u8; 32]; NODE_WORDS as usize];
+    words[0][0..2].copy_from_slice(&node.state_mask.get().to_le_bytes());
+    words[0][2..4].copy_from_slice(&node.tree_mask.get().to_le_bytes());
+    words[0][4..6].copy_from_slice(&node.hash_mask.get().to_le_bytes());
+
+    // `hashes` holds one hash per set HashMask bit, in nibble order
+    let mut hashes = node.hashes.iter();
+    for nibble in 0..16u8 {
+        if node.hash_mask.is_bit_set(nibble) {
+            if let Some(hash) = hashes.next() {
+                words[1 + nibble as usize] = hash.0;
+            }
+        }
+    }
+    words
+}
+
+/// Packs nibbles two per byte, padding an odd path with a zero low nibble.
+fn pack_nibbles(nibbles: &[u8]) -> Vec<u8> {
+    nibbles
+        .chunks(2)
+        .map(|pair| (pair[0] << 4) | pair.get(1).copied().unwrap_or(0))
+        .collect()
+}
+
+/// Per-depth database and mapping writers for one trie (`account` or `storage`).
+struct LevelWriters {
+    dir: Option<PathBuf>,
+    name: &'static str,
+    index_width: u32,
+    /// Database and mapping writers, and the mapping key size, per depth.
+    levels: Vec<Option<(BufWriter<File>, BufWriter<File>, usize)>>,
+    counts: Vec<u64>,
+}
+
+impl LevelWriters {
+    fn new(dir: Option<PathBuf>, name: &'static str, index_width: u32) -> Self {
+        Self {
+            dir,
+            name,
+            index_width,
+            levels: Vec::new(),
+            counts: Vec::new(),
+        }
+    }
+
+    /// Appends a node at `nibbles` (below `prefix`, the hashed address for storage tries).
+    fn write(&mut self, prefix: &[u8], nibbles: &[u8], node: &BranchNodeCompact) -> Result<()> {
+        let depth = nibbles.len();
+        if self.counts.len() <= depth {
+            self.counts.resize(depth + 1, 0);
+            self.levels.resize_with(depth + 1, || None);
+        }
+        let index = self.counts[depth] * NODE_WORDS;
+        self.counts[depth] += 1;
+
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        if self.levels[depth].is_none() {
+            let base = format!("{}-{:02}", self.name, depth);
+            let key_size = prefix.len() + depth.div_ceil(2);
+            let mut map = BufWriter::new(File::create(dir.join(format!("{}-mapping.bin", base)))?);
+            map.write_all(&MappingHeader::new(key_size, self.index_width, 0).encode())?;
+            self.levels[depth] = Some((
+                BufWriter::new(File::create(dir.join(format!("{}.bin", base)))?),
+                map,
+                key_size,
+            ));
+        }
+        let (db, map, _) = self.levels[depth].as_mut().unwrap();
+
+        for word in node_words(node) {
+            db.write_all(&word)?;
+        }
+        // Prefix + PackedPath + Index (index_width)
+        map.write_all(prefix)?;
+        map.write_all(&pack_nibbles(nibbles))?;
+        write_index(map, index, self.index_width)?;
+        Ok(())
+    }
+
+    /// Flushes every depth and writes the final record counts into the mapping headers.
+    fn finish(self) -> Result<Vec<u64>> {
+        for (depth, level) in self.levels.into_iter().enumerate() {
+            let Some((mut db, mut map, key_size)) = level else {
+                continue;
+            };
+            db.flush()?;
+            let header = MappingHeader::new(key_size, self.index_width, self.counts[depth]);
+            map.seek(SeekFrom::Start(0))?;
+            map.write_all(&header.encode())?;
+            map.flush()?;
+        }
+        Ok(self.counts)
+    }
+}
+
+/// Walks `AccountsTrie` and `StoragesTrie` in batches and writes the per-depth databases into
+/// `output_dir/trie` (or only counts when `output_dir` is `None`).
+pub fn extract_trie<DB: Database>(
+    db: &DB,
+    output_dir: Option<&Path>,
+    batch_size: usize,
+    progress: &Progress,
+) -> Result<TrieTotals> {
+    let trie_dir = match output_dir {
+        Some(dir) => {
+            let trie_dir = dir.join("trie");
+            std::fs::create_dir_all(&trie_dir)?;
+            Some(trie_dir)
+        }
+        None => None,
+    };
+
+    // Every node of a trie could sit at one depth, which bounds each depth's index space
+    let (account_width, storage_width) = {
+        let tx = db.tx()?;
+        (
+            index_width_for(tx.entries::<tables::AccountsTrie>()? as u64 * NODE_WORDS),
+            index_width_for(tx.entries::<tables::StoragesTrie>()? as u64 * NODE_WORDS),
+        )
+    };
+
+    // --- ACCOUNT TRIE ---
+    let mut accounts = LevelWriters::new(trie_dir.clone(), "account", account_width);
+    let mut total = 0u64;
+    let mut last_key = None;
+    loop {
+        let tx = db.tx()?;
+        let mut cursor = tx.cursor_read::<tables::AccountsTrie>()?;
+
+        let mut batch_count = 0;
+        let mut current_key = None;
+        for entry in cursor.walk(last_key.clone())? {
+            let (path, node) = entry?;
+            if last_key.as_ref() == Some(&path) {
+                continue;
+            }
+
+            accounts.write(&[], &path.0.to_vec(), &node)?;
+            total += 1;
+            if total % 10000 == 0 {
+                progress.update(Phase::Trie, total, format!("Trie nodes: {}", total));
+            }
+
+            batch_count += 1;
+            current_key = Some(path);
+            if batch_count >= batch_size {
+                break;
+            }
+        }
+
+        if batch_count == 0 {
+            break;
+        }
+        last_key = current_key;
+    }
+
+    // --- STORAGE TRIES ---
+    let mut storage = LevelWriters::new(trie_dir, "storage", storage_width);
+    let mut last_addr: Option<B256> = None;
+    loop {
+        let tx = db.tx()?;
+        let mut cursor = tx.cursor_dup_read::<tables::StoragesTrie>()?;
+
+        // Batches end on address boundaries; jump past the last address we finished
+        let start = match last_addr {
+            Some(addr) => match cursor.seek_exact(addr)? {
+                Some(_) => match cursor.next_no_dup()? {
+                    Some((next, _)) => Some(next),
+                    None => break,
+                },
+                None => Some(addr),
+            },
+            None => None,
+        };
+
+        let mut batch_count = 0;
+        let mut current_addr = None;
+        for entry in cursor.walk(start)? {
+            let (hashed_address, trie_entry) = entry?;
+            if last_addr == Some(hashed_address) {
+                continue;
+            }
+            if let Some(curr) = current_addr {
+                if hashed_address != curr && batch_count >= batch_size {
+                    break;
+                }
+            }
+            current_addr = Some(hashed_address);
+
+            storage.write(
+                hashed_address.as_slice(),
+                &trie_entry.nibbles.0.to_vec(),
+                &trie_entry.node,
+            )?;
+            total += 1;
+            if total % 10000 == 0 {
+                progress.update(Phase::Trie, total, format!("Trie nodes: {}", total));
+            }
+
+            batch_count += 1;
+        }
+
+        if batch_count == 0 {
+            break;
+        }
+        last_addr = current_addr;
+    }
+    progress.finish_phase(Phase::Trie, total);
+
+    Ok(TrieTotals {
+        account_nodes: accounts.finish()?,
+        storage_nodes: storage.finish()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state_syncer::mapping::MAPPING_HEADER_SIZE;
+
+    /// Inverse of [`pack_nibbles`] for a path of `len` nibbles.
+    fn unpack_nibbles(packed: &[u8], len: usize) -> Vec<u8> {
+        packed
+            .iter()
+            .flat_map(|byte| [byte >> 4, byte & 0x0f])
+            .take(len)
+            .collect()
+    }
+
+    /// Inverse of [`node_words`].
+    fn decode_node(words: &[[u8; 32]]) -> BranchNodeCompact {
+        let mask = |i: usize| u16::from_le_bytes([words[0][i], words[0][i + 1]]);
+        let hash_mask = mask(4);
+        let hashes = (0..16)
+            .filter(|nibble| hash_mask & (1 << nibble) != 0)
+            .map(|nibble| B256::from(words[1 + nibble]))
+            .collect();
+        BranchNodeCompact::new(mask(0), mask(2), hash_mask, hashes, None)
+    }
+
+    #[test]
+    fn nibbles_round_trip_for_odd_and_even_paths() {
+        for len in 0..=7usize {
+            let nibbles: Vec<u8> = (0..len).map(|i| (i as u8 * 5 + 3) % 16).collect();
+            let packed = pack_nibbles(&nibbles);
+            assert_eq!(packed.len(), len.div_ceil(2));
+            assert_eq!(unpack_nibbles(&packed, len), nibbles);
+            if len % 2 == 1 {
+                assert_eq!(packed.last().unwrap() & 0x0f, 0, "odd paths pad low nibble");
+            }
+        }
+        assert_eq!(pack_nibbles(&[0xa, 0xb, 0xc]), [0xab, 0xc0]);
+    }
+
+    #[test]
+    fn node_words_round_trip() {
+        let nodes = [
+            // Hashes for an odd and an even set of children, with gaps
+            BranchNodeCompact::new(
+                0b1010_0000_0000_0111u16,
+                0b0000_0000_0000_0010u16,
+                0b1000_0000_0000_0101u16,
+                vec![
+                    B256::repeat_byte(1),
+                    B256::repeat_byte(2),
+                    B256::repeat_byte(3),
+                ],
+                None,
+            ),
+            BranchNodeCompact::new(
+                0xffffu16,
+                0u16,
+                0b0100_0000_0000_1000u16,
+                vec![B256::repeat_byte(4), B256::repeat_byte(5)],
+                None,
+            ),
+            BranchNodeCompact::new(0b11u16, 0b01u16, 0u16, Vec::new(), None),
+        ];
+        for node in nodes {
+            let words = node_words(&node);
+            assert_eq!(decode_node(&words), node);
+            assert_eq!(words[0][6..], [0; 26]);
+            for nibble in 0..16u8 {
+                if !node.hash_mask.is_bit_set(nibble) {
+                    assert_eq!(words[1 + nibble as usize], [0; 32]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn level_mappings_have_headers() {
+        let dir = std::env::temp_dir().join(format!("plinko-trie-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let node = BranchNodeCompact::new(0b11u16, 0u16, 0b01u16, vec![B256::repeat_byte(9)], None);
+        let prefix = [0x77; 32];
+        let mut levels = LevelWriters::new(Some(dir.clone()), "storage", 8);
+        levels.write(&prefix, &[1, 2, 3], &node).unwrap();
+        levels.write(&prefix, &[1, 2, 4], &node).unwrap();
+        levels.write(&prefix, &[5, 6], &node).unwrap();
+        assert_eq!(levels.finish().unwrap(), [0, 0, 1, 2]);
+
+        let map = std::fs::read(dir.join("storage-03-mapping.bin")).unwrap();
+        let header = MappingHeader::decode(&map).unwrap().unwrap();
+        assert_eq!(header, MappingHeader::new(34, 8, 2));
+        assert_eq!(map.len(), MAPPING_HEADER_SIZE + 2 * header.record_size());
+        let second = &map[MAPPING_HEADER_SIZE + header.record_size()..];
+        assert_eq!(second[..32], prefix);
+        assert_eq!(second[32..34], [0x12, 0x40]);
+        assert_eq!(second[34..], NODE_WORDS.to_le_bytes());
+
+        let map = std::fs::read(dir.join("storage-02-mapping.bin")).unwrap();
+        assert_eq!(
+            MappingHeader::decode(&map).unwrap(),
+            Some(MappingHeader::new(33, 8, 1))
+        );
+        let db = std::fs::read(dir.join("storage-03.bin")).unwrap();
+        assert_eq!(db.len() as u64, 2 * NODE_WORDS * 32);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+use crate::checkpoint::FileOffsets;
+use alloy_primitives::{B256, U256};
+use eyre::{ensure, eyre, Result};
+use reth_primitives::Account;
+use state_syncer::{
+    layout::{AccountLayout, AccountRecord},
+    manifest::{ChunkHasher, FileHashes, DEFAULT_CHUNK_SIZE},
+    mapping::{write_index, MappingHeader, MAPPING_HEADER_SIZE},
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Row counts of a finished extraction, as recorded in `metadata.json`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Totals {
+    pub accounts: u64,
+    pub storage_slots: u64,
+    pub total_indices: u64,
+}
+
+/// The fields of `account` that the account layouts encode.
+pub fn account_record(account: &Account) -> AccountRecord {
+    AccountRecord {
+        nonce: account.nonce,
+        balance: account.balance.to_le_bytes::<32>(),
+        code_hash: account.bytecode_hash.unwrap_or_default().0,
+    }
+}
+
+/// Key and index widths of the mapping files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingFormat {
+    /// Account key size: 20 (address) or 32 (`--hashed`); storage keys add the 32-byte slot.
+    pub key_size: usize,
+    /// Bytes per index (4 or 8).
+    pub index_width: u32,
+}
+
+impl MappingFormat {
+    fn account_header(&self, count: u64) -> MappingHeader {
+        MappingHeader::new(self.key_size, self.index_width, count)
+    }
+
+    fn storage_header(&self, count: u64) -> MappingHeader {
+        MappingHeader::new(self.key_size + 32, self.index_width, count)
+    }
+}
+
+/// An artifact file that hashes everything written through it for `manifest.json`.
+struct HashedFile {
+    file: File,
+    hasher: ChunkHasher,
+}
+
+impl Write for HashedFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Buffered writers for `database.bin`, `account-mapping.bin` and `storage-mapping.bin`.
+///
+/// The files are hashed as they are written, so [`ArtifactWriter::finish`] returns their
+/// manifest entries without reading them back.
+pub struct ArtifactWriter {
+    db: BufWriter<HashedFile>,
+    acc_map: BufWriter<HashedFile>,
+    sto_map: BufWriter<HashedFile>,
+    format: MappingFormat,
+    layout: AccountLayout,
+    offsets: FileOffsets,
+}
+
+impl ArtifactWriter {
+    /// Creates (truncating) the three artifact files inside `output_dir`.
+    ///
+    /// The mapping headers are written with a zero record count, filled in by
+    /// [`ArtifactWriter::finish`].
+    pub fn create(output_dir: &Path, format: MappingFormat, layout: AccountLayout) -> Result<Self> {
+        let create = |name: &str| -> Result<BufWriter<HashedFile>> {
+            // Readable too: finish() reads the rewritten header chunk back for its hash
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(output_dir.join(name))?;
+            Ok(BufWriter::new(HashedFile {
+                file,
+                hasher: ChunkHasher::new(DEFAULT_CHUNK_SIZE)?,
+            }))
+        };
+        let mut acc_map = create("account-mapping.bin")?;
+        let mut sto_map = create("storage-mapping.bin")?;
+        acc_map.write_all(&format.account_header(0).encode())?;
+        sto_map.write_all(&format.storage_header(0).encode())?;
+
+        Ok(Self {
+            db: create("database.bin")?,
+            acc_map,
+            sto_map,
+            format,
+            layout,
+            offsets: FileOffsets {
+                database: 0,
+                account_mapping: MAPPING_HEADER_SIZE as u64,
+                storage_mapping: MAPPING_HEADER_SIZE as u64,
+            },
+        })
+    }
+
+    /// Reads the mapping format from the header of the account mapping in `output_dir`.
+    pub fn read_format(output_dir: &Path) -> Result<MappingFormat> {
+        let path = output_dir.join("account-mapping.bin");
+        let mut header = [0u8; MAPPING_HEADER_SIZE];
+        File::open(&path)?.read_exact(&mut header)?;
+        let header = MappingHeader::decode(&header)?
+            .ok_or_else(|| eyre!("{:?} has no mapping header, cannot resume", path))?;
+        Ok(MappingFormat {
+            key_size: header.key_size as usize,
+            index_width: header.index_width,
+        })
+    }
+
+    /// Reopens the artifact files inside `output_dir`, truncating each one back to the
+    /// checkpointed `offsets` so writing continues exactly where the checkpoint was taken.
+    ///
+    /// The kept prefix of each file is re-hashed once so the manifest still covers it.
+    pub fn resume(output_dir: &Path, offsets: FileOffsets, layout: AccountLayout) -> Result<Self> {
+        let format = Self::read_format(output_dir)?;
+        let reopen = |name: &str, len: u64| -> Result<BufWriter<HashedFile>> {
+            let path = output_dir.join(name);
+            let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+            let actual = file.metadata()?.len();
+            ensure!(
+                actual >= len,
+                "{:?} is {} bytes, shorter than the checkpointed {} bytes",
+                path,
+                actual,
+                len
+            );
+            file.set_len(len)?;
+            let mut hasher = ChunkHasher::new(DEFAULT_CHUNK_SIZE)?;
+            std::io::copy(&mut file, &mut hasher)?;
+            file.seek(SeekFrom::End(0))?;
+            Ok(BufWriter::new(HashedFile { file, hasher }))
+        };
+
+        Ok(Self {
+            db: reopen("database.bin", offsets.database)?,
+            acc_map: reopen("account-mapping.bin", offsets.account_mapping)?,
+            sto_map: reopen("storage-mapping.bin", offsets.storage_mapping)?,
+            format,
+            layout,
+            offsets,
+        })
+    }
+
+    /// Appends an account entry and its mapping record pointing at `index`.
+    ///
+    /// `address` is the mapping key: the 20-byte address, or its 32-byte hash in `--hashed`
+    /// mode. Fails if `index` does not fit the mapping's index width.
+    pub fn write_account(&mut self, address: &[u8], account: &Account, index: u64) -> Result<()> {
+        let words = self.layout.encode(&account_record(account))?;
+        let n = self.layout.words();
+        for word in &words[..n as usize] {
+            self.db.write_all(word)?;
+        }
+
+        // Address (20 or 32) + Index (4 or 8)
+        self.acc_map.write_all(address)?;
+        write_index(&mut self.acc_map, index, self.format.index_width)?;
+
+        self.offsets.database += n * 32;
+        self.offsets.account_mapping += (address.len() + self.format.index_width as usize) as u64;
+        Ok(())
+    }
+
+    /// Appends a storage slot entry and its mapping record pointing at `index`.
+    ///
+    /// As with [`ArtifactWriter::write_account`], `address` and `key` are plain or hashed.
+    pub fn write_storage(
+        &mut self,
+        address: &[u8],
+        key: &B256,
+        value: &U256,
+        index: u64,
+    ) -> Result<()> {
+        self.db.write_all(&value.to_le_bytes::<32>())?;
+
+        // Address (20 or 32) + SlotKey (32) + Index (4 or 8)
+        self.sto_map.write_all(address)?;
+        self.sto_map.write_all(key.as_slice())?;
+        write_index(&mut self.sto_map, index, self.format.index_width)?;
+
+        self.offsets.database += 32;
+        self.offsets.storage_mapping +=
+            (address.len() + 32 + self.format.index_width as usize) as u64;
+        Ok(())
+    }
+
+    /// Appends `entries` zero entries that no mapping points at (`--storage-alignment`).
+    pub fn write_padding(&mut self, entries: u64) -> Result<()> {
+        for _ in 0..entries {
+            self.db.write_all(&[0u8; 32])?;
+        }
+        self.offsets.database += entries * 32;
+        Ok(())
+    }
+
+    /// Flushes and fsyncs all outputs, returning their lengths for a checkpoint.
+    pub fn sync(&mut self) -> Result<FileOffsets> {
+        for writer in [&mut self.db, &mut self.acc_map, &mut self.sto_map] {
+            writer.flush()?;
+            writer.get_ref().file.sync_data()?;
+        }
+        Ok(self.offsets)
+    }
+
+    /// Flushes all buffered output to disk, records the final counts in the mapping
+    /// headers and returns the manifest entries of the three files.
+    pub fn finish(self) -> Result<Vec<FileHashes>> {
+        let header_size = MAPPING_HEADER_SIZE as u64;
+        let accounts = (self.offsets.account_mapping - header_size)
+            / self.format.account_header(0).record_size() as u64;
+        let slots = (self.offsets.storage_mapping - header_size)
+            / self.format.storage_header(0).record_size() as u64;
+
+        let db = self.db.into_inner().map_err(|e| e.into_error())?;
+        let mut hashes = vec![db.hasher.finish("database.bin")];
+        for (writer, name, header) in [
+            (
+                self.acc_map,
+                "account-mapping.bin",
+                self.format.account_header(accounts),
+            ),
+            (
+                self.sto_map,
+                "storage-mapping.bin",
+                self.format.storage_header(slots),
+            ),
+        ] {
+            let HashedFile { mut file, hasher } =
+                writer.into_inner().map_err(|e| e.into_error())?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&header.encode())?;
+            hashes.push(hasher.finish_rereading_first_chunk(name, &mut file)?);
+        }
+        Ok(hashes)
+    }
+}
@@ -0,0 +1,82 @@
+use clap::Parser;
+use state_syncer::delta::{
+    delta_path, range_path, read_delta, write_delta, ChainBinding, Compactor,
+};
+use std::path::PathBuf;
+use std::time::Instant;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Compact per-block Plinko deltas into a range delta", long_about = None)]
+struct Args {
+    /// Directory holding delta-<block>.bin
+    #[arg(short, long, default_value = "deltas")]
+    deltas_dir: PathBuf,
+
+    /// First block of the range
+    #[arg(long)]
+    from: u64,
+
+    /// Last block of the range (inclusive)
+    #[arg(long)]
+    to: u64,
+
+    /// Range delta to write (default: <deltas-dir>/delta-<from>-<to>.bin)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+/// Merges the deltas of blocks `--from..=--to` into one range delta, so clients catching up
+/// download one file instead of one per block. Legacy files without a chain binding are
+/// bound by their file name, without hashes.
+fn main() -> eyre::Result<()> {
+    let args = Args::parse();
+    eyre::ensure!(
+        args.from <= args.to,
+        "--from ({}) must not exceed --to ({})",
+        args.from,
+        args.to
+    );
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| range_path(&args.deltas_dir, args.from, args.to));
+
+    println!("Plinko Delta Compaction");
+    println!("-----------------------");
+    println!("Deltas: {:?}", args.deltas_dir);
+    println!("Blocks: #{}..=#{}", args.from, args.to);
+
+    let start = Instant::now();
+    let mut compactor = Compactor::new();
+    let mut legacy = 0u64;
+    for block in args.from..=args.to {
+        let path = delta_path(&args.deltas_dir, block);
+        let delta = read_delta(&path)?;
+        let binding = delta.binding.unwrap_or_else(|| {
+            legacy += 1;
+            ChainBinding::block(block, None, None)
+        });
+        compactor
+            .add(&binding, &delta.records)
+            .map_err(|e| e.wrap_err(format!("{:?}", path)))?;
+    }
+    if legacy > 0 {
+        println!(
+            "Warning: {} legacy deltas have no chain binding; their hashes are not checked",
+            legacy
+        );
+    }
+
+    let input_records = compactor.input_records();
+    let (binding, records) = compactor.finish()?;
+    write_delta(&output, &binding, &records)?;
+    println!(
+        "Wrote {} records ({} in {} block deltas) to {:?} in {:.2?}",
+        records.len(),
+        input_records,
+        args.to - args.from + 1,
+        output,
+        start.elapsed()
+    );
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use clap::Parser;
+use state_syncer::layout::AccountLayout;
+use state_syncer::synthetic::{generate, SyntheticConfig};
+use std::path::PathBuf;
+use std::time::Instant;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Generate a synthetic Plinko database", long_about = None)]
+struct Args {
+    /// Directory to write database.bin, the mappings, manifest.json and metadata.json into
+    #[arg(short, long, default_value = "synthetic")]
+    output_dir: PathBuf,
+
+    /// Number of accounts
+    #[arg(long, default_value_t = 1_000_000)]
+    accounts: u64,
+
+    /// Number of storage slots, spread over the contracts
+    #[arg(long, default_value_t = 4_000_000)]
+    storage_slots: u64,
+
+    /// RNG seed; the same arguments and seed always produce the same files
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Percent of accounts that are contracts (only contracts have storage)
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u8).range(0..=100))]
+    contract_percent: u8,
+
+    /// Percent of storage slots whose value is zero
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u8).range(0..=100))]
+    zero_slot_percent: u8,
+
+    /// Account record layout
+    #[arg(long, value_enum, default_value_t = AccountLayout::Standard)]
+    account_layout: AccountLayout,
+}
+
+/// Writes a seeded synthetic snapshot in the extractor's formats, for running `plinko_hints`,
+/// `bench_hints` and the tests without a synced node.
+fn main() -> eyre::Result<()> {
+    let args = Args::parse();
+    let config = SyntheticConfig {
+        accounts: args.accounts,
+        storage_slots: args.storage_slots,
+        seed: args.seed,
+        contract_percent: args.contract_percent,
+        zero_slot_percent: args.zero_slot_percent,
+        account_layout: args.account_layout,
+    };
+
+    println!("Synthetic Plinko Database");
+    println!("-------------------------");
+    println!("Output: {:?}", args.output_dir);
+    println!("Accounts: {}", config.accounts);
+    println!("Storage slots: {}", config.storage_slots);
+    println!("Seed: {}", config.seed);
+
+    let start = Instant::now();
+    let metadata = generate(&args.output_dir, &config)?;
+
+    println!(
+        "Wrote {} entries ({:.2} MB) in {:.2?}",
+        metadata.total_indices,
+        (metadata.total_indices * metadata.entry_size) as f64 / 1024.0 / 1024.0,
+        start.elapsed()
+    );
+    Ok(())
+}
@@ -32,6 +32,10 @@ pub struct Args {
 
     #[arg(long)]
     pub constant_time: bool,
+
+    /// Skip checking the database against manifest.json before streaming it
+    #[arg(long)]
+    pub skip_verify: bool,
 }
 
 pub struct RegularHint {
@@ -6,7 +6,9 @@ use clap::Parser;
 use indicatif::{ProgressBar, ProgressStyle};
 use memmap2::MmapOptions;
 use state_syncer::iprf::{Iprf, IprfTee};
+use state_syncer::manifest::Manifest;
 use std::fs::File;
+use std::path::Path;
 use std::time::Instant;
 
 use hint_gen::*;
@@ -19,6 +21,10 @@ fn main() -> eyre::Result<()> {
     println!("============================================");
     println!("Database: {:?}", args.db_path);
 
+    if !args.skip_verify {
+        verify_database(&args.db_path)?;
+    }
+
     let file = File::open(&args.db_path)?;
     let file_len = file.metadata()?.len() as usize;
     println!(
@@ -139,3 +145,21 @@ fn main() -> eyre::Result<()> {
 
     Ok(())
 }
+
+/// Refuses to start on a database that does not match the extractor's `manifest.json`.
+fn verify_database(db_path: &Path) -> eyre::Result<()> {
+    let dir = db_path.parent().unwrap_or(Path::new("."));
+    let name = db_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    match Manifest::load(dir)? {
+        Some(manifest) if manifest.entry(name).is_some() => {
+            println!("Verifying {} against manifest...", name);
+            manifest.check(dir, name)?;
+            println!("Manifest check passed");
+        }
+        _ => println!("No manifest entry for {}, skipping integrity check", name),
+    }
+    Ok(())
+}
@@ -0,0 +1,355 @@
+//! Cuckoo-hashed keyword layout for Plinko databases.
+//!
+//! Instead of shipping a multi-GB `account-mapping.bin` to clients, every key is placed in
+//! one of κ candidate buckets derived from public hash seeds. A client resolves a key to its
+//! κ candidate indices locally, queries all of them (plus the small stash) and keeps the
+//! slot whose tag matches `key_tag(key)`.
+//!
+//! Bucket `h` of a key is `BLAKE3(seed_h_le || key)[0..8] (LE) mod num_buckets`. Table
+//! construction uses random-walk eviction; keys that cannot be placed go to a bounded stash,
+//! and when the stash overflows the table is rebuilt with fresh seeds. All randomness comes
+//! from a ChaCha20 stream seeded by the caller, so a build is fully reproducible.
+
+use eyre::{bail, ensure, Result};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// Default number of hash functions (candidate buckets per key).
+pub const DEFAULT_KAPPA: usize = 3;
+/// Default ratio of buckets to keys; 3-way cuckoo hashing needs ~1.1x, 1.5x is comfortable.
+pub const DEFAULT_OVERHEAD: f64 = 1.5;
+/// Default stash capacity before a rehash is triggered.
+pub const DEFAULT_STASH: usize = 64;
+/// Eviction chain length before a key is sent to the stash.
+pub const MAX_EVICTIONS: usize = 500;
+/// Number of seed sets tried before giving up.
+pub const MAX_ATTEMPTS: u32 = 16;
+
+/// Marker for an empty bucket in [`CuckooLayout::buckets`].
+pub const EMPTY: u32 = u32::MAX;
+
+/// Public parameters a client needs to locate keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CuckooParams {
+    /// One seed per hash function (κ = `seeds.len()`).
+    pub seeds: Vec<u64>,
+    pub num_buckets: u64,
+    /// Number of stash slots stored after the buckets.
+    pub stash_size: u64,
+}
+
+impl CuckooParams {
+    /// Bucket chosen by hash function `h` for `key`.
+    pub fn bucket(&self, h: usize, key: &[u8]) -> u64 {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.seeds[h].to_le_bytes());
+        hasher.update(key);
+        let hash = hasher.finalize();
+        let v = u64::from_le_bytes(hash.as_bytes()[0..8].try_into().unwrap());
+        v % self.num_buckets
+    }
+
+    /// All κ candidate buckets for `key`.
+    pub fn candidates(&self, key: &[u8]) -> Vec<u64> {
+        (0..self.seeds.len()).map(|h| self.bucket(h, key)).collect()
+    }
+}
+
+/// Placement of one cuckoo table inside `database.bin`.
+///
+/// Slot `s` (buckets first, then stash) starts at word `offset + s * slot_words`; its first
+/// word is the key tag and the record follows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CuckooTable {
+    pub params: CuckooParams,
+    /// Database index of the table's first slot.
+    pub offset: u64,
+    /// Words per slot, including the tag.
+    pub slot_words: u64,
+}
+
+impl CuckooTable {
+    /// Number of slots (buckets plus stash).
+    pub fn slots(&self) -> u64 {
+        self.params.num_buckets + self.params.stash_size
+    }
+
+    /// Number of database words the table occupies.
+    pub fn words(&self) -> u64 {
+        self.slots() * self.slot_words
+    }
+
+    /// Database index of the tag word of `slot`.
+    pub fn slot_index(&self, slot: u64) -> u64 {
+        self.offset + slot * self.slot_words
+    }
+
+    /// Database indices of the tag words a client has to query for `key`: the κ candidate
+    /// buckets followed by every stash slot. The length is the same for every key, so
+    /// candidates that collide are repeated rather than deduplicated.
+    pub fn query_indices(&self, key: &[u8]) -> Vec<u64> {
+        let stash = self.params.num_buckets..self.slots();
+        self.params
+            .candidates(key)
+            .into_iter()
+            .chain(stash)
+            .map(|slot| self.slot_index(slot))
+            .collect()
+    }
+}
+
+/// 32-byte tag stored in front of every record so clients can tell which candidate holds
+/// their key. An all-zero tag marks an empty slot.
+pub fn key_tag(key: &[u8]) -> [u8; 32] {
+    *blake3::hash(key).as_bytes()
+}
+
+/// Result of placing items `0..n` into a cuckoo table.
+#[derive(Debug, Clone)]
+pub struct CuckooLayout {
+    pub params: CuckooParams,
+    /// Item id stored in each bucket, or [`EMPTY`].
+    pub buckets: Vec<u32>,
+    /// Items that could not be placed in any candidate bucket.
+    pub stash: Vec<u32>,
+    /// Number of seed sets tried (1 = no rehash was needed).
+    pub attempts: u32,
+}
+
+impl CuckooLayout {
+    /// Slot holding `item` (whose key is `key`): a candidate bucket or a stash slot.
+    pub fn slot_of(&self, item: u32, key: &[u8]) -> Option<u64> {
+        self.params
+            .candidates(key)
+            .into_iter()
+            .find(|&b| self.buckets[b as usize] == item)
+            .or_else(|| {
+                let pos = self.stash.iter().position(|&s| s == item)?;
+                Some(self.params.num_buckets + pos as u64)
+            })
+    }
+
+    /// Item stored in `slot` (buckets first, then stash), if any.
+    pub fn item_at(&self, slot: u64) -> Option<u32> {
+        let item = if slot < self.params.num_buckets {
+            self.buckets[slot as usize]
+        } else {
+            let pos = (slot - self.params.num_buckets) as usize;
+            self.stash.get(pos).copied().unwrap_or(EMPTY)
+        };
+        (item != EMPTY).then_some(item)
+    }
+}
+
+/// Tunables for [`build`].
+#[derive(Debug, Clone, Copy)]
+pub struct BuildOptions {
+    pub kappa: usize,
+    pub overhead: f64,
+    pub stash_capacity: usize,
+    pub seed: u64,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            kappa: DEFAULT_KAPPA,
+            overhead: DEFAULT_OVERHEAD,
+            stash_capacity: DEFAULT_STASH,
+            seed: 0,
+        }
+    }
+}
+
+/// Builds a cuckoo table for items `0..num_items`, where `key_of(i)` returns item `i`'s key.
+///
+/// Keys are looked up through the callback rather than held in memory so the table for
+/// billions of mainnet entries only costs 4 bytes per bucket.
+pub fn build<K, F>(num_items: u32, key_of: F, opts: BuildOptions) -> Result<CuckooLayout>
+where
+    K: AsRef<[u8]>,
+    F: Fn(u32) -> K,
+{
+    ensure!(
+        opts.kappa >= 2,
+        "cuckoo hashing needs at least 2 hash functions"
+    );
+    ensure!(opts.overhead >= 1.0, "cuckoo overhead must be >= 1.0");
+    ensure!(num_items < EMPTY, "too many items for a u32 cuckoo table");
+
+    let num_buckets = ((num_items as f64 * opts.overhead).ceil() as u64).max(1);
+    let mut rng = ChaCha20Rng::seed_from_u64(opts.seed);
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let params = CuckooParams {
+            seeds: (0..opts.kappa).map(|_| rng.gen()).collect(),
+            num_buckets,
+            stash_size: opts.stash_capacity as u64,
+        };
+        let mut buckets = vec![EMPTY; num_buckets as usize];
+        let mut stash = Vec::new();
+
+        for item in 0..num_items {
+            if let Some(homeless) = insert(&params, &mut buckets, item, &key_of, &mut rng) {
+                stash.push(homeless);
+                if stash.len() > opts.stash_capacity {
+                    break;
+                }
+            }
+        }
+
+        if stash.len() <= opts.stash_capacity {
+            return Ok(CuckooLayout {
+                params,
+                buckets,
+                stash,
+                attempts: attempt,
+            });
+        }
+    }
+
+    bail!(
+        "cuckoo table construction failed after {} attempts (kappa={}, overhead={})",
+        MAX_ATTEMPTS,
+        opts.kappa,
+        opts.overhead
+    )
+}
+
+/// Inserts `item` with random-walk eviction; returns the item left without a bucket, if any.
+fn insert<K, F>(
+    params: &CuckooParams,
+    buckets: &mut [u32],
+    item: u32,
+    key_of: &F,
+    rng: &mut ChaCha20Rng,
+) -> Option<u32>
+where
+    K: AsRef<[u8]>,
+    F: Fn(u32) -> K,
+{
+    let kappa = params.seeds.len();
+    let mut current = item;
+
+    for _ in 0..MAX_EVICTIONS {
+        let key = key_of(current);
+        let candidates = params.candidates(key.as_ref());
+        if let Some(&pos) = candidates
+            .iter()
+            .find(|&&pos| buckets[pos as usize] == EMPTY)
+        {
+            buckets[pos as usize] = current;
+            return None;
+        }
+        let pos = candidates[rng.gen_range(0..kappa)] as usize;
+        current = std::mem::replace(&mut buckets[pos], current);
+    }
+
+    Some(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: u32) -> [u8; 20] {
+        let mut k = [0u8; 20];
+        k[..4].copy_from_slice(&i.to_be_bytes());
+        k[19] = 0xaa;
+        k
+    }
+
+    fn locate(layout: &CuckooLayout, i: u32) -> bool {
+        match layout.slot_of(i, &key(i)) {
+            Some(slot) => layout.item_at(slot) == Some(i),
+            None => false,
+        }
+    }
+
+    #[test]
+    fn every_item_is_in_a_candidate_bucket_or_stash() {
+        let layout = build(10_000, key, BuildOptions::default()).unwrap();
+        assert_eq!(layout.params.seeds.len(), DEFAULT_KAPPA);
+        for i in 0..10_000 {
+            assert!(locate(&layout, i), "item {} not found", i);
+        }
+        let placed = layout.buckets.iter().filter(|&&b| b != EMPTY).count();
+        assert_eq!(placed + layout.stash.len(), 10_000);
+    }
+
+    #[test]
+    fn build_is_deterministic() {
+        let opts = BuildOptions {
+            seed: 42,
+            ..Default::default()
+        };
+        let a = build(2_000, key, opts).unwrap();
+        let b = build(2_000, key, opts).unwrap();
+        assert_eq!(a.params, b.params);
+        assert_eq!(a.buckets, b.buckets);
+        assert_eq!(a.stash, b.stash);
+    }
+
+    #[test]
+    fn stash_overflow_rehashes_with_fresh_seeds() {
+        // Little slack and no stash: the first seed sets leave a key homeless
+        let opts = BuildOptions {
+            overhead: 1.05,
+            stash_capacity: 0,
+            seed: 3,
+            ..Default::default()
+        };
+        let layout = build(100, key, opts).unwrap();
+        assert!(layout.attempts > 1, "built on attempt {}", layout.attempts);
+        assert!(layout.stash.is_empty());
+        for i in 0..100 {
+            assert!(locate(&layout, i), "item {} not found", i);
+        }
+    }
+
+    #[test]
+    fn construction_gives_up_after_max_attempts() {
+        // Two hash functions cannot fill every bucket
+        let opts = BuildOptions {
+            kappa: 2,
+            overhead: 1.0,
+            stash_capacity: 0,
+            seed: 0,
+        };
+        let err = build(500, key, opts).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains(&format!("failed after {} attempts", MAX_ATTEMPTS)),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn table_indices_cover_candidates_and_stash() {
+        let table = CuckooTable {
+            params: CuckooParams {
+                seeds: vec![1, 2, 3],
+                num_buckets: 10,
+                stash_size: 2,
+            },
+            offset: 100,
+            slot_words: 4,
+        };
+        assert_eq!(table.words(), 48);
+        let indices = table.query_indices(&key(7));
+        assert_eq!(indices.len(), 5);
+        for (i, b) in table.params.candidates(&key(7)).into_iter().enumerate() {
+            assert_eq!(indices[i], 100 + b * 4);
+        }
+        assert_eq!(&indices[3..], &[140, 144]);
+    }
+
+    #[test]
+    fn empty_table() {
+        let layout = build(0, key, BuildOptions::default()).unwrap();
+        assert_eq!(layout.params.num_buckets, 1);
+        assert_eq!(layout.buckets, vec![EMPTY]);
+        assert!(layout.stash.is_empty());
+    }
+}
@@ -1,16 +1,25 @@
+use crate::metadata::Metadata;
 use eyre::{ensure, Result};
 use memmap2::MmapMut;
 use std::fs::OpenOptions;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 pub const DB_ENTRY_SIZE: usize = 32; // 32 bytes (256 bits)
 pub const DB_ENTRY_U64_COUNT: usize = 4; // 32 bytes = 4 * u64
 
+/// File name of the extractor's main database; only this file is described by the
+/// `metadata.json` next to it.
+pub const DATABASE_FILE: &str = "database.bin";
+
 pub struct Database {
     pub mmap: MmapMut,
     pub num_entries: u64,
     pub chunk_size: u64,
     pub set_size: u64,
+    /// Path of the memory-mapped file.
+    pub path: PathBuf,
+    /// The extraction's `metadata.json`, for a `database.bin` that has one next to it.
+    pub metadata: Option<Metadata>,
 }
 
 impl Database {
@@ -19,7 +28,8 @@ impl Database {
     /// The file at `path` is opened for read/write, validated to have a length that is a multiple
     /// of the database entry size, and then memory-mapped for in-place access. The number of
     /// entries and the derived `chunk_size` and `set_size` are computed and stored in the returned
-    /// Database.
+    /// Database. For a `database.bin`, the `metadata.json` next to it is loaded and checked
+    /// against the file.
     ///
     /// # Arguments
     ///
@@ -29,7 +39,7 @@ impl Database {
     ///
     /// `Ok(Database)` containing a writable memory-mapped view of the file and derived parameters
     /// on success; `Err` if the file cannot be opened, its metadata read, its size is not a multiple
-    /// of the entry size, or the memory map cannot be created.
+    /// of the entry size, it disagrees with `metadata.json`, or the memory map cannot be created.
     pub fn load(path: impl AsRef<Path>) -> Result<Self> {
         let path = path.as_ref();
         let file = OpenOptions::new().read(true).write(true).open(path)?;
@@ -45,6 +55,25 @@ impl Database {
         let num_entries = len / DB_ENTRY_SIZE as u64;
         let (chunk_size, set_size) = derive_plinko_params(num_entries);
 
+        let metadata = match (path.file_name(), path.parent()) {
+            (Some(name), Some(dir)) if name == DATABASE_FILE => Metadata::load(dir)?,
+            _ => None,
+        };
+        if let Some(metadata) = &metadata {
+            ensure!(
+                metadata.entry_size == DB_ENTRY_SIZE as u64,
+                "metadata.json declares {}-byte entries, expected {}",
+                metadata.entry_size,
+                DB_ENTRY_SIZE
+            );
+            ensure!(
+                metadata.total_indices == num_entries,
+                "Database has {} entries but metadata.json declares {}",
+                num_entries,
+                metadata.total_indices
+            );
+        }
+
         // Memory map the file
         let mmap = unsafe { MmapMut::map_mut(&file)? };
 
@@ -53,6 +82,8 @@ impl Database {
             num_entries,
             chunk_size,
             set_size,
+            path: path.to_path_buf(),
+            metadata,
         })
     }
 
@@ -103,6 +134,15 @@ impl Database {
         self.mmap.flush()?;
         Ok(())
     }
+
+    /// Writes [`Database::metadata`] (e.g. after updating its block) back to `metadata.json`
+    /// next to the database file. Does nothing when there is no metadata.
+    pub fn save_metadata(&self) -> Result<()> {
+        match (&self.metadata, self.path.parent()) {
+            (Some(metadata), Some(dir)) => metadata.save(dir),
+            _ => Ok(()),
+        }
+    }
 }
 
 /// Compute chunk and set sizes for partitioning a database of entries.
@@ -249,6 +289,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn load_checks_metadata() {
+        let dir = std::env::temp_dir().join(format!("plinko-db-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(DATABASE_FILE);
+        std::fs::write(&path, vec![0u8; 4 * DB_ENTRY_SIZE]).unwrap();
+        let write_metadata = |total_indices: u64| {
+            let json = format!(
+                r#"{{"schema_version": 1, "block": 7, "accounts": 1, "storage_slots": 1,
+                    "total_indices": {}, "generated_at": "2025-01-01T00:00:00Z"}}"#,
+                total_indices
+            );
+            std::fs::write(dir.join("metadata.json"), json).unwrap();
+        };
+
+        write_metadata(4);
+        let mut db = Database::load(&path).unwrap();
+        assert_eq!(db.metadata.as_ref().unwrap().block, 7);
+        db.metadata.as_mut().unwrap().block = 8;
+        db.save_metadata().unwrap();
+        assert_eq!(Database::load(&path).unwrap().metadata.unwrap().block, 8);
+
+        write_metadata(5);
+        assert!(Database::load(&path).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_isqrt() {
         assert_eq!(isqrt(0), 0);
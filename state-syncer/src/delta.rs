@@ -0,0 +1,587 @@
+//! XOR delta files (`delta-<block>.bin`, format: `docs/delta-format.md`).
+//!
+//! Version 2 files start with a header binding the records to the chain (the blocks they
+//! cover, the hash of the block before and of the last block) and end with a BLAKE3
+//! checksum, so a client can tell a truncated download, a delta for the wrong block or a
+//! delta from a reorged-out chain apart from a good one. [`DeltaChain`] tracks the block a
+//! client's hints are at and refuses deltas that do not follow on from it.
+//!
+//! [`read_delta`] also reads the headerless files written before the format was versioned,
+//! detecting the record layout from the file size: 40-byte records as documented, and the
+//! 48-byte records of the early prototype (the committed `deltas/` files), whose second word
+//! is ignored. Those carry no chain binding.
+//!
+//! A [`Compactor`] merges the deltas of consecutive blocks into one range delta, so a client
+//! that was offline for a day downloads one file instead of thousands: records for the same
+//! index are XORed together and dropped if they cancel out, and the binding spans from the
+//! first block's parent to the last block.
+
+use crate::db::DB_ENTRY_U64_COUNT;
+use crate::metadata::Metadata;
+use crate::syncer::parse_bytes;
+use eyre::{bail, ensure, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Magic bytes at the start of a versioned delta file.
+pub const DELTA_MAGIC: [u8; 8] = *b"PLKODLT\0";
+/// Current delta format version (version 1 is the headerless legacy format).
+pub const DELTA_VERSION: u32 = 2;
+/// Size of the version 2 header in bytes.
+pub const DELTA_HEADER_SIZE: usize = 104;
+/// Size of the trailing BLAKE3 checksum in bytes.
+pub const DELTA_CHECKSUM_SIZE: usize = 32;
+/// Bytes per record: index plus one XORed entry.
+pub const DELTA_RECORD_SIZE: usize = 8 + 8 * DB_ENTRY_U64_COUNT;
+/// Bytes per record of the early prototype's files, which are not supported.
+const PROTOTYPE_RECORD_SIZE: usize = 48;
+
+/// A single delta record: database index and the XOR of old and new entry.
+pub type DeltaRecord = (u64, [u64; DB_ENTRY_U64_COUNT]);
+
+/// How a delta file was encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeltaFormat {
+    /// Headerless, 40-byte records (Index, Delta).
+    Legacy,
+    /// Header, 40-byte records and checksum.
+    V2,
+}
+
+/// The blocks a delta covers and the chain it was taken from. Hashes are `None` when the
+/// producer did not know them (stored as zero).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChainBinding {
+    /// First block whose changes are in the file (`block` for a per-block delta).
+    pub first_block: u64,
+    /// Last block whose changes are in the file.
+    pub block: u64,
+    /// Hash of block `first_block - 1`, the state the delta applies to.
+    pub parent_hash: Option<[u8; 32]>,
+    /// Hash of `block`, the state the delta leads to.
+    pub block_hash: Option<[u8; 32]>,
+}
+
+impl ChainBinding {
+    /// Binding of the delta of the single block `number`.
+    pub fn block(number: u64, parent_hash: Option<[u8; 32]>, block_hash: Option<[u8; 32]>) -> Self {
+        Self {
+            first_block: number,
+            block: number,
+            parent_hash,
+            block_hash,
+        }
+    }
+}
+
+/// A decoded delta file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delta {
+    pub format: DeltaFormat,
+    /// `None` for legacy files.
+    pub binding: Option<ChainBinding>,
+    pub records: Vec<DeltaRecord>,
+}
+
+impl Delta {
+    /// Decodes a delta file of any version, verifying the checksum of version 2 files.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() >= DELTA_MAGIC.len() && bytes[..DELTA_MAGIC.len()] == DELTA_MAGIC {
+            return Self::decode_v2(bytes);
+        }
+
+        ensure!(
+            bytes.len() >= 16,
+            "Delta file too short ({} bytes)",
+            bytes.len()
+        );
+        let count = u64_at(bytes, 0);
+        let entry_words = u64_at(bytes, 8);
+        ensure!(
+            entry_words == DB_ENTRY_U64_COUNT as u64,
+            "Unsupported delta entry length {}",
+            entry_words
+        );
+        let body = (bytes.len() - 16) as u64;
+        // The prototype's records hold another word before the index and only three words
+        // after it, so they cannot be read as entries
+        ensure!(
+            Some(body) != count.checked_mul(PROTOTYPE_RECORD_SIZE as u64),
+            "Unsupported delta file: {} records of {} bytes (early prototype layout)",
+            count,
+            PROTOTYPE_RECORD_SIZE
+        );
+        ensure!(
+            Some(body) == count.checked_mul(DELTA_RECORD_SIZE as u64),
+            "Not a delta file: {} bytes of records for {} records",
+            body,
+            count
+        );
+        let records = bytes[16..]
+            .chunks_exact(DELTA_RECORD_SIZE)
+            .map(|record| (u64_at(record, 0), entry(&record[8..])))
+            .collect();
+        Ok(Self {
+            format: DeltaFormat::Legacy,
+            binding: None,
+            records,
+        })
+    }
+
+    fn decode_v2(bytes: &[u8]) -> Result<Self> {
+        ensure!(
+            bytes.len() >= DELTA_HEADER_SIZE + DELTA_CHECKSUM_SIZE,
+            "Delta file too short ({} bytes)",
+            bytes.len()
+        );
+        let version = u32_at(bytes, 8);
+        ensure!(
+            version == DELTA_VERSION,
+            "Unsupported delta version {}",
+            version
+        );
+        let entry_words = u32_at(bytes, 12);
+        ensure!(
+            entry_words as usize == DB_ENTRY_U64_COUNT,
+            "Unsupported delta entry width {}",
+            entry_words
+        );
+        let count = u64_at(bytes, 32);
+        let expected = count
+            .checked_mul(DELTA_RECORD_SIZE as u64)
+            .and_then(|records| {
+                records.checked_add((DELTA_HEADER_SIZE + DELTA_CHECKSUM_SIZE) as u64)
+            });
+        ensure!(
+            expected == Some(bytes.len() as u64),
+            "Delta file is {} bytes, expected {} records (truncated?)",
+            bytes.len(),
+            count
+        );
+        let (body, checksum) = bytes.split_at(bytes.len() - DELTA_CHECKSUM_SIZE);
+        ensure!(
+            blake3::hash(body).as_bytes() == checksum,
+            "Delta checksum mismatch (corrupted file)"
+        );
+
+        let hash = |pos: usize| {
+            let hash: [u8; 32] = bytes[pos..pos + 32].try_into().unwrap();
+            (hash != [0; 32]).then_some(hash)
+        };
+        let binding = ChainBinding {
+            first_block: u64_at(bytes, 16),
+            block: u64_at(bytes, 24),
+            parent_hash: hash(40),
+            block_hash: hash(72),
+        };
+        ensure!(
+            binding.first_block <= binding.block,
+            "Delta covers blocks #{}..=#{}",
+            binding.first_block,
+            binding.block
+        );
+        let records = body[DELTA_HEADER_SIZE..]
+            .chunks_exact(DELTA_RECORD_SIZE)
+            .map(|record| (u64_at(record, 0), entry(&record[8..])))
+            .collect();
+        Ok(Self {
+            format: DeltaFormat::V2,
+            binding: Some(binding),
+            records,
+        })
+    }
+}
+
+/// Encodes a version 2 delta file.
+pub fn encode_delta(binding: &ChainBinding, records: &[DeltaRecord]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(
+        DELTA_HEADER_SIZE + records.len() * DELTA_RECORD_SIZE + DELTA_CHECKSUM_SIZE,
+    );
+    out.extend_from_slice(&DELTA_MAGIC);
+    out.extend_from_slice(&DELTA_VERSION.to_le_bytes());
+    out.extend_from_slice(&(DB_ENTRY_U64_COUNT as u32).to_le_bytes());
+    out.extend_from_slice(&binding.first_block.to_le_bytes());
+    out.extend_from_slice(&binding.block.to_le_bytes());
+    out.extend_from_slice(&(records.len() as u64).to_le_bytes());
+    out.extend_from_slice(&binding.parent_hash.unwrap_or_default());
+    out.extend_from_slice(&binding.block_hash.unwrap_or_default());
+    for (index, delta) in records {
+        out.extend_from_slice(&index.to_le_bytes());
+        for word in delta {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+    let checksum = blake3::hash(&out);
+    out.extend_from_slice(checksum.as_bytes());
+    out
+}
+
+/// Writes a version 2 delta file.
+///
+/// The file is written next to `path`, synced and renamed into place, so readers never see
+/// a partial delta; the directory is synced too, so the delta survives a crash once this
+/// returns.
+pub fn write_delta(path: &Path, binding: &ChainBinding, records: &[DeltaRecord]) -> Result<()> {
+    let tmp = path.with_extension("bin.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    writer.write_all(&encode_delta(binding, records))?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Reads a delta file of any version.
+pub fn read_delta(path: &Path) -> Result<Delta> {
+    Delta::decode(&std::fs::read(path)?).map_err(|e| e.wrap_err(format!("{:?}", path)))
+}
+
+/// Path of the delta file of `block` in `dir`.
+pub fn delta_path(dir: &Path, block: u64) -> PathBuf {
+    dir.join(format!("delta-{:06}.bin", block))
+}
+
+/// Path of the range delta of blocks `first..=last` in `dir`.
+pub fn range_path(dir: &Path, first: u64, last: u64) -> PathBuf {
+    dir.join(format!("delta-{:06}-{:06}.bin", first, last))
+}
+
+/// Merges the deltas of consecutive blocks into one range delta.
+#[derive(Debug, Default)]
+pub struct Compactor {
+    first: Option<ChainBinding>,
+    chain: Option<DeltaChain>,
+    entries: BTreeMap<u64, [u64; DB_ENTRY_U64_COUNT]>,
+    input_records: u64,
+}
+
+/// Result of [`compact_files`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactSummary {
+    pub binding: ChainBinding,
+    /// Records in the input deltas.
+    pub input_records: u64,
+    /// Records in the range delta.
+    pub records: u64,
+}
+
+impl Compactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the records of the delta bound by `binding`, which must follow on from the last
+    /// delta added (checked like [`DeltaChain::advance`]).
+    pub fn add(&mut self, binding: &ChainBinding, records: &[DeltaRecord]) -> Result<()> {
+        match &mut self.chain {
+            Some(chain) => chain.advance(binding)?,
+            None => {
+                self.first = Some(*binding);
+                self.chain = Some(DeltaChain::new(binding.block, binding.block_hash));
+            }
+        }
+        for (index, delta) in records {
+            let entry = self.entries.entry(*index).or_default();
+            for (word, d) in entry.iter_mut().zip(delta) {
+                *word ^= d;
+            }
+        }
+        self.input_records += records.len() as u64;
+        Ok(())
+    }
+
+    /// Adds a decoded delta file; legacy files carry no binding and are refused.
+    pub fn add_delta(&mut self, delta: &Delta) -> Result<()> {
+        match &delta.binding {
+            Some(binding) => self.add(binding, &delta.records),
+            None => bail!(
+                "Legacy ({:?}) delta has no chain binding to check",
+                delta.format
+            ),
+        }
+    }
+
+    /// Records in the deltas added so far.
+    pub fn input_records(&self) -> u64 {
+        self.input_records
+    }
+
+    /// Binding and records of the range delta, sorted by index, without the records that
+    /// cancelled out.
+    pub fn finish(self) -> Result<(ChainBinding, Vec<DeltaRecord>)> {
+        let (Some(first), Some(chain)) = (self.first, self.chain) else {
+            bail!("No deltas to compact");
+        };
+        let binding = ChainBinding {
+            first_block: first.first_block,
+            block: chain.block(),
+            parent_hash: first.parent_hash,
+            block_hash: chain.hash(),
+        };
+        let records = self
+            .entries
+            .into_iter()
+            .filter(|(_, delta)| *delta != [0; DB_ENTRY_U64_COUNT])
+            .collect();
+        Ok((binding, records))
+    }
+}
+
+/// Compacts the version 2 delta files at `paths`, in block order, into a range delta at
+/// `out`.
+pub fn compact_files(paths: &[PathBuf], out: &Path) -> Result<CompactSummary> {
+    let mut compactor = Compactor::new();
+    for path in paths {
+        compactor
+            .add_delta(&read_delta(path)?)
+            .map_err(|e| e.wrap_err(format!("{:?}", path)))?;
+    }
+    let input_records = compactor.input_records();
+    let (binding, records) = compactor.finish()?;
+    write_delta(out, &binding, &records)?;
+    Ok(CompactSummary {
+        binding,
+        input_records,
+        records: records.len() as u64,
+    })
+}
+
+/// The block a client's hints are at, for refusing deltas that do not apply to them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeltaChain {
+    block: u64,
+    hash: Option<[u8; 32]>,
+}
+
+impl DeltaChain {
+    /// Hints built at `block` (with hash `hash`, if known).
+    pub fn new(block: u64, hash: Option<[u8; 32]>) -> Self {
+        Self { block, hash }
+    }
+
+    /// Hints built from the snapshot described by `metadata`.
+    pub fn from_metadata(metadata: &Metadata) -> Result<Self> {
+        let hash = metadata
+            .block_hash
+            .as_deref()
+            .map(parse_bytes)
+            .transpose()?;
+        Ok(Self::new(metadata.block, hash))
+    }
+
+    pub fn block(&self) -> u64 {
+        self.block
+    }
+
+    pub fn hash(&self) -> Option<[u8; 32]> {
+        self.hash
+    }
+
+    /// Checks that a delta bound by `binding` continues from the current block, on the same
+    /// chain, and moves past it. Hashes are compared when both sides know them.
+    pub fn advance(&mut self, binding: &ChainBinding) -> Result<()> {
+        ensure!(
+            binding.first_block == self.block + 1,
+            "Delta for blocks #{}..=#{} does not follow block #{}",
+            binding.first_block,
+            binding.block,
+            self.block
+        );
+        if let (Some(parent), Some(hash)) = (binding.parent_hash, self.hash) {
+            ensure!(
+                parent == hash,
+                "Delta for block #{} builds on 0x{}, not on block #{} 0x{} (reorg or other chain)",
+                binding.first_block,
+                hex(&parent),
+                self.block,
+                hex(&hash)
+            );
+        }
+        self.block = binding.block;
+        self.hash = binding.block_hash;
+        Ok(())
+    }
+
+    /// Checks `delta`'s binding (per-block or range) and moves past it. Legacy files carry
+    /// no binding and are refused.
+    pub fn apply(&mut self, delta: &Delta) -> Result<()> {
+        match &delta.binding {
+            Some(binding) => self.advance(binding),
+            None => bail!(
+                "Legacy ({:?}) delta has no chain binding to check",
+                delta.format
+            ),
+        }
+    }
+}
+
+/// Lowercase hex without a prefix.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn u32_at(bytes: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap())
+}
+
+fn entry(bytes: &[u8]) -> [u64; DB_ENTRY_U64_COUNT] {
+    std::array::from_fn(|i| u64_at(bytes, i * 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<DeltaRecord> {
+        vec![(3, [1, 2, 3, 4]), (7, [0, 0, 0, u64::MAX])]
+    }
+
+    #[test]
+    fn v2_round_trips_and_detects_corruption() {
+        let binding = ChainBinding::block(101, Some([1; 32]), Some([2; 32]));
+        let bytes = encode_delta(&binding, &records());
+        assert_eq!(
+            bytes.len(),
+            DELTA_HEADER_SIZE + 2 * DELTA_RECORD_SIZE + DELTA_CHECKSUM_SIZE
+        );
+        let delta = Delta::decode(&bytes).unwrap();
+        assert_eq!(delta.format, DeltaFormat::V2);
+        assert_eq!(delta.binding, Some(binding));
+        assert_eq!(delta.records, records());
+
+        // Unknown hashes are stored as zero
+        let unbound = ChainBinding::block(5, None, None);
+        let decoded = Delta::decode(&encode_delta(&unbound, &[])).unwrap();
+        assert_eq!(decoded.binding, Some(unbound));
+        assert!(decoded.records.is_empty());
+
+        let mut flipped = bytes.clone();
+        flipped[DELTA_HEADER_SIZE + 9] ^= 1;
+        let err = Delta::decode(&flipped).unwrap_err().to_string();
+        assert!(err.contains("checksum"), "{}", err);
+        assert!(Delta::decode(&bytes[..bytes.len() - 1]).is_err());
+        let mut future = bytes;
+        future[8] = 3;
+        let err = Delta::decode(&future).unwrap_err().to_string();
+        assert!(err.contains("version 3"), "{}", err);
+    }
+
+    #[test]
+    fn reads_legacy_files() {
+        let mut legacy = Vec::new();
+        legacy.extend_from_slice(&2u64.to_le_bytes());
+        legacy.extend_from_slice(&4u64.to_le_bytes());
+        for (index, delta) in records() {
+            legacy.extend_from_slice(&index.to_le_bytes());
+            delta
+                .iter()
+                .for_each(|w| legacy.extend_from_slice(&w.to_le_bytes()));
+        }
+        let delta = Delta::decode(&legacy).unwrap();
+        assert_eq!(delta.format, DeltaFormat::Legacy);
+        assert_eq!(delta.binding, None);
+        assert_eq!(delta.records, records());
+        assert!(Delta::decode(&legacy[..legacy.len() - 8]).is_err());
+
+        let mut chain = DeltaChain::new(0, None);
+        assert!(chain.apply(&delta).is_err());
+
+        // The prototype's files in the repository root are refused rather than misread
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../deltas/delta-000050.bin");
+        let err = read_delta(&path).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("early prototype layout"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn compacts_consecutive_deltas() {
+        let hash = |n: u8| Some([n; 32]);
+        let mut compactor = Compactor::new();
+        compactor
+            .add(
+                &ChainBinding::block(11, hash(10), hash(11)),
+                &[(5, [1, 0, 0, 0]), (9, [0, 2, 0, 0])],
+            )
+            .unwrap();
+        compactor
+            .add(
+                &ChainBinding::block(12, hash(11), hash(12)),
+                &[(5, [1, 0, 0, 0])],
+            )
+            .unwrap();
+        // A gap is refused and leaves the compactor as it was
+        assert!(compactor
+            .add(&ChainBinding::block(14, hash(13), hash(14)), &[(1, [1; 4])])
+            .is_err());
+        compactor
+            .add(
+                &ChainBinding::block(13, hash(12), hash(13)),
+                &[(3, [7, 0, 0, 0]), (9, [0, 0, 0, 4])],
+            )
+            .unwrap();
+        assert_eq!(compactor.input_records(), 5);
+
+        let (binding, records) = compactor.finish().unwrap();
+        assert_eq!(
+            binding,
+            ChainBinding {
+                first_block: 11,
+                block: 13,
+                parent_hash: hash(10),
+                block_hash: hash(13),
+            }
+        );
+        // Index 5 flipped twice and cancelled out
+        assert_eq!(records, vec![(3, [7, 0, 0, 0]), (9, [0, 2, 0, 4])]);
+
+        // A client at block 10 applies the range in one step and continues after it
+        let mut chain = DeltaChain::new(10, hash(10));
+        chain.advance(&binding).unwrap();
+        assert_eq!((chain.block(), chain.hash()), (13, hash(13)));
+        assert!(DeltaChain::new(11, hash(11)).advance(&binding).is_err());
+
+        assert!(Compactor::new().finish().is_err());
+    }
+
+    #[test]
+    fn chain_refuses_gaps_and_forks() {
+        let mut chain = DeltaChain::new(100, Some([0xaa; 32]));
+        let next = ChainBinding::block(101, Some([0xaa; 32]), Some([0xbb; 32]));
+
+        let skipped = ChainBinding::block(102, Some([0xbb; 32]), None);
+        let err = chain.advance(&skipped).unwrap_err().to_string();
+        assert!(err.contains("does not follow block #100"), "{}", err);
+        let fork = ChainBinding::block(101, Some([0xcc; 32]), Some([0xdd; 32]));
+        let err = chain.advance(&fork).unwrap_err().to_string();
+        assert!(err.contains("reorg"), "{}", err);
+        assert_eq!(chain.block(), 100);
+
+        chain.advance(&next).unwrap();
+        assert_eq!((chain.block(), chain.hash()), (101, Some([0xbb; 32])));
+        // Replaying the same delta is refused
+        assert!(chain.advance(&next).is_err());
+
+        // A delta without hashes is only checked by number
+        chain
+            .advance(&ChainBinding::block(102, None, None))
+            .unwrap();
+        assert_eq!((chain.block(), chain.hash()), (102, None));
+        chain
+            .advance(&ChainBinding::block(103, Some([0xee; 32]), None))
+            .unwrap();
+    }
+}
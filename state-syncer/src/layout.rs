@@ -0,0 +1,174 @@
+//! Account record layouts of `database.bin`.
+//!
+//! The extractor encodes every account with the layout chosen by `--account-layout` and
+//! records it in `metadata.json`; readers decode entries with the same [`AccountLayout`].
+//! Hint generation cost scales with the number of words, so the smaller layouts trade
+//! fields for a smaller N. Storage slots are always one word (the value, 32 bytes LE).
+
+use eyre::{ensure, Result};
+use serde::{Deserialize, Serialize};
+
+/// Words in the largest account layout.
+pub const MAX_ACCOUNT_WORDS: usize = 3;
+
+#[derive(
+    Serialize, Deserialize, clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq, Hash,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccountLayout {
+    /// 3 words: Nonce (u64 LE, zero-padded) || Balance (u256 LE) || CodeHash
+    #[default]
+    Standard,
+    /// 2 words: Nonce (u64 LE) || Balance (low 24 bytes, LE), then CodeHash
+    Packed,
+    /// 1 word: Balance (u256 LE)
+    BalanceOnly,
+}
+
+/// Account fields to encode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccountRecord {
+    pub nonce: u64,
+    /// Balance in wei, 32 bytes LE.
+    pub balance: [u8; 32],
+    /// Code hash; all zeros for accounts without code.
+    pub code_hash: [u8; 32],
+}
+
+/// Account fields decoded from an entry; `None` where the layout drops the field.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecodedAccount {
+    pub nonce: Option<u64>,
+    /// Balance in wei, 32 bytes LE.
+    pub balance: [u8; 32],
+    pub code_hash: Option<[u8; 32]>,
+}
+
+impl AccountLayout {
+    /// Words per account entry.
+    pub fn words(&self) -> u64 {
+        match self {
+            AccountLayout::Standard => 3,
+            AccountLayout::Packed => 2,
+            AccountLayout::BalanceOnly => 1,
+        }
+    }
+
+    /// Name of each word, in order.
+    pub fn fields(&self) -> &'static [&'static str] {
+        match self {
+            AccountLayout::Standard => &["nonce", "balance", "code_hash"],
+            AccountLayout::Packed => &["nonce_balance", "code_hash"],
+            AccountLayout::BalanceOnly => &["balance"],
+        }
+    }
+
+    /// Encodes `account`; only the first [`AccountLayout::words`] words are used.
+    ///
+    /// Fails if the balance does not fit the packed layout's 24 bytes.
+    pub fn encode(&self, account: &AccountRecord) -> Result<[[u8; 32]; MAX_ACCOUNT_WORDS]> {
+        let mut words = [[0u8; 32]; MAX_ACCOUNT_WORDS];
+        match self {
+            AccountLayout::Standard => {
+                words[0][..8].copy_from_slice(&account.nonce.to_le_bytes());
+                words[1] = account.balance;
+                words[2] = account.code_hash;
+            }
+            AccountLayout::Packed => {
+                ensure!(
+                    account.balance[24..].iter().all(|&b| b == 0),
+                    "Balance does not fit the packed account layout (24 bytes)"
+                );
+                words[0][..8].copy_from_slice(&account.nonce.to_le_bytes());
+                words[0][8..].copy_from_slice(&account.balance[..24]);
+                words[1] = account.code_hash;
+            }
+            AccountLayout::BalanceOnly => words[0] = account.balance,
+        }
+        Ok(words)
+    }
+
+    /// Decodes an entry of [`AccountLayout::words`] words.
+    pub fn decode(&self, entry: &[u8]) -> Result<DecodedAccount> {
+        ensure!(
+            entry.len() as u64 == self.words() * 32,
+            "{:?} account entries are {} bytes, got {}",
+            self,
+            self.words() * 32,
+            entry.len()
+        );
+        let word = |i: usize| -> [u8; 32] { entry[i * 32..(i + 1) * 32].try_into().unwrap() };
+        let nonce = |w: &[u8; 32]| u64::from_le_bytes(w[..8].try_into().unwrap());
+
+        Ok(match self {
+            AccountLayout::Standard => DecodedAccount {
+                nonce: Some(nonce(&word(0))),
+                balance: word(1),
+                code_hash: Some(word(2)),
+            },
+            AccountLayout::Packed => {
+                let mut balance = [0u8; 32];
+                balance[..24].copy_from_slice(&word(0)[8..]);
+                DecodedAccount {
+                    nonce: Some(nonce(&word(0))),
+                    balance,
+                    code_hash: Some(word(1)),
+                }
+            }
+            AccountLayout::BalanceOnly => DecodedAccount {
+                nonce: None,
+                balance: word(0),
+                code_hash: None,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layouts_round_trip() {
+        let mut balance = [0u8; 32];
+        balance[..12].copy_from_slice(&[0xab; 12]);
+        let account = AccountRecord {
+            nonce: 0x0102_0304_0506_0708,
+            balance,
+            code_hash: [0xcd; 32],
+        };
+
+        for layout in [
+            AccountLayout::Standard,
+            AccountLayout::Packed,
+            AccountLayout::BalanceOnly,
+        ] {
+            let n = layout.words() as usize;
+            assert_eq!(layout.fields().len(), n);
+            let words = layout.encode(&account).unwrap();
+            assert!(words[n..].iter().all(|w| *w == [0u8; 32]));
+
+            let decoded = layout.decode(&words[..n].concat()).unwrap();
+            assert_eq!(decoded.balance, balance);
+            let full = layout != AccountLayout::BalanceOnly;
+            assert_eq!(decoded.nonce, full.then_some(account.nonce));
+            assert_eq!(decoded.code_hash, full.then_some(account.code_hash));
+        }
+
+        // The standard layout is the extractor's original 3-word encoding
+        let words = AccountLayout::Standard.encode(&account).unwrap();
+        assert_eq!(&words[0][..8], &account.nonce.to_le_bytes());
+        assert_eq!(&words[0][8..], &[0u8; 24]);
+    }
+
+    #[test]
+    fn packed_rejects_oversized_balance() {
+        let account = AccountRecord {
+            balance: [0xff; 32],
+            ..Default::default()
+        };
+        assert!(AccountLayout::Packed.encode(&account).is_err());
+        assert!(AccountLayout::Standard.encode(&account).is_ok());
+        assert!(AccountLayout::Packed.decode(&[0u8; 96]).is_err());
+    }
+}
@@ -3,13 +3,33 @@
 //! This crate provides core Plinko PIR primitives:
 //! - `iprf`: Invertible PRF implementation (paper §4.2)
 //! - `db`: Database loading and Plinko parameter derivation
+//! - `mapping`: Address/slot to index lookups over the extractor's mapping files
+//! - `layout`: Account record layouts (standard 3-word, packed 2-word, balance-only)
+//! - `cuckoo`: Cuckoo-hashed keyword layout (κ candidate indices per key, no mapping)
+//! - `manifest`: Per-chunk BLAKE3 integrity manifest for extraction artifacts
+//! - `metadata`: Typed, schema-versioned `metadata.json` describing an extraction
+//! - `synthetic`: Seeded synthetic snapshots in the extractor's formats (`gen-synthetic`)
+//! - `delta`: Versioned XOR delta files bound to the chain, with legacy readers
+//! - `syncer`: In-place block updates and per-block delta publishing (the `state-syncer` daemon)
+//! - `simulated`: Seeded pseudo-random block feed for the daemon (`PLINKO_STATE_SIMULATED`)
+//! - `rpc`: JSON-RPC state-diff feed for the daemon (`PLINKO_STATE_RPC_URL`)
 //! - `constant_time`: Data-oblivious operations for TEE execution
 //! - `binomial`: True derandomized binomial sampling for PMNS
 
 pub mod binomial;
 pub mod constant_time;
+pub mod cuckoo;
 pub mod db;
+pub mod delta;
 pub mod iprf;
+pub mod layout;
+pub mod manifest;
+pub mod mapping;
+pub mod metadata;
+pub mod rpc;
+pub mod simulated;
+pub mod syncer;
+pub mod synthetic;
 
 #[cfg(any(kani, test))]
 #[path = "kani_proofs.rs"]
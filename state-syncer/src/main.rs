@@ -0,0 +1,236 @@
+//! Plinko state syncer: keeps an extracted snapshot up to date and publishes one XOR delta
+//! file per block for PIR clients to patch their hints with.
+
+use clap::Parser;
+use state_syncer::rpc::{DiffMethod, RpcClient, RpcConfig, RpcSource, DEFAULT_CONFIRMATIONS};
+use state_syncer::simulated::{SimulatedSource, SimulationConfig};
+use state_syncer::syncer::{self, JsonlSource, RangeLevel, Syncer, UpdateSource};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Plinko state syncer daemon", long_about = None)]
+struct Args {
+    /// database.bin of the snapshot to keep up to date (updated in place)
+    #[arg(
+        long,
+        env = "PLINKO_STATE_DB_PATH",
+        default_value = "/data/database.bin"
+    )]
+    db_path: PathBuf,
+
+    /// account-mapping.bin of the snapshot
+    #[arg(
+        long,
+        env = "PLINKO_STATE_ADDRESS_MAPPING_PATH",
+        default_value = "/data/account-mapping.bin"
+    )]
+    address_mapping_path: PathBuf,
+
+    /// storage-mapping.bin of the snapshot (default: next to the account mapping; storage
+    /// changes are skipped if it does not exist)
+    #[arg(long, env = "PLINKO_STATE_STORAGE_MAPPING_PATH")]
+    storage_mapping_path: Option<PathBuf>,
+
+    /// Directory served over HTTP; deltas are written to <public-root>/deltas
+    #[arg(long, env = "PLINKO_STATE_PUBLIC_ROOT", default_value = "/public")]
+    public_root: PathBuf,
+
+    /// Port to serve the public root on
+    #[arg(long, env = "PLINKO_STATE_HTTP_PORT", default_value_t = 3002)]
+    http_port: u16,
+
+    /// Blocks per hourly range delta, published under <public-root>/deltas/hourly (0: none)
+    #[arg(long, env = "PLINKO_STATE_HOURLY_BLOCKS", default_value_t = 300)]
+    hourly_blocks: u64,
+
+    /// Blocks per daily range delta, published under <public-root>/deltas/daily (0: none)
+    #[arg(long, env = "PLINKO_STATE_DAILY_BLOCKS", default_value_t = 7200)]
+    daily_blocks: u64,
+
+    /// Block updates as JSON lines, one block per line (`-` for stdin)
+    #[arg(long, env = "PLINKO_STATE_UPDATES")]
+    updates: Option<PathBuf>,
+
+    /// JSON-RPC endpoint of a node to follow, block by block. Besides the transactions'
+    /// diffs, withdrawals, pre-Merge block rewards and the system contracts' slots are read
+    /// from the block
+    #[arg(long, env = "PLINKO_STATE_RPC_URL")]
+    rpc_url: Option<String>,
+
+    /// How state diffs are fetched: `prestate` (debug_traceBlockByNumber, geth/reth) or
+    /// `trace` (trace_replayBlockTransactions, reth/erigon/nethermind)
+    #[arg(
+        long,
+        env = "PLINKO_STATE_RPC_METHOD",
+        value_enum,
+        default_value_t = DiffMethod::Prestate
+    )]
+    rpc_method: DiffMethod,
+
+    /// Milliseconds between polls for a new block
+    #[arg(long, env = "PLINKO_STATE_RPC_POLL_MS", default_value_t = 2_000)]
+    rpc_poll_ms: u64,
+
+    /// Blocks to stay behind the node's head; a reorg deeper than this stops the daemon
+    #[arg(
+        long,
+        env = "PLINKO_STATE_RPC_CONFIRMATIONS",
+        default_value_t = DEFAULT_CONFIRMATIONS
+    )]
+    rpc_confirmations: u64,
+
+    /// Seconds before an RPC request times out
+    #[arg(long, env = "PLINKO_STATE_RPC_TIMEOUT_SECS", default_value_t = 60)]
+    rpc_timeout_secs: u64,
+
+    /// Apply seeded pseudo-random blocks instead of a real feed (load tests, client
+    /// development); the same snapshot and seed always produce the same deltas
+    #[arg(long, env = "PLINKO_STATE_SIMULATED")]
+    simulated: bool,
+
+    /// Seed of the simulated blocks
+    #[arg(long, env = "PLINKO_STATE_SIMULATED_SEED", default_value_t = 0)]
+    simulated_seed: u64,
+
+    /// Accounts changed per simulated block
+    #[arg(long, env = "PLINKO_STATE_SIMULATED_ACCOUNTS", default_value_t = 200)]
+    simulated_accounts: u64,
+
+    /// Storage slots changed per simulated block
+    #[arg(long, env = "PLINKO_STATE_SIMULATED_SLOTS", default_value_t = 1000)]
+    simulated_slots: u64,
+
+    /// Milliseconds between simulated blocks (0: as fast as they are applied)
+    #[arg(
+        long,
+        env = "PLINKO_STATE_SIMULATED_BLOCK_TIME_MS",
+        default_value_t = 12_000
+    )]
+    simulated_block_time_ms: u64,
+
+    /// Stop after this many simulated blocks
+    #[arg(long, env = "PLINKO_STATE_SIMULATED_BLOCKS")]
+    simulated_blocks: Option<u64>,
+}
+
+fn main() -> eyre::Result<()> {
+    let args = Args::parse();
+    let feeds = [
+        args.rpc_url.is_some(),
+        args.updates.is_some(),
+        args.simulated,
+    ];
+    eyre::ensure!(
+        feeds.iter().filter(|&&set| set).count() <= 1,
+        "Set only one of PLINKO_STATE_RPC_URL, PLINKO_STATE_UPDATES and PLINKO_STATE_SIMULATED"
+    );
+
+    println!("Plinko State Syncer");
+    println!("-------------------");
+    println!("Database: {:?}", args.db_path);
+
+    let storage_mapping = args.storage_mapping_path.clone().or_else(|| {
+        let path = args
+            .address_mapping_path
+            .with_file_name("storage-mapping.bin");
+        path.exists().then_some(path)
+    });
+    if storage_mapping.is_none() {
+        println!("No storage mapping; storage changes will be skipped");
+    }
+    let mut syncer = Syncer::open(
+        &args.db_path,
+        &args.address_mapping_path,
+        storage_mapping.as_deref(),
+        &args.public_root,
+    )?;
+    match syncer.block() {
+        Some(block) => println!(
+            "Loaded {} entries at block #{}",
+            syncer.database().num_entries,
+            block
+        ),
+        None => println!(
+            "Loaded {} entries (no metadata.json; the first update sets the block)",
+            syncer.database().num_entries
+        ),
+    }
+
+    syncer.set_ranges(vec![
+        RangeLevel::new("hourly", args.hourly_blocks),
+        RangeLevel::new("daily", args.daily_blocks),
+    ]);
+
+    let listener = TcpListener::bind(("0.0.0.0", args.http_port))?;
+    println!("Serving {:?} on port {}", args.public_root, args.http_port);
+    let root = args.public_root.clone();
+    let server = std::thread::spawn(move || syncer::serve(listener, root));
+
+    let mut source: Box<dyn UpdateSource> = match (&args.rpc_url, &args.updates) {
+        (Some(url), _) => {
+            let Some(block) = syncer.block() else {
+                eyre::bail!("Following a node needs the snapshot block from metadata.json");
+            };
+            let config = RpcConfig {
+                method: args.rpc_method,
+                poll_interval: Duration::from_millis(args.rpc_poll_ms),
+                confirmations: args.rpc_confirmations,
+            };
+            println!(
+                "Following {} from block #{} ({:?} diffs)",
+                url,
+                block + 1,
+                config.method
+            );
+            let client = RpcClient::new(url, Duration::from_secs(args.rpc_timeout_secs));
+            Box::new(RpcSource::new(client, config, block + 1, syncer.hash()))
+        }
+        _ if args.simulated => {
+            let config = SimulationConfig {
+                seed: args.simulated_seed,
+                accounts: args.simulated_accounts,
+                slots: args.simulated_slots,
+                block_time: Duration::from_millis(args.simulated_block_time_ms),
+                blocks: args.simulated_blocks,
+            };
+            println!(
+                "Simulating {} account and {} slot changes every {:?} (seed {})",
+                config.accounts, config.slots, config.block_time, config.seed
+            );
+            Box::new(SimulatedSource::open(
+                config,
+                &args.db_path,
+                &args.address_mapping_path,
+                storage_mapping.as_deref(),
+                syncer.layout(),
+                syncer.block().map_or(1, |block| block + 1),
+            )?)
+        }
+        (_, Some(path)) if path.as_os_str() == "-" => {
+            Box::new(JsonlSource::new(BufReader::new(std::io::stdin())))
+        }
+        (_, Some(path)) => Box::new(JsonlSource::new(BufReader::new(File::open(path)?))),
+        (_, None) => eyre::bail!(
+            "No update feed configured (set PLINKO_STATE_RPC_URL, PLINKO_STATE_UPDATES or \
+             PLINKO_STATE_SIMULATED)"
+        ),
+    };
+    syncer::run(&mut syncer, source.as_mut(), |summary| {
+        println!(
+            "Block #{}: {} records ({} unmapped changes) -> {:?}",
+            summary.block, summary.records, summary.unmapped, summary.path
+        );
+        for path in &summary.ranges {
+            println!("Compacted range delta -> {:?}", path);
+        }
+    })?;
+
+    println!("Update feed ended; still serving deltas");
+    server
+        .join()
+        .map_err(|_| eyre::eyre!("HTTP server panicked"))?
+}
@@ -0,0 +1,469 @@
+//! Integrity manifest for extraction artifacts (`manifest.json`).
+//!
+//! The extractor records a BLAKE3 hash for every `chunk_size`-byte chunk of each artifact,
+//! next to `metadata.json`. Consumers (hint generation, the delta service, `verify`) re-hash
+//! their inputs against it and refuse truncated or corrupted files, naming the first bad
+//! chunk so a partial re-download is possible.
+//!
+//! Writers that produce an artifact front to back hash it as they go with a [`ChunkHasher`]
+//! instead of re-reading the finished file.
+
+use blake3::hazmat::{
+    merge_subtrees_non_root, merge_subtrees_root, ChainingValue, HasherExt, Mode,
+};
+use eyre::{bail, ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// File name of the manifest inside an artifact directory.
+pub const MANIFEST_FILE: &str = "manifest.json";
+/// Current manifest schema version.
+pub const MANIFEST_VERSION: u32 = 1;
+/// Default chunk size (64 MiB): ~1300 hashes for mainnet's `database.bin`.
+pub const DEFAULT_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Size and per-chunk hashes of one artifact.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileHashes {
+    /// Path relative to the artifact directory.
+    pub name: String,
+    pub size: u64,
+    /// Hex BLAKE3 hash of the whole file.
+    #[serde(default)]
+    pub blake3: String,
+    /// Hex BLAKE3 hash of each chunk, in file order; the last chunk may be short.
+    pub chunks: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub version: u32,
+    pub chunk_size: u64,
+    pub files: Vec<FileHashes>,
+}
+
+/// Why a file failed verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    Missing,
+    /// The file has the wrong length (e.g. truncated during transfer).
+    Size {
+        expected: u64,
+        actual: u64,
+    },
+    /// The first chunk whose hash does not match.
+    Chunk {
+        index: usize,
+        offset: u64,
+    },
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Missing => write!(f, "file is missing"),
+            Problem::Size { expected, actual } => {
+                write!(f, "size is {} bytes, expected {}", actual, expected)
+            }
+            Problem::Chunk { index, offset } => {
+                write!(f, "chunk {} (byte offset {}) does not match", index, offset)
+            }
+        }
+    }
+}
+
+/// Hashes `path` in `chunk_size`-byte chunks, and as a whole, with a single streaming read.
+pub fn hash_file(path: &Path, name: &str, chunk_size: u64) -> Result<FileHashes> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; chunk_size as usize];
+    let mut size = 0u64;
+    let mut chunks = Vec::new();
+    let mut whole = blake3::Hasher::new();
+    loop {
+        let n = read_full(&mut file, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        chunks.push(blake3::hash(&buf[..n]).to_hex().to_string());
+        whole.update(&buf[..n]);
+        size += n as u64;
+        if n < buf.len() {
+            break;
+        }
+    }
+    Ok(FileHashes {
+        name: name.to_string(),
+        size,
+        blake3: whole.finalize().to_hex().to_string(),
+        chunks,
+    })
+}
+
+/// [`FileHashes`] of a file computed from the bytes written to it, in order.
+///
+/// Every chunk is hashed twice: on its own for `chunks`, and as the BLAKE3 subtree it forms
+/// inside the whole file. With a power-of-two chunk size those subtrees line up with the
+/// file's BLAKE3 tree, so the whole-file hash is merged from them at the end.
+pub struct ChunkHasher {
+    chunk_size: u64,
+    size: u64,
+    /// Hash of the current chunk on its own.
+    chunk: blake3::Hasher,
+    /// The current chunk as a subtree at its offset in the file.
+    subtree: blake3::Hasher,
+    chunks: Vec<String>,
+    /// Chaining values of the completed chunks.
+    subtrees: Vec<ChainingValue>,
+}
+
+impl ChunkHasher {
+    pub fn new(chunk_size: u64) -> Result<Self> {
+        ensure!(
+            chunk_size.is_power_of_two() && chunk_size >= blake3::CHUNK_LEN as u64,
+            "Streaming hashes need a power-of-two chunk size of at least {} bytes, got {}",
+            blake3::CHUNK_LEN,
+            chunk_size
+        );
+        Ok(Self {
+            chunk_size,
+            size: 0,
+            chunk: blake3::Hasher::new(),
+            subtree: blake3::Hasher::new(),
+            chunks: Vec::new(),
+            subtrees: Vec::new(),
+        })
+    }
+
+    /// Bytes hashed so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let room = self.chunk_size - self.size % self.chunk_size;
+            let (head, rest) = data.split_at(data.len().min(room as usize));
+            self.chunk.update(head);
+            self.subtree.update(head);
+            self.size += head.len() as u64;
+            data = rest;
+            if self.size.is_multiple_of(self.chunk_size) {
+                self.end_chunk();
+            }
+        }
+    }
+
+    /// Records the current chunk; a full one also starts the next.
+    fn end_chunk(&mut self) {
+        self.chunks.push(self.chunk.finalize().to_hex().to_string());
+        self.subtrees.push(self.subtree.finalize_non_root());
+        if self.size.is_multiple_of(self.chunk_size) {
+            self.chunk = blake3::Hasher::new();
+            self.subtree = blake3::Hasher::new();
+            self.subtree.set_input_offset(self.size);
+        }
+    }
+
+    /// Hashes of everything written, named `name`.
+    pub fn finish(mut self, name: &str) -> FileHashes {
+        if !self.size.is_multiple_of(self.chunk_size) {
+            self.end_chunk();
+        }
+        let blake3 = match self.chunks.len() {
+            0 => blake3::hash(&[]).to_hex().to_string(),
+            // A file of one chunk is a single subtree: its hash is the chunk's
+            1 => self.chunks[0].clone(),
+            _ => {
+                let (left, right) = split_subtrees(&self.subtrees);
+                merge_subtrees_root(&merge(left), &merge(right), Mode::Hash)
+                    .to_hex()
+                    .to_string()
+            }
+        };
+        FileHashes {
+            name: name.to_string(),
+            size: self.size,
+            blake3,
+            chunks: self.chunks,
+        }
+    }
+
+    /// Like [`finish`](Self::finish) for a file whose start was overwritten in place after
+    /// being hashed (e.g. a header with the final record count): the first chunk is read
+    /// back from `file`.
+    pub fn finish_rereading_first_chunk(
+        mut self,
+        name: &str,
+        file: &mut File,
+    ) -> Result<FileHashes> {
+        let mut first = vec![0u8; self.size.min(self.chunk_size) as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut first)?;
+        if self.chunks.is_empty() {
+            // Still inside the first chunk
+            self.chunk = blake3::Hasher::new();
+            self.chunk.update(&first);
+            self.subtree = self.chunk.clone();
+        } else {
+            self.chunks[0] = blake3::hash(&first).to_hex().to_string();
+            self.subtrees[0] = blake3::Hasher::new().update(&first).finalize_non_root();
+        }
+        Ok(self.finish(name))
+    }
+}
+
+impl Write for ChunkHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Splits chunk subtrees the way BLAKE3 splits their parent: the left side holds the
+/// largest power of two that leaves the right side non-empty.
+fn split_subtrees(subtrees: &[ChainingValue]) -> (&[ChainingValue], &[ChainingValue]) {
+    subtrees.split_at(subtrees.len().next_power_of_two() / 2)
+}
+
+/// Chaining value of the (non-root) subtree over `subtrees`.
+fn merge(subtrees: &[ChainingValue]) -> ChainingValue {
+    if subtrees.len() == 1 {
+        return subtrees[0];
+    }
+    let (left, right) = split_subtrees(subtrees);
+    merge_subtrees_non_root(&merge(left), &merge(right), Mode::Hash)
+}
+
+/// Reads until `buf` is full or EOF, returning the number of bytes read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+impl Manifest {
+    /// Hashes `names` (relative to `dir`), one thread per file.
+    pub fn build(dir: &Path, names: &[String], chunk_size: u64) -> Result<Self> {
+        let files = std::thread::scope(|scope| {
+            let handles: Vec<_> = names
+                .iter()
+                .map(|name| scope.spawn(move || hash_file(&dir.join(name), name, chunk_size)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("hashing thread panicked"))
+                .collect::<Result<Vec<_>>>()
+        })?;
+        Ok(Self {
+            version: MANIFEST_VERSION,
+            chunk_size,
+            files,
+        })
+    }
+
+    /// Loads `manifest.json` from `dir`, if present.
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let manifest: Self = serde_json::from_slice(&std::fs::read(path)?)?;
+        if manifest.version != MANIFEST_VERSION {
+            bail!(
+                "Unsupported manifest version {} (expected {})",
+                manifest.version,
+                MANIFEST_VERSION
+            );
+        }
+        Ok(Some(manifest))
+    }
+
+    /// Writes `manifest.json` into `dir`.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        std::fs::write(dir.join(MANIFEST_FILE), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// The manifest entry for `name`, if the manifest covers it.
+    pub fn entry(&self, name: &str) -> Option<&FileHashes> {
+        self.files.iter().find(|f| f.name == name)
+    }
+
+    /// Re-hashes `entry`'s file under `dir`, returning the first problem found.
+    pub fn verify_file(&self, dir: &Path, entry: &FileHashes) -> Result<Option<Problem>> {
+        let path = dir.join(&entry.name);
+        let actual = match std::fs::metadata(&path) {
+            Ok(meta) => meta.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Some(Problem::Missing)),
+            Err(e) => return Err(e.into()),
+        };
+        if actual != entry.size {
+            return Ok(Some(Problem::Size {
+                expected: entry.size,
+                actual,
+            }));
+        }
+
+        let mut file = File::open(&path)?;
+        let mut buf = vec![0u8; self.chunk_size as usize];
+        for (index, expected) in entry.chunks.iter().enumerate() {
+            let n = read_full(&mut file, &mut buf)?;
+            if blake3::hash(&buf[..n]).to_hex().as_str() != expected {
+                return Ok(Some(Problem::Chunk {
+                    index,
+                    offset: index as u64 * self.chunk_size,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Fails unless `name` is listed in the manifest and verifies cleanly.
+    pub fn check(&self, dir: &Path, name: &str) -> Result<()> {
+        let Some(entry) = self.entry(name) else {
+            bail!("{} is not listed in {}", name, MANIFEST_FILE);
+        };
+        if let Some(problem) = self.verify_file(dir, entry)? {
+            bail!("{} failed verification: {}", name, problem);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(tag: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("plinko-manifest-{}-{}", tag, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn chunks_cover_whole_file() {
+        let dir = temp_dir("chunks");
+        std::fs::write(dir.join("a.bin"), vec![7u8; 100]).unwrap();
+        std::fs::write(dir.join("b.bin"), vec![7u8; 96]).unwrap();
+        std::fs::write(dir.join("c.bin"), []).unwrap();
+
+        let names: Vec<String> = ["a.bin", "b.bin", "c.bin"].map(String::from).to_vec();
+        let manifest = Manifest::build(&dir, &names, 32).unwrap();
+        assert_eq!(manifest.files[0].size, 100);
+        assert_eq!(manifest.files[0].chunks.len(), 4);
+        assert_eq!(manifest.files[1].chunks.len(), 3);
+        assert!(manifest.files[2].chunks.is_empty());
+        assert_eq!(
+            manifest.files[0].blake3,
+            blake3::hash(&[7u8; 100]).to_hex().as_str()
+        );
+        for name in &names {
+            manifest.check(&dir, name).unwrap();
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn streaming_hashes_match_hash_file() {
+        let dir = temp_dir("stream");
+        let data: Vec<u8> = (0..9 * 1024 + 100).map(|i| (i * 7 % 251) as u8).collect();
+        // Empty, within one chunk, exactly one chunk, and 2 to 10 chunks with a short tail
+        for len in [0, 1, 1024, 1025, 2048, 3000, 4096, 5 * 1024 + 1, data.len()] {
+            std::fs::write(dir.join("f.bin"), &data[..len]).unwrap();
+            let expected = hash_file(&dir.join("f.bin"), "f.bin", 1024).unwrap();
+            assert_eq!(
+                expected.blake3,
+                blake3::hash(&data[..len]).to_hex().as_str()
+            );
+
+            let mut hasher = ChunkHasher::new(1024).unwrap();
+            // Uneven writes straddle chunk boundaries
+            for piece in data[..len].chunks(700) {
+                hasher.update(piece);
+            }
+            assert_eq!(hasher.size(), len as u64);
+            assert_eq!(hasher.finish("f.bin"), expected, "{} bytes", len);
+        }
+        assert!(ChunkHasher::new(1000).is_err());
+        assert!(ChunkHasher::new(512).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rewritten_first_chunk_is_read_back() {
+        let dir = temp_dir("rewrite");
+        for len in [10usize, 3000] {
+            let mut data = vec![3u8; len];
+            let path = dir.join("map.bin");
+            let mut hasher = ChunkHasher::new(1024).unwrap();
+            hasher.update(&data);
+            data[..4].copy_from_slice(&[9; 4]);
+            std::fs::write(&path, &data).unwrap();
+
+            let mut file = File::open(&path).unwrap();
+            let hashes = hasher
+                .finish_rereading_first_chunk("map.bin", &mut file)
+                .unwrap();
+            assert_eq!(hashes, hash_file(&path, "map.bin", 1024).unwrap());
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reports_first_bad_chunk_and_truncation() {
+        let dir = temp_dir("bad");
+        let mut data = vec![1u8; 256];
+        std::fs::write(dir.join("db.bin"), &data).unwrap();
+        let manifest = Manifest::build(&dir, &["db.bin".to_string()], 64).unwrap();
+        manifest.save(&dir).unwrap();
+        let manifest = Manifest::load(&dir).unwrap().unwrap();
+        let entry = manifest.entry("db.bin").unwrap().clone();
+
+        data[130] ^= 1;
+        data[250] ^= 1;
+        std::fs::write(dir.join("db.bin"), &data).unwrap();
+        assert_eq!(
+            manifest.verify_file(&dir, &entry).unwrap(),
+            Some(Problem::Chunk {
+                index: 2,
+                offset: 128
+            })
+        );
+
+        std::fs::write(dir.join("db.bin"), &data[..200]).unwrap();
+        assert_eq!(
+            manifest.verify_file(&dir, &entry).unwrap(),
+            Some(Problem::Size {
+                expected: 256,
+                actual: 200
+            })
+        );
+        assert!(manifest.check(&dir, "db.bin").is_err());
+
+        std::fs::remove_file(dir.join("db.bin")).unwrap();
+        assert_eq!(
+            manifest.verify_file(&dir, &entry).unwrap(),
+            Some(Problem::Missing)
+        );
+        assert!(manifest.check(&dir, "other.bin").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1059 @@
+//! Readers for the extractor's `account-mapping.bin`, `storage-mapping.bin` and
+//! `code-mapping.bin`.
+//!
+//! All files are written in MDBX cursor order, which is sorted by key, so a lookup is a
+//! binary search over fixed-size records in a read-only memory map.
+//!
+//! Account, storage and code mappings start with a [`MappingHeader`] recording the key size,
+//! index width (4 or 8 bytes) and record count; code records also carry a 4-byte length
+//! after the index. Legacy headerless files (4-byte indices) are still read.
+//!
+//! Snapshots extracted with `--hashed` key the account and storage mappings by
+//! `keccak256(address)`/`keccak256(slot)` instead; their header says so, and legacy hashed
+//! files are opened with the `open_hashed` constructors.
+//!
+//! A storage mapping can also be grouped by contract (`--storage-mapping grouped`): a
+//! directory with one entry per contract, then every slot key with its index implied by its
+//! position (see [`GroupedHeader`]). [`StorageMapping`] reads both formats.
+
+use eyre::{bail, ensure, eyre, Result};
+use memmap2::Mmap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Legacy account mapping record: Address (20) || Index (4, LE).
+pub const ACCOUNT_RECORD_SIZE: usize = 24;
+/// Legacy storage mapping record: Address (20) || SlotKey (32) || Index (4, LE).
+pub const STORAGE_RECORD_SIZE: usize = 56;
+/// Legacy hashed account mapping record: keccak256(Address) (32) || Index (4, LE).
+pub const HASHED_ACCOUNT_RECORD_SIZE: usize = 36;
+/// Legacy hashed storage mapping record: keccak256(Address) (32) || keccak256(SlotKey) (32) ||
+/// Index (4, LE).
+pub const HASHED_STORAGE_RECORD_SIZE: usize = 68;
+/// Legacy code mapping record: CodeHash (32) || StartIndex (4, LE) || Length in bytes (4, LE).
+pub const CODE_RECORD_SIZE: usize = 40;
+
+const ADDRESS_SIZE: usize = 20;
+const HASH_SIZE: usize = 32;
+const SLOT_KEY_SIZE: usize = 32;
+const CODE_HASH_SIZE: usize = 32;
+/// Code lengths are bounded by EIP-170's 24576 bytes, so they are always 4 bytes.
+const CODE_LENGTH_SIZE: usize = 4;
+
+/// Magic bytes at the start of a versioned account, storage or code mapping.
+pub const MAPPING_MAGIC: [u8; 8] = *b"PLKOMAP\0";
+/// Current mapping format version.
+pub const MAPPING_VERSION: u32 = 1;
+/// Size of [`MappingHeader`] on disk.
+pub const MAPPING_HEADER_SIZE: usize = 32;
+
+/// Header of a versioned mapping file, followed by `count` records of
+/// `Key (key_size) || Index (index_width, LE)`:
+///
+/// `Magic (8) || Version (4, LE) || IndexWidth (4, LE) || KeySize (4, LE) || Reserved (4) ||
+/// Count (8, LE)`
+///
+/// Files without the magic are legacy headerless mappings with 4-byte indices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingHeader {
+    pub version: u32,
+    /// Bytes per index: 4, or 8 once the database has more than 2^32 entries.
+    pub index_width: u32,
+    pub key_size: u32,
+    pub count: u64,
+}
+
+impl MappingHeader {
+    pub fn new(key_size: usize, index_width: u32, count: u64) -> Self {
+        Self {
+            version: MAPPING_VERSION,
+            index_width,
+            key_size: key_size as u32,
+            count,
+        }
+    }
+
+    /// Bytes per record (key + index).
+    pub fn record_size(&self) -> usize {
+        self.key_size as usize + self.index_width as usize
+    }
+
+    pub fn encode(&self) -> [u8; MAPPING_HEADER_SIZE] {
+        let mut out = [0u8; MAPPING_HEADER_SIZE];
+        out[0..8].copy_from_slice(&MAPPING_MAGIC);
+        out[8..12].copy_from_slice(&self.version.to_le_bytes());
+        out[12..16].copy_from_slice(&self.index_width.to_le_bytes());
+        out[16..20].copy_from_slice(&self.key_size.to_le_bytes());
+        out[24..32].copy_from_slice(&self.count.to_le_bytes());
+        out
+    }
+
+    /// Parses the header at the start of `bytes`; `None` for a legacy headerless file.
+    pub fn decode(bytes: &[u8]) -> Result<Option<Self>> {
+        if bytes.len() < MAPPING_HEADER_SIZE || bytes[0..8] != MAPPING_MAGIC {
+            return Ok(None);
+        }
+        let u32_at = |pos: usize| u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap());
+        let header = Self {
+            version: u32_at(8),
+            index_width: u32_at(12),
+            key_size: u32_at(16),
+            count: u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
+        };
+        ensure!(
+            header.version == MAPPING_VERSION,
+            "Unsupported mapping version {}",
+            header.version
+        );
+        ensure!(
+            header.index_width == 4 || header.index_width == 8,
+            "Invalid mapping index width {}",
+            header.index_width
+        );
+        Ok(Some(header))
+    }
+}
+
+/// Magic bytes at the start of a grouped storage mapping.
+pub const GROUPED_MAGIC: [u8; 8] = *b"PLKOGRP\0";
+/// Current grouped storage mapping format version.
+pub const GROUPED_VERSION: u32 = 1;
+/// Size of [`GroupedHeader`] on disk.
+pub const GROUPED_HEADER_SIZE: usize = 40;
+
+/// Layout of `storage-mapping.bin`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StorageMappingFormat {
+    /// One `Address || SlotKey || Index` record per slot
+    #[default]
+    Flat,
+    /// One entry per contract, then the slot keys alone
+    Grouped,
+}
+
+/// Header of a grouped storage mapping:
+///
+/// `Magic (8) || Version (4, LE) || IndexWidth (4, LE) || AddressSize (4, LE) || Reserved (4)
+/// || Groups (8, LE) || Slots (8, LE)`
+///
+/// followed by `groups` directory entries sorted by address,
+///
+/// `Address (address_size) || FirstIndex || SlotCount || FirstKey` (each `index_width`, LE),
+///
+/// then `slots` 32-byte slot keys. A contract's keys are sorted and stored from position
+/// `FirstKey`; the `i`-th of them is at database index `FirstIndex + i`. Compared to the flat
+/// format this drops the address and index from every slot record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupedHeader {
+    pub version: u32,
+    pub index_width: u32,
+    /// 20 for addresses, 32 for `keccak256(address)`.
+    pub address_size: u32,
+    /// Number of contracts.
+    pub groups: u64,
+    pub slots: u64,
+}
+
+impl GroupedHeader {
+    pub fn new(address_size: usize, index_width: u32, groups: u64, slots: u64) -> Self {
+        Self {
+            version: GROUPED_VERSION,
+            index_width,
+            address_size: address_size as u32,
+            groups,
+            slots,
+        }
+    }
+
+    /// Bytes per directory entry.
+    pub fn entry_size(&self) -> usize {
+        self.address_size as usize + 3 * self.index_width as usize
+    }
+
+    /// Byte offset of the first slot key.
+    pub fn keys_offset(&self) -> u64 {
+        GROUPED_HEADER_SIZE as u64 + self.groups * self.entry_size() as u64
+    }
+
+    /// Total file size.
+    pub fn file_size(&self) -> u64 {
+        self.keys_offset() + self.slots * SLOT_KEY_SIZE as u64
+    }
+
+    pub fn encode(&self) -> [u8; GROUPED_HEADER_SIZE] {
+        let mut out = [0u8; GROUPED_HEADER_SIZE];
+        out[0..8].copy_from_slice(&GROUPED_MAGIC);
+        out[8..12].copy_from_slice(&self.version.to_le_bytes());
+        out[12..16].copy_from_slice(&self.index_width.to_le_bytes());
+        out[16..20].copy_from_slice(&self.address_size.to_le_bytes());
+        out[24..32].copy_from_slice(&self.groups.to_le_bytes());
+        out[32..40].copy_from_slice(&self.slots.to_le_bytes());
+        out
+    }
+
+    /// Parses the header at the start of `bytes`; `None` if it is not a grouped mapping.
+    pub fn decode(bytes: &[u8]) -> Result<Option<Self>> {
+        if bytes.len() < GROUPED_HEADER_SIZE || bytes[0..8] != GROUPED_MAGIC {
+            return Ok(None);
+        }
+        let u32_at = |pos: usize| u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap());
+        let u64_at = |pos: usize| u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap());
+        let header = Self {
+            version: u32_at(8),
+            index_width: u32_at(12),
+            address_size: u32_at(16),
+            groups: u64_at(24),
+            slots: u64_at(32),
+        };
+        ensure!(
+            header.version == GROUPED_VERSION,
+            "Unsupported grouped mapping version {}",
+            header.version
+        );
+        ensure!(
+            header.index_width == 4 || header.index_width == 8,
+            "Invalid mapping index width {}",
+            header.index_width
+        );
+        Ok(Some(header))
+    }
+}
+
+/// Smallest index width (4 or 8 bytes) that can address a database of `total_indices` words.
+pub fn index_width_for(total_indices: u64) -> u32 {
+    if total_indices <= u32::MAX as u64 + 1 {
+        4
+    } else {
+        8
+    }
+}
+
+/// Writes `index` as a `width`-byte little-endian integer, failing if it does not fit.
+pub fn write_index(out: &mut impl Write, index: u64, width: u32) -> Result<()> {
+    match width {
+        4 => {
+            let index = u32::try_from(index).map_err(|_| {
+                eyre!(
+                    "Index {} does not fit in a 4-byte mapping index (use 8-byte indices)",
+                    index
+                )
+            })?;
+            out.write_all(&index.to_le_bytes())?;
+        }
+        8 => out.write_all(&index.to_le_bytes())?,
+        _ => bail!("Invalid mapping index width {}", width),
+    }
+    Ok(())
+}
+
+/// Sorted fixed-size `Key || Index` records, versioned or legacy.
+struct Records {
+    mmap: Mmap,
+    /// Byte offset of the first record (past the header, if any).
+    offset: usize,
+    key_size: usize,
+    index_width: usize,
+}
+
+impl Records {
+    /// Maps `path`, using the header when present. Legacy files are assumed to have
+    /// `legacy_key_size`-byte keys; versioned files must have one of `key_sizes`.
+    fn open(path: &Path, legacy_key_size: usize, key_sizes: &[usize]) -> Result<Self> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        match MappingHeader::decode(&mmap)? {
+            Some(header) => {
+                let key_size = header.key_size as usize;
+                ensure!(
+                    key_sizes.contains(&key_size),
+                    "Mapping {:?} has {}-byte keys, expected one of {:?}",
+                    path,
+                    key_size,
+                    key_sizes
+                );
+                let expected =
+                    MAPPING_HEADER_SIZE as u64 + header.count * header.record_size() as u64;
+                ensure!(
+                    mmap.len() as u64 == expected,
+                    "Mapping {:?} is {} bytes but its header declares {} records ({} bytes)",
+                    path,
+                    mmap.len(),
+                    header.count,
+                    expected
+                );
+                Ok(Self {
+                    mmap,
+                    offset: MAPPING_HEADER_SIZE,
+                    key_size,
+                    index_width: header.index_width as usize,
+                })
+            }
+            None => {
+                let record_size = legacy_key_size + 4;
+                ensure!(
+                    mmap.len() % record_size == 0,
+                    "Mapping {:?} size {} is not a multiple of {}",
+                    path,
+                    mmap.len(),
+                    record_size
+                );
+                Ok(Self {
+                    mmap,
+                    offset: 0,
+                    key_size: legacy_key_size,
+                    index_width: 4,
+                })
+            }
+        }
+    }
+
+    fn record_size(&self) -> usize {
+        self.key_size + self.index_width
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.mmap[self.offset..]
+    }
+
+    fn len(&self) -> u64 {
+        (self.data().len() / self.record_size()) as u64
+    }
+
+    fn get(&self, key: &[u8]) -> Option<u64> {
+        if key.len() != self.key_size {
+            return None;
+        }
+        search(self.data(), self.record_size(), key)
+            .map(|record| trailing_index(record, self.index_width))
+    }
+
+    fn record(&self, i: u64) -> Option<(&[u8], u64)> {
+        if i >= self.len() {
+            return None;
+        }
+        let size = self.record_size();
+        let record = &self.data()[i as usize * size..(i as usize + 1) * size];
+        Some((
+            &record[..self.key_size],
+            trailing_index(record, self.index_width),
+        ))
+    }
+}
+
+/// A grouped storage mapping: the contract directory and the slot keys.
+struct Groups {
+    mmap: Mmap,
+    header: GroupedHeader,
+}
+
+/// One directory entry of a grouped storage mapping.
+struct Group<'a> {
+    address: &'a [u8],
+    first_index: u64,
+    count: u64,
+    first_key: u64,
+}
+
+impl Groups {
+    /// Maps `path` if it is a grouped mapping keyed by one of `address_sizes`.
+    fn open(path: &Path, address_sizes: &[usize]) -> Result<Option<Self>> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let Some(header) = GroupedHeader::decode(&mmap)? else {
+            return Ok(None);
+        };
+        ensure!(
+            address_sizes.contains(&(header.address_size as usize)),
+            "Mapping {:?} has {}-byte addresses, expected one of {:?}",
+            path,
+            header.address_size,
+            address_sizes
+        );
+        ensure!(
+            mmap.len() as u64 == header.file_size(),
+            "Mapping {:?} is {} bytes but its header declares {} contracts and {} slots ({} bytes)",
+            path,
+            mmap.len(),
+            header.groups,
+            header.slots,
+            header.file_size()
+        );
+        Ok(Some(Self { mmap, header }))
+    }
+
+    fn directory(&self) -> &[u8] {
+        &self.mmap[GROUPED_HEADER_SIZE..self.header.keys_offset() as usize]
+    }
+
+    fn keys(&self) -> &[u8] {
+        &self.mmap[self.header.keys_offset() as usize..]
+    }
+
+    fn parse<'a>(&self, entry: &'a [u8]) -> Group<'a> {
+        let address_size = self.header.address_size as usize;
+        let width = self.header.index_width as usize;
+        let field = |i: usize| {
+            let start = address_size + i * width;
+            read_index(&entry[start..start + width])
+        };
+        Group {
+            address: &entry[..address_size],
+            first_index: field(0),
+            count: field(1),
+            first_key: field(2),
+        }
+    }
+
+    fn group(&self, g: u64) -> Group<'_> {
+        let size = self.header.entry_size();
+        self.parse(&self.directory()[g as usize * size..(g as usize + 1) * size])
+    }
+
+    fn get(&self, address: &[u8], slot: &[u8]) -> Option<u64> {
+        let size = self.header.entry_size();
+        let g = position(self.directory(), size, address)?;
+        let group = self.group(g as u64);
+        let start = group.first_key as usize * SLOT_KEY_SIZE;
+        let keys = self
+            .keys()
+            .get(start..start + group.count as usize * SLOT_KEY_SIZE)?;
+        position(keys, SLOT_KEY_SIZE, slot).map(|i| group.first_index + i as u64)
+    }
+
+    fn slot(&self, i: u64) -> Option<(&[u8], &[u8], u64)> {
+        if i >= self.header.slots {
+            return None;
+        }
+        // Last contract whose keys start at or before `i`
+        let (mut lo, mut hi) = (0u64, self.header.groups);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.group(mid).first_key <= i {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let group = self.group(lo.checked_sub(1)?);
+        let offset = i.checked_sub(group.first_key)?;
+        if offset >= group.count {
+            return None;
+        }
+        let start = i as usize * SLOT_KEY_SIZE;
+        Some((
+            group.address,
+            &self.keys()[start..start + SLOT_KEY_SIZE],
+            group.first_index + offset,
+        ))
+    }
+}
+
+/// Memory-mapped `account-mapping.bin` (address -> database index).
+pub struct AccountMapping {
+    records: Records,
+}
+
+impl AccountMapping {
+    /// Opens and memory-maps an account mapping file. Versioned files declare whether they
+    /// are keyed by address or by hash; legacy files are assumed to be keyed by address.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let records = Records::open(path.as_ref(), ADDRESS_SIZE, &[ADDRESS_SIZE, HASH_SIZE])?;
+        Ok(Self { records })
+    }
+
+    /// Opens an account mapping keyed by `keccak256(address)`, including legacy files.
+    pub fn open_hashed(path: impl AsRef<Path>) -> Result<Self> {
+        let records = Records::open(path.as_ref(), HASH_SIZE, &[HASH_SIZE])?;
+        Ok(Self { records })
+    }
+
+    /// Number of accounts in the mapping.
+    pub fn len(&self) -> u64 {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the mapping is keyed by `keccak256(address)`.
+    pub fn is_hashed(&self) -> bool {
+        self.records.key_size == HASH_SIZE
+    }
+
+    /// Returns the index of the first database word of `address`, if present.
+    pub fn get(&self, address: &[u8]) -> Option<u64> {
+        self.records.get(address)
+    }
+
+    /// The `i`-th record in key order: (address or hashed address, index).
+    pub fn record(&self, i: u64) -> Option<(&[u8], u64)> {
+        self.records.record(i)
+    }
+}
+
+/// Memory-mapped `storage-mapping.bin` ((address, slot) -> database index), flat or grouped.
+pub struct StorageMapping {
+    inner: StorageRecords,
+}
+
+enum StorageRecords {
+    Flat(Records),
+    Grouped(Groups),
+}
+
+impl StorageMapping {
+    /// Opens and memory-maps a storage mapping file. As with [`AccountMapping::open`],
+    /// legacy files are assumed to be keyed by (address, slot).
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with(path.as_ref(), ADDRESS_SIZE, &[ADDRESS_SIZE, HASH_SIZE])
+    }
+
+    /// Opens a storage mapping keyed by (`keccak256(address)`, `keccak256(slot)`),
+    /// including legacy files.
+    pub fn open_hashed(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with(path.as_ref(), HASH_SIZE, &[HASH_SIZE])
+    }
+
+    fn open_with(path: &Path, legacy_address_size: usize, address_sizes: &[usize]) -> Result<Self> {
+        if let Some(groups) = Groups::open(path, address_sizes)? {
+            return Ok(Self {
+                inner: StorageRecords::Grouped(groups),
+            });
+        }
+        let key_sizes: Vec<usize> = address_sizes.iter().map(|a| a + SLOT_KEY_SIZE).collect();
+        let records = Records::open(path, legacy_address_size + SLOT_KEY_SIZE, &key_sizes)?;
+        Ok(Self {
+            inner: StorageRecords::Flat(records),
+        })
+    }
+
+    fn address_size(&self) -> usize {
+        match &self.inner {
+            StorageRecords::Flat(records) => records.key_size - SLOT_KEY_SIZE,
+            StorageRecords::Grouped(groups) => groups.header.address_size as usize,
+        }
+    }
+
+    fn index_width(&self) -> u32 {
+        match &self.inner {
+            StorageRecords::Flat(records) => records.index_width as u32,
+            StorageRecords::Grouped(groups) => groups.header.index_width,
+        }
+    }
+
+    /// Number of storage slots in the mapping.
+    pub fn len(&self) -> u64 {
+        match &self.inner {
+            StorageRecords::Flat(records) => records.len(),
+            StorageRecords::Grouped(groups) => groups.header.slots,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the mapping is keyed by (`keccak256(address)`, `keccak256(slot)`).
+    pub fn is_hashed(&self) -> bool {
+        self.address_size() == HASH_SIZE
+    }
+
+    /// Whether the mapping is grouped by contract.
+    pub fn is_grouped(&self) -> bool {
+        matches!(self.inner, StorageRecords::Grouped(_))
+    }
+
+    /// Returns the database index of slot `slot` of `address`, if present.
+    pub fn get(&self, address: &[u8], slot: &[u8]) -> Option<u64> {
+        let address_size = self.address_size();
+        if address.len() != address_size || slot.len() != SLOT_KEY_SIZE {
+            return None;
+        }
+        let records = match &self.inner {
+            StorageRecords::Flat(records) => records,
+            StorageRecords::Grouped(groups) => return groups.get(address, slot),
+        };
+        let mut key = [0u8; HASH_SIZE + SLOT_KEY_SIZE];
+        let key_len = address_size + SLOT_KEY_SIZE;
+        key[..address_size].copy_from_slice(address);
+        key[address_size..key_len].copy_from_slice(slot);
+        records.get(&key[..key_len])
+    }
+
+    /// The `i`-th record of a flat mapping in key order: (address || slot, index). `None`
+    /// for grouped mappings, which do not store the key contiguously; see
+    /// [`StorageMapping::slot`].
+    pub fn record(&self, i: u64) -> Option<(&[u8], u64)> {
+        match &self.inner {
+            StorageRecords::Flat(records) => records.record(i),
+            StorageRecords::Grouped(_) => None,
+        }
+    }
+
+    /// The `i`-th slot in key order, in either format: (address, slot, index).
+    pub fn slot(&self, i: u64) -> Option<(&[u8], &[u8], u64)> {
+        match &self.inner {
+            StorageRecords::Flat(records) => {
+                let (key, index) = records.record(i)?;
+                let (address, slot) = key.split_at(key.len() - SLOT_KEY_SIZE);
+                Some((address, slot, index))
+            }
+            StorageRecords::Grouped(groups) => groups.slot(i),
+        }
+    }
+}
+
+/// Writes the slots of `flat` to `path` as a grouped storage mapping and returns its header.
+///
+/// Each contract's slots must sit at consecutive database indices, as the extractor writes
+/// them; a cuckoo layout scatters them and cannot be grouped.
+pub fn write_grouped(flat: &StorageMapping, path: &Path) -> Result<GroupedHeader> {
+    ensure!(!flat.is_grouped(), "Storage mapping is already grouped");
+    let slots = flat.len();
+    let slot = |i: u64| flat.slot(i).expect("slot below count");
+
+    // Count the contracts first so the directory can precede the keys
+    let mut groups = 0u64;
+    for i in 0..slots {
+        if i == 0 || slot(i).0 != slot(i - 1).0 {
+            groups += 1;
+        }
+    }
+    let header = GroupedHeader::new(flat.address_size(), flat.index_width(), groups, slots);
+    let width = header.index_width;
+
+    let mut directory = BufWriter::new(File::create(path)?);
+    directory.write_all(&header.encode())?;
+    let mut keys_file = OpenOptions::new().write(true).open(path)?;
+    keys_file.seek(SeekFrom::Start(header.keys_offset()))?;
+    let mut keys = BufWriter::new(keys_file);
+
+    let mut i = 0;
+    while i < slots {
+        let (address, _, first_index) = slot(i);
+        let first_key = i;
+        while i < slots {
+            let (next_address, key, index) = slot(i);
+            if next_address != address {
+                break;
+            }
+            ensure!(
+                index == first_index + (i - first_key),
+                "Slot {} is at index {}, not next to the other slots of its contract",
+                i,
+                index
+            );
+            keys.write_all(key)?;
+            i += 1;
+        }
+        directory.write_all(address)?;
+        write_index(&mut directory, first_index, width)?;
+        write_index(&mut directory, i - first_key, width)?;
+        write_index(&mut directory, first_key, width)?;
+    }
+    directory.flush()?;
+    keys.flush()?;
+    Ok(header)
+}
+
+/// Location of a contract's code in `code-database.bin`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodeLocation {
+    /// Index of the first 32-byte word.
+    pub start: u64,
+    /// Code length in bytes; the last word is zero-padded.
+    pub len: u64,
+}
+
+impl CodeLocation {
+    /// Number of 32-byte words the code occupies.
+    pub fn words(&self) -> u64 {
+        self.len.div_ceil(32)
+    }
+}
+
+/// Memory-mapped `code-mapping.bin` (code hash -> location in `code-database.bin`).
+///
+/// Versioned files start with a [`MappingHeader`] whose key size is the 32-byte code hash;
+/// each record is `CodeHash (32) || StartIndex (index_width, LE) || Length (4, LE)`. Legacy
+/// headerless files use [`CODE_RECORD_SIZE`]-byte records with a 4-byte start index.
+pub struct CodeMapping {
+    mmap: Mmap,
+    /// Byte offset of the first record.
+    offset: usize,
+    index_width: usize,
+}
+
+impl CodeMapping {
+    /// Opens and memory-maps a code mapping file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let (offset, index_width) = match MappingHeader::decode(&mmap)? {
+            Some(header) => {
+                ensure!(
+                    header.key_size as usize == CODE_HASH_SIZE,
+                    "Code mapping {:?} has {}-byte keys, expected {}",
+                    path,
+                    header.key_size,
+                    CODE_HASH_SIZE
+                );
+                let expected = MAPPING_HEADER_SIZE as u64
+                    + header.count * (header.record_size() + CODE_LENGTH_SIZE) as u64;
+                ensure!(
+                    mmap.len() as u64 == expected,
+                    "Code mapping {:?} is {} bytes but its header declares {} records ({} bytes)",
+                    path,
+                    mmap.len(),
+                    header.count,
+                    expected
+                );
+                (MAPPING_HEADER_SIZE, header.index_width as usize)
+            }
+            None => {
+                ensure!(
+                    mmap.len() % CODE_RECORD_SIZE == 0,
+                    "Mapping {:?} size {} is not a multiple of {}",
+                    path,
+                    mmap.len(),
+                    CODE_RECORD_SIZE
+                );
+                (0, 4)
+            }
+        };
+        Ok(Self {
+            mmap,
+            offset,
+            index_width,
+        })
+    }
+
+    fn record_size(&self) -> usize {
+        CODE_HASH_SIZE + self.index_width + CODE_LENGTH_SIZE
+    }
+
+    fn records(&self) -> &[u8] {
+        &self.mmap[self.offset..]
+    }
+
+    /// Number of distinct bytecodes in the mapping.
+    pub fn len(&self) -> u64 {
+        (self.records().len() / self.record_size()) as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records().is_empty()
+    }
+
+    /// Returns where the code with hash `code_hash` is stored, if present.
+    pub fn get(&self, code_hash: &[u8]) -> Option<CodeLocation> {
+        if code_hash.len() != CODE_HASH_SIZE {
+            return None;
+        }
+        let end = CODE_HASH_SIZE + self.index_width;
+        search(self.records(), self.record_size(), code_hash).map(|record| CodeLocation {
+            start: read_index(&record[CODE_HASH_SIZE..end]),
+            len: u32::from_le_bytes(record[end..].try_into().unwrap()) as u64,
+        })
+    }
+}
+
+/// Index stored in the last `width` bytes (LE) of an account or storage record.
+fn trailing_index(record: &[u8], width: usize) -> u64 {
+    read_index(&record[record.len() - width..])
+}
+
+/// A 4- or 8-byte little-endian index.
+fn read_index(bytes: &[u8]) -> u64 {
+    match bytes.len() {
+        4 => u32::from_le_bytes(bytes.try_into().unwrap()) as u64,
+        _ => u64::from_le_bytes(bytes.try_into().unwrap()),
+    }
+}
+
+/// Binary search over sorted `record_size`-byte records whose prefix is the key.
+fn search<'a>(data: &'a [u8], record_size: usize, key: &[u8]) -> Option<&'a [u8]> {
+    position(data, record_size, key).map(|i| &data[i * record_size..(i + 1) * record_size])
+}
+
+/// Position of the record with prefix `key` among sorted `record_size`-byte records.
+fn position(data: &[u8], record_size: usize, key: &[u8]) -> Option<usize> {
+    let mut lo = 0usize;
+    let mut hi = data.len() / record_size;
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        let record = &data[mid * record_size..(mid + 1) * record_size];
+        match record[..key.len()].cmp(key) {
+            std::cmp::Ordering::Less => lo = mid + 1,
+            std::cmp::Ordering::Greater => hi = mid,
+            std::cmp::Ordering::Equal => return Some(mid),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account_records(addrs: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (i, &a) in addrs.iter().enumerate() {
+            data.extend_from_slice(&[a; ADDRESS_SIZE]);
+            data.extend_from_slice(&((i * 3) as u32).to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn search_finds_every_account() {
+        let addrs = [1u8, 3, 7, 8, 200];
+        let data = account_records(&addrs);
+        for (i, &a) in addrs.iter().enumerate() {
+            assert_eq!(
+                search(&data, ACCOUNT_RECORD_SIZE, &[a; ADDRESS_SIZE])
+                    .map(|r| trailing_index(r, 4)),
+                Some(i as u64 * 3)
+            );
+        }
+    }
+
+    #[test]
+    fn search_misses_absent_keys() {
+        let data = account_records(&[1, 3, 7]);
+        for a in [0u8, 2, 4, 255] {
+            assert!(search(&data, ACCOUNT_RECORD_SIZE, &[a; ADDRESS_SIZE]).is_none());
+        }
+        assert!(search(&[], ACCOUNT_RECORD_SIZE, &[1; ADDRESS_SIZE]).is_none());
+    }
+
+    #[test]
+    fn search_storage_uses_address_and_slot() {
+        let mut data = Vec::new();
+        for (i, (a, s)) in [(1u8, 1u8), (1, 9), (2, 0)].iter().enumerate() {
+            data.extend_from_slice(&[*a; ADDRESS_SIZE]);
+            data.extend_from_slice(&[*s; SLOT_KEY_SIZE]);
+            data.extend_from_slice(&(100 + i as u32).to_le_bytes());
+        }
+        let key = |a: u8, s: u8| {
+            let mut k = vec![a; ADDRESS_SIZE];
+            k.extend_from_slice(&[s; SLOT_KEY_SIZE]);
+            k
+        };
+        let find =
+            |k: Vec<u8>| search(&data, STORAGE_RECORD_SIZE, &k).map(|r| trailing_index(r, 4));
+        assert_eq!(find(key(1, 9)), Some(101));
+        assert_eq!(find(key(2, 0)), Some(102));
+        assert_eq!(find(key(1, 5)), None);
+    }
+
+    #[test]
+    fn hashed_mappings_use_32_byte_keys() {
+        let dir = std::env::temp_dir().join(format!("plinko-mapping-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut acc = Vec::new();
+        let mut sto = Vec::new();
+        for (i, h) in [2u8, 5, 9].iter().enumerate() {
+            acc.extend_from_slice(&[*h; HASH_SIZE]);
+            acc.extend_from_slice(&(i as u32 * 3).to_le_bytes());
+            sto.extend_from_slice(&[*h; HASH_SIZE]);
+            sto.extend_from_slice(&[h + 1; SLOT_KEY_SIZE]);
+            sto.extend_from_slice(&(9 + i as u32).to_le_bytes());
+        }
+        std::fs::write(dir.join("acc"), &acc).unwrap();
+        std::fs::write(dir.join("sto"), &sto).unwrap();
+
+        let acc = AccountMapping::open_hashed(dir.join("acc")).unwrap();
+        assert_eq!(acc.len(), 3);
+        assert_eq!(acc.get(&[5; HASH_SIZE]), Some(3));
+        assert_eq!(acc.get(&[5; ADDRESS_SIZE]), None);
+
+        let sto = StorageMapping::open_hashed(dir.join("sto")).unwrap();
+        assert_eq!(sto.len(), 3);
+        assert_eq!(sto.get(&[9; HASH_SIZE], &[10; SLOT_KEY_SIZE]), Some(11));
+        assert_eq!(sto.get(&[9; HASH_SIZE], &[9; SLOT_KEY_SIZE]), None);
+
+        // A hashed file is not a whole number of plain records
+        assert!(AccountMapping::open(dir.join("acc")).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn header_round_trips_and_legacy_is_detected() {
+        let header = MappingHeader::new(ADDRESS_SIZE, 8, 12345);
+        let bytes = header.encode();
+        assert_eq!(MappingHeader::decode(&bytes).unwrap(), Some(header));
+        assert_eq!(header.record_size(), 28);
+
+        // A legacy file starts with an address
+        assert_eq!(
+            MappingHeader::decode(&account_records(&[1, 2])).unwrap(),
+            None
+        );
+
+        let mut bad = bytes;
+        bad[12] = 5;
+        assert!(MappingHeader::decode(&bad).is_err());
+    }
+
+    #[test]
+    fn index_width_and_overflow() {
+        assert_eq!(index_width_for(0), 4);
+        assert_eq!(index_width_for(u32::MAX as u64 + 1), 4);
+        assert_eq!(index_width_for(10_000_000_000), 8);
+
+        let mut out = Vec::new();
+        write_index(&mut out, u32::MAX as u64, 4).unwrap();
+        assert!(write_index(&mut out, u32::MAX as u64 + 1, 4).is_err());
+        write_index(&mut out, 10_000_000_000, 8).unwrap();
+        assert_eq!(out.len(), 12);
+        assert_eq!(trailing_index(&out, 8), 10_000_000_000);
+    }
+
+    #[test]
+    fn versioned_mapping_with_u64_indices() {
+        let dir = std::env::temp_dir().join(format!("plinko-mapping-v1-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut data = MappingHeader::new(ADDRESS_SIZE, 8, 3).encode().to_vec();
+        for (a, idx) in [(1u8, 0u64), (4, 5_000_000_000), (9, 9_000_000_000)] {
+            data.extend_from_slice(&[a; ADDRESS_SIZE]);
+            write_index(&mut data, idx, 8).unwrap();
+        }
+        std::fs::write(dir.join("acc"), &data).unwrap();
+
+        let acc = AccountMapping::open(dir.join("acc")).unwrap();
+        assert_eq!(acc.len(), 3);
+        assert!(!acc.is_hashed());
+        assert_eq!(acc.get(&[4; ADDRESS_SIZE]), Some(5_000_000_000));
+        assert_eq!(
+            acc.record(2),
+            Some((&[9u8; ADDRESS_SIZE][..], 9_000_000_000))
+        );
+        assert_eq!(acc.record(3), None);
+        // Hashed readers reject a header declaring address keys
+        assert!(AccountMapping::open_hashed(dir.join("acc")).is_err());
+
+        // A truncated versioned file no longer matches its declared count
+        std::fs::write(dir.join("acc"), &data[..data.len() - 1]).unwrap();
+        assert!(AccountMapping::open(dir.join("acc")).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn grouped_mapping_matches_flat() {
+        let dir = std::env::temp_dir().join(format!("plinko-mapping-grp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Accounts take indices 0..9; contract 3 has one slot, 5 has three, 8 has two
+        let slots = [(3u8, 1u8), (5, 0), (5, 2), (5, 7), (8, 4), (8, 6)];
+        let mut flat = MappingHeader::new(ADDRESS_SIZE + SLOT_KEY_SIZE, 4, 6)
+            .encode()
+            .to_vec();
+        for (i, (a, s)) in slots.iter().enumerate() {
+            flat.extend_from_slice(&[*a; ADDRESS_SIZE]);
+            flat.extend_from_slice(&[*s; SLOT_KEY_SIZE]);
+            write_index(&mut flat, 9 + i as u64, 4).unwrap();
+        }
+        std::fs::write(dir.join("flat"), &flat).unwrap();
+
+        let flat = StorageMapping::open(dir.join("flat")).unwrap();
+        let header = write_grouped(&flat, &dir.join("grouped")).unwrap();
+        assert_eq!((header.groups, header.slots), (3, 6));
+
+        let grouped = StorageMapping::open(dir.join("grouped")).unwrap();
+        assert!(grouped.is_grouped() && !grouped.is_hashed());
+        assert_eq!(grouped.len(), 6);
+        assert_eq!(
+            std::fs::metadata(dir.join("grouped")).unwrap().len(),
+            (GROUPED_HEADER_SIZE + 3 * (ADDRESS_SIZE + 12) + 6 * SLOT_KEY_SIZE) as u64
+        );
+        for i in 0..6 {
+            let (address, slot, index) = flat.slot(i).unwrap();
+            assert_eq!(grouped.slot(i), Some((address, slot, index)));
+            assert_eq!(grouped.get(address, slot), Some(index));
+        }
+        assert_eq!(grouped.slot(6), None);
+        assert_eq!(grouped.record(0), None);
+        assert_eq!(grouped.get(&[5; ADDRESS_SIZE], &[1; SLOT_KEY_SIZE]), None);
+        assert_eq!(grouped.get(&[4; ADDRESS_SIZE], &[0; SLOT_KEY_SIZE]), None);
+        assert!(StorageMapping::open_hashed(dir.join("grouped")).is_err());
+        assert!(write_grouped(&grouped, &dir.join("again")).is_err());
+
+        // Slots of one contract split apart (as by a cuckoo layout) cannot be grouped
+        let mut scattered = std::fs::read(dir.join("flat")).unwrap();
+        let record = ADDRESS_SIZE + SLOT_KEY_SIZE + 4;
+        let last = MAPPING_HEADER_SIZE + 3 * record - 4;
+        scattered[last..last + 4].copy_from_slice(&100u32.to_le_bytes());
+        std::fs::write(dir.join("scattered"), &scattered).unwrap();
+        let scattered = StorageMapping::open(dir.join("scattered")).unwrap();
+        assert!(write_grouped(&scattered, &dir.join("bad")).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn code_mapping_reads_versioned_and_legacy_files() {
+        let dir = std::env::temp_dir().join(format!("plinko-mapping-code-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let codes = [(1u8, 0u64, 33u32), (5, 2, 0), (9, 5_000_000_000, 64)];
+        let mut versioned = MappingHeader::new(CODE_HASH_SIZE, 8, 3).encode().to_vec();
+        let mut legacy = Vec::new();
+        for (hash, start, len) in codes {
+            versioned.extend_from_slice(&[hash; CODE_HASH_SIZE]);
+            write_index(&mut versioned, start, 8).unwrap();
+            versioned.extend_from_slice(&len.to_le_bytes());
+            legacy.extend_from_slice(&[hash; CODE_HASH_SIZE]);
+            write_index(&mut legacy, start % 1000, 4).unwrap();
+            legacy.extend_from_slice(&len.to_le_bytes());
+        }
+        std::fs::write(dir.join("versioned"), &versioned).unwrap();
+        std::fs::write(dir.join("legacy"), &legacy).unwrap();
+
+        let code = CodeMapping::open(dir.join("versioned")).unwrap();
+        assert_eq!(code.len(), 3);
+        assert_eq!(
+            code.get(&[9; CODE_HASH_SIZE]),
+            Some(CodeLocation {
+                start: 5_000_000_000,
+                len: 64
+            })
+        );
+        assert_eq!(
+            code.get(&[5; CODE_HASH_SIZE]),
+            Some(CodeLocation { start: 2, len: 0 })
+        );
+        assert_eq!(code.get(&[2; CODE_HASH_SIZE]), None);
+
+        let code = CodeMapping::open(dir.join("legacy")).unwrap();
+        assert_eq!(code.len(), 3);
+        assert_eq!(
+            code.get(&[1; CODE_HASH_SIZE]),
+            Some(CodeLocation { start: 0, len: 33 })
+        );
+
+        // A truncated versioned file no longer matches its declared count
+        std::fs::write(dir.join("versioned"), &versioned[..versioned.len() - 4]).unwrap();
+        assert!(CodeMapping::open(dir.join("versioned")).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn code_location_words_round_up() {
+        let loc = |len| CodeLocation { start: 0, len };
+        assert_eq!(loc(0).words(), 0);
+        assert_eq!(loc(1).words(), 1);
+        assert_eq!(loc(32).words(), 1);
+        assert_eq!(loc(33).words(), 2);
+    }
+}
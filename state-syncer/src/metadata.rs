@@ -0,0 +1,414 @@
+//! Typed `metadata.json` describing an extraction.
+//!
+//! The extractor writes it next to the artifacts; [`crate::db::Database`] and the delta
+//! producer read it, so consumers take the chain, block, key order and record layout of a
+//! snapshot from here instead of guessing them from file sizes.
+//!
+//! Files written before the schema was versioned have no `schema_version` and load as
+//! version 0, with the fields they lack defaulted (standard layout, plain key order).
+
+use crate::cuckoo::{CuckooParams, CuckooTable};
+use crate::db::DB_ENTRY_SIZE;
+use crate::layout::AccountLayout;
+use eyre::{bail, eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+/// File name of the metadata inside an artifact directory.
+pub const METADATA_FILE: &str = "metadata.json";
+/// Current metadata schema version.
+pub const METADATA_SCHEMA_VERSION: u32 = 1;
+
+/// Order of the entries in `database.bin` and the keys of the mapping files.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyOrder {
+    /// Plain addresses and slots (`PlainAccountState`/`PlainStorageState`).
+    #[default]
+    Plain,
+    /// `keccak256(address)`/`keccak256(slot)` (`--hashed`).
+    Hashed,
+}
+
+/// Words making up each record in `database.bin`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RecordLayout {
+    /// Account encoding; decode entries with [`AccountLayout::decode`].
+    #[serde(default)]
+    pub account_layout: AccountLayout,
+    /// Words per account entry.
+    pub account_words: u64,
+    /// Name of each account word, in order.
+    pub account_fields: Vec<String>,
+    /// Words per storage entry.
+    pub storage_words: u64,
+    /// Name of each storage word, in order.
+    pub storage_fields: Vec<String>,
+    /// Bytes per index in the account and storage mappings (4 or 8).
+    pub index_width: u32,
+}
+
+impl RecordLayout {
+    pub fn new(account_layout: AccountLayout, index_width: u32) -> Self {
+        Self {
+            account_layout,
+            account_words: account_layout.words(),
+            account_fields: account_layout
+                .fields()
+                .iter()
+                .map(|f| f.to_string())
+                .collect(),
+            storage_words: 1,
+            storage_fields: vec!["value".to_string()],
+            index_width,
+        }
+    }
+}
+
+impl Default for RecordLayout {
+    /// Standard accounts (nonce, balance, code hash) and one value word per slot.
+    fn default() -> Self {
+        Self::new(AccountLayout::Standard, 4)
+    }
+}
+
+/// Size and whole-file BLAKE3 hash of one artifact.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    /// Path relative to the artifact directory.
+    pub name: String,
+    pub size: u64,
+    /// Hex BLAKE3 hash of the whole file (what `b3sum` prints).
+    pub blake3: String,
+}
+
+/// An address list file used as an extraction filter.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AddressListInfo {
+    pub file: String,
+    /// Number of distinct addresses listed.
+    pub addresses: u64,
+    /// Hex BLAKE3 hash of the file contents.
+    pub blake3: String,
+}
+
+/// Filters applied during extraction. Absent from `metadata.json` when nothing was filtered.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FilterInfo {
+    /// Only accounts (and their storage) listed here were extracted.
+    #[serde(default)]
+    pub include: Option<AddressListInfo>,
+    /// Accounts (and their storage) listed here were skipped.
+    #[serde(default)]
+    pub exclude: Option<AddressListInfo>,
+    /// Accounts with nonce 0, balance 0 and no code were skipped.
+    #[serde(default)]
+    pub skip_empty_accounts: bool,
+    /// Zero-valued slots were skipped.
+    #[serde(default)]
+    pub skip_zero_storage: bool,
+    /// Whether accounts were extracted (`false` with `--storage-only`).
+    #[serde(default = "default_true")]
+    pub accounts: bool,
+    /// Whether storage was extracted (`false` with `--accounts-only`).
+    #[serde(default = "default_true")]
+    pub storage: bool,
+}
+
+impl Default for FilterInfo {
+    /// No filtering.
+    fn default() -> Self {
+        Self {
+            include: None,
+            exclude: None,
+            skip_empty_accounts: false,
+            skip_zero_storage: false,
+            accounts: true,
+            storage: true,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Counts of the `--bytecode` databases.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodeInfo {
+    pub bytecodes: u64,
+    pub code_words: u64,
+}
+
+/// Node counts of the `--trie` databases, indexed by depth.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TrieInfo {
+    pub node_words: u64,
+    pub account_nodes: Vec<u64>,
+    pub storage_nodes: Vec<u64>,
+}
+
+/// One table of a `--cuckoo` layout. Seeds are hex strings so they survive JSON parsers
+/// that read numbers as doubles.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CuckooTableInfo {
+    pub seeds: Vec<String>,
+    pub buckets: u64,
+    pub stash: u64,
+    pub offset: u64,
+    pub slot_words: u64,
+}
+
+impl From<&CuckooTable> for CuckooTableInfo {
+    fn from(table: &CuckooTable) -> Self {
+        Self {
+            seeds: table
+                .params
+                .seeds
+                .iter()
+                .map(|s| format!("{:#018x}", s))
+                .collect(),
+            buckets: table.params.num_buckets,
+            stash: table.params.stash_size,
+            offset: table.offset,
+            slot_words: table.slot_words,
+        }
+    }
+}
+
+impl CuckooTableInfo {
+    /// The table a client queries with [`CuckooTable::query_indices`].
+    pub fn to_table(&self) -> Result<CuckooTable> {
+        let seeds = self
+            .seeds
+            .iter()
+            .map(|s| {
+                u64::from_str_radix(s.trim_start_matches("0x"), 16)
+                    .map_err(|_| eyre!("Invalid cuckoo seed {:?}", s))
+            })
+            .collect::<Result<_>>()?;
+        Ok(CuckooTable {
+            params: CuckooParams {
+                seeds,
+                num_buckets: self.buckets,
+                stash_size: self.stash,
+            },
+            offset: self.offset,
+            slot_words: self.slot_words,
+        })
+    }
+}
+
+/// Zero entries written by `--storage-alignment` so contracts' storage runs do not
+/// straddle page boundaries. No mapping points at them; they count towards `total_indices`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PaddingInfo {
+    /// Page size in bytes.
+    pub alignment: u64,
+    /// Number of padding entries.
+    pub entries: u64,
+}
+
+/// Generator settings of a `gen-synthetic` database; its state is random, not a chain's.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SyntheticInfo {
+    pub seed: u64,
+    /// Percent of accounts that are contracts.
+    pub contract_percent: u8,
+    /// Percent of storage slots whose value is zero.
+    pub zero_slot_percent: u8,
+}
+
+/// Both tables of a `--cuckoo` layout.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CuckooInfo {
+    pub kappa: usize,
+    /// Key tag hash (`"blake3"`).
+    pub tag: String,
+    pub accounts: CuckooTableInfo,
+    pub storage: CuckooTableInfo,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    #[serde(default)]
+    pub schema_version: u32,
+    /// EIP-155 chain id, when known.
+    #[serde(default)]
+    pub chain_id: Option<u64>,
+    pub block: u64,
+    #[serde(default)]
+    pub block_hash: Option<String>,
+    #[serde(default)]
+    pub state_root: Option<String>,
+    /// Bytes per database word.
+    #[serde(default = "default_entry_size")]
+    pub entry_size: u64,
+    #[serde(default)]
+    pub layout: RecordLayout,
+    #[serde(default)]
+    pub key_order: KeyOrder,
+    pub accounts: u64,
+    pub storage_slots: u64,
+    /// Words in `database.bin`.
+    pub total_indices: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<FilterInfo>,
+    #[serde(default)]
+    pub files: Vec<FileInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytecode: Option<CodeInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trie: Option<TrieInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cuckoo: Option<CuckooInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub padding: Option<PaddingInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub synthetic: Option<SyntheticInfo>,
+    /// RFC 3339 UTC time the extraction finished (local time in legacy files).
+    pub generated_at: String,
+}
+
+fn default_entry_size() -> u64 {
+    DB_ENTRY_SIZE as u64
+}
+
+impl Metadata {
+    /// Loads `metadata.json` from `dir`, if present.
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(METADATA_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let metadata: Self = serde_json::from_slice(&std::fs::read(path)?)?;
+        if metadata.schema_version > METADATA_SCHEMA_VERSION {
+            bail!(
+                "Unsupported metadata schema version {} (expected at most {})",
+                metadata.schema_version,
+                METADATA_SCHEMA_VERSION
+            );
+        }
+        Ok(Some(metadata))
+    }
+
+    /// Writes `metadata.json` into `dir`, through a synced temporary file renamed into place
+    /// so a crash leaves either the old or the new metadata.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(METADATA_FILE);
+        let tmp = path.with_extension("json.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// The entry for `name` in [`Metadata::files`], if listed.
+    pub fn file(&self, name: &str) -> Option<&FileInfo> {
+        self.files.iter().find(|f| f.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(tag: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("plinko-metadata-{}-{}", tag, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn round_trips_with_cuckoo_seeds() {
+        let dir = temp_dir("round-trip");
+        let table = CuckooTable {
+            params: CuckooParams {
+                seeds: vec![1, u64::MAX],
+                num_buckets: 10,
+                stash_size: 2,
+            },
+            offset: 40,
+            slot_words: 2,
+        };
+        let metadata = Metadata {
+            schema_version: METADATA_SCHEMA_VERSION,
+            chain_id: Some(1),
+            block: 100,
+            block_hash: Some(format!("0x{}", "ab".repeat(32))),
+            state_root: None,
+            entry_size: 32,
+            layout: RecordLayout::new(AccountLayout::Packed, 8),
+            key_order: KeyOrder::Hashed,
+            accounts: 2,
+            storage_slots: 3,
+            total_indices: 9,
+            filter: Some(FilterInfo {
+                skip_zero_storage: true,
+                storage: false,
+                ..FilterInfo::default()
+            }),
+            files: vec![FileInfo {
+                name: "database.bin".to_string(),
+                size: 288,
+                blake3: "00".repeat(32),
+            }],
+            bytecode: None,
+            trie: None,
+            cuckoo: Some(CuckooInfo {
+                kappa: 2,
+                tag: "blake3".to_string(),
+                accounts: (&table).into(),
+                storage: (&table).into(),
+            }),
+            padding: Some(PaddingInfo {
+                alignment: 4096,
+                entries: 0,
+            }),
+            synthetic: None,
+            generated_at: "2025-01-01T00:00:00Z".to_string(),
+        };
+        metadata.save(&dir).unwrap();
+
+        let loaded = Metadata::load(&dir).unwrap().unwrap();
+        assert_eq!(loaded, metadata);
+        assert_eq!(loaded.file("database.bin").unwrap().size, 288);
+        assert_eq!(loaded.cuckoo.unwrap().storage.to_table().unwrap(), table);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loads_legacy_metadata_with_defaults() {
+        let dir = temp_dir("legacy");
+        std::fs::write(
+            dir.join(METADATA_FILE),
+            r#"{
+  "block": 23889314,
+  "accounts": 2,
+  "storage_slots": 5,
+  "total_indices": 11,
+  "generated_at": "2025-11-26 10:00:00"
+}"#,
+        )
+        .unwrap();
+
+        let metadata = Metadata::load(&dir).unwrap().unwrap();
+        assert_eq!(metadata.schema_version, 0);
+        assert_eq!(metadata.entry_size, 32);
+        assert_eq!(metadata.layout, RecordLayout::default());
+        assert_eq!(metadata.key_order, KeyOrder::Plain);
+        assert!(metadata.files.is_empty());
+
+        let mut future = metadata;
+        future.schema_version = METADATA_SCHEMA_VERSION + 1;
+        future.save(&dir).unwrap();
+        assert!(Metadata::load(&dir).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1066 @@
+//! JSON-RPC block feed for the `state-syncer` daemon (`PLINKO_STATE_RPC_URL`).
+//!
+//! [`RpcSource`] polls a node for new blocks and fetches each block's state diff with one of
+//! two tracing APIs ([`DiffMethod`]):
+//!
+//! - `debug_traceBlockByNumber` with the `prestateTracer` in diff mode (geth, reth): per
+//!   transaction, the `pre` and `post` state of every account it modified. `post` only holds
+//!   the fields that changed; an account in `pre` but not `post` was deleted, and a slot in
+//!   `pre` but not `post` was cleared.
+//! - `trace_replayBlockTransactions` with `stateDiff` (reth, erigon, nethermind): per
+//!   transaction, every changed field as `"="`, `{"*": {"from", "to"}}`, `{"+": new}` or
+//!   `{"-": old}`.
+//!
+//! The block's hash and parent hash (`eth_getBlockByNumber`) bind its delta to the chain.
+//! The transactions' diffs are folded into the block's final values, which the
+//! [`Syncer`](crate::syncer::Syncer) resolves through the mappings; fields the diff leaves
+//! out keep their current value in the database.
+//!
+//! Neither API traces what the block itself changes outside its transactions, so these are
+//! added from the block header:
+//!
+//! - withdrawals (Shanghai) credit their amount, in gwei, to the recipient's balance;
+//! - before the Merge, the balances of the miner and the uncle miners are read after the
+//!   block with `eth_getBalance`, as they hold the block rewards;
+//! - the slots the system contracts write are read after the block with
+//!   `eth_getStorageAt`: the EIP-4788 beacon roots ring (Cancun), the EIP-2935 block hash
+//!   ring, and the EIP-7002 and EIP-7251 request queues (Prague).
+//!
+//! Only blocks [`RpcConfig::confirmations`] behind the node's head are followed. A block
+//! that does not build on the last one means a deeper reorg; it is asked for again a few
+//! times in case the node is still switching over, then the feed fails, as the snapshot
+//! already holds the replaced blocks.
+
+use crate::delta::hex;
+use crate::syncer::{parse_bytes, parse_quantity, BlockUpdate, Change, UpdateSource};
+use eyre::{bail, eyre, Result};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha3::{Digest, Keccak256};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// API the state diffs are fetched with.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiffMethod {
+    /// `debug_traceBlockByNumber` with `{"tracer": "prestateTracer", "tracerConfig": {"diffMode": true}}`
+    #[default]
+    Prestate,
+    /// `trace_replayBlockTransactions` with `["stateDiff"]`
+    Trace,
+}
+
+/// A minimal blocking JSON-RPC 2.0 client over HTTP(S).
+pub struct RpcClient {
+    url: String,
+    agent: ureq::Agent,
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse {
+    #[serde(default)]
+    result: Option<Value>,
+    #[serde(default)]
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcClient {
+    pub fn new(url: &str, timeout: Duration) -> Self {
+        Self {
+            url: url.to_string(),
+            agent: ureq::AgentBuilder::new().timeout(timeout).build(),
+            id: 0,
+        }
+    }
+
+    /// Calls `method` and decodes its result.
+    pub fn call<T: DeserializeOwned>(&mut self, method: &str, params: Value) -> Result<T> {
+        self.id += 1;
+        let request = json!({"jsonrpc": "2.0", "id": self.id, "method": method, "params": params});
+        let response: RpcResponse = self
+            .agent
+            .post(&self.url)
+            .send_json(request)
+            .map_err(|e| eyre!("{} failed: {}", method, e))?
+            .into_json()?;
+        if let Some(error) = response.error {
+            bail!("{} failed: {} (code {})", method, error.message, error.code);
+        }
+        let result = response.result.unwrap_or(Value::Null);
+        serde_json::from_value(result).map_err(|e| eyre!("{}: unexpected result: {}", method, e))
+    }
+
+    /// Number of the node's latest block.
+    pub fn block_number(&mut self) -> Result<u64> {
+        let number: String = self.call("eth_blockNumber", json!([]))?;
+        parse_u64(&number)
+    }
+}
+
+/// Settings of an [`RpcSource`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RpcConfig {
+    pub method: DiffMethod,
+    /// How long to wait before asking for a block the node does not have yet.
+    pub poll_interval: Duration,
+    /// Blocks to stay behind the node's head, so shallow reorgs are not published.
+    pub confirmations: u64,
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        Self {
+            method: DiffMethod::Prestate,
+            poll_interval: Duration::from_secs(2),
+            confirmations: DEFAULT_CONFIRMATIONS,
+        }
+    }
+}
+
+/// Default [`RpcConfig::confirmations`]; reorgs on mainnet since the Merge are far shallower.
+pub const DEFAULT_CONFIRMATIONS: u64 = 12;
+
+/// Times a block that does not build on the last one is asked for again, one
+/// [`RpcConfig::poll_interval`] apart, before giving up.
+const REORG_RETRIES: u32 = 3;
+
+/// Blocks from a node, one state diff each.
+pub struct RpcSource {
+    client: RpcClient,
+    config: RpcConfig,
+    next: u64,
+    /// Hash of the last block handed out (or of the snapshot's block), if known.
+    parent: Option<[u8; 32]>,
+}
+
+impl RpcSource {
+    /// Follows the node at `client` from block `first_block`, which must build on
+    /// `parent_hash` when it is known.
+    pub fn new(
+        client: RpcClient,
+        config: RpcConfig,
+        first_block: u64,
+        parent_hash: Option<[u8; 32]>,
+    ) -> Self {
+        Self {
+            client,
+            config,
+            next: first_block,
+            parent: parent_hash,
+        }
+    }
+
+    /// Fetches the hashes and state diff of block `number`.
+    pub fn block(&mut self, number: u64) -> Result<BlockUpdate> {
+        let tag = format!("{:#x}", number);
+        let header: Option<BlockHeader> = self
+            .client
+            .call("eth_getBlockByNumber", json!([tag, false]))?;
+        let header = header.ok_or_else(|| eyre!("Node has no block #{}", number))?;
+
+        let mut diff = BlockDiff::default();
+        match self.config.method {
+            DiffMethod::Prestate => {
+                let traces: Vec<PrestateTrace> = self.client.call(
+                    "debug_traceBlockByNumber",
+                    json!([tag, {"tracer": "prestateTracer", "tracerConfig": {"diffMode": true}}]),
+                )?;
+                for trace in traces {
+                    diff.add_prestate(trace.result)?;
+                }
+            }
+            DiffMethod::Trace => {
+                let traces: Vec<TraceReplay> = self
+                    .client
+                    .call("trace_replayBlockTransactions", json!([tag, ["stateDiff"]]))?;
+                for trace in traces {
+                    diff.add_state_diff(trace.state_diff.unwrap_or_default())?;
+                }
+            }
+        }
+        self.add_block_changes(&tag, number, &header, &mut diff)?;
+        let mut update = diff.into_update(number);
+        update.hash = Some(parse_bytes(&header.hash)?);
+        update.parent_hash = Some(parse_bytes(&header.parent_hash)?);
+        Ok(update)
+    }
+
+    /// Folds in what block `number` changes outside its transactions (see the module docs).
+    fn add_block_changes(
+        &mut self,
+        tag: &str,
+        number: u64,
+        header: &BlockHeader,
+        diff: &mut BlockDiff,
+    ) -> Result<()> {
+        for withdrawal in header.withdrawals.iter().flatten() {
+            let gwei = parse_u64(&withdrawal.amount)?;
+            diff.credit(parse_bytes(&withdrawal.address)?, gwei_to_wei(gwei));
+        }
+
+        let pre_merge = match &header.difficulty {
+            Some(difficulty) => parse_quantity(difficulty)? != [0; 32],
+            None => false,
+        };
+        if pre_merge {
+            let mut miners = header.miner.iter().cloned().collect::<Vec<_>>();
+            for index in 0..header.uncles.len() {
+                let uncle: Option<UncleHeader> = self.client.call(
+                    "eth_getUncleByBlockNumberAndIndex",
+                    json!([tag, format!("{:#x}", index)]),
+                )?;
+                let uncle = uncle
+                    .ok_or_else(|| eyre!("Node has no uncle {} of block #{}", index, number))?;
+                miners.push(uncle.miner);
+            }
+            for miner in miners {
+                let balance: String = self.client.call("eth_getBalance", json!([miner, tag]))?;
+                diff.account(parse_bytes(&miner)?).balance = Some(parse_quantity(&balance)?);
+            }
+        }
+
+        for (address, slot) in system_slots(number, header)? {
+            let value: String = self.client.call(
+                "eth_getStorageAt",
+                json!([
+                    format!("0x{}", hex(&address)),
+                    format!("0x{}", hex(&slot)),
+                    tag
+                ]),
+            )?;
+            diff.storage
+                .insert((address, slot), parse_quantity(&value)?);
+        }
+        Ok(())
+    }
+}
+
+impl UpdateSource for RpcSource {
+    fn next_block(&mut self) -> Result<Option<BlockUpdate>> {
+        loop {
+            let head = self.client.block_number()?;
+            if head.saturating_sub(self.config.confirmations) >= self.next {
+                break;
+            }
+            std::thread::sleep(self.config.poll_interval);
+        }
+        let mut update = self.block(self.next)?;
+        let mut retries = 0;
+        while let (Some(expected), Some(parent)) = (self.parent, update.parent_hash) {
+            if parent == expected {
+                break;
+            }
+            if retries == REORG_RETRIES {
+                bail!(
+                    "Reorg deeper than {} confirmations: block #{} builds on 0x{}, not on the last block applied 0x{}; \
+                     re-extract the snapshot and raise PLINKO_STATE_RPC_CONFIRMATIONS",
+                    self.config.confirmations,
+                    self.next,
+                    hex(&parent),
+                    hex(&expected)
+                );
+            }
+            retries += 1;
+            std::thread::sleep(self.config.poll_interval);
+            update = self.block(self.next)?;
+        }
+        self.parent = update.hash;
+        self.next += 1;
+        Ok(Some(update))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BlockHeader {
+    hash: String,
+    parent_hash: String,
+    #[serde(default)]
+    timestamp: Option<String>,
+    #[serde(default)]
+    miner: Option<String>,
+    /// Non-zero before the Merge.
+    #[serde(default)]
+    difficulty: Option<String>,
+    #[serde(default)]
+    uncles: Vec<String>,
+    /// Since Shanghai.
+    #[serde(default)]
+    withdrawals: Option<Vec<Withdrawal>>,
+    /// Since Cancun.
+    #[serde(default)]
+    parent_beacon_block_root: Option<String>,
+    /// Since Prague.
+    #[serde(default)]
+    requests_hash: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Withdrawal {
+    address: String,
+    /// In gwei.
+    amount: String,
+}
+
+#[derive(Deserialize)]
+struct UncleHeader {
+    miner: String,
+}
+
+/// EIP-4788 contract keeping the parent beacon block root by timestamp.
+const BEACON_ROOTS: [u8; 20] = [
+    0x00, 0x0f, 0x3d, 0xf6, 0xd7, 0x32, 0x80, 0x7e, 0xf1, 0x31, 0x9f, 0xb7, 0xb8, 0xbb, 0x85, 0x22,
+    0xd0, 0xbe, 0xac, 0x02,
+];
+
+/// EIP-2935 contract keeping the parent block hash by number.
+const HISTORY_STORAGE: [u8; 20] = [
+    0x00, 0x00, 0xf9, 0x08, 0x27, 0xf1, 0xc5, 0x3a, 0x10, 0xcb, 0x7a, 0x02, 0x33, 0x5b, 0x17, 0x53,
+    0x20, 0x00, 0x29, 0x35,
+];
+
+/// EIP-7002 withdrawal request queue.
+const WITHDRAWAL_REQUESTS: [u8; 20] = [
+    0x00, 0x00, 0x09, 0x61, 0xef, 0x48, 0x0e, 0xb5, 0x5e, 0x80, 0xd1, 0x9a, 0xd8, 0x35, 0x79, 0xa6,
+    0x4c, 0x00, 0x70, 0x02,
+];
+
+/// EIP-7251 consolidation request queue.
+const CONSOLIDATION_REQUESTS: [u8; 20] = [
+    0x00, 0x00, 0xbb, 0xdd, 0xc7, 0xce, 0x48, 0x86, 0x42, 0xfb, 0x57, 0x9f, 0x8b, 0x00, 0xf3, 0xa5,
+    0x90, 0x00, 0x72, 0x51,
+];
+
+/// Length of the EIP-4788 and EIP-2935 ring buffers.
+const RING_LENGTH: u64 = 8191;
+
+/// Slots (address, big-endian slot) the system contracts write at block `number`.
+fn system_slots(number: u64, header: &BlockHeader) -> Result<Vec<([u8; 20], [u8; 32])>> {
+    let slot = |n: u64| {
+        let mut slot = [0u8; 32];
+        slot[24..].copy_from_slice(&n.to_be_bytes());
+        slot
+    };
+    let mut slots = Vec::new();
+    if header.parent_beacon_block_root.is_some() {
+        let timestamp = header
+            .timestamp
+            .as_deref()
+            .ok_or_else(|| eyre!("Block #{} has no timestamp", number))?;
+        let index = parse_u64(timestamp)? % RING_LENGTH;
+        // The timestamp, and the root at `index + RING_LENGTH`
+        slots.push((BEACON_ROOTS, slot(index)));
+        slots.push((BEACON_ROOTS, slot(index + RING_LENGTH)));
+    }
+    if header.requests_hash.is_some() {
+        slots.push((
+            HISTORY_STORAGE,
+            slot(number.saturating_sub(1) % RING_LENGTH),
+        ));
+        for queue in [WITHDRAWAL_REQUESTS, CONSOLIDATION_REQUESTS] {
+            // Excess requests, request count, queue head and queue tail
+            slots.extend((0..4).map(|i| (queue, slot(i))));
+        }
+    }
+    Ok(slots)
+}
+
+/// 32-byte LE wei of `gwei`.
+fn gwei_to_wei(gwei: u64) -> [u8; 32] {
+    let mut wei = [0u8; 32];
+    wei[..16].copy_from_slice(&(gwei as u128 * 1_000_000_000).to_le_bytes());
+    wei
+}
+
+#[derive(Deserialize)]
+struct PrestateTrace {
+    result: PrestateDiff,
+}
+
+#[derive(Deserialize, Default)]
+struct PrestateDiff {
+    #[serde(default)]
+    pre: BTreeMap<String, PrestateAccount>,
+    #[serde(default)]
+    post: BTreeMap<String, PrestateAccount>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct PrestateAccount {
+    #[serde(default)]
+    balance: Option<String>,
+    /// A number (geth) or a hex quantity.
+    #[serde(default)]
+    nonce: Option<Value>,
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    code_hash: Option<String>,
+    #[serde(default)]
+    storage: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TraceReplay {
+    #[serde(default)]
+    state_diff: Option<BTreeMap<String, TraceAccount>>,
+}
+
+#[derive(Deserialize, Default)]
+struct TraceAccount {
+    #[serde(default)]
+    balance: Option<Value>,
+    #[serde(default)]
+    nonce: Option<Value>,
+    #[serde(default)]
+    code: Option<Value>,
+    #[serde(default)]
+    storage: BTreeMap<String, Value>,
+}
+
+/// Final values of a block, folded from its transactions in order.
+#[derive(Default)]
+struct BlockDiff {
+    accounts: BTreeMap<[u8; 20], AccountFields>,
+    storage: BTreeMap<([u8; 20], [u8; 32]), [u8; 32]>,
+    /// Wei credited after the transactions, in order.
+    credits: Vec<([u8; 20], [u8; 32])>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct AccountFields {
+    nonce: Option<u64>,
+    balance: Option<[u8; 32]>,
+    code_hash: Option<[u8; 32]>,
+}
+
+impl BlockDiff {
+    fn account(&mut self, address: [u8; 20]) -> &mut AccountFields {
+        self.accounts.entry(address).or_default()
+    }
+
+    fn credit(&mut self, address: [u8; 20], amount: [u8; 32]) {
+        self.credits.push((address, amount));
+    }
+
+    /// Folds in one transaction's `prestateTracer` diff.
+    fn add_prestate(&mut self, diff: PrestateDiff) -> Result<()> {
+        let post = diff
+            .post
+            .into_iter()
+            .map(|(address, account)| Ok((parse_bytes(&address)?, account)))
+            .collect::<Result<BTreeMap<[u8; 20], PrestateAccount>>>()?;
+
+        for (address, pre) in &diff.pre {
+            let address = parse_bytes(address)?;
+            let Some(after) = post.get(&address) else {
+                // Deleted
+                *self.account(address) = AccountFields {
+                    nonce: Some(0),
+                    balance: Some([0; 32]),
+                    code_hash: Some([0; 32]),
+                };
+                for slot in pre.storage.keys() {
+                    self.storage.insert((address, parse_bytes(slot)?), [0; 32]);
+                }
+                continue;
+            };
+            // Cleared slots are only in `pre`
+            for slot in pre.storage.keys() {
+                if !after.storage.contains_key(slot) {
+                    self.storage.insert((address, parse_bytes(slot)?), [0; 32]);
+                }
+            }
+        }
+
+        for (address, after) in post {
+            let fields = self.account(address);
+            if let Some(balance) = &after.balance {
+                fields.balance = Some(parse_quantity(balance)?);
+            }
+            if let Some(nonce) = &after.nonce {
+                fields.nonce = Some(parse_nonce(nonce)?);
+            }
+            match (&after.code_hash, &after.code) {
+                (Some(hash), _) => fields.code_hash = Some(code_hash_of(parse_bytes(hash)?)),
+                (None, Some(code)) => fields.code_hash = Some(hash_code(code)?),
+                (None, None) => {}
+            }
+            for (slot, value) in &after.storage {
+                self.storage
+                    .insert((address, parse_bytes(slot)?), parse_quantity(value)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Folds in one transaction's `stateDiff`.
+    fn add_state_diff(&mut self, diff: BTreeMap<String, TraceAccount>) -> Result<()> {
+        for (address, account) in diff {
+            let address = parse_bytes(&address)?;
+            if let Some(balance) = trace_change(account.balance.as_ref())? {
+                self.account(address).balance = Some(match balance {
+                    Some(value) => parse_quantity(&value)?,
+                    None => [0; 32],
+                });
+            }
+            if let Some(nonce) = trace_change(account.nonce.as_ref())? {
+                self.account(address).nonce = Some(match nonce {
+                    Some(value) => parse_u64(&value)?,
+                    None => 0,
+                });
+            }
+            if let Some(code) = trace_change(account.code.as_ref())? {
+                self.account(address).code_hash = Some(match code {
+                    Some(code) => hash_code(&code)?,
+                    None => [0; 32],
+                });
+            }
+            for (slot, change) in &account.storage {
+                if let Some(value) = trace_change(Some(change))? {
+                    let value = match value {
+                        Some(value) => parse_quantity(&value)?,
+                        None => [0; 32],
+                    };
+                    self.storage.insert((address, parse_bytes(slot)?), value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn into_update(self, number: u64) -> BlockUpdate {
+        let accounts = self
+            .accounts
+            .into_iter()
+            // Accounts with only storage changes
+            .filter(|(_, fields)| {
+                fields.nonce.is_some() || fields.balance.is_some() || fields.code_hash.is_some()
+            })
+            .map(|(address, fields)| Change::AccountFields {
+                address,
+                nonce: fields.nonce,
+                balance: fields.balance,
+                code_hash: fields.code_hash,
+            });
+        let storage = self
+            .storage
+            .into_iter()
+            .map(|((address, slot), value)| Change::Storage {
+                address,
+                slot,
+                value,
+            });
+        let credits = self
+            .credits
+            .into_iter()
+            .map(|(address, amount)| Change::Credit { address, amount });
+        BlockUpdate {
+            number,
+            changes: accounts.chain(storage).chain(credits).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+/// New value of a `stateDiff` field: `None` if unchanged (`"="`), `Some(None)` if it died
+/// (`{"-": old}`), otherwise `Some(Some(new))`.
+fn trace_change(change: Option<&Value>) -> Result<Option<Option<String>>> {
+    let Some(change) = change else {
+        return Ok(None);
+    };
+    if change == "=" {
+        return Ok(None);
+    }
+    let text = |value: &Value| {
+        value
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| eyre!("Unexpected stateDiff value {}", value))
+    };
+    if let Some(value) = change.get("+") {
+        return Ok(Some(Some(text(value)?)));
+    }
+    if change.get("-").is_some() {
+        return Ok(Some(None));
+    }
+    match change.get("*").and_then(|c| c.get("to")) {
+        Some(to) => Ok(Some(Some(text(to)?))),
+        None => bail!("Unexpected stateDiff change {}", change),
+    }
+}
+
+fn parse_u64(hex: &str) -> Result<u64> {
+    u64::from_str_radix(hex.trim_start_matches("0x"), 16)
+        .map_err(|_| eyre!("Invalid quantity {:?}", hex))
+}
+
+fn parse_nonce(nonce: &Value) -> Result<u64> {
+    match nonce {
+        Value::Number(n) => n.as_u64().ok_or_else(|| eyre!("Invalid nonce {}", n)),
+        Value::String(hex) => parse_u64(hex),
+        _ => bail!("Invalid nonce {}", nonce),
+    }
+}
+
+/// `keccak256` of the empty code; the extractor stores accounts without code as zero.
+const EMPTY_CODE_HASH: [u8; 32] = [
+    0xc5, 0xd2, 0x46, 0x01, 0x86, 0xf7, 0x23, 0x3c, 0x92, 0x7e, 0x7d, 0xb2, 0xdc, 0xc7, 0x03, 0xc0,
+    0xe5, 0x00, 0xb6, 0x53, 0xca, 0x82, 0x27, 0x3b, 0x7b, 0xfa, 0xd8, 0x04, 0x5d, 0x85, 0xa4, 0x70,
+];
+
+fn code_hash_of(hash: [u8; 32]) -> [u8; 32] {
+    if hash == EMPTY_CODE_HASH {
+        [0; 32]
+    } else {
+        hash
+    }
+}
+
+fn hash_code(code: &str) -> Result<[u8; 32]> {
+    let hex = code.trim_start_matches("0x");
+    if hex.is_empty() {
+        return Ok([0; 32]);
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| eyre!("Invalid code hex"))
+        })
+        .collect::<Result<Vec<u8>>>()?;
+    Ok(code_hash_of(Keccak256::digest(&bytes).into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DB_ENTRY_SIZE;
+    use crate::delta::read_delta;
+    use crate::mapping::{write_index, MappingHeader};
+    use crate::syncer::Syncer;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::{Path, PathBuf};
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/rpc");
+
+    const ALICE: &str = "1111111111111111111111111111111111111111";
+    const TOKEN: &str = "2222222222222222222222222222222222222222";
+
+    /// Serves recorded results by method name (`<method>.json` in `dir`) until dropped.
+    fn mock_node(
+        dir: &'static str,
+        method_files: &'static [(&'static str, &'static str)],
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { return };
+                answer(stream, dir, method_files);
+            }
+        });
+        url
+    }
+
+    fn answer(stream: TcpStream, dir: &str, method_files: &[(&str, &str)]) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        let request: Value = serde_json::from_slice(&body).unwrap();
+
+        let method = request["method"].as_str().unwrap();
+        let file = method_files
+            .iter()
+            .find(|(m, _)| *m == method)
+            .map(|(_, file)| *file)
+            .unwrap_or_else(|| panic!("unexpected call {}", request));
+        let result: Value =
+            serde_json::from_slice(&std::fs::read(Path::new(dir).join(file)).unwrap()).unwrap();
+        let response = json!({"jsonrpc": "2.0", "id": request["id"], "result": result}).to_string();
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            response.len(),
+            response
+        )
+        .unwrap();
+    }
+
+    fn word(value: u64) -> [u8; 32] {
+        let mut word = [0u8; 32];
+        word[..8].copy_from_slice(&value.to_le_bytes());
+        word
+    }
+
+    fn hex20(hex: &str) -> [u8; 20] {
+        parse_bytes(hex).unwrap()
+    }
+
+    /// A snapshot at block 100 holding ALICE (nonce 4, 1 ether), TOKEN (a contract) and two
+    /// of TOKEN's slots.
+    fn snapshot(tag: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("plinko-rpc-{}-{}", tag, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let token_code =
+            parse_bytes::<32>("0x1dfa6b7d1ba0cd6b78cc5511dbf649bf078cc47a738e9bd0a693ac0021c96ce5")
+                .unwrap();
+        let entries = [
+            word(4),
+            parse_quantity("0xde0b6b3a7640000").unwrap(),
+            [0; 32],
+            word(1),
+            [0; 32],
+            token_code,
+            word(0x64),
+            word(0x2a),
+        ];
+        std::fs::write(dir.join("database.bin"), entries.concat()).unwrap();
+
+        let mut accounts = MappingHeader::new(20, 4, 2).encode().to_vec();
+        for (address, index) in [(ALICE, 0), (TOKEN, 3)] {
+            accounts.extend_from_slice(&hex20(address));
+            write_index(&mut accounts, index, 4).unwrap();
+        }
+        std::fs::write(dir.join("account-mapping.bin"), accounts).unwrap();
+
+        let mut storage = MappingHeader::new(52, 4, 2).encode().to_vec();
+        for (slot, index) in [(0u8, 6), (1, 7)] {
+            storage.extend_from_slice(&hex20(TOKEN));
+            let mut key = [0u8; 32];
+            key[31] = slot;
+            storage.extend_from_slice(&key);
+            write_index(&mut storage, index, 4).unwrap();
+        }
+        std::fs::write(dir.join("storage-mapping.bin"), storage).unwrap();
+
+        let metadata = format!(
+            r#"{{"schema_version": 1, "block": 100, "accounts": 2, "storage_slots": 2,
+                "total_indices": {}, "generated_at": "2025-01-01T00:00:00Z"}}"#,
+            entries.len()
+        );
+        std::fs::write(dir.join("metadata.json"), metadata).unwrap();
+        dir
+    }
+
+    /// Syncs block 101 from a node serving `files`, with `unmapped` changes to accounts or
+    /// slots without an index.
+    fn sync(
+        tag: &str,
+        method: DiffMethod,
+        files: &'static [(&'static str, &'static str)],
+        unmapped: u64,
+    ) -> PathBuf {
+        let dir = snapshot(tag);
+        let mut syncer = Syncer::open(
+            &dir.join("database.bin"),
+            &dir.join("account-mapping.bin"),
+            Some(&dir.join("storage-mapping.bin")),
+            &dir.join("public"),
+        )
+        .unwrap();
+        let client = RpcClient::new(&mock_node(FIXTURES, files), Duration::from_secs(5));
+        let config = RpcConfig {
+            method,
+            confirmations: 0,
+            ..Default::default()
+        };
+        let mut source = RpcSource::new(client, config, 101, Some([0x64; 32]));
+        let update = source.next_block().unwrap().unwrap();
+        let summary = syncer.apply(&update).unwrap();
+        assert_eq!(summary.unmapped, unmapped);
+        dir
+    }
+
+    fn entries(dir: &Path) -> Vec<[u8; 32]> {
+        std::fs::read(dir.join("database.bin"))
+            .unwrap()
+            .chunks(DB_ENTRY_SIZE)
+            .map(|chunk| chunk.try_into().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn prestate_and_trace_diffs_agree() {
+        let prestate = sync(
+            "prestate",
+            DiffMethod::Prestate,
+            &[
+                ("eth_blockNumber", "eth_blockNumber.json"),
+                ("eth_getBlockByNumber", "eth_getBlockByNumber-101.json"),
+                (
+                    "debug_traceBlockByNumber",
+                    "debug_traceBlockByNumber-101.json",
+                ),
+            ],
+            // A contract created in the block has no index
+            1,
+        );
+        let trace = sync(
+            "trace",
+            DiffMethod::Trace,
+            &[
+                ("eth_blockNumber", "eth_blockNumber.json"),
+                ("eth_getBlockByNumber", "eth_getBlockByNumber-101.json"),
+                (
+                    "trace_replayBlockTransactions",
+                    "trace_replayBlockTransactions-101.json",
+                ),
+            ],
+            1,
+        );
+
+        // ALICE sent two transactions (nonce 4 -> 6) and paid 0.5 ether plus fees; TOKEN's
+        // slot 0 went 0x64 -> 0x65 -> 0x66 and slot 1 was cleared. TOKEN's code is unchanged.
+        let expected = entries(&prestate);
+        assert_eq!(expected[0], word(6));
+        assert_eq!(expected[1], parse_quantity("0x6e5b2dde55e8000").unwrap());
+        assert_eq!(expected[3..6], entries(&snapshot("original"))[3..6]);
+        assert_eq!(expected[6], word(0x66));
+        assert_eq!(expected[7], [0; 32]);
+        assert_eq!(entries(&trace), expected);
+
+        let delta = |dir: &Path| read_delta(&dir.join("public/deltas/delta-000101.bin")).unwrap();
+        assert_eq!(delta(&prestate), delta(&trace));
+        let binding = delta(&prestate).binding.unwrap();
+        assert_eq!(binding.parent_hash, Some([0x64; 32]));
+        assert_eq!(binding.block_hash, Some([0x65; 32]));
+        for dir in [prestate, trace, snapshot("original")] {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn applies_withdrawals_and_system_contract_writes() {
+        let dir = sync(
+            "prague",
+            DiffMethod::Prestate,
+            &[
+                ("eth_blockNumber", "eth_blockNumber.json"),
+                (
+                    "eth_getBlockByNumber",
+                    "eth_getBlockByNumber-101-prague.json",
+                ),
+                (
+                    "debug_traceBlockByNumber",
+                    "debug_traceBlockByNumber-101.json",
+                ),
+                ("eth_getStorageAt", "eth_getStorageAt.json"),
+            ],
+            // The created contract, the withdrawal to 0x33.. and the 11 system contract slots
+            13,
+        );
+        // The transactions' balance, plus 1 ether and 1 gwei withdrawn after them
+        let balance = entries(&dir)[1];
+        assert_eq!(balance, parse_quantity("0x14c66991c85d4a00").unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let node = mock_node(
+            FIXTURES,
+            &[
+                (
+                    "eth_getBlockByNumber",
+                    "eth_getBlockByNumber-101-prague.json",
+                ),
+                (
+                    "debug_traceBlockByNumber",
+                    "debug_traceBlockByNumber-101.json",
+                ),
+                ("eth_getStorageAt", "eth_getStorageAt.json"),
+            ],
+        );
+        let client = RpcClient::new(&node, Duration::from_secs(5));
+        let update = RpcSource::new(client, RpcConfig::default(), 101, None)
+            .block(101)
+            .unwrap();
+        let slot = |n: u64| {
+            let mut slot = [0u8; 32];
+            slot[24..].copy_from_slice(&n.to_be_bytes());
+            slot
+        };
+        // 0x6553f100 % 8191 = 7096; block 101 stores block 100's hash
+        for (address, slot) in [
+            (BEACON_ROOTS, slot(7096)),
+            (BEACON_ROOTS, slot(7096 + 8191)),
+            (HISTORY_STORAGE, slot(100)),
+            (WITHDRAWAL_REQUESTS, slot(3)),
+            (CONSOLIDATION_REQUESTS, slot(0)),
+        ] {
+            let change = Change::Storage {
+                address,
+                slot,
+                value: word(0x2a),
+            };
+            assert!(update.changes.contains(&change), "{:?}", change);
+        }
+        let credits: Vec<_> = update
+            .changes
+            .iter()
+            .filter(|change| matches!(change, Change::Credit { .. }))
+            .copied()
+            .collect();
+        assert_eq!(
+            credits,
+            [
+                Change::Credit {
+                    address: hex20(ALICE),
+                    amount: parse_quantity("0xde0b6b3a7640000").unwrap(),
+                },
+                Change::Credit {
+                    address: [0x33; 20],
+                    amount: word(5_000_000_000),
+                },
+                Change::Credit {
+                    address: hex20(ALICE),
+                    amount: word(1_000_000_000),
+                },
+            ]
+        );
+        // Credits come after the transactions' balances
+        assert!(matches!(update.changes.last(), Some(Change::Credit { .. })));
+    }
+
+    #[test]
+    fn reads_block_rewards_before_the_merge() {
+        let dir = sync(
+            "pow",
+            DiffMethod::Prestate,
+            &[
+                ("eth_blockNumber", "eth_blockNumber.json"),
+                ("eth_getBlockByNumber", "eth_getBlockByNumber-101-pow.json"),
+                (
+                    "debug_traceBlockByNumber",
+                    "debug_traceBlockByNumber-101.json",
+                ),
+                (
+                    "eth_getUncleByBlockNumberAndIndex",
+                    "eth_getUncleByBlockNumberAndIndex.json",
+                ),
+                ("eth_getBalance", "eth_getBalance.json"),
+            ],
+            1,
+        );
+        // ALICE mined the block and TOKEN's owner the uncle; both hold 2 ether afterwards
+        let entries = entries(&dir);
+        assert_eq!(entries[1], parse_quantity("0x1bc16d674ec80000").unwrap());
+        assert_eq!(entries[4], parse_quantity("0x1bc16d674ec80000").unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reorgs_deeper_than_the_confirmations_fail() {
+        let node = mock_node(
+            FIXTURES,
+            &[
+                ("eth_blockNumber", "eth_blockNumber.json"),
+                ("eth_getBlockByNumber", "eth_getBlockByNumber-101.json"),
+                (
+                    "debug_traceBlockByNumber",
+                    "debug_traceBlockByNumber-101.json",
+                ),
+            ],
+        );
+        let config = RpcConfig {
+            poll_interval: Duration::from_millis(1),
+            confirmations: 0,
+            ..Default::default()
+        };
+        let client = RpcClient::new(&node, Duration::from_secs(5));
+        // The snapshot is at a block 0x99.. that block 101 no longer builds on
+        let mut source = RpcSource::new(client, config, 101, Some([0x99; 32]));
+        let err = source.next_block().unwrap_err().to_string();
+        assert!(
+            err.contains("Reorg deeper than 0 confirmations: block #101 builds on 0x6464"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn waits_for_confirmations() {
+        let node = mock_node(FIXTURES, &[("eth_blockNumber", "eth_blockNumber.json")]);
+        let config = RpcConfig {
+            poll_interval: Duration::from_millis(50),
+            ..Default::default()
+        };
+        assert_eq!(config.confirmations, DEFAULT_CONFIRMATIONS);
+        let client = RpcClient::new(&node, Duration::from_secs(5));
+        // The head is 101, so block 101 is not confirmed yet: the source keeps polling
+        let mut source = RpcSource::new(client, config, 101, None);
+        let (done, result) = std::sync::mpsc::channel();
+        std::thread::spawn(move || done.send(source.next_block().is_ok()).unwrap());
+        assert!(result.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn rpc_errors_are_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let body =
+                r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32601,"message":"Method not found"}}"#;
+            let mut stream = stream;
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+        let mut source = RpcSource::new(
+            RpcClient::new(&url, Duration::from_secs(5)),
+            RpcConfig::default(),
+            1,
+            None,
+        );
+        let err = source.block(1).unwrap_err().to_string();
+        assert!(err.contains("Method not found (code -32601)"), "{}", err);
+    }
+
+    #[test]
+    fn hashes_code_like_the_extractor() {
+        assert_eq!(hash_code("0x").unwrap(), [0; 32]);
+        assert_eq!(code_hash_of(EMPTY_CODE_HASH), [0; 32]);
+        // keccak256(0x00)
+        assert_eq!(
+            hash_code("0x00").unwrap(),
+            parse_bytes::<32>("0xbc36789e7a1e281436464229828f817d6612f7b477d66591ff96a9e064bcc98a")
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,286 @@
+//! Simulated block feed for the `state-syncer` daemon (`PLINKO_STATE_SIMULATED`).
+//!
+//! [`SimulatedSource`] is an [`UpdateSource`] that changes random accounts and slots of the
+//! snapshot every block, at a fixed block time, so the delta pipeline can be load tested and
+//! clients developed without a node. Block `n` is drawn from a ChaCha20 stream keyed by the
+//! seed and `n`: starting from the same snapshot, the same seed always produces the same
+//! blocks and delta files, however often the daemon is restarted.
+//!
+//! Changed accounts keep their code hash and bump their nonce, with a new balance; changed
+//! slots get a new value. Values follow the shapes of [`crate::synthetic`]. Blocks get
+//! made-up hashes derived from the seed, chained from the first block produced.
+
+use crate::db::DB_ENTRY_SIZE;
+use crate::layout::{AccountLayout, AccountRecord};
+use crate::mapping::{AccountMapping, StorageMapping};
+use crate::syncer::{BlockUpdate, Change, UpdateSource};
+use crate::synthetic::{balance, slot_value};
+use eyre::{eyre, Result};
+use memmap2::Mmap;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::fs::File;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Percent of the changed accounts whose new balance is zero.
+const EMPTY_PERCENT: u32 = 10;
+/// Percent of the changed slots whose new value is zero.
+const ZERO_SLOT_PERCENT: u8 = 20;
+
+/// What [`SimulatedSource`] produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimulationConfig {
+    pub seed: u64,
+    /// Accounts changed per block.
+    pub accounts: u64,
+    /// Storage slots changed per block.
+    pub slots: u64,
+    /// Time between blocks; zero produces them as fast as they are applied.
+    pub block_time: Duration,
+    /// Stop after this many blocks.
+    pub blocks: Option<u64>,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            accounts: 200,
+            slots: 1000,
+            block_time: Duration::from_secs(12),
+            blocks: None,
+        }
+    }
+}
+
+/// Pseudo-random blocks over the keys of a snapshot.
+pub struct SimulatedSource {
+    config: SimulationConfig,
+    accounts: AccountMapping,
+    storage: Option<StorageMapping>,
+    layout: AccountLayout,
+    /// Read-only view of `database.bin`, which the syncer updates through a shared mapping,
+    /// for the current nonce and code hash of changed accounts.
+    db: Mmap,
+    first: u64,
+    next: u64,
+    produced: u64,
+    /// When the next block is due.
+    due: Option<Instant>,
+}
+
+impl SimulatedSource {
+    /// Changes the keys in the mappings of the snapshot at `db_path`, starting at block
+    /// `first_block`.
+    pub fn open(
+        config: SimulationConfig,
+        db_path: &Path,
+        account_mapping: &Path,
+        storage_mapping: Option<&Path>,
+        layout: AccountLayout,
+        first_block: u64,
+    ) -> Result<Self> {
+        let db = unsafe { Mmap::map(&File::open(db_path)?)? };
+        Ok(Self {
+            config,
+            accounts: AccountMapping::open(account_mapping)?,
+            storage: storage_mapping.map(StorageMapping::open).transpose()?,
+            layout,
+            db,
+            first: first_block,
+            next: first_block,
+            produced: 0,
+            due: None,
+        })
+    }
+
+    /// The changes of block `number`.
+    pub fn block(&self, number: u64) -> Result<BlockUpdate> {
+        let mut rng = ChaCha20Rng::seed_from_u64(self.config.seed);
+        rng.set_stream(number);
+
+        let mut changes = Vec::new();
+        if !self.accounts.is_empty() {
+            for _ in 0..self.config.accounts {
+                let i = rng.gen_range(0..self.accounts.len());
+                let (address, index) = self.accounts.record(i).expect("record below count");
+                let start = index as usize * DB_ENTRY_SIZE;
+                let entry = self
+                    .db
+                    .get(start..start + self.layout.words() as usize * DB_ENTRY_SIZE)
+                    .ok_or_else(|| eyre!("Account index {} is beyond the database", index))?;
+                let current = self.layout.decode(entry)?;
+                changes.push(Change::Account {
+                    address: address.try_into()?,
+                    account: AccountRecord {
+                        nonce: current.nonce.unwrap_or(0).wrapping_add(1),
+                        balance: balance(&mut rng, EMPTY_PERCENT),
+                        code_hash: current.code_hash.unwrap_or_default(),
+                    },
+                });
+            }
+        }
+        if let Some(storage) = self.storage.as_ref().filter(|s| !s.is_empty()) {
+            for _ in 0..self.config.slots {
+                let i = rng.gen_range(0..storage.len());
+                let (address, slot, _) = storage.slot(i).expect("slot below count");
+                changes.push(Change::Storage {
+                    address: address.try_into()?,
+                    slot: slot.try_into()?,
+                    value: slot_value(&mut rng, ZERO_SLOT_PERCENT),
+                });
+            }
+        }
+        Ok(BlockUpdate {
+            number,
+            hash: Some(self.hash(number)),
+            // The snapshot's own hash is real
+            parent_hash: (number > self.first).then(|| self.hash(number - 1)),
+            changes,
+        })
+    }
+
+    /// Made-up hash of block `number`, so clients can exercise their chain checks.
+    fn hash(&self, number: u64) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(b"plinko-simulated-block");
+        hasher.update(&self.config.seed.to_le_bytes());
+        hasher.update(&number.to_le_bytes());
+        *hasher.finalize().as_bytes()
+    }
+}
+
+impl UpdateSource for SimulatedSource {
+    fn next_block(&mut self) -> Result<Option<BlockUpdate>> {
+        if self
+            .config
+            .blocks
+            .is_some_and(|blocks| self.produced >= blocks)
+        {
+            return Ok(None);
+        }
+        // Keep a steady rate however long applying a block takes
+        let due = *self.due.get_or_insert_with(Instant::now);
+        if let Some(wait) = due.checked_duration_since(Instant::now()) {
+            std::thread::sleep(wait);
+        }
+        self.due = Some(due + self.config.block_time);
+
+        let update = self.block(self.next)?;
+        self.next += 1;
+        self.produced += 1;
+        Ok(Some(update))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syncer::Syncer;
+    use crate::synthetic::{generate, SyntheticConfig};
+    use std::path::PathBuf;
+
+    fn snapshot(tag: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("plinko-simulated-{}-{}", tag, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = SyntheticConfig {
+            accounts: 100,
+            storage_slots: 400,
+            ..Default::default()
+        };
+        generate(&dir, &config).unwrap();
+        dir
+    }
+
+    /// Runs `blocks` simulated blocks over a fresh snapshot and returns its directory.
+    fn simulate(tag: &str, blocks: u64) -> PathBuf {
+        let dir = snapshot(tag);
+        let mut syncer = Syncer::open(
+            &dir.join("database.bin"),
+            &dir.join("account-mapping.bin"),
+            Some(&dir.join("storage-mapping.bin")),
+            &dir.join("public"),
+        )
+        .unwrap();
+        let config = SimulationConfig {
+            seed: 7,
+            accounts: 5,
+            slots: 20,
+            block_time: Duration::ZERO,
+            blocks: Some(blocks),
+        };
+        let mut source = SimulatedSource::open(
+            config,
+            &dir.join("database.bin"),
+            &dir.join("account-mapping.bin"),
+            Some(&dir.join("storage-mapping.bin")),
+            AccountLayout::Standard,
+            1,
+        )
+        .unwrap();
+
+        let mut applied = 0;
+        crate::syncer::run(&mut syncer, &mut source, |summary| {
+            assert_eq!(summary.unmapped, 0);
+            assert!(summary.records > 0);
+            applied += 1;
+        })
+        .unwrap();
+        assert_eq!(applied, blocks);
+        dir
+    }
+
+    #[test]
+    fn same_seed_same_deltas() {
+        let a = simulate("a", 3);
+        let b = simulate("b", 3);
+        for block in 1..=3 {
+            let name = format!("public/deltas/delta-{:06}.bin", block);
+            assert_eq!(
+                std::fs::read(a.join(&name)).unwrap(),
+                std::fs::read(b.join(&name)).unwrap(),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(
+            std::fs::read(a.join("database.bin")).unwrap(),
+            std::fs::read(b.join("database.bin")).unwrap()
+        );
+        std::fs::remove_dir_all(a).unwrap();
+        std::fs::remove_dir_all(b).unwrap();
+    }
+
+    #[test]
+    fn accounts_keep_code_and_bump_nonce() {
+        let dir = snapshot("fields");
+        let source = SimulatedSource::open(
+            SimulationConfig::default(),
+            &dir.join("database.bin"),
+            &dir.join("account-mapping.bin"),
+            None,
+            AccountLayout::Standard,
+            1,
+        )
+        .unwrap();
+        let update = source.block(42).unwrap();
+        assert_eq!(update, source.block(42).unwrap());
+        assert_ne!(update, source.block(43).unwrap());
+        // No storage mapping, no slot changes
+        assert_eq!(update.changes.len(), 200);
+
+        for change in &update.changes {
+            let Change::Account { address, account } = change else {
+                panic!("unexpected {:?}", change);
+            };
+            let index = source.accounts.get(address).unwrap() as usize;
+            let entry = &source.db[index * DB_ENTRY_SIZE..(index + 3) * DB_ENTRY_SIZE];
+            let current = AccountLayout::Standard.decode(entry).unwrap();
+            assert_eq!(account.nonce, current.nonce.unwrap() + 1);
+            assert_eq!(Some(account.code_hash), current.code_hash);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1261 @@
+//! Live updates of a snapshot, for the `state-syncer` daemon.
+//!
+//! A [`Syncer`] owns an extracted `database.bin` and its mappings and applies one block of
+//! state changes at a time: each change is resolved to its index through the mappings, the
+//! entry is rewritten in place with [`Database::update`], and the XOR of the old and new
+//! entry is recorded. The records of every block are published as
+//! `<public root>/deltas/delta-<block>.bin` ([`crate::delta`]), which clients fetch over
+//! HTTP ([`serve`]) to patch their hints. Each delta is bound to the block's hash and its
+//! parent's when the source knows them, and a block whose parent is not the last block
+//! applied is refused. At the end of every hour and day of blocks ([`RangeLevel`]) the
+//! block deltas are also compacted into one range delta under `deltas/hourly` and
+//! `deltas/daily`, for clients catching up after being offline.
+//!
+//! A block is made durable in three steps: its delta file is written and synced, then the
+//! database is changed and flushed and the range deltas the block completes are written, then
+//! `metadata.json` records the block. A crash between the steps leaves a delta for a block the
+//! metadata does not record yet; the block is applied again on restart, and the delta already
+//! published is kept if it matches the block's changes and refused otherwise, so clients never
+//! see a delta rewritten. Its ranges are compacted again from the same files.
+//!
+//! The inputs are checked against the `manifest.json` next to them when it lists them. As
+//! the database no longer matches its hashes once it changes, and rehashing it every block
+//! would take too long, its entries in `manifest.json` and `metadata.json` are dropped before
+//! the first change; `plinko_hints` then skips its integrity check.
+//!
+//! Changes come from an [`UpdateSource`]. Accounts and slots created after the snapshot have
+//! no index and are skipped, as in the extractor's `deltas` command.
+
+use crate::db::{Database, DB_ENTRY_SIZE, DB_ENTRY_U64_COUNT};
+use crate::delta::{
+    compact_files, delta_path, hex, range_path, read_delta, write_delta, ChainBinding, DeltaRecord,
+};
+use crate::layout::{AccountLayout, AccountRecord, DecodedAccount};
+use crate::manifest::Manifest;
+use crate::mapping::{AccountMapping, StorageMapping};
+use crate::metadata::KeyOrder;
+use eyre::{bail, ensure, eyre, Result};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+/// New state of one account or slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// Every field of the account after the block.
+    Account {
+        address: [u8; 20],
+        account: AccountRecord,
+    },
+    /// The fields of the account that changed; the others keep their current value.
+    AccountFields {
+        address: [u8; 20],
+        nonce: Option<u64>,
+        balance: Option<[u8; 32]>,
+        code_hash: Option<[u8; 32]>,
+    },
+    /// Value of the slot after the block (32 bytes LE).
+    Storage {
+        address: [u8; 20],
+        slot: [u8; 32],
+        value: [u8; 32],
+    },
+    /// Wei (32 bytes LE) added to the account's balance after the changes before it, such as
+    /// a withdrawal.
+    Credit { address: [u8; 20], amount: [u8; 32] },
+}
+
+/// The changes of one block.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockUpdate {
+    pub number: u64,
+    /// Hash of the block and of its parent, if the source knows them.
+    pub hash: Option<[u8; 32]>,
+    pub parent_hash: Option<[u8; 32]>,
+    pub changes: Vec<Change>,
+}
+
+/// A feed of blocks, in order.
+pub trait UpdateSource {
+    /// Waits for the next block; `None` once the feed has ended.
+    fn next_block(&mut self) -> Result<Option<BlockUpdate>>;
+}
+
+/// Result of applying one block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockSummary {
+    pub block: u64,
+    pub records: u64,
+    /// Changes to accounts or slots without an index.
+    pub unmapped: u64,
+    pub path: PathBuf,
+    /// Range deltas completed by this block.
+    pub ranges: Vec<PathBuf>,
+}
+
+/// Range deltas published every `blocks` blocks, under `deltas/<name>`. A range covers
+/// blocks `k * blocks..=(k + 1) * blocks - 1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeLevel {
+    pub name: String,
+    pub blocks: u64,
+}
+
+impl RangeLevel {
+    pub fn new(name: &str, blocks: u64) -> Self {
+        Self {
+            name: name.to_string(),
+            blocks,
+        }
+    }
+
+    /// Hourly (300 blocks) and daily (7200 blocks) ranges, at 12-second blocks.
+    pub fn defaults() -> Vec<Self> {
+        vec![Self::new("hourly", 300), Self::new("daily", 7200)]
+    }
+}
+
+/// A snapshot being kept up to date.
+pub struct Syncer {
+    db: Database,
+    accounts: AccountMapping,
+    storage: Option<StorageMapping>,
+    layout: AccountLayout,
+    deltas_dir: PathBuf,
+    /// Finest first.
+    ranges: Vec<RangeLevel>,
+    /// Last block applied (the snapshot's block until the first update).
+    block: Option<u64>,
+    /// Hash of `block`, if known.
+    hash: Option<[u8; 32]>,
+    /// Whether the database's manifest and metadata entries were dropped.
+    unlisted: bool,
+}
+
+impl Syncer {
+    /// Opens `db_path` for in-place updates, with the mappings at `account_mapping` and
+    /// (when given) `storage_mapping`, publishing deltas under `public_root/deltas`.
+    pub fn open(
+        db_path: &Path,
+        account_mapping: &Path,
+        storage_mapping: Option<&Path>,
+        public_root: &Path,
+    ) -> Result<Self> {
+        for path in [Some(db_path), Some(account_mapping), storage_mapping]
+            .into_iter()
+            .flatten()
+        {
+            check_manifest(path)?;
+        }
+        let db = Database::load(db_path)?;
+        let layout = match &db.metadata {
+            Some(metadata) => {
+                ensure!(
+                    metadata.key_order == KeyOrder::Plain,
+                    "Updates are keyed by address; hashed snapshots are not supported"
+                );
+                ensure!(
+                    metadata.cuckoo.is_none(),
+                    "Cuckoo layouts have no mapping files to resolve updates through"
+                );
+                metadata.layout.account_layout
+            }
+            None => AccountLayout::Standard,
+        };
+        let accounts = AccountMapping::open(account_mapping)?;
+        ensure!(
+            !accounts.is_hashed(),
+            "{:?} is keyed by hashed address",
+            account_mapping
+        );
+        let storage = storage_mapping.map(StorageMapping::open).transpose()?;
+
+        let deltas_dir = public_root.join("deltas");
+        std::fs::create_dir_all(&deltas_dir)?;
+        let block = db.metadata.as_ref().map(|metadata| metadata.block);
+        let hash = db
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.block_hash.as_deref())
+            .map(parse_bytes)
+            .transpose()?;
+        Ok(Self {
+            db,
+            accounts,
+            storage,
+            layout,
+            deltas_dir,
+            ranges: RangeLevel::defaults(),
+            block,
+            hash,
+            unlisted: false,
+        })
+    }
+
+    /// Last block applied, or the snapshot's block before any update.
+    pub fn block(&self) -> Option<u64> {
+        self.block
+    }
+
+    /// Hash of the last block applied, if known.
+    pub fn hash(&self) -> Option<[u8; 32]> {
+        self.hash
+    }
+
+    /// Account layout of the snapshot's entries.
+    pub fn layout(&self) -> AccountLayout {
+        self.layout
+    }
+
+    pub fn database(&self) -> &Database {
+        &self.db
+    }
+
+    /// Replaces the range deltas published (default: [`RangeLevel::defaults`]); levels of
+    /// zero blocks are dropped.
+    pub fn set_ranges(&mut self, mut ranges: Vec<RangeLevel>) {
+        ranges.retain(|level| level.blocks > 0);
+        ranges.sort_by_key(|level| level.blocks);
+        self.ranges = ranges;
+    }
+
+    /// Applies `update` and publishes its delta file. Blocks must follow the last one (and
+    /// build on its hash, when both are known).
+    pub fn apply(&mut self, update: &BlockUpdate) -> Result<BlockSummary> {
+        if let Some(block) = self.block {
+            ensure!(
+                update.number == block + 1,
+                "Expected block #{}, got #{}",
+                block + 1,
+                update.number
+            );
+        }
+        if let (Some(parent), Some(hash)) = (update.parent_hash, self.hash) {
+            ensure!(
+                parent == hash,
+                "Block #{} builds on 0x{}, not on the last block applied 0x{} (reorg?)",
+                update.number,
+                hex(&parent),
+                hex(&hash)
+            );
+        }
+
+        // New value of every entry the block changes, before anything is written
+        let mut entries = BTreeMap::new();
+        let mut unmapped = 0;
+        for change in &update.changes {
+            match *change {
+                Change::Account { address, account } => match self.accounts.get(&address) {
+                    Some(index) => self.stage_account(index, &account, &mut entries)?,
+                    None => unmapped += 1,
+                },
+                Change::AccountFields {
+                    address,
+                    nonce,
+                    balance,
+                    code_hash,
+                } => match self.accounts.get(&address) {
+                    Some(index) => {
+                        let current = self.account(index, &entries)?;
+                        let account = AccountRecord {
+                            nonce: nonce.or(current.nonce).unwrap_or(0),
+                            balance: balance.unwrap_or(current.balance),
+                            code_hash: code_hash.or(current.code_hash).unwrap_or_default(),
+                        };
+                        self.stage_account(index, &account, &mut entries)?;
+                    }
+                    None => unmapped += 1,
+                },
+                Change::Storage {
+                    address,
+                    slot,
+                    value,
+                } => match self.storage.as_ref().and_then(|s| s.get(&address, &slot)) {
+                    Some(index) => {
+                        self.stored(index)?;
+                        entries.insert(index, value);
+                    }
+                    None => unmapped += 1,
+                },
+                Change::Credit { address, amount } => match self.accounts.get(&address) {
+                    Some(index) => {
+                        let current = self.account(index, &entries)?;
+                        let balance = add(&current.balance, &amount).ok_or_else(|| {
+                            eyre!(
+                                "Balance of 0x{} overflows at block #{}",
+                                hex(&address),
+                                update.number
+                            )
+                        })?;
+                        let account = AccountRecord {
+                            nonce: current.nonce.unwrap_or(0),
+                            balance,
+                            code_hash: current.code_hash.unwrap_or_default(),
+                        };
+                        self.stage_account(index, &account, &mut entries)?;
+                    }
+                    None => unmapped += 1,
+                },
+            }
+        }
+        let mut records: Vec<DeltaRecord> = Vec::new();
+        for (&index, new) in &entries {
+            let delta = xor(&words(&self.stored(index)?), &words(new));
+            if delta != [0u64; DB_ENTRY_U64_COUNT] {
+                records.push((index, delta));
+            }
+        }
+
+        // Publish the delta, then change the database and publish the ranges it completes,
+        // then record the block as applied
+        let path = delta_path(&self.deltas_dir, update.number);
+        let binding =
+            ChainBinding::block(update.number, update.parent_hash.or(self.hash), update.hash);
+        let records = if path.exists() {
+            self.check_published(&path, &binding, &entries)?
+        } else {
+            write_delta(&path, &binding, &records)?;
+            records.len() as u64
+        };
+        if !self.unlisted {
+            self.unlist_database()?;
+        }
+        for (&index, new) in &entries {
+            self.db.update(index, words(new));
+        }
+        self.db.flush()?;
+        let ranges = self.publish_ranges(update.number)?;
+        if let Some(metadata) = self.db.metadata.as_mut() {
+            metadata.block = update.number;
+            metadata.block_hash = update.hash.map(|hash| format!("0x{}", hex(&hash)));
+        }
+        self.db.save_metadata()?;
+        self.block = Some(update.number);
+        self.hash = update.hash;
+
+        Ok(BlockSummary {
+            block: update.number,
+            records,
+            unmapped,
+            path,
+            ranges,
+        })
+    }
+
+    /// Drops the database from `manifest.json` and `metadata.json` before it is first
+    /// changed, so that neither vouches for contents it no longer has.
+    fn unlist_database(&mut self) -> Result<()> {
+        let path = self.db.path.clone();
+        let (dir, name) = file_dir_and_name(&path)?;
+        if let Some(mut manifest) = Manifest::load(dir)? {
+            if manifest.entry(name).is_some() {
+                manifest.files.retain(|file| file.name != name);
+                manifest.save(dir)?;
+            }
+        }
+        if let Some(metadata) = self.db.metadata.as_mut() {
+            metadata.files.retain(|file| file.name != name);
+        }
+        self.db.save_metadata()?;
+        self.unlisted = true;
+        Ok(())
+    }
+
+    /// Checks the delta a crashed run already published for this block against the block's
+    /// `entries`, returning its record count. The database may or may not hold the new
+    /// entries yet, so each one must either already be there or be what the published
+    /// record turns the current entry into.
+    fn check_published(
+        &self,
+        path: &Path,
+        binding: &ChainBinding,
+        entries: &BTreeMap<u64, [u8; 32]>,
+    ) -> Result<u64> {
+        let published = read_delta(path)?;
+        let refuse = |reason: &str| -> Result<u64> {
+            bail!(
+                "{:?} is already published and {}; refusing to overwrite it",
+                path,
+                reason
+            )
+        };
+        if published.binding.as_ref() != Some(binding) {
+            return refuse("belongs to another chain or block");
+        }
+        let records: BTreeMap<u64, [u64; DB_ENTRY_U64_COUNT]> =
+            published.records.iter().copied().collect();
+        if records.len() != published.records.len()
+            || records.keys().any(|index| !entries.contains_key(index))
+        {
+            return refuse("changes other entries than the block");
+        }
+        for (&index, new) in entries {
+            let current = words(&self.stored(index)?);
+            let new = words(new);
+            let delta = records.get(&index).copied().unwrap_or_default();
+            if current != new && xor(&current, &delta) != new {
+                return refuse(&format!("disagrees with the block at index {}", index));
+            }
+        }
+        Ok(records.len() as u64)
+    }
+
+    /// Compacts the ranges that end at `block`. A range is built from the finer ranges that
+    /// tile it, or else from the block deltas; ranges whose deltas are not all there (the
+    /// daemon started inside them) are skipped.
+    fn publish_ranges(&self, block: u64) -> Result<Vec<PathBuf>> {
+        let mut published = Vec::new();
+        for (level, range) in self.ranges.iter().enumerate() {
+            if !(block + 1).is_multiple_of(range.blocks) {
+                continue;
+            }
+            let first = block + 1 - range.blocks;
+            let finer = self.ranges[..level]
+                .iter()
+                .rev()
+                .filter(|finer| range.blocks.is_multiple_of(finer.blocks))
+                .map(|finer| {
+                    let dir = self.deltas_dir.join(&finer.name);
+                    (first..=block)
+                        .step_by(finer.blocks as usize)
+                        .map(|start| range_path(&dir, start, start + finer.blocks - 1))
+                        .collect::<Vec<_>>()
+                });
+            let blocks = (first..=block)
+                .map(|n| delta_path(&self.deltas_dir, n))
+                .collect();
+            let Some(sources) = finer
+                .chain(std::iter::once(blocks))
+                .find(|paths| paths.iter().all(|path| path.exists()))
+            else {
+                continue;
+            };
+
+            let dir = self.deltas_dir.join(&range.name);
+            std::fs::create_dir_all(&dir)?;
+            let path = range_path(&dir, first, block);
+            compact_files(&sources, &path)?;
+            published.push(path);
+        }
+        Ok(published)
+    }
+
+    /// The entry at `index` in the database.
+    fn stored(&self, index: u64) -> Result<[u8; 32]> {
+        let entry = self
+            .db
+            .get(index)
+            .ok_or_else(|| eyre!("Index {} is beyond the database", index))?;
+        Ok(entry.try_into().unwrap())
+    }
+
+    /// Decodes the account whose entry starts at `index`, as changed so far by the block.
+    fn account(&self, index: u64, entries: &BTreeMap<u64, [u8; 32]>) -> Result<DecodedAccount> {
+        let mut bytes = Vec::with_capacity(self.layout.words() as usize * DB_ENTRY_SIZE);
+        for i in index..index + self.layout.words() {
+            match entries.get(&i) {
+                Some(entry) => bytes.extend_from_slice(entry),
+                None => bytes.extend_from_slice(&self.stored(i)?),
+            }
+        }
+        self.layout.decode(&bytes)
+    }
+
+    fn stage_account(
+        &self,
+        index: u64,
+        account: &AccountRecord,
+        entries: &mut BTreeMap<u64, [u8; 32]>,
+    ) -> Result<()> {
+        let words = self.layout.encode(account)?;
+        for (i, word) in words[..self.layout.words() as usize].iter().enumerate() {
+            self.stored(index + i as u64)?;
+            entries.insert(index + i as u64, *word);
+        }
+        Ok(())
+    }
+}
+
+/// Applies every block of `source` to `syncer`, calling `on_block` after each.
+pub fn run(
+    syncer: &mut Syncer,
+    source: &mut dyn UpdateSource,
+    mut on_block: impl FnMut(&BlockSummary),
+) -> Result<()> {
+    while let Some(update) = source.next_block()? {
+        let summary = syncer.apply(&update)?;
+        on_block(&summary);
+    }
+    Ok(())
+}
+
+/// Refuses `path` when the `manifest.json` next to it lists it and it does not match.
+fn check_manifest(path: &Path) -> Result<()> {
+    let (dir, name) = file_dir_and_name(path)?;
+    match Manifest::load(dir)? {
+        Some(manifest) if manifest.entry(name).is_some() => manifest.check(dir, name),
+        _ => Ok(()),
+    }
+}
+
+fn file_dir_and_name(path: &Path) -> Result<(&Path, &str)> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| eyre!("{:?} is not a file name", path))?;
+    Ok((path.parent().unwrap_or(Path::new("")), name))
+}
+
+fn xor(a: &[u64; DB_ENTRY_U64_COUNT], b: &[u64; DB_ENTRY_U64_COUNT]) -> [u64; DB_ENTRY_U64_COUNT] {
+    let mut out = [0u64; DB_ENTRY_U64_COUNT];
+    for (o, (a, b)) in out.iter_mut().zip(a.iter().zip(b.iter())) {
+        *o = a ^ b;
+    }
+    out
+}
+
+/// `a + b` of two 256-bit LE numbers, `None` on overflow.
+fn add(a: &[u8; 32], b: &[u8; 32]) -> Option<[u8; 32]> {
+    let mut sum = [0u8; 32];
+    let mut carry = 0u16;
+    for (s, (a, b)) in sum.iter_mut().zip(a.iter().zip(b)) {
+        let digit = *a as u16 + *b as u16 + carry;
+        *s = digit as u8;
+        carry = digit >> 8;
+    }
+    (carry == 0).then_some(sum)
+}
+
+fn words(entry: &[u8]) -> [u64; DB_ENTRY_U64_COUNT] {
+    let mut out = [0u64; DB_ENTRY_U64_COUNT];
+    for (i, word) in out.iter_mut().enumerate() {
+        *word = u64::from_le_bytes(entry[i * 8..(i + 1) * 8].try_into().unwrap());
+    }
+    out
+}
+
+/// One line of a JSON-lines update feed.
+#[derive(Deserialize)]
+struct JsonBlock {
+    block: u64,
+    #[serde(default)]
+    hash: Option<String>,
+    #[serde(default)]
+    parent_hash: Option<String>,
+    #[serde(default)]
+    accounts: Vec<JsonAccount>,
+    #[serde(default)]
+    storage: Vec<JsonSlot>,
+}
+
+#[derive(Deserialize)]
+struct JsonAccount {
+    address: String,
+    #[serde(default)]
+    nonce: u64,
+    #[serde(default)]
+    balance: Option<String>,
+    #[serde(default)]
+    code_hash: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JsonSlot {
+    address: String,
+    slot: String,
+    value: String,
+}
+
+/// Blocks as JSON lines, one block per line:
+///
+/// ```json
+/// {"block": 101, "accounts": [{"address": "0x..", "nonce": 2, "balance": "0x..", "code_hash": "0x.."}],
+///  "storage": [{"address": "0x..", "slot": "0x..", "value": "0x.."}]}
+/// ```
+///
+/// Accounts list every field after the block (missing ones are zero); balances and values
+/// are hex quantities. Optional `"hash"` and `"parent_hash"` bind the block's delta to the
+/// chain.
+pub struct JsonlSource<R> {
+    reader: R,
+    line: usize,
+}
+
+impl<R: BufRead> JsonlSource<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, line: 0 }
+    }
+}
+
+impl<R: BufRead> UpdateSource for JsonlSource<R> {
+    fn next_block(&mut self) -> Result<Option<BlockUpdate>> {
+        let mut text = String::new();
+        loop {
+            text.clear();
+            if self.reader.read_line(&mut text)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            if !text.trim().is_empty() {
+                break;
+            }
+        }
+        let line = self.line;
+        let json: JsonBlock =
+            serde_json::from_str(&text).map_err(|e| eyre!("Update line {}: {}", line, e))?;
+
+        let mut changes = Vec::new();
+        for account in &json.accounts {
+            changes.push(Change::Account {
+                address: parse_bytes(&account.address)?,
+                account: AccountRecord {
+                    nonce: account.nonce,
+                    balance: account
+                        .balance
+                        .as_deref()
+                        .map_or(Ok([0; 32]), parse_quantity)?,
+                    code_hash: account
+                        .code_hash
+                        .as_deref()
+                        .map_or(Ok([0; 32]), parse_bytes)?,
+                },
+            });
+        }
+        for slot in &json.storage {
+            changes.push(Change::Storage {
+                address: parse_bytes(&slot.address)?,
+                slot: parse_bytes(&slot.slot)?,
+                value: parse_quantity(&slot.value)?,
+            });
+        }
+        Ok(Some(BlockUpdate {
+            number: json.block,
+            hash: json.hash.as_deref().map(parse_bytes).transpose()?,
+            parent_hash: json.parent_hash.as_deref().map(parse_bytes).transpose()?,
+            changes,
+        }))
+    }
+}
+
+fn nibbles(hex: &str) -> Result<Vec<u8>> {
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    hex.chars()
+        .map(|c| {
+            c.to_digit(16)
+                .map(|d| d as u8)
+                .ok_or_else(|| eyre!("Invalid hex {:?}", hex))
+        })
+        .collect()
+}
+
+/// Parses exactly `N` bytes of hex (an address, slot key or hash).
+pub fn parse_bytes<const N: usize>(hex: &str) -> Result<[u8; N]> {
+    let nibbles = nibbles(hex)?;
+    ensure!(
+        nibbles.len() == 2 * N,
+        "Expected {} bytes of hex, got {:?}",
+        N,
+        hex
+    );
+    let mut out = [0u8; N];
+    for (byte, pair) in out.iter_mut().zip(nibbles.chunks(2)) {
+        *byte = pair[0] << 4 | pair[1];
+    }
+    Ok(out)
+}
+
+/// Parses a hex quantity of up to 256 bits into 32 bytes LE.
+pub fn parse_quantity(hex: &str) -> Result<[u8; 32]> {
+    let nibbles = nibbles(hex)?;
+    let digits = &nibbles[nibbles.iter().take_while(|&&n| n == 0).count()..];
+    ensure!(digits.len() <= 64, "Quantity {:?} exceeds 256 bits", hex);
+    let mut out = [0u8; 32];
+    for (i, nibble) in digits.iter().rev().enumerate() {
+        out[i / 2] |= nibble << (4 * (i % 2));
+    }
+    Ok(out)
+}
+
+/// Connections served at once; further ones wait in the listener's backlog.
+pub const MAX_HTTP_CONNECTIONS: usize = 64;
+/// How long a connection may wait for the client to send or accept data.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest request line and headers accepted.
+const MAX_REQUEST_HEAD: u64 = 16 << 10;
+
+/// Serves the `.bin` and `.json` files under `root` over HTTP (`GET`/`HEAD`), one thread
+/// per connection up to [`MAX_HTTP_CONNECTIONS`], plus `/health`. Runs until the listener
+/// fails.
+pub fn serve(listener: TcpListener, root: PathBuf) -> Result<()> {
+    let active = Arc::new((Mutex::new(0usize), Condvar::new()));
+    loop {
+        {
+            let (count, released) = &*active;
+            let mut count = count.lock().unwrap();
+            while *count >= MAX_HTTP_CONNECTIONS {
+                count = released.wait(count).unwrap();
+            }
+            *count += 1;
+        }
+        let stream = listener.accept()?.0;
+        let root = root.clone();
+        let active = active.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = respond(stream, &root) {
+                eprintln!("HTTP request failed: {}", e);
+            }
+            let (count, released) = &*active;
+            *count.lock().unwrap() -= 1;
+            released.notify_one();
+        });
+    }
+}
+
+fn respond(stream: TcpStream, root: &Path) -> Result<()> {
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?).take(MAX_REQUEST_HEAD);
+    let mut request = String::new();
+    if reader.read_line(&mut request)? == 0 {
+        return Ok(());
+    }
+    // Skip the headers
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = target.split('?').next().unwrap_or("");
+    let mut out = BufWriter::new(stream);
+    let head = method == "HEAD";
+    if method != "GET" && !head {
+        return reply(&mut out, "405 Method Not Allowed", "text/plain", b"", true);
+    }
+    if path == "/health" {
+        return reply(&mut out, "200 OK", "text/plain", b"ok\n", head);
+    }
+
+    // Only published files (not the `.tmp` files they are written through), by plain
+    // relative paths below the root
+    let relative = Path::new(path.trim_start_matches('/'));
+    let content_type = match relative.extension().and_then(|e| e.to_str()) {
+        Some("json") => Some("application/json"),
+        Some("bin") => Some("application/octet-stream"),
+        _ => None,
+    };
+    let file = match content_type {
+        Some(_)
+            if relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_))) =>
+        {
+            File::open(root.join(relative)).ok()
+        }
+        _ => None,
+    };
+    let file = file.and_then(|file| {
+        let length = file.metadata().ok().filter(|m| m.is_file())?.len();
+        Some((file, length))
+    });
+    match (file, content_type) {
+        (Some((file, length)), Some(content_type)) => {
+            write_head(&mut out, "200 OK", content_type, length)?;
+            if !head {
+                std::io::copy(&mut file.take(length), &mut out)?;
+            }
+            out.flush()?;
+            Ok(())
+        }
+        _ => reply(&mut out, "404 Not Found", "text/plain", b"", head),
+    }
+}
+
+fn reply(
+    out: &mut impl Write,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+    head: bool,
+) -> Result<()> {
+    write_head(out, status, content_type, body.len() as u64)?;
+    if !head {
+        out.write_all(body)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn write_head(out: &mut impl Write, status: &str, content_type: &str, length: u64) -> Result<()> {
+    write!(
+        out,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, content_type, length
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delta::read_delta;
+    use crate::metadata::Metadata;
+    use crate::synthetic::{generate, SyntheticConfig};
+
+    fn temp_dir(tag: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("plinko-syncer-{}-{}", tag, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &Path) -> Syncer {
+        Syncer::open(
+            &dir.join("database.bin"),
+            &dir.join("account-mapping.bin"),
+            Some(&dir.join("storage-mapping.bin")),
+            &dir.join("public"),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn applies_blocks_in_place_and_publishes_deltas() {
+        let dir = temp_dir("apply");
+        let config = SyntheticConfig {
+            accounts: 50,
+            storage_slots: 100,
+            ..Default::default()
+        };
+        generate(&dir, &config).unwrap();
+        let mut syncer = open(&dir);
+        assert_eq!(syncer.block(), Some(0));
+
+        let accounts = AccountMapping::open(dir.join("account-mapping.bin")).unwrap();
+        let (address, account_index) = accounts.record(3).unwrap();
+        let address: [u8; 20] = address.try_into().unwrap();
+        let storage = StorageMapping::open(dir.join("storage-mapping.bin")).unwrap();
+        let (slot_address, slot, slot_index) = storage.slot(7).unwrap();
+        let old_value = words(syncer.database().get(slot_index).unwrap());
+
+        let mut balance = [0u8; 32];
+        balance[0] = 0x2a;
+        let mut value = [0u8; 32];
+        value[31] = 0x80;
+        let update = BlockUpdate {
+            number: 1,
+            hash: None,
+            parent_hash: None,
+            changes: vec![
+                Change::Account {
+                    address,
+                    account: AccountRecord {
+                        nonce: 9,
+                        balance,
+                        code_hash: [0; 32],
+                    },
+                },
+                Change::Storage {
+                    address: slot_address.try_into().unwrap(),
+                    slot: slot.try_into().unwrap(),
+                    value,
+                },
+                // Created after the snapshot
+                Change::Storage {
+                    address: [0xee; 20],
+                    slot: [0; 32],
+                    value,
+                },
+            ],
+        };
+        let old_account: Vec<_> = (0..3)
+            .map(|i| words(syncer.database().get(account_index + i).unwrap()))
+            .collect();
+        let summary = syncer.apply(&update).unwrap();
+        assert_eq!(summary.unmapped, 1);
+        assert_eq!(summary.path, dir.join("public/deltas/delta-000001.bin"));
+
+        // XORing each record into the old entry gives the new one
+        let delta = read_delta(&summary.path).unwrap();
+        assert_eq!(delta.binding, Some(ChainBinding::block(1, None, None)));
+        let records = delta.records;
+        assert_eq!(records.len() as u64, summary.records);
+        let mut patched: Vec<_> = old_account.clone();
+        patched.push(old_value);
+        let indices = [
+            account_index,
+            account_index + 1,
+            account_index + 2,
+            slot_index,
+        ];
+        for (index, delta) in &records {
+            let i = indices.iter().position(|i| i == index).unwrap();
+            for (w, d) in patched[i].iter_mut().zip(delta) {
+                *w ^= d;
+            }
+        }
+        for (i, index) in indices.iter().enumerate() {
+            assert_eq!(patched[i], words(syncer.database().get(*index).unwrap()));
+        }
+        assert_eq!(patched[0][0], 9);
+        assert_eq!(patched[1][0], 0x2a);
+        assert_eq!(patched[3][3], 0x80 << 56);
+
+        // Progress survives a restart, and blocks must follow on
+        drop(syncer);
+        let mut syncer = open(&dir);
+        assert_eq!(syncer.block(), Some(1));
+        let err = syncer.apply(&BlockUpdate {
+            number: 3,
+            ..Default::default()
+        });
+        assert!(err.unwrap_err().to_string().contains("Expected block #2"));
+
+        // Once hashes are known, the next block must build on the last one
+        let block = |number, parent_hash, hash| BlockUpdate {
+            number,
+            hash: Some(hash),
+            parent_hash: Some(parent_hash),
+            changes: vec![],
+        };
+        syncer.apply(&block(2, [1; 32], [2; 32])).unwrap();
+        let err = syncer.apply(&block(3, [9; 32], [3; 32])).unwrap_err();
+        assert!(err.to_string().contains("reorg"), "{}", err);
+        drop(syncer);
+        let mut syncer = open(&dir);
+        syncer.apply(&block(3, [2; 32], [3; 32])).unwrap();
+        let delta = read_delta(&dir.join("public/deltas/delta-000003.bin")).unwrap();
+        assert_eq!(
+            delta.binding,
+            Some(ChainBinding::block(3, Some([2; 32]), Some([3; 32])))
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checks_inputs_and_unlists_the_changed_database() {
+        let dir = temp_dir("manifest");
+        let config = SyntheticConfig {
+            accounts: 50,
+            storage_slots: 100,
+            ..Default::default()
+        };
+        generate(&dir, &config).unwrap();
+        let listed = |name: &str| {
+            let manifest = Manifest::load(&dir).unwrap().unwrap();
+            let metadata = Metadata::load(&dir).unwrap().unwrap();
+            (
+                manifest.entry(name).is_some(),
+                metadata.file(name).is_some(),
+            )
+        };
+
+        // A corrupted mapping is refused
+        let path = dir.join("storage-mapping.bin");
+        let mapping = std::fs::read(&path).unwrap();
+        let mut corrupted = mapping.clone();
+        corrupted[0] ^= 1;
+        std::fs::write(&path, &corrupted).unwrap();
+        let err = Syncer::open(
+            &dir.join("database.bin"),
+            &dir.join("account-mapping.bin"),
+            Some(&path),
+            &dir.join("public"),
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(
+            err.contains("storage-mapping.bin failed verification"),
+            "{}",
+            err
+        );
+        std::fs::write(&path, &mapping).unwrap();
+
+        // Opening alone changes nothing; the first block drops the database's entries
+        let mut syncer = open(&dir);
+        assert_eq!(listed("database.bin"), (true, true));
+        syncer
+            .apply(&BlockUpdate {
+                number: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(listed("database.bin"), (false, false));
+        assert_eq!(listed("account-mapping.bin"), (true, true));
+        assert_eq!(listed("storage-mapping.bin"), (true, true));
+        drop(syncer);
+        assert_eq!(open(&dir).block(), Some(1));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reapplies_a_block_after_a_crash() {
+        let dir = temp_dir("crash");
+        let config = SyntheticConfig {
+            accounts: 50,
+            storage_slots: 100,
+            ..Default::default()
+        };
+        generate(&dir, &config).unwrap();
+        let storage = StorageMapping::open(dir.join("storage-mapping.bin")).unwrap();
+        let (address, slot, _) = storage.slot(5).unwrap();
+        let update = |value: u8| BlockUpdate {
+            number: 1,
+            hash: Some([1; 32]),
+            parent_hash: None,
+            changes: vec![Change::Storage {
+                address: address.try_into().unwrap(),
+                slot: slot.try_into().unwrap(),
+                value: [value; 32],
+            }],
+        };
+        let snapshot = |name: &str| std::fs::read(dir.join(name)).unwrap();
+        let restore = |name: &str, bytes: &[u8]| std::fs::write(dir.join(name), bytes).unwrap();
+        let (database, metadata) = (snapshot("database.bin"), snapshot("metadata.json"));
+
+        let summary = open(&dir).apply(&update(9)).unwrap();
+        assert_eq!(summary.records, 1);
+        let delta = std::fs::read(&summary.path).unwrap();
+        let applied = snapshot("database.bin");
+
+        // Crashed after the database was flushed, or before it was changed: the block is
+        // applied again and the published delta is kept as it is
+        for database in [&applied, &database] {
+            restore("database.bin", database);
+            restore("metadata.json", &metadata);
+            let mut syncer = open(&dir);
+            assert_eq!(syncer.block(), Some(0));
+            assert_eq!(syncer.apply(&update(9)).unwrap().records, 1);
+            assert_eq!(std::fs::read(&summary.path).unwrap(), delta);
+            assert_eq!(snapshot("database.bin"), applied);
+            assert_eq!(syncer.block(), Some(1));
+        }
+
+        // A block that differs from the published delta is refused
+        restore("database.bin", &database);
+        restore("metadata.json", &metadata);
+        let err = open(&dir).apply(&update(8)).unwrap_err().to_string();
+        assert!(err.contains("refusing to overwrite"), "{}", err);
+        assert_eq!(std::fs::read(&summary.path).unwrap(), delta);
+        assert_eq!(snapshot("database.bin"), database);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn publishes_compacted_ranges() {
+        let dir = temp_dir("ranges");
+        let config = SyntheticConfig {
+            accounts: 50,
+            storage_slots: 100,
+            ..Default::default()
+        };
+        generate(&dir, &config).unwrap();
+        let mut syncer = open(&dir);
+        syncer.set_ranges(vec![
+            RangeLevel::new("daily", 8),
+            RangeLevel::new("hourly", 4),
+        ]);
+
+        let storage = StorageMapping::open(dir.join("storage-mapping.bin")).unwrap();
+        let (address, slot, index) = storage.slot(0).unwrap();
+        let hash = |n: u64| [n as u8 + 1; 32];
+        let mut ranges = Vec::new();
+        let mut metadata = Vec::new();
+        for number in 1..=15 {
+            if number == 15 {
+                metadata = std::fs::read(dir.join("metadata.json")).unwrap();
+            }
+            // The slot holds the block number, until block 15 sets it back to 7
+            let value = [if number == 15 { 7 } else { number as u8 }; 32];
+            let summary = syncer
+                .apply(&BlockUpdate {
+                    number,
+                    hash: Some(hash(number)),
+                    parent_hash: Some(hash(number - 1)),
+                    changes: vec![Change::Storage {
+                        address: address.try_into().unwrap(),
+                        slot: slot.try_into().unwrap(),
+                        value,
+                    }],
+                })
+                .unwrap();
+            ranges.extend(summary.ranges);
+        }
+
+        // The snapshot is at block 0, so blocks 0..=3 and 0..=7 are incomplete
+        let deltas = dir.join("public/deltas");
+        assert_eq!(
+            ranges,
+            [
+                range_path(&deltas.join("hourly"), 4, 7),
+                range_path(&deltas.join("hourly"), 8, 11),
+                range_path(&deltas.join("hourly"), 12, 15),
+                range_path(&deltas.join("daily"), 8, 15),
+            ]
+        );
+        let daily = read_delta(&ranges[3]).unwrap();
+        assert_eq!(
+            daily.binding,
+            Some(ChainBinding {
+                first_block: 8,
+                block: 15,
+                parent_hash: Some(hash(7)),
+                block_hash: Some(hash(15)),
+            })
+        );
+        // Blocks 8..=15 end where they started
+        assert!(daily.records.is_empty());
+        // Four block records for the slot merge into one
+        let hourly = read_delta(&ranges[0]).unwrap();
+        assert_eq!(hourly.records, [(index, [0x0404_0404_0404_0404; 4])]);
+
+        // Crashed before block 15 was recorded, and before or after its ranges were written:
+        // applying it again publishes them
+        let daily = std::fs::read(&ranges[3]).unwrap();
+        for lost in [false, true] {
+            std::fs::write(dir.join("metadata.json"), &metadata).unwrap();
+            if lost {
+                std::fs::remove_file(&ranges[2]).unwrap();
+                std::fs::remove_file(&ranges[3]).unwrap();
+            }
+            let mut syncer = open(&dir);
+            syncer.set_ranges(vec![
+                RangeLevel::new("daily", 8),
+                RangeLevel::new("hourly", 4),
+            ]);
+            let summary = syncer
+                .apply(&BlockUpdate {
+                    number: 15,
+                    hash: Some(hash(15)),
+                    parent_hash: Some(hash(14)),
+                    changes: vec![Change::Storage {
+                        address: address.try_into().unwrap(),
+                        slot: slot.try_into().unwrap(),
+                        value: [7; 32],
+                    }],
+                })
+                .unwrap();
+            assert_eq!(summary.ranges, ranges[2..]);
+            assert_eq!(std::fs::read(&ranges[3]).unwrap(), daily);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parses_jsonl_updates() {
+        let feed = concat!(
+            r#"{"block": 5, "accounts": [{"address": "0x1111111111111111111111111111111111111111", "nonce": 1, "balance": "0x100"}],"#,
+            r#" "storage": [{"address": "0x2222222222222222222222222222222222222222", "slot": "0x0000000000000000000000000000000000000000000000000000000000000001", "value": "0x0"}]}"#,
+            "\n\n",
+            r#"{"block": 6, "parent_hash": "0x0505050505050505050505050505050505050505050505050505050505050505"}"#,
+            "\n"
+        );
+        let mut source = JsonlSource::new(feed.as_bytes());
+        let first = source.next_block().unwrap().unwrap();
+        let mut balance = [0u8; 32];
+        balance[1] = 1;
+        let mut slot = [0u8; 32];
+        slot[31] = 1;
+        assert_eq!(
+            first,
+            BlockUpdate {
+                number: 5,
+                hash: None,
+                parent_hash: None,
+                changes: vec![
+                    Change::Account {
+                        address: [0x11; 20],
+                        account: AccountRecord {
+                            nonce: 1,
+                            balance,
+                            code_hash: [0; 32]
+                        },
+                    },
+                    Change::Storage {
+                        address: [0x22; 20],
+                        slot,
+                        value: [0; 32]
+                    },
+                ],
+            }
+        );
+        let second = source.next_block().unwrap().unwrap();
+        assert_eq!(
+            (second.number, second.parent_hash, second.hash),
+            (6, Some([5; 32]), None)
+        );
+        assert!(source.next_block().unwrap().is_none());
+
+        assert!(parse_quantity(&format!("0x1{}", "0".repeat(64))).is_err());
+        assert!(parse_bytes::<20>("0x11").is_err());
+    }
+
+    #[test]
+    fn serves_public_files() {
+        let root = temp_dir("http");
+        std::fs::create_dir_all(root.join("deltas")).unwrap();
+        std::fs::write(root.join("deltas/delta-000001.bin"), b"delta").unwrap();
+        std::fs::write(root.join("deltas/delta-000002.bin.tmp"), b"partial").unwrap();
+        std::fs::write(root.join("notes.txt"), b"private").unwrap();
+        let large: Vec<u8> = (0..3 << 20).map(|i| (i % 251) as u8).collect();
+        std::fs::write(root.join("database.bin"), &large).unwrap();
+        std::fs::write(root.join("metadata.json"), b"{}").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = root.clone();
+        std::thread::spawn(move || serve(listener, served));
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            response
+        };
+        let text = |path: &str| String::from_utf8_lossy(&get(path)).into_owned();
+        let found = text("/deltas/delta-000001.bin");
+        assert!(found.starts_with("HTTP/1.1 200 OK"), "{}", found);
+        assert!(found.ends_with("\r\n\r\ndelta"));
+        assert!(text("/metadata.json").contains("Content-Type: application/json"));
+        let response = get("/database.bin");
+        assert!(response.ends_with(&large));
+        let head = String::from_utf8_lossy(&response[..response.len() - large.len()]);
+        assert!(head.contains(&format!("Content-Length: {}\r\n", large.len())));
+        for path in [
+            "/deltas/delta-000002.bin",
+            "/deltas/delta-000002.bin.tmp",
+            "/notes.txt",
+            "/deltas",
+            "/../etc/passwd",
+        ] {
+            assert!(text(path).starts_with("HTTP/1.1 404"), "{}", path);
+        }
+        assert!(text("/health").ends_with("ok\n"));
+
+        // Idle connections hold their slots until they close; the next one waits
+        let idle: Vec<_> = (0..MAX_HTTP_CONNECTIONS)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect();
+        let mut waiting = TcpStream::connect(addr).unwrap();
+        write!(waiting, "GET /health HTTP/1.1\r\n\r\n").unwrap();
+        waiting
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(waiting.read(&mut [0; 1]).is_err());
+        drop(idle);
+        waiting.set_read_timeout(None).unwrap();
+        let mut response = String::new();
+        waiting.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("ok\n"), "{}", response);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,390 @@
+//! Synthetic snapshots for tests and benchmarks (`gen-synthetic`).
+//!
+//! [`generate`] writes `database.bin`, `account-mapping.bin`, `storage-mapping.bin`,
+//! `manifest.json` and `metadata.json` in the extractor's formats, so `plinko_hints`,
+//! `bench_hints` and the readers in this crate can run without a synced node. The state is
+//! drawn from a ChaCha20 stream seeded with [`SyntheticConfig::seed`]: the same config always
+//! produces the same files (only `generated_at` differs).
+//!
+//! Values roughly follow mainnet's shape: power-law balances with many empty accounts, a
+//! few contracts holding all the storage (slot counts are power-law too), shared code
+//! hashes, and slots that are mostly zero or small integers.
+
+use crate::db::{DATABASE_FILE, DB_ENTRY_SIZE};
+use crate::layout::{AccountLayout, AccountRecord};
+use crate::manifest::{Manifest, DEFAULT_CHUNK_SIZE};
+use crate::mapping::{index_width_for, write_index, MappingHeader};
+use crate::metadata::{
+    FileInfo, KeyOrder, Metadata, RecordLayout, SyntheticInfo, METADATA_SCHEMA_VERSION,
+};
+use eyre::{ensure, Result};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Smallest non-zero balance drawn, in wei (0.0001 ETH).
+const MIN_BALANCE: f64 = 1e14;
+/// Largest balance drawn, in wei (100M ETH).
+const MAX_BALANCE: f64 = 1e26;
+/// Pareto shape of the balances; 1.0 puts about half of the accounts below 2x the minimum.
+const BALANCE_ALPHA: f64 = 1.0;
+/// Pareto shape of the per-contract slot counts.
+const SLOTS_ALPHA: f64 = 1.2;
+/// Percent of externally owned accounts with a zero balance.
+const EMPTY_EOA_PERCENT: u32 = 30;
+/// Percent of contracts with a zero balance.
+const EMPTY_CONTRACT_PERCENT: u32 = 80;
+/// Distinct code hashes per contract (proxies and factories share code).
+const CONTRACTS_PER_CODE_HASH: u64 = 10;
+/// Most low-numbered slots (`0`, `1`, ...) a contract gets before its hashed slots.
+const MAX_SEQUENTIAL_SLOTS: u64 = 8;
+
+/// What [`generate`] writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyntheticConfig {
+    pub accounts: u64,
+    pub storage_slots: u64,
+    pub seed: u64,
+    /// Percent of accounts that are contracts; contracts hold all the storage.
+    pub contract_percent: u8,
+    /// Percent of storage slots whose value is zero.
+    pub zero_slot_percent: u8,
+    pub account_layout: AccountLayout,
+}
+
+impl Default for SyntheticConfig {
+    fn default() -> Self {
+        Self {
+            accounts: 1000,
+            storage_slots: 4000,
+            seed: 0,
+            contract_percent: 10,
+            zero_slot_percent: 30,
+            account_layout: AccountLayout::Standard,
+        }
+    }
+}
+
+/// Writes a synthetic snapshot into `dir` and returns its metadata.
+///
+/// Accounts and slots are generated in key order and streamed to disk, so memory use only
+/// grows with the number of contracts.
+pub fn generate(dir: &Path, config: &SyntheticConfig) -> Result<Metadata> {
+    ensure!(
+        config.contract_percent <= 100 && config.zero_slot_percent <= 100,
+        "Percentages must be at most 100"
+    );
+    ensure!(
+        config.storage_slots == 0 || (config.accounts > 0 && config.contract_percent > 0),
+        "Storage slots need at least one contract (accounts and contract percent above 0)"
+    );
+    std::fs::create_dir_all(dir)?;
+
+    let mut rng = ChaCha20Rng::seed_from_u64(config.seed);
+    let layout = config.account_layout;
+    let account_words = layout.words();
+    let total_indices = config.accounts * account_words + config.storage_slots;
+    let index_width = index_width_for(total_indices);
+
+    let mut db = BufWriter::new(File::create(dir.join(DATABASE_FILE))?);
+    let mut account_map = BufWriter::new(File::create(dir.join("account-mapping.bin"))?);
+    let mut storage_map = BufWriter::new(File::create(dir.join("storage-mapping.bin"))?);
+    account_map.write_all(&MappingHeader::new(20, index_width, config.accounts).encode())?;
+    storage_map.write_all(&MappingHeader::new(52, index_width, config.storage_slots).encode())?;
+
+    // --- ACCOUNTS ---
+    let wanted = (config.accounts * config.contract_percent as u64).div_ceil(100);
+    let code_hashes: Vec<[u8; 32]> = (0..wanted.div_ceil(CONTRACTS_PER_CODE_HASH).max(1))
+        .map(|_| rng.gen())
+        .collect();
+    let stride = u64::MAX / config.accounts.max(1);
+    let mut contracts: Vec<[u8; 20]> = Vec::with_capacity(wanted as usize);
+
+    for i in 0..config.accounts {
+        let address: [u8; 20] = sorted_key(&mut rng, 0, i, stride);
+        // Selection sampling: exactly `wanted` contracts, spread over the address space
+        let contract = rng.gen_range(0..config.accounts - i) < wanted - contracts.len() as u64;
+        let record = if contract {
+            contracts.push(address);
+            // Popular code first: most contracts share a few hashes
+            let pick = (code_hashes.len() as f64 * rng.gen::<f64>().powi(3)) as usize;
+            AccountRecord {
+                nonce: 1,
+                balance: balance(&mut rng, EMPTY_CONTRACT_PERCENT),
+                code_hash: code_hashes[pick],
+            }
+        } else {
+            AccountRecord {
+                nonce: (-unit(&mut rng).ln() * 8.0) as u64,
+                balance: balance(&mut rng, EMPTY_EOA_PERCENT),
+                code_hash: [0u8; 32],
+            }
+        };
+        let words = layout.encode(&record)?;
+        for word in &words[..account_words as usize] {
+            db.write_all(word)?;
+        }
+        account_map.write_all(&address)?;
+        write_index(&mut account_map, i * account_words, index_width)?;
+    }
+
+    // --- STORAGE ---
+    let mut index = config.accounts * account_words;
+    let counts = slot_counts(&mut rng, contracts.len(), config.storage_slots);
+    for (address, count) in contracts.iter().zip(counts) {
+        // Declared variables take the first slots; mappings and arrays hash theirs
+        let sequential = count.min(rng.gen_range(0..=MAX_SEQUENTIAL_SLOTS));
+        let stride = (u64::MAX - 1) / (count - sequential).max(1);
+        for j in 0..count {
+            let slot: [u8; 32] = if j < sequential {
+                let mut slot = [0u8; 32];
+                slot[24..].copy_from_slice(&j.to_be_bytes());
+                slot
+            } else {
+                // Prefix at least 1, so above every sequential slot
+                sorted_key(&mut rng, 1, j - sequential, stride)
+            };
+            db.write_all(&slot_value(&mut rng, config.zero_slot_percent))?;
+            storage_map.write_all(address)?;
+            storage_map.write_all(&slot)?;
+            write_index(&mut storage_map, index, index_width)?;
+            index += 1;
+        }
+    }
+    debug_assert_eq!(index, total_indices);
+
+    db.flush()?;
+    account_map.flush()?;
+    storage_map.flush()?;
+
+    let names = [DATABASE_FILE, "account-mapping.bin", "storage-mapping.bin"].map(String::from);
+    let manifest = Manifest::build(dir, &names, DEFAULT_CHUNK_SIZE)?;
+    manifest.save(dir)?;
+
+    let metadata = Metadata {
+        schema_version: METADATA_SCHEMA_VERSION,
+        chain_id: None,
+        block: 0,
+        block_hash: None,
+        state_root: None,
+        entry_size: DB_ENTRY_SIZE as u64,
+        layout: RecordLayout::new(layout, index_width),
+        key_order: KeyOrder::Plain,
+        accounts: config.accounts,
+        storage_slots: config.storage_slots,
+        total_indices,
+        filter: None,
+        files: manifest
+            .files
+            .iter()
+            .map(|f| FileInfo {
+                name: f.name.clone(),
+                size: f.size,
+                blake3: f.blake3.clone(),
+            })
+            .collect(),
+        bytecode: None,
+        trie: None,
+        cuckoo: None,
+        padding: None,
+        synthetic: Some(SyntheticInfo {
+            seed: config.seed,
+            contract_percent: config.contract_percent,
+            zero_slot_percent: config.zero_slot_percent,
+        }),
+        generated_at: rfc3339(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
+    };
+    metadata.save(dir)?;
+    Ok(metadata)
+}
+
+/// Uniform in (0, 1], safe to take the log of or divide by.
+fn unit(rng: &mut ChaCha20Rng) -> f64 {
+    1.0 - rng.gen::<f64>()
+}
+
+/// A random key that sorts as the `i`th of its sequence: the first 8 bytes (big-endian) fall
+/// in `[base + i * stride, base + (i + 1) * stride)`, the rest are random.
+fn sorted_key<const N: usize>(rng: &mut ChaCha20Rng, base: u64, i: u64, stride: u64) -> [u8; N] {
+    let mut key = [0u8; N];
+    let prefix = base + i * stride + rng.gen_range(0..stride);
+    key[..8].copy_from_slice(&prefix.to_be_bytes());
+    rng.fill(&mut key[8..]);
+    key
+}
+
+/// A balance (32 bytes LE): zero with probability `empty_percent`, otherwise Pareto.
+pub(crate) fn balance(rng: &mut ChaCha20Rng, empty_percent: u32) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    if rng.gen_range(0..100) >= empty_percent {
+        let wei = (MIN_BALANCE / unit(rng).powf(1.0 / BALANCE_ALPHA)).min(MAX_BALANCE);
+        out[..16].copy_from_slice(&(wei as u128).to_le_bytes());
+    }
+    out
+}
+
+/// Splits `total` slots over `contracts` contracts with Pareto weights.
+fn slot_counts(rng: &mut ChaCha20Rng, contracts: usize, total: u64) -> Vec<u64> {
+    if contracts == 0 {
+        return Vec::new();
+    }
+    // Integer weights, so the counts add up to exactly `total`
+    let weights: Vec<u128> = (0..contracts)
+        .map(|_| (unit(rng).powf(-1.0 / SLOTS_ALPHA) * 1e6) as u128)
+        .collect();
+    let sum: u128 = weights.iter().sum();
+    let mut counts: Vec<u64> = weights
+        .iter()
+        .map(|w| (total as u128 * w / sum) as u64)
+        .collect();
+    let remainder = total - counts.iter().sum::<u64>();
+    for count in counts.iter_mut().take(remainder as usize) {
+        *count += 1;
+    }
+    counts
+}
+
+/// A slot value (32 bytes LE): zero with probability `zero_percent`, otherwise a small
+/// integer (half the time), an address or a full 32-byte word (hashes, packed structs).
+pub(crate) fn slot_value(rng: &mut ChaCha20Rng, zero_percent: u8) -> [u8; 32] {
+    let mut value = [0u8; 32];
+    if rng.gen_range(0..100) < zero_percent {
+        return value;
+    }
+    match rng.gen_range(0..4) {
+        0 | 1 => {
+            let bits = rng.gen_range(1..=64);
+            let small = (rng.gen::<u64>() >> (64 - bits)).max(1);
+            value[..8].copy_from_slice(&small.to_le_bytes());
+        }
+        2 => rng.fill(&mut value[..20]),
+        _ => rng.fill(&mut value[..]),
+    }
+    value
+}
+
+/// Formats Unix time `secs` as RFC 3339 UTC (`2025-01-01T00:00:00Z`).
+fn rfc3339(secs: u64) -> String {
+    // Civil date from days since the epoch (Howard Hinnant's `civil_from_days`)
+    let z = (secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    let time = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::mapping::{AccountMapping, StorageMapping};
+
+    fn temp_dir(tag: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("plinko-synthetic-{}-{}", tag, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn read(dir: &Path, name: &str) -> Vec<u8> {
+        std::fs::read(dir.join(name)).unwrap()
+    }
+
+    #[test]
+    fn same_seed_same_files() {
+        let config = SyntheticConfig {
+            accounts: 300,
+            storage_slots: 1000,
+            seed: 7,
+            ..Default::default()
+        };
+        let (a, b, c) = (temp_dir("seed-a"), temp_dir("seed-b"), temp_dir("seed-c"));
+        generate(&a, &config).unwrap();
+        generate(&b, &config).unwrap();
+        generate(&c, &SyntheticConfig { seed: 8, ..config }).unwrap();
+
+        for name in [
+            DATABASE_FILE,
+            "account-mapping.bin",
+            "storage-mapping.bin",
+            "manifest.json",
+        ] {
+            assert_eq!(read(&a, name), read(&b, name), "{}", name);
+            assert_ne!(read(&a, name), read(&c, name), "{}", name);
+        }
+        for dir in [a, b, c] {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn readers_accept_generated_files() {
+        let dir = temp_dir("readers");
+        let config = SyntheticConfig {
+            accounts: 500,
+            storage_slots: 2000,
+            seed: 1,
+            account_layout: AccountLayout::Packed,
+            ..Default::default()
+        };
+        let metadata = generate(&dir, &config).unwrap();
+        assert_eq!(Metadata::load(&dir).unwrap().unwrap(), metadata);
+        assert_eq!(metadata.total_indices, 500 * 2 + 2000);
+
+        let db = Database::load(dir.join(DATABASE_FILE)).unwrap();
+        assert_eq!(db.num_entries, metadata.total_indices);
+
+        // Every key is found at its own index, so the keys are sorted and unique
+        let accounts = AccountMapping::open(dir.join("account-mapping.bin")).unwrap();
+        assert_eq!(accounts.len(), 500);
+        let mut contracts = 0;
+        for i in 0..accounts.len() {
+            let (address, index) = accounts.record(i).unwrap();
+            assert_eq!(index, i * 2);
+            assert_eq!(accounts.get(address), Some(index));
+            let entry = &db.mmap[index as usize * 32..(index as usize + 2) * 32];
+            let account = AccountLayout::Packed.decode(entry).unwrap();
+            contracts += (account.code_hash != Some([0u8; 32])) as u64;
+        }
+        assert_eq!(contracts, 50);
+
+        let storage = StorageMapping::open(dir.join("storage-mapping.bin")).unwrap();
+        assert_eq!(storage.len(), 2000);
+        let mut zeros = 0;
+        for i in 0..storage.len() {
+            let (key, index) = storage.record(i).unwrap();
+            assert_eq!(index, 1000 + i);
+            assert_eq!(storage.get(&key[..20], &key[20..]), Some(index));
+            assert!(accounts.get(&key[..20]).is_some());
+            let value = &db.mmap[index as usize * 32..(index as usize + 1) * 32];
+            zeros += value.iter().all(|&b| b == 0) as u64;
+        }
+        assert!((400..800).contains(&zeros), "{} zero slots", zeros);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn formats_rfc3339() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(1_735_689_600), "2025-01-01T00:00:00Z");
+        assert_eq!(rfc3339(1_709_210_096), "2024-02-29T12:34:56Z");
+    }
+}